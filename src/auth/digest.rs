@@ -0,0 +1,418 @@
+use crate::crypto::{ self, hmac::hmac_sha256, md5::md5, sha256::sha256 };
+use std::collections::HashMap;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+#[derive(Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn from_param(value: Option<&str>) -> Option<Self> {
+        match value {
+            None => Some(Algorithm::Md5),
+            Some(v) if v.eq_ignore_ascii_case("MD5") => Some(Algorithm::Md5),
+            Some(v) if v.eq_ignore_ascii_case("SHA-256") => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    fn hash_hex(self, data: &str) -> String {
+        match self {
+            Algorithm::Md5 => crypto::to_hex(&md5(data.as_bytes())),
+            Algorithm::Sha256 => crypto::to_hex(&sha256(data.as_bytes())),
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha256 => 64,
+        }
+    }
+}
+
+pub enum DigestOutcome {
+    Authorized(String),
+    Challenge {
+        stale: bool,
+    },
+}
+
+/// Server-side state for RFC 7616 Digest authentication.
+/// Nonces are a timestamp plus a MAC over it keyed by a per-process secret;
+/// the only state kept is the last `nc` accepted for each nonce in use.
+pub struct DigestAuth {
+    secret: Vec<u8>,
+    opaque: String,
+    nonce_lifetime: u64,
+    /// The highest `nc` accepted per unexpired nonce, so a captured
+    /// `Authorization` header cannot be sent again.
+    counts: HashMap<String, u64>,
+}
+
+impl DigestAuth {
+    pub fn new(nonce_lifetime: u64) -> Self {
+        Self {
+            secret: crypto::random_bytes(32),
+            opaque: crypto::to_hex(&crypto::random_bytes(16)),
+            nonce_lifetime,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Outstanding nonces stay valid; only their lifetime changes.
+    pub fn set_nonce_lifetime(&mut self, nonce_lifetime: u64) {
+        self.nonce_lifetime = nonce_lifetime;
+    }
+
+    pub fn challenge_headers(&self, realm: &str, stale: bool) -> Vec<(String, String)> {
+        let nonce = self.generate_nonce();
+        ["SHA-256", "MD5"]
+            .iter()
+            .map(|algorithm| {
+                let mut value = format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\", opaque=\"{}\"",
+                    realm,
+                    algorithm,
+                    nonce,
+                    self.opaque
+                );
+                if stale {
+                    value.push_str(", stale=true");
+                }
+                ("WWW-Authenticate".to_string(), value)
+            })
+            .collect()
+    }
+
+    pub fn verify(
+        &mut self,
+        authorization: Option<&str>,
+        method: &str,
+        request_uri: &str,
+        realm: &str,
+        user_file: &str
+    ) -> DigestOutcome {
+        let deny = DigestOutcome::Challenge { stale: false };
+
+        let params = match authorization.and_then(|h| h.trim().strip_prefix("Digest ")) {
+            Some(rest) => parse_params(rest),
+            None => {
+                return deny;
+            }
+        };
+
+        let (Some(username), Some(nonce), Some(uri), Some(response)) = (
+            params.get("username"),
+            params.get("nonce"),
+            params.get("uri"),
+            params.get("response"),
+        ) else {
+            return deny;
+        };
+
+        let algorithm = match Algorithm::from_param(params.get("algorithm").map(|s| s.as_str())) {
+            Some(a) => a,
+            None => {
+                return deny;
+            }
+        };
+
+        if params.get("realm").map(|r| r.as_str()) != Some(realm) || uri != request_uri {
+            return deny;
+        }
+        if params.get("opaque").is_some_and(|o| o != &self.opaque) {
+            return deny;
+        }
+
+        let nonce_fresh = match self.check_nonce(nonce) {
+            Some(fresh) => fresh,
+            None => {
+                return deny;
+            }
+        };
+
+        let ha1 = match lookup_ha1(user_file, username, realm, algorithm) {
+            Some(h) => h,
+            None => {
+                return deny;
+            }
+        };
+        let ha2 = algorithm.hash_hex(&format!("{}:{}", method, uri));
+
+        // Without `qop` there is no count, so such a response is good once
+        let (expected, count) = match params.get("qop").map(|s| s.as_str()) {
+            Some("auth") => {
+                let (Some(nc), Some(cnonce)) = (params.get("nc"), params.get("cnonce")) else {
+                    return deny;
+                };
+                let Ok(count) = u64::from_str_radix(nc, 16) else {
+                    return deny;
+                };
+                (algorithm.hash_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)), count)
+            }
+            None => (algorithm.hash_hex(&format!("{}:{}:{}", ha1, nonce, ha2)), 1),
+            Some(_) => {
+                return deny;
+            }
+        };
+
+        if !crypto::constant_time_eq(expected.as_bytes(), response.to_ascii_lowercase().as_bytes()) {
+            return deny;
+        }
+
+        // A correct response to an expired nonce only needs a fresh nonce, not new credentials
+        if !nonce_fresh {
+            return DigestOutcome::Challenge { stale: true };
+        }
+        // A replay, or requests arriving out of order: a fresh nonce lets the
+        // client answer again without asking the user
+        if !self.record_count(nonce, count) {
+            return DigestOutcome::Challenge { stale: true };
+        }
+
+        DigestOutcome::Authorized(username.clone())
+    }
+
+    /// Remembers `count` for `nonce` if it is above the last one, forgetting
+    /// nonces that have expired since.
+    fn record_count(&mut self, nonce: &str, count: u64) -> bool {
+        match self.counts.get_mut(nonce) {
+            Some(last) if count <= *last => false,
+            Some(last) => {
+                *last = count;
+                true
+            }
+            None => {
+                let now = unix_now();
+                let lifetime = self.nonce_lifetime;
+                self.counts.retain(|nonce, _| {
+                    u64::from_str_radix(&nonce[..16], 16).is_ok_and(|ts| now.saturating_sub(ts) <= lifetime)
+                });
+                self.counts.insert(nonce.to_string(), count);
+                true
+            }
+        }
+    }
+
+    fn generate_nonce(&self) -> String {
+        let ts = unix_now();
+        format!("{:016x}{}", ts, self.nonce_mac(ts))
+    }
+
+    /// Returns `None` for forged nonces, `Some(false)` for expired ones.
+    fn check_nonce(&self, nonce: &str) -> Option<bool> {
+        if nonce.len() != 16 + 64 || !nonce.is_ascii() {
+            return None;
+        }
+        let ts = u64::from_str_radix(&nonce[..16], 16).ok()?;
+        if !crypto::constant_time_eq(&nonce.as_bytes()[16..], self.nonce_mac(ts).as_bytes()) {
+            return None;
+        }
+
+        Some(unix_now().saturating_sub(ts) <= self.nonce_lifetime)
+    }
+
+    fn nonce_mac(&self, ts: u64) -> String {
+        crypto::to_hex(&hmac_sha256(&self.secret, &ts.to_be_bytes()))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads an htdigest-style file (`user:realm:HA1`). The HA1 length tells MD5 and SHA-256 entries apart.
+fn lookup_ha1(user_file: &str, username: &str, realm: &str, algorithm: Algorithm) -> Option<String> {
+    let content = std::fs::read_to_string(user_file).ok()?;
+    content.lines().find_map(|line| {
+        let mut parts = line.trim().splitn(3, ':');
+        let (user, line_realm, ha1) = (parts.next()?, parts.next()?, parts.next()?.trim());
+        if user == username && line_realm == realm && ha1.len() == algorithm.hex_len() {
+            Some(ha1.to_ascii_lowercase())
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+
+        let key: String = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        break;
+                    }
+                    _ => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+
+        params.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REALM: &str = "testrealm@host.com";
+
+    /// An htdigest file holding RFC 2617's example user, Mufasa / "Circle Of Life".
+    fn user_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("localserver-digest-{}-{}", name, std::process::id()));
+        let ha1 = Algorithm::Md5.hash_hex(&format!("Mufasa:{}:Circle Of Life", REALM));
+        std::fs::write(&path, format!("Mufasa:{}:{}\n", REALM, ha1)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// What a client sends for `password` after a challenge carrying `nonce`.
+    fn authorization(auth: &DigestAuth, nonce: &str, password: &str) -> String {
+        counted_authorization(auth, nonce, password, 1)
+    }
+
+    /// The same for the `nc`th request on that nonce.
+    fn counted_authorization(auth: &DigestAuth, nonce: &str, password: &str, nc: u32) -> String {
+        let ha1 = Algorithm::Md5.hash_hex(&format!("Mufasa:{}:{}", REALM, password));
+        let ha2 = Algorithm::Md5.hash_hex("GET:/dir/index.html");
+        let response = Algorithm::Md5.hash_hex(&format!("{}:{}:{:08x}:0a4f113b:auth:{}", ha1, nonce, nc, ha2));
+        format!(
+            "Digest username=\"Mufasa\", realm=\"{}\", nonce=\"{}\", uri=\"/dir/index.html\", qop=auth, nc={:08x}, cnonce=\"0a4f113b\", response=\"{}\", opaque=\"{}\"",
+            REALM,
+            nonce,
+            nc,
+            response,
+            auth.opaque
+        )
+    }
+
+    fn nonce_of(headers: &[(String, String)]) -> String {
+        let challenge = headers[0].1.strip_prefix("Digest ").unwrap();
+        parse_params(challenge).remove("nonce").unwrap()
+    }
+
+    fn outcome(result: DigestOutcome) -> Result<String, bool> {
+        match result {
+            DigestOutcome::Authorized(user) => Ok(user),
+            DigestOutcome::Challenge { stale } => Err(stale),
+        }
+    }
+
+    #[test]
+    fn computes_the_rfc_2617_example_response() {
+        let ha1 = Algorithm::Md5.hash_hex("Mufasa:testrealm@host.com:Circle Of Life");
+        assert_eq!(ha1, "939e7578ed9e3c518a452acee763bce9");
+        let ha2 = Algorithm::Md5.hash_hex("GET:/dir/index.html");
+        assert_eq!(ha2, "39aff3a2bab6126f332b942af96d3366");
+        let response = Algorithm::Md5.hash_hex(
+            &format!("{}:dcd98b7102dd2f0e8b11d0f600bfb0c093:00000001:0a4f113b:auth:{}", ha1, ha2)
+        );
+        assert_eq!(response, "6629fae49393a05397450978507c4ef1");
+    }
+
+    #[test]
+    fn challenges_and_accepts_a_correct_response() {
+        let users = user_file("accept");
+        let mut auth = DigestAuth::new(300);
+        let headers = auth.challenge_headers(REALM, false);
+        assert_eq!(headers.len(), 2);
+        assert!(headers[0].1.contains("algorithm=SHA-256"));
+        assert!(headers[1].1.contains("algorithm=MD5"));
+        assert!(!headers[0].1.contains("stale"));
+
+        let nonce = nonce_of(&headers);
+        let good = authorization(&auth, &nonce, "Circle Of Life");
+        assert_eq!(outcome(auth.verify(Some(&good), "GET", "/dir/index.html", REALM, &users)), Ok("Mufasa".to_string()));
+
+        let wrong = authorization(&auth, &nonce, "Circle of Life");
+        assert_eq!(outcome(auth.verify(Some(&wrong), "GET", "/dir/index.html", REALM, &users)), Err(false));
+        // The response covers the method and URI, so it cannot be replayed elsewhere
+        assert_eq!(outcome(auth.verify(Some(&good), "POST", "/dir/index.html", REALM, &users)), Err(false));
+        assert_eq!(outcome(auth.verify(Some(&good), "GET", "/other", REALM, &users)), Err(false));
+        assert_eq!(outcome(auth.verify(None, "GET", "/dir/index.html", REALM, &users)), Err(false));
+    }
+
+    #[test]
+    fn expired_nonces_are_stale_and_forged_ones_are_refused() {
+        let users = user_file("stale");
+        let mut auth = DigestAuth::new(300);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        let old = now - 301;
+        let expired = format!("{:016x}{}", old, auth.nonce_mac(old));
+        let answer = authorization(&auth, &expired, "Circle Of Life");
+        assert_eq!(outcome(auth.verify(Some(&answer), "GET", "/dir/index.html", REALM, &users)), Err(true));
+        // A wrong password on an expired nonce is no reason to ask again quietly
+        let wrong = authorization(&auth, &expired, "guess");
+        assert_eq!(outcome(auth.verify(Some(&wrong), "GET", "/dir/index.html", REALM, &users)), Err(false));
+        assert!(auth.challenge_headers(REALM, true)[0].1.ends_with(", stale=true"));
+
+        // Moving the timestamp forward breaks the MAC
+        let forged = format!("{:016x}{}", now, &expired[16..]);
+        let answer = authorization(&auth, &forged, "Circle Of Life");
+        assert_eq!(outcome(auth.verify(Some(&answer), "GET", "/dir/index.html", REALM, &users)), Err(false));
+
+        // Another process's nonces carry another secret
+        let other = DigestAuth::new(300);
+        let foreign = nonce_of(&other.challenge_headers(REALM, false));
+        let answer = authorization(&auth, &foreign, "Circle Of Life");
+        assert_eq!(outcome(auth.verify(Some(&answer), "GET", "/dir/index.html", REALM, &users)), Err(false));
+    }
+
+    #[test]
+    fn each_count_on_a_nonce_is_accepted_once() {
+        let users = user_file("replay");
+        let mut auth = DigestAuth::new(300);
+        let nonce = nonce_of(&auth.challenge_headers(REALM, false));
+        let headers: Vec<String> = (1..=4).map(|nc| counted_authorization(&auth, &nonce, "Circle Of Life", nc)).collect();
+        let wrong = counted_authorization(&auth, &nonce, "guess", 9);
+        let mut verify = |header: &str| outcome(auth.verify(Some(header), "GET", "/dir/index.html", REALM, &users));
+
+        assert_eq!(verify(&headers[0]), Ok("Mufasa".to_string()));
+        // The same header again is a replay; the client is offered a fresh nonce
+        assert_eq!(verify(&headers[0]), Err(true));
+        assert_eq!(verify(&headers[2]), Ok("Mufasa".to_string()));
+        assert_eq!(verify(&headers[1]), Err(true));
+        // A wrong password neither counts nor is taken for a replay
+        assert_eq!(verify(&wrong), Err(false));
+        assert_eq!(verify(&headers[3]), Ok("Mufasa".to_string()));
+    }
+}
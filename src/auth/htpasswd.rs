@@ -0,0 +1,221 @@
+use crate::crypto::{ base64, bcrypt, sha_crypt };
+use crate::log;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::time::SystemTime;
+
+/// The most expensive entries accepted. Each login is checked on the event
+/// loop, so a costlier hash would hold up every other connection meanwhile;
+/// at these limits one check takes a fraction of a second.
+pub const MAX_BCRYPT_COST: u32 = 12;
+pub const MAX_SHA512_ROUNDS: u32 = 100_000;
+
+/// Apache-style htpasswd files as last read, by path. Only bcrypt
+/// (`$2a$`/`$2b$`/`$2y$`) and SHA-512 crypt (`$6$`) entries are accepted. A
+/// file is read again once its modification time or size changes.
+#[derive(Default)]
+pub struct UserFiles {
+    files: HashMap<String, UserFile>,
+}
+
+/// A file's modification time and size.
+type Stamp = (Option<SystemTime>, u64);
+
+struct UserFile {
+    /// When read; `None` while the file cannot be read.
+    stamp: Option<Stamp>,
+    entries: HashMap<String, String>,
+}
+
+impl UserFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks a `user:password` pair against the file's entries.
+    pub fn verify(&mut self, user_file: &str, username: &str, password: &str) -> bool {
+        self.entries(user_file)
+            .get(username)
+            .is_some_and(|hash| verify_hash(password.as_bytes(), hash))
+    }
+
+    fn entries(&mut self, user_file: &str) -> &HashMap<String, String> {
+        let stamp = std::fs::metadata(user_file).map(|m| (m.modified().ok(), m.len()));
+        let cached = self.files.get(user_file).map(|file| file.stamp);
+        let reported = cached == Some(None);
+        let read = match stamp {
+            Ok(stamp) if cached == Some(Some(stamp)) => None,
+            Ok(stamp) => {
+                match std::fs::read_to_string(user_file) {
+                    Ok(content) => Some(UserFile { stamp: Some(stamp), entries: parse_entries(user_file, &content) }),
+                    Err(e) => Some(unreadable(user_file, &e, reported)),
+                }
+            }
+            Err(e) => Some(unreadable(user_file, &e, reported)),
+        };
+        if let Some(file) = read {
+            self.files.insert(user_file.to_string(), file);
+        }
+        &self.files[user_file].entries
+    }
+}
+
+/// An empty file in place of one that cannot be read, reported when it stops
+/// being readable rather than on every login.
+fn unreadable(user_file: &str, error: &std::io::Error, reported: bool) -> UserFile {
+    if !reported {
+        log::error!("[Auth] Could not read htpasswd file {}: {}", user_file, error);
+    }
+    UserFile { stamp: None, entries: HashMap::new() }
+}
+
+/// The first entry for each user, without the ones too costly to check.
+fn parse_entries(user_file: &str, content: &str) -> HashMap<String, String> {
+    let mut entries = HashMap::new();
+    for (user, hash) in content.lines().filter_map(|line| line.trim().split_once(':')) {
+        if let Some(cost) = excess_cost(hash) {
+            log::warning!("[Auth] {}: ignoring the entry for '{}', {}", user_file, user, cost);
+            continue;
+        }
+        entries.entry(user.to_string()).or_insert_with(|| hash.to_string());
+    }
+    entries
+}
+
+/// Why `hash` is above `MAX_BCRYPT_COST` or `MAX_SHA512_ROUNDS`, if it is.
+fn excess_cost(hash: &str) -> Option<String> {
+    if hash.starts_with("$2") {
+        let cost = hash.split('$').nth(2)?.parse::<u32>().ok()?;
+        (cost > MAX_BCRYPT_COST).then(|| format!("bcrypt cost {} is above {}", cost, MAX_BCRYPT_COST))
+    } else {
+        let rounds = hash.strip_prefix("$6$rounds=")?.split_once('$')?.0.parse::<u32>().ok()?;
+        (rounds > MAX_SHA512_ROUNDS).then(|| format!("{} SHA-512 crypt rounds are above {}", rounds, MAX_SHA512_ROUNDS))
+    }
+}
+
+/// Decodes the credentials of an `Authorization: Basic ...` header.
+pub fn parse_basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.trim().strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded)?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn verify_hash(password: &[u8], hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash)
+    } else if hash.starts_with("$6$") {
+        sha_crypt::verify_sha512_crypt(password, hash)
+    } else {
+        false
+    }
+}
+
+/// `localserver passwd [--bcrypt] [--cost N] <user>`: reads a password from stdin
+/// and prints an htpasswd line for it.
+pub fn run_passwd_command(args: &[String]) -> i32 {
+    let mut use_bcrypt = false;
+    let mut cost = bcrypt::DEFAULT_COST;
+    let mut username = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--bcrypt" => {
+                use_bcrypt = true;
+            }
+            "--cost" => {
+                match iter.next().and_then(|v| v.parse().ok()).filter(|c| (4..=MAX_BCRYPT_COST).contains(c)) {
+                    Some(c) => {
+                        cost = c;
+                    }
+                    None => {
+                        eprintln!("--cost expects a number between 4 and {}", MAX_BCRYPT_COST);
+                        return 2;
+                    }
+                }
+            }
+            other if username.is_none() && !other.starts_with('-') => {
+                username = Some(other.to_string());
+            }
+            other => {
+                eprintln!("Unexpected argument: {}", other);
+                return 2;
+            }
+        }
+    }
+
+    let Some(username) = username.filter(|u| !u.contains(':')) else {
+        eprintln!("Usage: localserver passwd [--bcrypt] [--cost N] <user>");
+        return 2;
+    };
+
+    let mut password = String::new();
+    if std::io::stdin().lock().read_line(&mut password).is_err() {
+        eprintln!("Failed to read password from stdin");
+        return 1;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    let hash = if use_bcrypt {
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&crate::crypto::random_bytes(16));
+        bcrypt::hash(password.as_bytes(), cost, &salt)
+    } else {
+        sha_crypt::sha512_crypt(password.as_bytes(), &sha_crypt::generate_salt(), None)
+    };
+
+    println!("{}:{}", username, hash);
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// bcrypt cost 5 and SHA-512 crypt with 10 000 rounds, both for "U*U".
+    const BOB: &str = "bob:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW";
+    const CAROL: &str =
+        "carol:$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.";
+
+    #[test]
+    fn entries_are_read_again_only_once_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("localserver-htpasswd-{}", std::process::id()));
+        let file = path.to_string_lossy().into_owned();
+        std::fs::write(&path, format!("{}\n", BOB)).unwrap();
+        let mut files = UserFiles::new();
+        assert!(files.verify(&file, "bob", "U*U"));
+        assert!(!files.verify(&file, "bob", "U*V"));
+        assert!(!files.verify(&file, "carol", "U*U"));
+
+        // Same size and time: the parsed copy is still used
+        let stamp = files.files[&file].stamp;
+        files.files.get_mut(&file).unwrap().entries.clear();
+        assert_eq!(files.files[&file].stamp, stamp);
+        assert!(!files.verify(&file, "bob", "U*U"));
+
+        std::fs::write(&path, format!("{}\n{}\n", BOB, CAROL)).unwrap();
+        assert!(files.verify(&file, "bob", "U*U"));
+        std::fs::remove_file(&path).unwrap();
+        assert!(!files.verify(&file, "bob", "U*U"));
+        assert_eq!(files.files[&file].stamp, None);
+    }
+
+    #[test]
+    fn entries_too_costly_to_check_are_left_out() {
+        let entries = parse_entries(
+            "users",
+            &format!(
+                "{}\n{}\nslow:$2b$13$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\nslower:$6$rounds=100001$salt$hash\nbob:second",
+                BOB,
+                CAROL
+            )
+        );
+        let mut users: Vec<&str> = entries.keys().map(String::as_str).collect();
+        users.sort();
+        assert_eq!(users, ["bob", "carol"]);
+        assert!(entries["bob"].starts_with("$2a$05$"));
+        assert_eq!(excess_cost("$2b$13$x").as_deref(), Some("bcrypt cost 13 is above 12"));
+        assert_eq!(excess_cost("$2b$12$x"), None);
+    }
+}
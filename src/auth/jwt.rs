@@ -0,0 +1,202 @@
+use crate::crypto::{ self, base64, hmac::hmac_sha256, rsa::RsaPublicKey };
+use crate::json::{ self, JsonValue };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+pub struct JwtSettings<'a> {
+    pub secret: Option<&'a str>,
+    pub public_key: Option<&'a RsaPublicKey>,
+    pub issuer: Option<&'a str>,
+    pub audience: Option<&'a str>,
+}
+
+/// Validates a compact JWS (`HS256` with a shared secret or `RS256` with a public key)
+/// and returns its claims. `alg: none` and algorithms without a configured key are rejected.
+pub fn verify(token: &str, settings: &JwtSettings) -> Result<JsonValue, String> {
+    let mut parts = token.split('.');
+    let (Some(header_b64), Some(payload_b64), Some(signature_b64), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Err("malformed token".to_string());
+    };
+
+    let header = decode_segment(header_b64)?;
+    let claims = decode_segment(payload_b64)?;
+    let signature = base64::decode_url(signature_b64).ok_or("bad signature encoding")?;
+    let signing_input = &token[..header_b64.len() + 1 + payload_b64.len()];
+
+    let valid = match header.get("alg").and_then(|a| a.as_str()) {
+        Some("HS256") => {
+            let secret = settings.secret.ok_or("HS256 not configured")?;
+            let expected = hmac_sha256(secret.as_bytes(), signing_input.as_bytes());
+            crypto::constant_time_eq(&expected, &signature)
+        }
+        Some("RS256") => {
+            let key = settings.public_key.ok_or("RS256 not configured")?;
+            key.verify_sha256(signing_input.as_bytes(), &signature)
+        }
+        _ => {
+            return Err("unsupported alg".to_string());
+        }
+    };
+    if !valid {
+        return Err("signature mismatch".to_string());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    if claims.get("exp").and_then(|v| v.as_f64()).is_some_and(|exp| now >= exp) {
+        return Err("token expired".to_string());
+    }
+    if claims.get("nbf").and_then(|v| v.as_f64()).is_some_and(|nbf| now < nbf) {
+        return Err("token not yet valid".to_string());
+    }
+
+    if let Some(issuer) = settings.issuer && claims.get("iss").and_then(|v| v.as_str()) != Some(issuer) {
+        return Err("issuer mismatch".to_string());
+    }
+    if let Some(audience) = settings.audience {
+        let matches = match claims.get("aud") {
+            Some(JsonValue::String(aud)) => aud == audience,
+            Some(JsonValue::Array(list)) => list.iter().any(|a| a.as_str() == Some(audience)),
+            _ => false,
+        };
+        if !matches {
+            return Err("audience mismatch".to_string());
+        }
+    }
+
+    Ok(claims)
+}
+
+/// Flattens top-level scalar claims into `JWT_CLAIM_<NAME>` variables for CGI scripts.
+pub fn claims_env(claims: &JsonValue) -> Vec<(String, String)> {
+    let JsonValue::Object(fields) = claims else {
+        return Vec::new();
+    };
+
+    fields
+        .iter()
+        .filter_map(|(name, value)| {
+            let text = match value {
+                JsonValue::String(s) => s.clone(),
+                JsonValue::Number(n) => n.to_string(),
+                JsonValue::Bool(b) => b.to_string(),
+                JsonValue::Array(items) =>
+                    items
+                        .iter()
+                        .filter_map(|i| i.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                _ => {
+                    return None;
+                }
+            };
+            if text.chars().any(|c| c.is_control()) {
+                return None;
+            }
+
+            let env_name: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect();
+            Some((format!("JWT_CLAIM_{}", env_name), text))
+        })
+        .collect()
+}
+
+fn decode_segment(segment: &str) -> Result<JsonValue, String> {
+    let bytes = base64::decode_url(segment).ok_or("bad base64url segment")?;
+    let text = String::from_utf8(bytes).map_err(|_| "segment is not UTF-8")?;
+    json::parse(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "jwt-secret";
+
+    fn segment(bytes: &[u8]) -> String {
+        base64::encode(bytes).trim_end_matches('=').replace('+', "-").replace('/', "_")
+    }
+
+    fn token(header: &str, claims: &str, secret: &str) -> String {
+        let input = format!("{}.{}", segment(header.as_bytes()), segment(claims.as_bytes()));
+        let signature = hmac_sha256(secret.as_bytes(), input.as_bytes());
+        format!("{}.{}", input, segment(&signature))
+    }
+
+    fn hs256(claims: &str) -> String {
+        token(r#"{"alg":"HS256","typ":"JWT"}"#, claims, SECRET)
+    }
+
+    fn settings() -> JwtSettings<'static> {
+        JwtSettings { secret: Some(SECRET), public_key: None, issuer: None, audience: None }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn accepts_a_signed_token_and_returns_its_claims() {
+        let claims = verify(&hs256(r#"{"sub":"alice","admin":true}"#), &settings()).unwrap();
+        assert_eq!(claims.get("sub").and_then(|v| v.as_str()), Some("alice"));
+    }
+
+    #[test]
+    fn refuses_unsigned_and_wrongly_signed_tokens() {
+        let claims = segment(br#"{"sub":"alice"}"#);
+        let none = format!("{}.{}.", segment(br#"{"alg":"none"}"#), claims);
+        assert_eq!(verify(&none, &settings()).unwrap_err(), "unsupported alg");
+        let none = token(r#"{"alg":"none"}"#, r#"{"sub":"alice"}"#, SECRET);
+        assert_eq!(verify(&none, &settings()).unwrap_err(), "unsupported alg");
+
+        let forged = token(r#"{"alg":"HS256"}"#, r#"{"sub":"alice"}"#, "guessed");
+        assert_eq!(verify(&forged, &settings()).unwrap_err(), "signature mismatch");
+        let signed = hs256(r#"{"sub":"alice"}"#);
+        let (_, signature) = signed.rsplit_once('.').unwrap();
+        let swapped = format!("{}.{}.{}", segment(br#"{"alg":"HS256","typ":"JWT"}"#), segment(br#"{"sub":"admin"}"#), signature);
+        assert_eq!(verify(&swapped, &settings()).unwrap_err(), "signature mismatch");
+
+        // RS256 without a configured key is not checked against the secret
+        let rs256 = token(r#"{"alg":"RS256"}"#, r#"{"sub":"alice"}"#, SECRET);
+        assert_eq!(verify(&rs256, &settings()).unwrap_err(), "RS256 not configured");
+        assert_eq!(verify("a.b", &settings()).unwrap_err(), "malformed token");
+        assert_eq!(verify(&format!("{}.x", signed), &settings()).unwrap_err(), "malformed token");
+    }
+
+    #[test]
+    fn checks_expiry_and_not_before() {
+        let now = now();
+        let valid = hs256(&format!(r#"{{"exp":{},"nbf":{}}}"#, now + 60, now - 60));
+        assert!(verify(&valid, &settings()).is_ok());
+        let expired = hs256(&format!(r#"{{"exp":{}}}"#, now - 1));
+        assert_eq!(verify(&expired, &settings()).unwrap_err(), "token expired");
+        let early = hs256(&format!(r#"{{"nbf":{}}}"#, now + 60));
+        assert_eq!(verify(&early, &settings()).unwrap_err(), "token not yet valid");
+    }
+
+    #[test]
+    fn checks_issuer_and_audience_when_configured() {
+        let settings = JwtSettings { issuer: Some("https://issuer.test"), audience: Some("localserver"), ..settings() };
+        let ok = hs256(r#"{"iss":"https://issuer.test","aud":"localserver"}"#);
+        assert!(verify(&ok, &settings).is_ok());
+        let listed = hs256(r#"{"iss":"https://issuer.test","aud":["other","localserver"]}"#);
+        assert!(verify(&listed, &settings).is_ok());
+
+        let wrong_issuer = hs256(r#"{"iss":"https://evil.test","aud":"localserver"}"#);
+        assert_eq!(verify(&wrong_issuer, &settings).unwrap_err(), "issuer mismatch");
+        let no_issuer = hs256(r#"{"aud":"localserver"}"#);
+        assert_eq!(verify(&no_issuer, &settings).unwrap_err(), "issuer mismatch");
+        let wrong_audience = hs256(r#"{"iss":"https://issuer.test","aud":["other"]}"#);
+        assert_eq!(verify(&wrong_audience, &settings).unwrap_err(), "audience mismatch");
+        let no_audience = hs256(r#"{"iss":"https://issuer.test"}"#);
+        assert_eq!(verify(&no_audience, &settings).unwrap_err(), "audience mismatch");
+    }
+}
@@ -0,0 +1,13 @@
+pub mod digest;
+pub mod htpasswd;
+pub mod jwt;
+
+pub use digest::{ DigestAuth, DigestOutcome };
+pub use htpasswd::UserFiles;
+
+/// Who a request was authenticated as, plus any extra variables to expose to CGI.
+pub struct AuthIdentity {
+    pub auth_type: &'static str,
+    pub user: String,
+    pub env: Vec<(String, String)>,
+}
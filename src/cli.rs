@@ -0,0 +1,261 @@
+//! Command-line handling for the server binary (the `passwd` and `init`
+//! subcommands parse their own arguments).
+
+use localserver::config::{ self, models::{ BindPolicy, Config } };
+use localserver::log::Level;
+use localserver::ServerError;
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: localserver [options]
+       localserver passwd [--bcrypt] [--cost N] <user>
+       localserver init [--force] [dir]
+
+Options:
+  -c, --config <path>       configuration file, or - to read it from stdin
+                            (default: config.yaml, config.toml or config.json)
+      --config-format <f>   yaml, toml or json instead of guessing from the name
+  -P, --profile <name>      apply a profile from 'profiles:' or config.<name>.yaml
+  -t, --test-config         check the configuration and exit without binding
+      --dump-config[=json]  print the resolved configuration (YAML by default)
+  -w, --watch               reload the configuration when the file changes
+      --dev                 reload browser pages when files under a route root change
+      --trace-http[=<n>]    log raw request and response heads, and n body bytes
+      --bind-policy <p>     strict: abort if any address cannot be bound
+                            best-effort: serve on the ones that could (default)
+  -p, --port <port>         listen port; without --config, serve --root ad hoc
+  -r, --root <dir>          document root; without --config, serve it ad hoc
+  -V, --version             print the version and exit
+  -h, --help                print this help and exit";
+
+pub enum Action {
+    Serve(Options),
+    Help,
+    Version,
+}
+
+#[derive(Default)]
+pub struct Options {
+    pub config: Option<String>,
+    pub config_format: Option<String>,
+    pub profile: Option<String>,
+    pub test_config: bool,
+    /// Output format for `--dump-config`.
+    pub dump_config: Option<String>,
+    pub watch: bool,
+    pub dev: bool,
+    /// Body bytes shown by `--trace-http`; `None` when not tracing.
+    pub trace_http: Option<usize>,
+    pub bind_policy: Option<BindPolicy>,
+    pub port: Option<String>,
+    pub root: Option<String>,
+}
+
+/// Parses everything after the program name.
+pub fn parse_args(args: &[String]) -> Result<Action, String> {
+    let mut options = Options::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        // Accept both `--port 8080` and `--port=8080`
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| iter.next().cloned())
+                .ok_or_else(|| format!("{} expects a value", name))
+        };
+
+        match flag {
+            "-h" | "--help" => {
+                return Ok(Action::Help);
+            }
+            "-V" | "--version" => {
+                return Ok(Action::Version);
+            }
+            "-t" | "--test-config" => {
+                options.test_config = true;
+            }
+            "-w" | "--watch" => {
+                options.watch = true;
+            }
+            "--dev" => {
+                options.dev = true;
+            }
+            "--trace-http" => {
+                let limit = inline.clone().unwrap_or_else(|| "0".to_string());
+                options.trace_http = Some(
+                    limit.parse().map_err(|_| format!("--trace-http expects a number of body bytes, found '{}'", limit))?
+                );
+            }
+            // The format is only taken inline so a following option is not swallowed
+            "--dump-config" => {
+                options.dump_config = Some(inline.clone().unwrap_or_else(|| "yaml".to_string()));
+            }
+            "-c" | "--config" => {
+                options.config = Some(value("--config")?);
+            }
+            "--config-format" => {
+                options.config_format = Some(value("--config-format")?);
+            }
+            "--bind-policy" => {
+                let policy = value("--bind-policy")?;
+                options.bind_policy = Some(
+                    BindPolicy::parse(&policy).ok_or_else(|| format!("--bind-policy expects strict or best-effort, found '{}'", policy))?
+                );
+            }
+            "-P" | "--profile" => {
+                options.profile = Some(value("--profile")?);
+            }
+            "-p" | "--port" => {
+                let port = value("--port")?;
+                if port.parse::<u16>().is_err() {
+                    return Err(format!("--port expects a port number, found '{}'", port));
+                }
+                options.port = Some(port);
+            }
+            "-r" | "--root" => {
+                options.root = Some(value("--root")?);
+            }
+            other => {
+                return Err(format!("Unexpected argument: {}", other));
+            }
+        }
+    }
+
+    Ok(Action::Serve(options))
+}
+
+impl Options {
+    /// Name used in config messages.
+    pub fn source(&self) -> String {
+        match self.config.as_deref() {
+            Some("-") => "<stdin>".to_string(),
+            Some(path) => path.to_string(),
+            None if self.is_adhoc() => "command line".to_string(),
+            None => default_config_path(self.config_format.as_deref()).to_string(),
+        }
+    }
+
+    /// `--port` / `--root` without `--config` serve a directory without any config file.
+    fn is_adhoc(&self) -> bool {
+        self.config.is_none() && (self.port.is_some() || self.root.is_some())
+    }
+
+    /// Loads the configuration, applies `--port` / `--root` on top of it, and
+    /// rejects listeners that could not all be bound.
+    pub fn load_config(&self) -> Result<Config, ServerError> {
+        let cfg = self.resolve_config()?;
+        let conflicts = config::listener_conflicts(&cfg);
+        if !conflicts.is_empty() {
+            return Err(
+                ServerError::Config(format!("{}: unusable listen addresses:\n  {}", self.source(), conflicts.join("\n  ")))
+            );
+        }
+        Ok(cfg)
+    }
+
+    /// The file the configuration is read from; `None` for stdin and ad hoc mode.
+    pub fn config_path(&self) -> Option<&str> {
+        match self.config.as_deref() {
+            Some("-") => None,
+            Some(path) => Some(path),
+            None if self.is_adhoc() => None,
+            None => Some(default_config_path(self.config_format.as_deref())),
+        }
+    }
+
+    fn resolve_config(&self) -> Result<Config, ServerError> {
+        let mut cfg = if self.is_adhoc() {
+            config::adhoc_config(self.port.as_deref().unwrap_or("8080"), self.root.as_deref().unwrap_or("."))
+        } else {
+            let path = self.config_path().unwrap_or("-");
+            config::parse_config(path, self.config_format.as_deref(), self.profile.as_deref())?
+        };
+
+        // Command-line flags beat the environment, which beats the file
+        let env = env_overrides()?;
+        if let Some(level) = env.log_level {
+            cfg.log_level = level;
+        }
+        if let Some(policy) = self.bind_policy {
+            cfg.bind_policy = policy;
+        }
+        let host = env.host;
+        let port = self.port.clone().or(env.port);
+        let root = self.root.clone().or(env.root);
+
+        for server in &mut cfg.servers {
+            if let Some(host) = &host {
+                server.host = host.clone();
+            }
+            if let Some(port) = &port {
+                server.port = port.clone();
+            }
+            if let Some(root) = &root {
+                for route in &mut server.routes {
+                    route.root = root.clone();
+                }
+            }
+        }
+        // A block with a port list would otherwise repeat itself on the one port
+        if port.is_some() {
+            cfg.servers.dedup_by(|b, a| a.host == b.host && a.port == b.port && a.server_names == b.server_names);
+        }
+        Ok(cfg)
+    }
+}
+
+/// `LOCALSERVER_*` settings for containers, applied on top of the file.
+#[derive(Default)]
+struct EnvOverrides {
+    host: Option<String>,
+    port: Option<String>,
+    root: Option<String>,
+    log_level: Option<Level>,
+}
+
+fn env_overrides() -> Result<EnvOverrides, String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let mut env = EnvOverrides {
+        host: var("LOCALSERVER_HOST"),
+        root: var("LOCALSERVER_ROOT"),
+        ..EnvOverrides::default()
+    };
+    if let Some(port) = var("LOCALSERVER_PORT") {
+        if port.trim().parse::<u16>().is_err() {
+            return Err(format!("LOCALSERVER_PORT expects a port number, found '{}'", port));
+        }
+        env.port = Some(port.trim().to_string());
+    }
+    if let Some(level) = var("LOCALSERVER_LOG_LEVEL") {
+        env.log_level = Some(
+            Level::parse(&level).ok_or_else(|| {
+                format!("LOCALSERVER_LOG_LEVEL expects error, warn, info or debug, found '{}'", level)
+            })?
+        );
+    }
+    Ok(env)
+}
+
+/// config.yaml stays the default; config.toml / config.json are picked up
+/// when they are the only one present (or match `--config-format`).
+fn default_config_path(format: Option<&str>) -> &'static str {
+    const CANDIDATES: [(&str, &str); 3] = [
+        ("yaml", "config.yaml"),
+        ("toml", "config.toml"),
+        ("json", "config.json"),
+    ];
+    if let Some(format) = format {
+        return CANDIDATES.iter()
+            .find(|(f, _)| *f == format)
+            .map_or("config.yaml", |(_, path)| path);
+    }
+    CANDIDATES.iter()
+        .map(|(_, path)| *path)
+        .find(|path| Path::new(path).exists())
+        .unwrap_or("config.yaml")
+}
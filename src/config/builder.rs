@@ -0,0 +1,564 @@
+//! Building a `Config` in code, for embedders and tests, with the same
+//! defaults as a config file:
+//!
+//! ```no_run
+//! use localserver::config::{ ConfigBuilder, RouteBuilder, ServerBlockBuilder };
+//!
+//! let config = ConfigBuilder::new()
+//!     .server(
+//!         ServerBlockBuilder::new("127.0.0.1", 8080)
+//!             .server_name("example.test")
+//!             .route(RouteBuilder::new("/").root("./www").methods(["GET", "HEAD"]).index("index.html"))
+//!     )
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use crate::config::models::{ self, Balance, BindPolicy, Canary, CanaryTrigger, CgiCache, Config, FixedResponse, HealthCheck, HostResolution, ProxyCache, ProxyProtocol, Robots, RouteConfig, ServerConfig, Sticky };
+use crate::config::parser::{ add_default_routes, default_config, default_route, default_server, inherit_error_pages, load_jwt_keys };
+use crate::config::validate::listener_conflicts;
+use crate::http::request::KNOWN_METHODS;
+use crate::error::ServerError;
+use crate::http::status::StatusCode;
+use crate::log::Level;
+use crate::server::forwarded::TrustedProxies;
+use crate::server::proxy::Upstream;
+use std::time::Duration;
+
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self { config: default_config() }
+    }
+
+    pub fn server(mut self, server: ServerBlockBuilder) -> Self {
+        self.config.servers.push(server.server);
+        self
+    }
+
+    /// Whole-request cap (headers and body) in bytes.
+    pub fn max_server_size(mut self, bytes: usize) -> Self {
+        self.config.max_server_size = bytes;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout_seconds = timeout;
+        self
+    }
+
+    pub fn cgi_timeout(mut self, timeout: Duration) -> Self {
+        self.config.cgi_timeout = Some(timeout);
+        self
+    }
+
+    pub fn session_secret(mut self, secret: &str) -> Self {
+        self.config.session_secret = Some(secret.to_string());
+        self
+    }
+
+    /// Refuses clients for `duration` once they cause `threshold` 4xx responses within `window`.
+    pub fn ban(mut self, threshold: usize, window: Duration, duration: Duration) -> Self {
+        self.config.ban_threshold = threshold;
+        self.config.ban_window_seconds = window;
+        self.config.ban_duration_seconds = duration;
+        self
+    }
+
+    pub fn trusted_proxy(mut self, cidr: &str) -> Self {
+        self.config.trusted_proxies.push(cidr.to_string());
+        self
+    }
+
+    pub fn mime_type(mut self, extension: &str, content_type: &str) -> Self {
+        self.config.mime_types.push((extension.to_string(), content_type.to_string()));
+        self
+    }
+
+    pub fn default_type(mut self, content_type: &str) -> Self {
+        self.config.default_type = Some(content_type.to_string());
+        self
+    }
+
+    /// `"off"` drops the `Server` header, `"version"` adds the crate version.
+    pub fn server_header(mut self, value: &str) -> Self {
+        self.config.server_header = models::server_header(value);
+        self
+    }
+
+    /// Inherited by every server block that does not set the same code.
+    pub fn error_page(mut self, code: StatusCode, path: &str) -> Self {
+        self.config.error_pages.insert(code, path.to_string());
+        self
+    }
+
+    /// For codes without their own page; see `ServerBlockBuilder::default_error_page`.
+    pub fn default_error_page(mut self, path: &str) -> Self {
+        self.config.default_error_page = Some(path.to_string());
+        self
+    }
+
+    pub fn log_level(mut self, level: Level) -> Self {
+        self.config.log_level = level;
+        self
+    }
+
+    /// Answers 503 with `Retry-After: retry_after` while `file` exists, except
+    /// for the `maintenance_allow` paths (`/healthz` unless changed).
+    pub fn maintenance(mut self, file: &str, retry_after: Duration) -> Self {
+        self.config.maintenance_file = Some(file.to_string());
+        self.config.maintenance_retry_after = retry_after;
+        self
+    }
+
+    pub fn maintenance_allow<'a>(mut self, paths: impl IntoIterator<Item = &'a str>) -> Self {
+        self.config.maintenance_allow = paths.into_iter().map(str::to_string).collect();
+        self
+    }
+
+    pub fn bind_policy(mut self, policy: BindPolicy) -> Self {
+        self.config.bind_policy = policy;
+        self
+    }
+
+    /// Checks what can be checked without touching the filesystem (listen
+    /// addresses, route paths, methods) and loads `jwt_public_key` files;
+    /// `validate_config` does the rest. Every problem is reported, one per line.
+    pub fn build(mut self) -> Result<Config, ServerError> {
+        let mut problems = listener_conflicts(&self.config);
+        for server in &self.config.servers {
+            if let Err(e) = TrustedProxies::parse_as("purge_allow", &server.purge_allow) {
+                problems.push(e);
+            }
+            for name in server.server_names.iter().filter(|name| !models::valid_server_name(name)) {
+                problems.push(format!("server_name '{}': '*' only fits '*.example.com' or 'www.example.*'", name));
+            }
+            for route in &server.routes {
+                if !route.path.starts_with('/') {
+                    problems.push(format!("route '{}': path must start with '/'", route.path));
+                }
+                for method in &route.methods {
+                    if !KNOWN_METHODS.contains(&method.as_str()) {
+                        problems.push(format!("route '{}': unknown method '{}'", route.path, method));
+                    }
+                }
+                if !route.redirect_code.is_redirect_code() {
+                    problems.push(format!("route '{}': {} is not a redirect status", route.path, route.redirect_code));
+                }
+                if
+                    let Some(fixed) = &route.fixed_response &&
+                    (fixed.code.is_informational() || fixed.code.is_redirection() || fixed.code.as_u16() >= 600)
+                {
+                    problems.push(format!("route '{}': {} is not a 2xx, 4xx or 5xx status", route.path, fixed.code));
+                }
+                let targets = route.proxy_pass.iter().chain(route.canary.iter().flat_map(|canary| &canary.proxy_pass));
+                for target in targets.filter(|target| Upstream::parse(target).is_none()) {
+                    problems.push(format!("route '{}': proxy_pass '{}' is not an http:// URL", route.path, target));
+                }
+                if let Some(target) = &route.mirror && Upstream::parse_bare(target).is_none() {
+                    problems.push(format!("route '{}': mirror '{}' is not host:port or an http:// URL", route.path, target));
+                }
+                if route.cgi_cache.is_some_and(|cache| cache.ttl.is_zero()) {
+                    problems.push(format!("route '{}': cgi_cache needs a ttl above zero", route.path));
+                }
+                if route.proxy_cache.as_ref().is_some_and(|cache| cache.force_ttl && cache.ttl.is_zero()) {
+                    problems.push(format!("route '{}': proxy_cache needs a ttl above zero for force_ttl", route.path));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            return Err(ServerError::Config(problems.join("\n")));
+        }
+
+        inherit_error_pages(&mut self.config);
+        add_default_routes(&mut self.config);
+        load_jwt_keys(&mut self.config).map_err(ServerError::Config)?;
+        Ok(self.config)
+    }
+}
+
+pub struct ServerBlockBuilder {
+    server: ServerConfig,
+}
+
+impl ServerBlockBuilder {
+    /// `host` may be a name such as `localhost`; port 0 picks a free port when the server binds.
+    pub fn new(host: &str, port: u16) -> Self {
+        let mut server = default_server();
+        server.host = host.to_string();
+        server.port = port.to_string();
+        Self { server }
+    }
+
+    /// The first name replaces the default `localhost`; later ones are added.
+    pub fn server_name(mut self, name: &str) -> Self {
+        if self.server.server_names == ["localhost"] {
+            self.server.server_names.clear();
+        }
+        self.server.server_names.push(name.to_string());
+        self
+    }
+
+    /// Whether a host name is bound on every address it resolves to (the default) or the first.
+    pub fn resolve(mut self, resolution: HostResolution) -> Self {
+        self.server.resolve = resolution;
+        self
+    }
+
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.server.max_body_size = bytes;
+        self
+    }
+
+    pub fn error_page(mut self, code: StatusCode, path: &str) -> Self {
+        self.server.error_pages.insert(code, path.to_string());
+        self
+    }
+
+    /// The page for clients whose `Accept-Language` prefers `language`. The first
+    /// language added also answers everyone else, unless `error_page` sets that page.
+    pub fn localized_error_page(mut self, code: StatusCode, language: &str, path: &str) -> Self {
+        self.server.error_pages.entry(code).or_insert_with(|| path.to_string());
+        self.server.localized_error_pages
+            .entry(code)
+            .or_default()
+            .push((language.to_ascii_lowercase(), path.to_string()));
+        self
+    }
+
+    /// A template for every code without its own page: `{{code}}`, `{{reason}}`,
+    /// `{{request_path}}` and `{{request_id}}` are filled in.
+    pub fn default_error_page(mut self, path: &str) -> Self {
+        self.server.default_error_page = Some(path.to_string());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.server.add_headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn https_redirect(mut self, enabled: bool) -> Self {
+        self.server.https_redirect = enabled;
+        self
+    }
+
+    pub fn robots(mut self, robots: Robots) -> Self {
+        self.server.robots = robots;
+        self
+    }
+
+    pub fn favicon(mut self, enabled: bool) -> Self {
+        self.server.favicon = enabled;
+        self
+    }
+
+    /// `GET <path>?id=<X-Upload-ID>` reports that upload's progress as JSON.
+    pub fn upload_progress(mut self, path: &str) -> Self {
+        self.server.upload_progress = Some(path.to_string());
+        self
+    }
+
+    /// Accepts `CONNECT` to the `host:port` patterns in `allow` and tunnels TCP there.
+    pub fn connect_proxy<'a>(mut self, allow: impl IntoIterator<Item = &'a str>) -> Self {
+        self.server.connect_proxy = true;
+        self.server.connect_allow = allow.into_iter().map(str::to_string).collect();
+        self
+    }
+
+    /// `GET <path>` reports every `proxy_pass` upstream's health as JSON.
+    pub fn upstream_status(mut self, path: &str) -> Self {
+        self.server.upstream_status = Some(path.to_string());
+        self
+    }
+
+    /// Reads a PROXY protocol preamble from `trusted_proxies` peers on this listener.
+    pub fn proxy_protocol(mut self, mode: ProxyProtocol) -> Self {
+        self.server.proxy_protocol = mode;
+        self
+    }
+
+    /// `PURGE <path>` from these addresses or CIDR ranges drops cached responses.
+    pub fn purge_allow<'a>(mut self, allow: impl IntoIterator<Item = &'a str>) -> Self {
+        self.server.purge_allow = allow.into_iter().map(str::to_string).collect();
+        self
+    }
+
+    pub fn route(mut self, route: RouteBuilder) -> Self {
+        self.server.routes.push(route.route);
+        self
+    }
+}
+
+pub struct RouteBuilder {
+    route: RouteConfig,
+}
+
+impl RouteBuilder {
+    pub fn new(path: &str) -> Self {
+        let mut route = default_route();
+        route.path = path.to_string();
+        Self { route }
+    }
+
+    pub fn root(mut self, root: &str) -> Self {
+        self.route.root = root.to_string();
+        self
+    }
+
+    /// Tried after `root` (and earlier fallbacks) for files it does not have.
+    pub fn fallback_root(mut self, dir: &str) -> Self {
+        self.route.fallback_roots.push(dir.to_string());
+        self
+    }
+
+    /// Allowed methods; none means every method is allowed.
+    pub fn methods<'a>(mut self, methods: impl IntoIterator<Item = &'a str>) -> Self {
+        self.route.methods = methods
+            .into_iter()
+            .map(|m| m.to_string())
+            .collect();
+        self
+    }
+
+    pub fn index(mut self, file: &str) -> Self {
+        self.route.index = Some(file.to_string());
+        self
+    }
+
+    pub fn autoindex(mut self, enabled: bool) -> Self {
+        self.route.autoindex = enabled;
+        self
+    }
+
+    pub fn upload_dir(mut self, dir: &str) -> Self {
+        self.route.upload_dir = Some(dir.to_string());
+        self
+    }
+
+    /// Rejects uploads whose content does not match their claimed type with a 422.
+    pub fn verify_uploads(mut self, enabled: bool) -> Self {
+        self.route.verify_uploads = enabled;
+        self
+    }
+
+    /// `target` may use the same variables as `redirect:` in a config file.
+    pub fn redirect(mut self, target: &str, code: StatusCode) -> Self {
+        self.route.redirect = Some(target.to_string());
+        self.route.redirect_code = code;
+        self
+    }
+
+    /// Answers every request with this response, like `return:` in a config file.
+    pub fn fixed_response(mut self, code: StatusCode, content_type: &str, body: &str) -> Self {
+        self.route.fixed_response = Some(FixedResponse {
+            code,
+            body: body.to_string(),
+            content_type: content_type.to_string(),
+        });
+        self
+    }
+
+    pub fn cgi(mut self, extension: &str, interpreter: &str) -> Self {
+        self.route.cgi_extension = Some(extension.to_string());
+        self.route.cgi_interpreter = Some(interpreter.to_string());
+        self
+    }
+
+    /// Answers from a handler registered under `name` (see `Pipeline::register_handler`).
+    pub fn handler(mut self, name: &str) -> Self {
+        self.route.handler = Some(name.to_string());
+        self
+    }
+
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.route.max_body_size = Some(bytes);
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.route.add_headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Replaces `from` with `to` in response bodies (HTML unless `sub_filter_types` says otherwise).
+    pub fn sub_filter(mut self, from: &str, to: &str) -> Self {
+        self.route.sub_filter.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    /// Serves `.md` files as HTML pages; `template` replaces the built-in page
+    /// around them (`{{title}}`, `{{content}}`).
+    pub fn markdown(mut self, template: Option<&str>) -> Self {
+        self.route.markdown = true;
+        self.route.markdown_template = template.map(str::to_string);
+        self
+    }
+
+    /// Requests whose `header` is `value` (any value when `None`) are served from `root`.
+    pub fn canary_header(mut self, header: &str, value: Option<&str>, root: &str) -> Self {
+        let trigger = CanaryTrigger::Header(header.to_ascii_lowercase());
+        self.route.canary = Some(Canary {
+            trigger,
+            value: value.map(str::to_string),
+            root: Some(root.to_string()),
+            proxy_pass: Vec::new(),
+        });
+        self
+    }
+
+    /// Same as `canary_header`, keyed by a cookie.
+    pub fn canary_cookie(mut self, cookie: &str, value: Option<&str>, root: &str) -> Self {
+        let trigger = CanaryTrigger::Cookie(cookie.to_string());
+        self.route.canary = Some(Canary {
+            trigger,
+            value: value.map(str::to_string),
+            root: Some(root.to_string()),
+            proxy_pass: Vec::new(),
+        });
+        self
+    }
+
+    /// Requests whose `header` is `value` (any value when `None`) go to the
+    /// `http://host:port[/path]` upstream `target` instead.
+    pub fn canary_header_proxy(mut self, header: &str, value: Option<&str>, target: &str) -> Self {
+        let trigger = CanaryTrigger::Header(header.to_ascii_lowercase());
+        self.route.canary = Some(Canary {
+            trigger,
+            value: value.map(str::to_string),
+            root: None,
+            proxy_pass: vec![target.to_string()],
+        });
+        self
+    }
+
+    /// Same as `canary_header_proxy`, keyed by a cookie.
+    pub fn canary_cookie_proxy(mut self, cookie: &str, value: Option<&str>, target: &str) -> Self {
+        let trigger = CanaryTrigger::Cookie(cookie.to_string());
+        self.route.canary = Some(Canary {
+            trigger,
+            value: value.map(str::to_string),
+            root: None,
+            proxy_pass: vec![target.to_string()],
+        });
+        self
+    }
+
+    pub fn sub_filter_types<'a>(mut self, types: impl IntoIterator<Item = &'a str>) -> Self {
+        self.route.sub_filter_types = types.into_iter().map(str::to_string).collect();
+        self
+    }
+
+    pub fn basic_auth(mut self, user_file: &str) -> Self {
+        self.route.auth = Some("basic".to_string());
+        self.route.auth_user_file = Some(user_file.to_string());
+        self
+    }
+
+    /// Requires an `HS256` bearer token signed with `secret`.
+    pub fn jwt_secret(mut self, secret: &str) -> Self {
+        self.route.auth = Some("jwt".to_string());
+        self.route.jwt_secret = Some(secret.to_string());
+        self
+    }
+
+    /// Requires an `RS256` bearer token signed for the PEM public key in `path`.
+    pub fn jwt_public_key(mut self, path: &str) -> Self {
+        self.route.auth = Some("jwt".to_string());
+        self.route.jwt_public_key = Some(path.to_string());
+        self
+    }
+
+    /// Bearer tokens must carry this `iss` and `aud` (either may be `None`).
+    pub fn jwt_claims(mut self, issuer: Option<&str>, audience: Option<&str>) -> Self {
+        self.route.jwt_issuer = issuer.map(str::to_string);
+        self.route.jwt_audience = audience.map(str::to_string);
+        self
+    }
+
+    pub fn csrf(mut self, enabled: bool) -> Self {
+        self.route.csrf = enabled;
+        self
+    }
+
+    pub fn websocket(mut self, enabled: bool) -> Self {
+        self.route.websocket = enabled;
+        self
+    }
+
+    pub fn sse(mut self, enabled: bool) -> Self {
+        self.route.sse = enabled;
+        self
+    }
+
+    /// Forwards the route's requests to `http://host:port[/path]` and relays the
+    /// answers; each call adds an upstream to balance over.
+    pub fn proxy_pass(mut self, target: &str) -> Self {
+        self.route.proxy_pass.push(target.to_string());
+        self
+    }
+
+    /// Also sends each request to `host:port[/path]`, discarding the answer.
+    pub fn mirror(mut self, target: &str) -> Self {
+        self.route.mirror = Some(target.to_string());
+        self
+    }
+
+    pub fn proxy_balance(mut self, balance: Balance) -> Self {
+        self.route.proxy_balance = balance;
+        self
+    }
+
+    /// Keeps each client on one upstream, by address hash or by cookie.
+    pub fn proxy_sticky(mut self, sticky: Sticky) -> Self {
+        self.route.proxy_sticky = Some(sticky);
+        self
+    }
+
+    /// How long a failed upstream is skipped; `Duration::ZERO` never skips one.
+    pub fn proxy_fail_timeout(mut self, timeout: Duration) -> Self {
+        self.route.proxy_fail_timeout = timeout;
+        self
+    }
+
+    /// Caps a request's attempts at `tries` upstreams (0 for each once) and stops
+    /// moving on to the next one `budget` after it arrived (zero for no limit).
+    pub fn proxy_next_upstream(mut self, tries: usize, budget: Duration) -> Self {
+        self.route.proxy_next_upstream_tries = tries;
+        self.route.proxy_next_upstream_timeout = budget;
+        self
+    }
+
+    /// Probes the route's upstreams every `health.interval` and leaves out those failing.
+    pub fn proxy_health(mut self, health: HealthCheck) -> Self {
+        self.route.proxy_health = Some(health);
+        self
+    }
+
+    /// Adds `Forwarded: for=...;proto=http;host=...` (RFC 7239) to upstream requests.
+    pub fn proxy_forwarded(mut self, enabled: bool) -> Self {
+        self.route.proxy_forwarded = enabled;
+        self
+    }
+
+    /// Keeps the route's upstream answers as their headers allow; see `ProxyCache`.
+    pub fn proxy_cache(mut self, cache: ProxyCache) -> Self {
+        self.route.proxy_cache = Some(cache);
+        self
+    }
+
+    /// Reuses the route's successful CGI GET responses for `ttl`, keeping at most `max_size` bytes.
+    pub fn cgi_cache(mut self, ttl: Duration, max_size: usize) -> Self {
+        self.route.cgi_cache = Some(CgiCache { ttl, max_size });
+        self
+    }
+}
@@ -0,0 +1,341 @@
+//! `--dump-config`: the configuration as the server will use it, with defaults
+//! filled in, includes merged, sizes in bytes and routes in matching order.
+
+use crate::config::models::{ CanaryTrigger, Config, Probe, ProxyProtocol, RouteConfig, ServerConfig, Sticky };
+use crate::config::units;
+use crate::error::ServerError;
+use crate::http::status::StatusCode;
+use crate::http::mime;
+use crate::json::{ self, JsonValue };
+use std::time::Duration;
+
+const REDACTED: &str = "<redacted>";
+
+pub fn dump_config(config: &Config, format: &str) -> Result<String, ServerError> {
+    let tree = config_tree(config);
+    match format {
+        "yaml" | "yml" => {
+            let mut out = String::new();
+            write_yaml_map(&mut out, &tree, 0);
+            Ok(out)
+        }
+        "json" => Ok(json::to_string_pretty(&tree) + "\n"),
+        other => Err(ServerError::Config(format!("unknown dump format '{}' (expected yaml or json)", other))),
+    }
+}
+
+fn config_tree(config: &Config) -> JsonValue {
+    JsonValue::Object(
+        vec![
+            field("max_server_size", size(config.max_server_size)),
+            field("timeout_seconds", duration(config.timeout_seconds)),
+            field("cgi_timeout", duration(config.cgi_timeout.unwrap_or(config.timeout_seconds))),
+            field("access_log_exclude", strings(&config.access_log_exclude)),
+            field("maintenance_file", opt(config.maintenance_file.as_deref())),
+            field("maintenance_retry_after", duration(config.maintenance_retry_after)),
+            field("maintenance_allow", strings(&config.maintenance_allow)),
+            field("auth_nonce_timeout", duration(config.auth_nonce_timeout)),
+            field("user", opt(config.user.as_deref())),
+            field("group", opt(config.group.as_deref())),
+            field("chroot", opt(config.chroot.as_deref())),
+            field("ban_threshold", size(config.ban_threshold)),
+            field("ban_window_seconds", duration(config.ban_window_seconds)),
+            field("ban_duration_seconds", duration(config.ban_duration_seconds)),
+            field("session_secret", secret(config.session_secret.as_deref())),
+            field("trusted_proxies", strings(&config.trusted_proxies)),
+            field("mime_types", pairs(&config.mime_types)),
+            field("default_type", text(config.default_type.as_deref().unwrap_or(mime::DEFAULT_TYPE))),
+            field("log_level", text(config.log_level.name())),
+            field("bind_policy", text(config.bind_policy.name())),
+            field("server_header", text(config.server_header.as_deref().unwrap_or("off"))),
+            field("servers", JsonValue::Array(config.servers.iter().map(server_tree).collect()))
+        ]
+    )
+}
+
+fn server_tree(server: &ServerConfig) -> JsonValue {
+    let mut error_pages: Vec<(&StatusCode, &String)> = server.error_pages.iter().collect();
+    error_pages.sort();
+
+    // Longest prefix wins when matching, so list routes in that order
+    let mut routes: Vec<&RouteConfig> = server.routes.iter().collect();
+    routes.sort_by_key(|r| std::cmp::Reverse(r.path.len()));
+
+    JsonValue::Object(
+        vec![
+            field("host", text(&server.host)),
+            field("port", text(&server.port)),
+            field("resolve", text(server.resolve.name())),
+            field("server_name", strings(&server.server_names)),
+            field("max_body_size", size(server.max_body_size)),
+            field("https_redirect", JsonValue::Bool(server.https_redirect)),
+            field("https_port", opt(server.https_port.as_deref())),
+            field(
+                "proxy_protocol",
+                text(match server.proxy_protocol {
+                    ProxyProtocol::Off => "off",
+                    ProxyProtocol::Optional => "optional",
+                    ProxyProtocol::Required => "required",
+                })
+            ),
+            field("connect_proxy", JsonValue::Bool(server.connect_proxy)),
+            field("connect_allow", strings(&server.connect_allow)),
+            field("upload_progress", opt(server.upload_progress.as_deref())),
+            field("upstream_status", opt(server.upstream_status.as_deref())),
+            field("purge_allow", strings(&server.purge_allow)),
+            field("robots", text(server.robots.name())),
+            field("favicon", JsonValue::Bool(server.favicon)),
+            field("add_headers", pairs(&server.add_headers)),
+            field(
+                "error_pages",
+                JsonValue::Object(
+                    error_pages
+                        .into_iter()
+                        .map(|(code, page)| {
+                            let value = match server.localized_error_pages.get(code) {
+                                Some(languages) => {
+                                    let mut variants = languages.clone();
+                                    variants.push(("default".to_string(), page.clone()));
+                                    pairs(&variants)
+                                }
+                                None => text(page),
+                            };
+                            (code.to_string(), value)
+                        })
+                        .chain(server.default_error_page.iter().map(|page| ("default".to_string(), text(page))))
+                        .collect()
+                )
+            ),
+            field("routes", JsonValue::Array(routes.into_iter().map(route_tree).collect()))
+        ]
+    )
+}
+
+fn route_tree(route: &RouteConfig) -> JsonValue {
+    JsonValue::Object(
+        vec![
+            field("path", text(&route.path)),
+            field(
+                "root",
+                if route.fallback_roots.is_empty() {
+                    text(&route.root)
+                } else {
+                    JsonValue::Array(
+                        std::iter::once(&route.root)
+                            .chain(&route.fallback_roots)
+                            .map(|r| text(r))
+                            .collect()
+                    )
+                }
+            ),
+            field("methods", strings(&route.methods)),
+            field("index", opt(route.index.as_deref())),
+            field("autoindex", JsonValue::Bool(route.autoindex)),
+            field(
+                "redirect",
+                route.redirect.as_deref().map_or(JsonValue::Null, |to| {
+                    JsonValue::Object(
+                        vec![field("to", text(to)), field("code", JsonValue::Number(route.redirect_code.as_u16() as f64))]
+                    )
+                })
+            ),
+            field(
+                "return",
+                route.fixed_response.as_ref().map_or(JsonValue::Null, |fixed| {
+                    JsonValue::Object(
+                        vec![
+                            field("code", JsonValue::Number(fixed.code.as_u16() as f64)),
+                            field("body", text(&fixed.body)),
+                            field("content_type", text(&fixed.content_type))
+                        ]
+                    )
+                })
+            ),
+            field("upload_dir", opt(route.upload_dir.as_deref())),
+            field("verify_uploads", JsonValue::Bool(route.verify_uploads)),
+            field("max_body_size", route.max_body_size.map_or(JsonValue::Null, size)),
+            field("cgi_extension", opt(route.cgi_extension.as_deref())),
+            field("cgi_interpreter", opt(route.cgi_interpreter.as_deref())),
+            field(
+                "cgi_cache",
+                route.cgi_cache.map_or(JsonValue::Null, |cache| {
+                    JsonValue::Object(vec![field("ttl", duration(cache.ttl)), field("max_size", size(cache.max_size))])
+                })
+            ),
+            field("access_log", JsonValue::Bool(route.access_log)),
+            field("auth", opt(route.auth.as_deref())),
+            field("auth_realm", opt(route.auth_realm.as_deref())),
+            field("auth_user_file", opt(route.auth_user_file.as_deref())),
+            field("jwt_secret", secret(route.jwt_secret.as_deref())),
+            field("jwt_public_key", opt(route.jwt_public_key.as_deref())),
+            field("jwt_issuer", opt(route.jwt_issuer.as_deref())),
+            field("jwt_audience", opt(route.jwt_audience.as_deref())),
+            field("add_headers", pairs(&route.add_headers)),
+            field("sub_filter", pairs(&route.sub_filter)),
+            field("sub_filter_types", strings(&route.sub_filter_types)),
+            field("markdown", JsonValue::Bool(route.markdown)),
+            field("markdown_template", opt(route.markdown_template.as_deref())),
+            field(
+                "canary",
+                route.canary.as_ref().map_or(JsonValue::Null, |canary| {
+                    let trigger = match &canary.trigger {
+                        CanaryTrigger::Header(name) => field("header", text(name)),
+                        CanaryTrigger::Cookie(name) => field("cookie", text(name)),
+                    };
+                    JsonValue::Object(vec![
+                        trigger,
+                        field("value", opt(canary.value.as_deref())),
+                        field("root", opt(canary.root.as_deref())),
+                        field("proxy_pass", strings(&canary.proxy_pass)),
+                    ])
+                })
+            ),
+            field("csrf", JsonValue::Bool(route.csrf)),
+            field("websocket", JsonValue::Bool(route.websocket)),
+            field("sse", JsonValue::Bool(route.sse)),
+            field("mirror", opt(route.mirror.as_deref())),
+            field("proxy_pass", strings(&route.proxy_pass)),
+            field("proxy_balance", text(route.proxy_balance.name())),
+            field("proxy_sticky", opt(route.proxy_sticky.map(Sticky::name))),
+            field("proxy_fail_timeout", duration(route.proxy_fail_timeout)),
+            field("proxy_forwarded", JsonValue::Bool(route.proxy_forwarded)),
+            field("proxy_next_upstream_tries", JsonValue::Number(route.proxy_next_upstream_tries as f64)),
+            field("proxy_next_upstream_timeout", duration(route.proxy_next_upstream_timeout)),
+            field(
+                "proxy_cache",
+                route.proxy_cache.as_ref().map_or(JsonValue::Null, |cache| {
+                    JsonValue::Object(vec![
+                        field("ttl", duration(cache.ttl)),
+                        field("force_ttl", JsonValue::Bool(cache.force_ttl)),
+                        field("max_size", size(cache.max_size)),
+                        field("stale", duration(cache.stale)),
+                        field("key", strings(&cache.key)),
+                    ])
+                })
+            ),
+            field(
+                "proxy_health",
+                route.proxy_health.as_ref().map_or(JsonValue::Null, |health| {
+                    let check = match &health.probe {
+                        Probe::Connect => "tcp",
+                        Probe::Get(path) => path,
+                    };
+                    JsonValue::Object(
+                        vec![
+                            field("check", text(check)),
+                            field("interval", duration(health.interval)),
+                            field("fails", JsonValue::Number(health.fails as f64)),
+                            field("passes", JsonValue::Number(health.passes as f64))
+                        ]
+                    )
+                })
+            ),
+            field("handler", opt(route.handler.as_deref()))
+        ]
+    )
+}
+
+fn field(key: &str, value: JsonValue) -> (String, JsonValue) {
+    (key.to_string(), value)
+}
+
+fn text(value: &str) -> JsonValue {
+    JsonValue::String(value.to_string())
+}
+
+fn opt(value: Option<&str>) -> JsonValue {
+    value.map_or(JsonValue::Null, text)
+}
+
+fn secret(value: Option<&str>) -> JsonValue {
+    value.map_or(JsonValue::Null, |_| text(REDACTED))
+}
+
+fn size(value: usize) -> JsonValue {
+    JsonValue::Number(value as f64)
+}
+
+fn duration(value: Duration) -> JsonValue {
+    text(&units::format_duration(value))
+}
+
+fn strings(values: &[String]) -> JsonValue {
+    JsonValue::Array(values.iter().map(|v| text(v)).collect())
+}
+
+fn pairs(values: &[(String, String)]) -> JsonValue {
+    JsonValue::Object(
+        values
+            .iter()
+            .map(|(k, v)| (k.clone(), text(v)))
+            .collect()
+    )
+}
+
+fn write_yaml_map(out: &mut String, value: &JsonValue, indent: usize) {
+    let JsonValue::Object(fields) = value else {
+        return;
+    };
+    for (i, (key, item)) in fields.iter().enumerate() {
+        // Map items of a sequence start on the `- ` line
+        if i > 0 || !out.ends_with("- ") {
+            out.push_str(&" ".repeat(indent));
+        }
+        out.push_str(&yaml_scalar(key));
+        out.push(':');
+        write_yaml_child(out, item, indent);
+    }
+}
+
+fn write_yaml_child(out: &mut String, value: &JsonValue, indent: usize) {
+    match value {
+        JsonValue::Object(fields) if !fields.is_empty() => {
+            out.push('\n');
+            write_yaml_map(out, value, indent + 2);
+        }
+        JsonValue::Array(items) if !items.is_empty() => {
+            out.push('\n');
+            for item in items {
+                out.push_str(&" ".repeat(indent + 2));
+                out.push_str("- ");
+                match item {
+                    JsonValue::Object(fields) if !fields.is_empty() => write_yaml_map(out, item, indent + 4),
+                    _ => {
+                        out.push_str(&yaml_inline(item));
+                        out.push('\n');
+                    }
+                }
+            }
+        }
+        _ => {
+            out.push(' ');
+            out.push_str(&yaml_inline(value));
+            out.push('\n');
+        }
+    }
+}
+
+fn yaml_inline(value: &JsonValue) -> String {
+    match value {
+        JsonValue::Null => "null".to_string(),
+        JsonValue::String(s) => yaml_scalar(s),
+        JsonValue::Array(_) => "[]".to_string(),
+        JsonValue::Object(_) => "{}".to_string(),
+        other => json::to_string_pretty(other),
+    }
+}
+
+/// Plain when it reads back unchanged, double-quoted otherwise.
+fn yaml_scalar(value: &str) -> String {
+    let plain =
+        !value.is_empty() &&
+        !matches!(value, "null" | "~" | "true" | "false" | "yes" | "no" | "on" | "off") &&
+        value.starts_with(|c: char| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_')) &&
+        value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_' | '-' | '+' | '@'));
+    if plain {
+        return value.to_string();
+    }
+    let mut out = String::new();
+    json::writer::write_string(&mut out, value);
+    out
+}
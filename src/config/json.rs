@@ -0,0 +1,218 @@
+//! JSON reader for generated configs. Unlike `crate::json` it keeps the line of
+//! every value, so mistakes are reported as `file:line:` like the other formats.
+
+use crate::config::node::{ MapEntry, Node, NodeKind };
+
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+/// Parses a document; errors are `line: message`, the caller adds the file name.
+pub fn parse(content: &str) -> Result<Node, String> {
+    let mut parser = Parser { chars: content.chars().collect(), pos: 0, line: 1 };
+    let root = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    if let Some(c) = parser.peek() {
+        return Err(format!("{}: unexpected '{}' after the document", parser.line, c));
+    }
+    Ok(root)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| matches!(c, ' ' | '\t' | '\n' | '\r')) {
+            self.bump();
+        }
+    }
+
+    fn expect(&mut self, wanted: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.bump() {
+            Some(c) if c == wanted => Ok(()),
+            Some(c) => Err(format!("{}: expected '{}', found '{}'", self.line, wanted, c)),
+            None => Err(format!("{}: expected '{}' before end of file", self.line, wanted)),
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Node, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("{}: nesting too deep", self.line));
+        }
+        self.skip_whitespace();
+        let line = self.line;
+
+        let kind = match self.peek() {
+            Some('{') => {
+                self.bump();
+                let mut entries: Vec<MapEntry> = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.bump();
+                } else {
+                    loop {
+                        self.skip_whitespace();
+                        let key_line = self.line;
+                        if self.peek() != Some('"') {
+                            return Err(format!("{}: object keys must be quoted strings", key_line));
+                        }
+                        let key = self.parse_string()?;
+                        if entries.iter().any(|e| e.key == key) {
+                            return Err(format!("{}: duplicate key '{}'", key_line, key));
+                        }
+                        self.expect(':')?;
+                        let value = self.parse_value(depth + 1)?;
+                        entries.push(MapEntry { key, line: key_line, value });
+                        self.skip_whitespace();
+                        match self.bump() {
+                            Some(',') => {}
+                            Some('}') => {
+                                break;
+                            }
+                            _ => {
+                                return Err(format!("{}: expected ',' or '}}' in object", self.line));
+                            }
+                        }
+                    }
+                }
+                NodeKind::Map(entries)
+            }
+            Some('[') => {
+                self.bump();
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.bump();
+                } else {
+                    loop {
+                        items.push(self.parse_value(depth + 1)?);
+                        self.skip_whitespace();
+                        match self.bump() {
+                            Some(',') => {}
+                            Some(']') => {
+                                break;
+                            }
+                            _ => {
+                                return Err(format!("{}: expected ',' or ']' in array", self.line));
+                            }
+                        }
+                    }
+                }
+                NodeKind::Seq(items)
+            }
+            Some('"') => NodeKind::Scalar(self.parse_string()?),
+            Some(_) => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')) {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                match word.as_str() {
+                    "null" => NodeKind::Null,
+                    "true" | "false" => NodeKind::Scalar(word),
+                    _ if is_number(&word) => NodeKind::Scalar(word),
+                    _ => {
+                        let found = if word.is_empty() { self.peek().unwrap_or(' ').to_string() } else { word };
+                        return Err(format!("{}: unexpected '{}'", line, found));
+                    }
+                }
+            }
+            None => {
+                return Err(format!("{}: unexpected end of file", line));
+            }
+        };
+
+        Ok(Node { line, kind })
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    /// The four digits of a `\u` escape (fewer at the end of the input).
+    fn take_hex(&mut self) -> String {
+        let hex: String = self.chars.get(self.pos..).unwrap_or(&[]).iter().take(4).collect();
+        self.pos += hex.chars().count();
+        hex
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        let line = self.line;
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => {
+                    return Err(format!("{}: unterminated string", line));
+                }
+                Some('"') => {
+                    return Ok(out);
+                }
+                Some('\\') => {
+                    match self.bump() {
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some('r') => out.push('\r'),
+                        Some('b') => out.push('\u{8}'),
+                        Some('f') => out.push('\u{c}'),
+                        Some(c @ ('"' | '\\' | '/')) => out.push(c),
+                        Some('u') => {
+                            let invalid = |hex: &str| format!("{}: invalid unicode escape '\\u{}'", line, hex);
+                            let hex = self.take_hex();
+                            let unit = u32::from_str_radix(&hex, 16).map_err(|_| invalid(&hex))?;
+                            // Characters outside the BMP come as a surrogate pair, `\ud83d\ude00`
+                            let code = if (0xd800..0xdc00).contains(&unit) && self.starts_with("\\u") {
+                                self.pos += 2;
+                                let low_hex = self.take_hex();
+                                match u32::from_str_radix(&low_hex, 16) {
+                                    Ok(low) if (0xdc00..0xe000).contains(&low) => 0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00),
+                                    _ => {
+                                        return Err(invalid(&low_hex));
+                                    }
+                                }
+                            } else {
+                                unit
+                            };
+                            out.push(char::from_u32(code).ok_or_else(|| invalid(&hex))?);
+                        }
+                        other => {
+                            return Err(format!("{}: invalid escape '\\{}'", line, other.unwrap_or(' ')));
+                        }
+                    }
+                }
+                Some(c) => out.push(c),
+            }
+        }
+    }
+}
+
+/// RFC 8259 numbers: no leading `+`, leading zeros, bare `.5` / `1.`, `inf` or `NaN`.
+fn is_number(word: &str) -> bool {
+    let digits = |text: &str| !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit());
+    let unsigned = word.strip_prefix('-').unwrap_or(word);
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent.strip_prefix(['+', '-']).unwrap_or(exponent))),
+        None => (unsigned, None),
+    };
+    let (int, fraction) = match mantissa.split_once('.') {
+        Some((int, fraction)) => (int, Some(fraction)),
+        None => (mantissa, None),
+    };
+    digits(int) && (int == "0" || !int.starts_with('0')) && fraction.is_none_or(digits) && exponent.is_none_or(digits)
+}
@@ -1,4 +1,17 @@
 pub  mod  models;
 pub mod parser;
+mod builder;
+mod dump;
+mod json;
+mod node;
+mod overlay;
+mod toml;
+mod units;
+mod validate;
+mod yaml;
 
-pub use parser::parse_config;
\ No newline at end of file
+pub use builder::{ ConfigBuilder, RouteBuilder, ServerBlockBuilder };
+pub use dump::dump_config;
+pub use parser::{ adhoc_config, parse_config };
+pub use validate::{ listener_conflicts, validate_config };
+pub(crate) use validate::validate_reload;
@@ -1,27 +1,416 @@
+use crate::crypto::rsa::RsaPublicKey;
+use crate::http::status::StatusCode;
+use std::net::{ SocketAddr, ToSocketAddrs };
+use std::sync::Arc;
+use std::time::Duration;
+
 #[derive(Clone)]
 pub struct RouteConfig {
     pub path: String,
     pub root: String,
+    /// `root: [site, theme]`: directories after the first, tried in order for
+    /// files the first one lacks. Uploads and CGI only use `root`.
+    pub fallback_roots: Vec<String>,
     pub upload_dir: Option<String>,
+    /// Uploads whose leading bytes do not match the type their name or
+    /// `Content-Type` claims (or that are programs posing as documents) get a 422.
+    pub verify_uploads: bool,
     pub methods: Vec<String>,
     pub index: Option<String>,
     pub autoindex: bool,
     pub redirect: Option<String>,
+    /// `return:` answers every request with this response, before auth and
+    /// method checks, without touching `root`.
+    pub fixed_response: Option<FixedResponse>,
     pub cgi_extension: Option<String>,
     pub cgi_interpreter: Option<String>,
+    pub cgi_cache: Option<CgiCache>,
+    pub access_log: bool,
+    pub auth: Option<String>,
+    pub auth_realm: Option<String>,
+    pub auth_user_file: Option<String>,
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key: Option<String>,
+    /// `jwt_public_key` as read and parsed when the config was loaded.
+    pub(crate) jwt_key: Option<Arc<RsaPublicKey>>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub add_headers: Vec<(String, String)>,
+    /// `(from, to)` replacements applied to response bodies of `sub_filter_types`.
+    pub sub_filter: Vec<(String, String)>,
+    pub sub_filter_types: Vec<String>,
+    /// Renders `.md` files to HTML, in `markdown_template` when set.
+    pub markdown: bool,
+    pub markdown_template: Option<String>,
+    pub canary: Option<Canary>,
+    pub csrf: bool,
+    pub websocket: bool,
+    pub sse: bool,
+    pub mirror: Option<String>,
+    /// `http://host:port[/path]` of the upstream servers that answer the route's
+    /// requests; empty for none.
+    pub proxy_pass: Vec<String>,
+    pub proxy_balance: Balance,
+    pub proxy_sticky: Option<Sticky>,
+    /// How long an upstream is skipped after a failure; zero never skips it.
+    pub proxy_fail_timeout: Duration,
+    /// Also describes the client in an RFC 7239 `Forwarded` header upstream.
+    pub proxy_forwarded: bool,
+    /// Most upstreams one request is tried on; 0 for each of them once.
+    pub proxy_next_upstream_tries: usize,
+    /// How long after the request arrived a failed attempt may still move on
+    /// to the next upstream; zero for no limit.
+    pub proxy_next_upstream_timeout: Duration,
+    pub proxy_health: Option<HealthCheck>,
+    pub proxy_cache: Option<ProxyCache>,
+    /// Name of a handler registered with `Pipeline::register_handler`.
+    pub handler: Option<String>,
+    pub max_body_size: Option<usize>,
+    /// 301, 302, 303, 307 or 308; only used with `redirect`.
+    pub redirect_code: StatusCode,
+}
+
+/// `return: { code: 200, body: "ok", content_type: text/plain }`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedResponse {
+    /// A 2xx, 4xx or 5xx status; redirects use `redirect` instead.
+    pub code: StatusCode,
+    pub body: String,
+    pub content_type: String,
+}
+
+/// `cgi_cache: { ttl: 10s, max_size: 1MB }`: how long the route's CGI responses
+/// are reused, and how many bytes of them the route keeps at most.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CgiCache {
+    pub ttl: Duration,
+    pub max_size: usize,
+}
+
+/// `proxy_cache: { ttl: 10s, max_size: 1MB, stale: 30s, key: [accept-language] }`:
+/// upstream answers are kept for as long as their `Cache-Control` or `Expires`
+/// allows, or `ttl` when they say nothing (always `ttl` with `force_ttl`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyCache {
+    pub ttl: Duration,
+    pub force_ttl: bool,
+    pub max_size: usize,
+    /// How long past its lifetime a copy is still served while a fresh one is
+    /// fetched, unless the upstream gives `stale-while-revalidate` itself.
+    pub stale: Duration,
+    /// Request headers (lowercase) whose values are part of the key, besides
+    /// the path and query.
+    pub key: Vec<String>,
+}
+
+/// `proxy_health: { check: /healthz, interval: 5s, fails: 2, passes: 2 }`: how
+/// a route's upstreams are probed between requests. `fails` failed probes in a
+/// row take an upstream out of the rotation and `passes` good ones bring it back.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub fails: usize,
+    pub passes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Probe {
+    /// `check: tcp`: the connection comes up.
+    Connect,
+    /// `check: /path`: `GET /path` is answered with a 2xx or 3xx status.
+    Get(String),
+}
+
+/// `canary:` on a route: requests carrying the header or cookie are served
+/// from `root` instead of the route's own root, or by the `proxy_pass`
+/// upstreams instead of the route's own target. One of the two is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Canary {
+    pub trigger: CanaryTrigger,
+    /// The value that selects the canary; `None` takes any non-empty value.
+    pub value: Option<String>,
+    pub root: Option<String>,
+    pub proxy_pass: Vec<String>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum CanaryTrigger {
+    /// Lowercase, as request headers are stored.
+    Header(String),
+    Cookie(String),
+}
+
+/// HAProxy PROXY protocol handling for a listener. A preamble only sets the
+/// client address when the TCP peer is one of the `trusted_proxies`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProxyProtocol {
+    Off,
+    /// Strip a preamble if one is sent, otherwise treat the bytes as HTTP.
+    Optional,
+    /// Drop connections that do not start with a preamble from a trusted peer.
+    Required,
+}
+
+/// What `Server::bind` does when some listen addresses cannot be bound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindPolicy {
+    /// Serve on whatever could be bound; fail only when nothing could.
+    BestEffort,
+    /// Any failure aborts startup.
+    Strict,
+}
+
+impl BindPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "strict" => Some(BindPolicy::Strict),
+            "best_effort" | "best-effort" => Some(BindPolicy::BestEffort),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BindPolicy::BestEffort => "best_effort",
+            BindPolicy::Strict => "strict",
+        }
+    }
+}
+
+/// What `/robots.txt` says when the document root has none.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Robots {
+    AllowAll,
+    DisallowAll,
+    /// No built-in file: a missing `robots.txt` is a 404.
+    Off,
+}
+
+impl Robots {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "allow_all" | "allow-all" => Some(Robots::AllowAll),
+            "disallow_all" | "disallow-all" => Some(Robots::DisallowAll),
+            "off" | "false" => Some(Robots::Off),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Robots::AllowAll => "allow_all",
+            Robots::DisallowAll => "disallow_all",
+            Robots::Off => "off",
+        }
+    }
+
+    pub fn body(self) -> Option<&'static str> {
+        match self {
+            Robots::AllowAll => Some("User-agent: *\nDisallow:\n"),
+            Robots::DisallowAll => Some("User-agent: *\nDisallow: /\n"),
+            Robots::Off => None,
+        }
+    }
+}
+
+/// The `Server` header unless `server_header` says otherwise.
+pub const DEFAULT_SERVER_HEADER: &str = "LocalServer";
+
+/// `resolve`: which addresses a `host` name that resolves to several is bound on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostResolution {
+    /// Every address, e.g. both `127.0.0.1` and `::1` for `localhost`.
+    All,
+    /// Only the first one the resolver returns.
+    First,
+}
+
+impl HostResolution {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "all" => Some(HostResolution::All),
+            "first" => Some(HostResolution::First),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HostResolution::All => "all",
+            HostResolution::First => "first",
+        }
+    }
+}
+
+/// `proxy_balance`: which of a route's upstreams gets the next request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balance {
+    /// Each in turn.
+    RoundRobin,
+    /// The one with the fewest requests in flight, in turn among equals.
+    LeastConnections,
+}
+
+impl Balance {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "round_robin" => Some(Balance::RoundRobin),
+            "least_conn" => Some(Balance::LeastConnections),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Balance::RoundRobin => "round_robin",
+            Balance::LeastConnections => "least_conn",
+        }
+    }
+}
+
+/// `proxy_sticky`: keeps each client on the upstream it was first sent to,
+/// while that one is up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sticky {
+    /// By a hash of the client's address.
+    IpHash,
+    /// By the `UPSTREAM_ID` cookie set on the first answer.
+    Cookie,
+}
+
+impl Sticky {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ip_hash" => Some(Sticky::IpHash),
+            "cookie" => Some(Sticky::Cookie),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sticky::IpHash => "ip_hash",
+            Sticky::Cookie => "cookie",
+        }
+    }
+}
+
+/// `server_header`: `off` (or empty) sends none, `version` adds the crate
+/// version, anything else is sent as written.
+pub fn server_header(value: &str) -> Option<String> {
+    match value.trim() {
+        "" | "off" => None,
+        "version" => Some(format!("{}/{}", DEFAULT_SERVER_HEADER, env!("CARGO_PKG_VERSION"))),
+        value => Some(value.to_string()),
+    }
+}
+
+/// A `server_name` is a host name, `*.example.com` or `www.example.*`; a `*`
+/// anywhere else could never match.
+pub fn valid_server_name(name: &str) -> bool {
+    let bare = match (name.strip_prefix("*."), name.strip_suffix(".*")) {
+        (Some(rest), None) | (None, Some(rest)) => rest,
+        (None, None) => name,
+        (Some(_), Some(_)) => {
+            return false;
+        }
+    };
+    !bare.is_empty() && !bare.contains('*') && !bare.contains(char::is_whitespace)
+}
+
+#[derive(Clone)]
 pub struct ServerConfig {
+    /// An IP address or a host name, resolved when the server binds.
     pub host: String,
     pub port: String,
-    pub server_name: String,
+    pub resolve: HostResolution,
+    /// Names this block answers to; the first is used when a request has no
+    /// Host header. `*.example.com` matches any subdomain, `www.example.*`
+    /// any top-level part.
+    pub server_names: Vec<String>,
     pub max_body_size: usize,
-    pub error_pages: std::collections::HashMap<u16, String>,
+    pub https_redirect: bool,
+    pub https_port: Option<String>,
+    pub proxy_protocol: ProxyProtocol,
+    pub connect_proxy: bool,
+    pub connect_allow: Vec<String>,
+    /// Path that reports the progress of uploads sent with `X-Upload-ID`.
+    pub upload_progress: Option<String>,
+    /// Path that reports every `proxy_pass` upstream's health as JSON.
+    pub upstream_status: Option<String>,
+    /// Addresses or CIDR ranges whose `PURGE` requests drop cached responses;
+    /// empty turns `PURGE` away.
+    pub purge_allow: Vec<String>,
+    /// Built-in `/robots.txt` and `/favicon.ico` for roots without their own.
+    pub robots: Robots,
+    pub favicon: bool,
+    pub add_headers: Vec<(String, String)>,
+    pub error_pages: std::collections::HashMap<StatusCode, String>,
+    /// `404: { en: ..., fr: ... }`: `(language, page)` in config order, picked by
+    /// `Accept-Language`; `error_pages` keeps the page used when none matches.
+    pub localized_error_pages: std::collections::HashMap<StatusCode, Vec<(String, String)>>,
+    /// `error_pages: { default: ... }`, used for codes without their own page.
+    pub default_error_page: Option<String>,
     pub routes: Vec<RouteConfig>,
 }
 
+impl ServerConfig {
+    /// The addresses to listen on: `host` itself when it is an IP address,
+    /// otherwise what it resolves to, all of them or the first per `resolve`.
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>, String> {
+        let port: u16 = self.port.parse().map_err(|_| format!("'{}' is not a valid port", self.port))?;
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        let resolved = (self.host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve host '{}': {}", self.host, e))?;
+        for addr in resolved {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        if addrs.is_empty() {
+            return Err(format!("host '{}' did not resolve to any address", self.host));
+        }
+        if self.resolve == HostResolution::First {
+            addrs.truncate(1);
+        }
+        Ok(addrs)
+    }
+}
+
 pub struct Config {
     pub servers: Vec<ServerConfig>,
     pub max_server_size: usize,
-    pub timeout_seconds: u64,
+    pub timeout_seconds: Duration,
+    /// How long a CGI script may run; `timeout_seconds` when unset.
+    pub cgi_timeout: Option<Duration>,
+    pub access_log_exclude: Vec<String>,
+    /// While this file exists, requests outside `maintenance_allow` get a 503.
+    pub maintenance_file: Option<String>,
+    pub maintenance_retry_after: Duration,
+    pub maintenance_allow: Vec<String>,
+    pub auth_nonce_timeout: Duration,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<String>,
+    pub ban_threshold: usize,
+    pub ban_window_seconds: Duration,
+    pub ban_duration_seconds: Duration,
+    pub session_secret: Option<String>,
+    pub trusted_proxies: Vec<String>,
+    /// `(extension, type)` pairs layered over the built-in table.
+    pub mime_types: Vec<(String, String)>,
+    pub default_type: Option<String>,
+    pub log_level: crate::log::Level,
+    pub bind_policy: BindPolicy,
+    /// `Server` response header; `None` leaves it out.
+    pub server_header: Option<String>,
+    /// Top-level pages every server block inherits unless it sets the same code;
+    /// already merged into `ServerConfig::error_pages` after parsing.
+    pub error_pages: std::collections::HashMap<StatusCode, String>,
+    pub localized_error_pages: std::collections::HashMap<StatusCode, Vec<(String, String)>>,
+    pub default_error_page: Option<String>,
+    /// Things the parser fixed up on its own, reported at startup.
+    pub warnings: Vec<String>,
 }
\ No newline at end of file
@@ -0,0 +1,20 @@
+//! Format-neutral document tree shared by the YAML and TOML readers, so both
+//! map onto `Config` through the same code in `parser.rs`.
+
+pub struct Node {
+    pub line: usize,
+    pub kind: NodeKind,
+}
+
+pub enum NodeKind {
+    Null,
+    Scalar(String),
+    Seq(Vec<Node>),
+    Map(Vec<MapEntry>),
+}
+
+pub struct MapEntry {
+    pub key: String,
+    pub line: usize,
+    pub value: Node,
+}
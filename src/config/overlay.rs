@@ -0,0 +1,85 @@
+//! Profiles: a named overlay (from the `profiles:` section or a sibling
+//! `config.<profile>.yaml`) deep-merged into the document before it is mapped
+//! onto `Config`, so only the settings that differ need to be repeated.
+
+use crate::config::node::{ Node, NodeKind };
+
+/// Merges `overlay` into `base`. Mappings merge key by key; lists of mappings
+/// merge item by item, matching items by `path` when the overlay item has one
+/// (routes) and by position otherwise (servers). Anything else is replaced.
+pub fn merge(base: &mut Node, overlay: Node) {
+    match (&mut base.kind, overlay.kind) {
+        (NodeKind::Map(entries), NodeKind::Map(overlay_entries)) => {
+            for entry in overlay_entries {
+                match entries.iter_mut().find(|e| e.key == entry.key) {
+                    Some(existing) => {
+                        // A replaced value is reported where the overlay sets it
+                        if !merges_into(&existing.value, &entry.value) {
+                            existing.line = entry.line;
+                        }
+                        merge(&mut existing.value, entry.value);
+                    }
+                    None => entries.push(entry),
+                }
+            }
+        }
+        (NodeKind::Seq(items), NodeKind::Seq(overlay_items)) if all_maps(&overlay_items) => {
+            for (idx, item) in overlay_items.into_iter().enumerate() {
+                let target = match path_of(&item) {
+                    Some(path) => items.iter().position(|i| path_of(i) == Some(path)),
+                    None if idx < items.len() => Some(idx),
+                    None => None,
+                };
+                match target {
+                    Some(pos) => merge(&mut items[pos], item),
+                    None => items.push(item),
+                }
+            }
+        }
+        (_, kind) => {
+            base.kind = kind;
+            base.line = overlay.line;
+        }
+    }
+}
+
+/// Whether `merge` combines the two instead of replacing `base`.
+fn merges_into(base: &Node, overlay: &Node) -> bool {
+    match (&base.kind, &overlay.kind) {
+        (NodeKind::Map(_), NodeKind::Map(_)) => true,
+        (NodeKind::Seq(_), NodeKind::Seq(items)) => all_maps(items),
+        _ => false,
+    }
+}
+
+fn all_maps(items: &[Node]) -> bool {
+    items.iter().all(|item| matches!(item.kind, NodeKind::Map(_)))
+}
+
+/// Removes and returns the top-level `profiles` mapping, if any.
+pub fn take_profiles(root: &mut Node) -> Option<Node> {
+    let NodeKind::Map(entries) = &mut root.kind else {
+        return None;
+    };
+    let idx = entries.iter().position(|e| e.key == "profiles")?;
+    Some(entries.remove(idx).value)
+}
+
+/// Removes and returns one profile from a `profiles` mapping.
+pub fn take_profile(profiles: &mut Node, name: &str) -> Option<Node> {
+    let NodeKind::Map(entries) = &mut profiles.kind else {
+        return None;
+    };
+    let idx = entries.iter().position(|e| e.key == name)?;
+    Some(entries.remove(idx).value)
+}
+
+fn path_of(node: &Node) -> Option<&str> {
+    let NodeKind::Map(entries) = &node.kind else {
+        return None;
+    };
+    match &entries.iter().find(|e| e.key == "path")?.value.kind {
+        NodeKind::Scalar(path) => Some(path),
+        _ => None,
+    }
+}
@@ -1,223 +1,1207 @@
-use crate::config::models::{ Config, ServerConfig, RouteConfig };
+use crate::config::models::{ server_header, Balance, BindPolicy, Canary, CanaryTrigger, CgiCache, Config, FixedResponse, HealthCheck, HostResolution, Probe, ProxyCache, ProxyProtocol, Robots, ServerConfig, RouteConfig, Sticky, valid_server_name, DEFAULT_SERVER_HEADER };
+use crate::config::node::{ MapEntry, Node, NodeKind };
+use crate::config::{ json, overlay, toml, units, yaml };
+use crate::crypto::rsa::RsaPublicKey;
+use crate::error::ServerError;
+use crate::http::status::StatusCode;
+use crate::log::Level;
+use crate::server::forwarded::TrustedProxies;
 use std::collections::HashMap;
 use std::fs;
+use std::io::{ self, Read };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(PartialEq)]
-enum ParseMode {
-    General,
-    ErrorPages,
-    Routes,
+/// Reads a config file. Without an explicit `format` (`yaml`, `toml` or
+/// `json`) it follows the extension, defaulting to YAML; `-` reads from stdin,
+/// where a leading `{` means JSON. `profile` selects an overlay, see `overlay.rs`.
+pub fn parse_config(path: &str, format: Option<&str>, profile: Option<&str>) -> Result<Config, ServerError> {
+    if let Some(format) = format {
+        reader(format)?;
+    }
+    let content = read_source(path)?;
+    let format = match format {
+        Some(format) => format,
+        None if path == "-" && content.trim_start().starts_with('{') => "json",
+        None => format_of(path),
+    };
+    parse_content(path, &content, format, profile)
+}
+
+fn format_of(path: &str) -> &'static str {
+    if path.ends_with(".toml") {
+        "toml"
+    } else if path.ends_with(".json") {
+        "json"
+    } else {
+        "yaml"
+    }
 }
 
-pub fn parse_config(path: &str) -> Result<Config, String> {
-    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
-    let mut config = Config { servers: Vec::new(), max_server_size: 10485760, timeout_seconds: 30 };
+type Reader = fn(&str) -> Result<Node, String>;
 
-    let mut current_server: Option<ServerConfig> = None;
-    let mut current_route: Option<RouteConfig> = None;
-    let mut mode = ParseMode::General;
+fn reader(format: &str) -> Result<Reader, String> {
+    match format {
+        "yaml" | "yml" => Ok(yaml::parse),
+        "toml" => Ok(toml::parse),
+        "json" => Ok(json::parse),
+        other => Err(format!("unknown config format '{}' (expected yaml, toml or json)", other)),
+    }
+}
+
+fn read_source(path: &str) -> Result<String, ServerError> {
+    if path == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content).map_err(ServerError::io("<stdin>"))?;
+        return Ok(content);
+    }
+    fs::read_to_string(path).map_err(ServerError::io(path))
+}
 
-    for raw_line in content.lines() {
-        let trimmed = raw_line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') {
+/// Line-numbered errors from the readers and `build_*` belong to `file`.
+fn in_file(file: &str) -> impl Fn(String) -> ServerError + '_ {
+    move |message| ServerError::Parse { file: file.to_string(), message }
+}
+
+fn parse_content(path: &str, content: &str, format: &str, profile: Option<&str>) -> Result<Config, ServerError> {
+    let name = if path == "-" { "<stdin>" } else { path };
+    let mut root = reader(format)?(content).map_err(in_file(name))?;
+
+    // Included paths and profile overlay files are relative to this file
+    let base = match path {
+        "-" => Path::new("."),
+        _ => Path::new(path).parent().unwrap_or(Path::new(".")),
+    };
+
+    let profiles = overlay::take_profiles(&mut root);
+    if let Some(profile) = profile {
+        let selected = match profiles.and_then(|mut p| overlay::take_profile(&mut p, profile)) {
+            Some(node) => node,
+            None => load_profile_file(base, path, profile)?,
+        };
+        overlay::merge(&mut root, selected);
+    }
+
+    let strict = strict_mode(&root).map_err(in_file(name))?;
+    let mut config = build_config(&root, strict).map_err(in_file(name))?;
+
+    // Each file is read once: `*.yaml` next to the main file matches it too
+    let mut loaded: Vec<PathBuf> = fs::canonicalize(path).into_iter().collect();
+    for pattern in include_patterns(&root).map_err(in_file(name))? {
+        for file in expand_include(base, &pattern).map_err(|e| ServerError::Config(format!("{}: {}", name, e)))? {
+            let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+            if loaded.contains(&canonical) {
+                continue;
+            }
+            loaded.push(canonical);
+            config.servers.extend(load_included_servers(&file, strict)?);
+        }
+    }
+
+    // Included blocks inherit too, so this waits until they are all loaded
+    inherit_error_pages(&mut config);
+    add_default_routes(&mut config);
+    load_jwt_keys(&mut config).map_err(|e| ServerError::Config(format!("{}: {}", name, e)))?;
+    Ok(config)
+}
+
+/// Reads and parses every route's `jwt_public_key` once, so requests do not
+/// touch the file and a bad key fails the load instead of every request.
+pub(super) fn load_jwt_keys(config: &mut Config) -> Result<(), String> {
+    let mut problems = Vec::new();
+    for route in config.servers.iter_mut().flat_map(|server| server.routes.iter_mut()) {
+        let Some(path) = &route.jwt_public_key else {
             continue;
+        };
+        // Read before any chroot, but named as the server will see it
+        let file = match &config.chroot {
+            Some(dir) => Path::new(dir).join(path.trim_start_matches("./").trim_start_matches('/')),
+            None => PathBuf::from(path),
+        };
+        let key = fs
+            ::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| RsaPublicKey::from_pem(&pem));
+        match key {
+            Ok(key) => {
+                route.jwt_key = Some(Arc::new(key));
+            }
+            Err(e) => problems.push(format!("route '{}': jwt_public_key '{}': {}", route.path, path, e)),
         }
+    }
+    if problems.is_empty() { Ok(()) } else { Err(problems.join("\n")) }
+}
 
-        let indent = raw_line
-            .chars()
-            .take_while(|c| c.is_whitespace())
-            .count();
-        let (key, value) = split_kv(trimmed);
+pub(super) fn inherit_error_pages(config: &mut Config) {
+    for server in &mut config.servers {
+        for (code, page) in &config.error_pages {
+            if server.error_pages.contains_key(code) {
+                continue;
+            }
+            server.error_pages.insert(*code, page.clone());
+            if let Some(languages) = config.localized_error_pages.get(code) {
+                server.localized_error_pages.insert(*code, languages.clone());
+            }
+        }
+        if server.default_error_page.is_none() {
+            server.default_error_page = config.default_error_page.clone();
+        }
+    }
+}
 
-        match indent {
-            0 => {
-                if key == "max_server_size" {
-                    config.max_server_size = value.parse().unwrap_or(10485760);
-                } else if key == "timeout_seconds" {
-                    config.timeout_seconds = value.parse().unwrap_or(30);
-                }
-                continue; // "servers:" is also at indent 0
-            }
-            2 => {
-                // NEW SERVER START
-                // Detects "- host: ..." or "- port: ..." or just a dash "- "
-                if trimmed.starts_with("- ") {
-                    if let Some(mut s) = current_server.take() {
-                        if let Some(r) = current_route.take() {
-                            s.routes.push(r);
-                        }
-                        config.servers.push(s);
-                    }
-                    current_server = Some(default_server());
-                    current_route = None;
-                    mode = ParseMode::General;
+/// A block without routes serves `./www` read-only instead of answering 404
+/// to everything.
+pub(super) fn add_default_routes(config: &mut Config) {
+    for (idx, server) in config.servers.iter_mut().enumerate() {
+        if !server.routes.is_empty() {
+            continue;
+        }
+        let mut route = default_route();
+        route.methods = vec!["GET".to_string(), "HEAD".to_string()];
+        route.index = Some("index.html".to_string());
+        server.routes.push(route);
+        config.warnings.push(
+            format!(
+                "server #{} ({}:{}) has no routes; serving {} at / (GET, HEAD, index.html)",
+                idx + 1,
+                server.host,
+                server.port,
+                server.routes[0].root
+            )
+        );
+    }
+}
 
-                    let line_after_dash = &trimmed[2..];
-                    if !line_after_dash.is_empty() {
-                        let (k, v) = split_kv(line_after_dash);
-                        apply_server_field(current_server.as_mut().unwrap(), k, v);
-                    }
-                    continue;
+/// `config.yaml` with profile `dev` looks for `config.dev.yaml` (or `.yml`,
+/// `.toml`, `.json`) next to it when `profiles:` does not define `dev`.
+fn load_profile_file(base: &Path, path: &str, profile: &str) -> Result<Node, ServerError> {
+    let stem = match path {
+        "-" => "config",
+        _ => Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or("config"),
+    };
+
+    for ext in ["yaml", "yml", "toml", "json"] {
+        let candidate = base.join(format!("{}.{}.{}", stem, profile, ext));
+        if candidate.is_file() {
+            let name = candidate.display().to_string();
+            let content = read_source(&name)?;
+            return reader(format_of(&name))?(&content).map_err(in_file(&name));
+        }
+    }
+
+    Err(
+        ServerError::Config(
+            format!(
+                "profile '{}' is not defined under 'profiles' and no {}.{}.yaml exists",
+                profile,
+                stem,
+                profile
+            )
+        )
+    )
+}
+
+/// Unknown keys are errors unless the main file sets `strict: false`, so a
+/// typo cannot silently switch a feature off.
+fn strict_mode(root: &Node) -> Result<bool, String> {
+    match expect_map(root, "the configuration")?.iter().find(|e| e.key == "strict") {
+        Some(entry) => boolean(entry),
+        None => Ok(true),
+    }
+}
+
+fn include_patterns(root: &Node) -> Result<Vec<String>, String> {
+    match expect_map(root, "the configuration")?.iter().find(|e| e.key == "include") {
+        Some(entry) => list(entry),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Resolves an include pattern; `*` and `?` are allowed in the file name only.
+/// A wildcard matching nothing is fine (an empty `sites-enabled/`), a plain
+/// path that does not exist is not.
+fn expand_include(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let full = base.join(pattern);
+    let file_pattern = full
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("include '{}' does not name a file", pattern))?;
+
+    if !file_pattern.contains(['*', '?']) {
+        if !full.is_file() {
+            return Err(format!("include '{}' does not exist", pattern));
+        }
+        return Ok(vec![full]);
+    }
+
+    let dir = full.parent().unwrap_or(Path::new("."));
+    let entries = fs::read_dir(dir).map_err(|e| format!("include '{}': {}", pattern, e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !name.starts_with('.') && wildcard_match(file_pattern.as_bytes(), name.as_bytes())
+        })
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) =>
+            wildcard_match(&pattern[1..], name) ||
+            (!name.is_empty() && wildcard_match(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// An included file holds either a `servers:` list or a single server block;
+/// includes do not nest, so they cannot loop.
+fn load_included_servers(file: &Path, strict: bool) -> Result<Vec<ServerConfig>, ServerError> {
+    let name = file.display().to_string();
+    let content = read_source(&name)?;
+    let root = reader(format_of(&name))?(&content).map_err(in_file(&name))?;
+
+    let build = || -> Result<Vec<ServerConfig>, String> {
+        let entries = expect_map(&root, "an included file")?;
+        if let Some(entry) = entries.iter().find(|e| e.key == "include") {
+            return Err(format!("{}: 'include' only works in the main configuration file", entry.line));
+        }
+        match entries.iter().find(|e| e.key == "servers") {
+            Some(servers) => {
+                for entry in entries.iter().filter(|e| e.key != "servers") {
+                    unknown_key(entry, strict)?;
+                }
+                let mut out = Vec::new();
+                for item in expect_seq(servers)? {
+                    out.extend(build_server(item, strict)?);
                 }
+                Ok(out)
+            }
+            None => build_server(&root, strict),
+        }
+    };
+    build().map_err(in_file(&name))
+}
 
-                // If we are at indent 2 and it's not a dash, it's a server field
-                if let Some(ref mut server) = current_server {
-                    mode = ParseMode::General; // reset mode if we come back to indent 2
-                    apply_server_field(server, key, value);
+/// Maps a parsed document onto `Config`. Errors are `line: message`.
+fn build_config(root: &Node, strict: bool) -> Result<Config, String> {
+    let mut config = default_config();
+
+    for entry in expect_map(root, "the configuration")? {
+        match entry.key.as_str() {
+            "servers" => {
+                for item in expect_seq(entry)? {
+                    config.servers.extend(build_server(item, strict)?);
                 }
             }
+            "max_server_size" => {
+                config.max_server_size = size(entry)?;
+            }
+            "timeout_seconds" => {
+                config.timeout_seconds = duration(entry)?;
+            }
+            "cgi_timeout" => {
+                config.cgi_timeout = match entry.value.kind {
+                    NodeKind::Null => None,
+                    _ => Some(duration(entry)?),
+                };
+            }
+            "access_log_exclude" => {
+                config.access_log_exclude = list(entry)?;
+            }
+            "maintenance_file" => {
+                config.maintenance_file = opt_string(entry)?;
+            }
+            "maintenance_retry_after" => {
+                config.maintenance_retry_after = duration(entry)?;
+            }
+            "maintenance_allow" => {
+                config.maintenance_allow = list(entry)?;
+            }
+            "auth_nonce_timeout" => {
+                config.auth_nonce_timeout = duration(entry)?;
+            }
+            "user" => {
+                config.user = opt_string(entry)?;
+            }
+            "group" => {
+                config.group = opt_string(entry)?;
+            }
+            "chroot" => {
+                config.chroot = opt_string(entry)?;
+            }
+            "ban_threshold" => {
+                config.ban_threshold = number(entry)?;
+            }
+            "ban_window_seconds" => {
+                config.ban_window_seconds = duration(entry)?;
+            }
+            "ban_duration_seconds" => {
+                config.ban_duration_seconds = duration(entry)?;
+            }
+            "session_secret" => {
+                config.session_secret = opt_string(entry)?;
+            }
+            "trusted_proxies" => {
+                config.trusted_proxies = list(entry)?;
+            }
+            "mime_types" => {
+                config.mime_types = pairs(entry)?;
+            }
+            "default_type" => {
+                config.default_type = opt_string(entry)?;
+            }
+            "error_pages" => {
+                let pages = error_pages(entry)?;
+                (config.error_pages, config.localized_error_pages, config.default_error_page) = pages;
+            }
+            "log_level" => {
+                config.log_level = log_level(entry)?;
+            }
+            "bind_policy" => {
+                let value = string(entry)?;
+                config.bind_policy = BindPolicy::parse(&value).ok_or_else(|| invalid(entry, "strict or best_effort", &value))?;
+            }
+            "server_header" => {
+                config.server_header = server_header(&string(entry)?);
+            }
+            // Expanded by `parse_content`, which knows where the file lives
+            "include" | "strict" => {}
+            _ => {
+                unknown_key(entry, strict)?;
+            }
+        }
+    }
 
-            4 | 6 | 8 => {
-                if let Some(ref mut server) = current_server {
-                    if key == "error_pages" {
-                        mode = ParseMode::ErrorPages;
-                        continue;
-                    }
-                    if key == "routes" {
-                        mode = ParseMode::Routes;
-                        continue;
+    Ok(config)
+}
+
+/// A block listing several ports becomes one server per port, all sharing
+/// the same settings and routes.
+fn build_server(node: &Node, strict: bool) -> Result<Vec<ServerConfig>, String> {
+    let mut server = default_server();
+    let mut ports = Vec::new();
+
+    for entry in expect_map(node, "a server")? {
+        match entry.key.as_str() {
+            "host" => {
+                server.host = string(entry)?;
+            }
+            "resolve" => {
+                let value = string(entry)?;
+                server.resolve = HostResolution::parse(&value).ok_or_else(|| invalid(entry, "all or first", &value))?;
+            }
+            "server_name" => {
+                server.server_names = list(entry)?;
+                if server.server_names.is_empty() {
+                    return Err(invalid(entry, "at least one name", ""));
+                }
+                if let Some(name) = server.server_names.iter().find(|name| !valid_server_name(name)) {
+                    return Err(invalid(entry, "names, '*.example.com' or 'www.example.*'", name));
+                }
+            }
+            "max_body_size" => {
+                server.max_body_size = size(entry)?;
+            }
+            "port" => {
+                ports = port_list(entry)?;
+            }
+            "https_redirect" => {
+                server.https_redirect = boolean(entry)?;
+            }
+            "https_port" => {
+                server.https_port = opt_string(entry)?;
+            }
+            "connect_proxy" => {
+                server.connect_proxy = boolean(entry)?;
+            }
+            "connect_allow" => {
+                server.connect_allow = list(entry)?;
+            }
+            "upstream_status" => {
+                server.upstream_status = opt_string(entry)?;
+            }
+            "purge_allow" => {
+                server.purge_allow = list(entry)?;
+                TrustedProxies::parse_as("purge_allow", &server.purge_allow).map_err(|e| format!("{}: {}", entry.line, e))?;
+            }
+            "upload_progress" => {
+                server.upload_progress = opt_string(entry)?;
+            }
+            "robots" => {
+                let value = string(entry)?;
+                server.robots = Robots::parse(&value).ok_or_else(|| invalid(entry, "allow_all, disallow_all or off", &value))?;
+            }
+            "favicon" => {
+                server.favicon = boolean(entry)?;
+            }
+            "proxy_protocol" => {
+                server.proxy_protocol = match string(entry)?.as_str() {
+                    "required" => ProxyProtocol::Required,
+                    "true" | "on" | "optional" => ProxyProtocol::Optional,
+                    "false" | "off" | "" => ProxyProtocol::Off,
+                    other => {
+                        return Err(invalid(entry, "optional, required or off", other));
                     }
+                };
+            }
+            "add_headers" => {
+                server.add_headers = pairs(entry)?;
+            }
+            "error_pages" => {
+                let (pages, localized, default) = error_pages(entry)?;
+                for code in pages.keys() {
+                    server.localized_error_pages.remove(code);
+                }
+                server.error_pages.extend(pages);
+                server.localized_error_pages.extend(localized);
+                server.default_error_page = default.or(server.default_error_page.take());
+            }
+            "routes" => {
+                for item in expect_seq(entry)? {
+                    server.routes.push(build_route(item, strict)?);
+                }
+            }
+            _ => {
+                unknown_key(entry, strict)?;
+            }
+        }
+    }
 
-                    match mode {
-                        ParseMode::ErrorPages => {
-                            if let Ok(code) = key.parse::<u16>() {
-                                server.error_pages.insert(code, value.to_string());
-                            } else {
-                                // If key is not a number, we likely exited the error_pages block
-                                mode = ParseMode::General;
-                                apply_server_field(server, key, value);
-                            }
-                        }
-                        ParseMode::Routes => {
-                            if
-                                trimmed.starts_with("- path") ||
-                                (indent == 4 && trimmed.starts_with("- "))
-                            {
-                                if let Some(r) = current_route.take() {
-                                    server.routes.push(r);
+    if ports.is_empty() {
+        return Ok(vec![server]);
+    }
+    Ok(
+        ports
+            .into_iter()
+            .map(|port| ServerConfig { port, ..server.clone() })
+            .collect()
+    )
+}
+
+fn build_route(node: &Node, strict: bool) -> Result<RouteConfig, String> {
+    let mut route = default_route();
+
+    for entry in expect_map(node, "a route")? {
+        match entry.key.as_str() {
+            "path" => {
+                route.path = string(entry)?;
+            }
+            "root" => {
+                let mut roots = match &entry.value.kind {
+                    NodeKind::Seq(_) => list(entry)?,
+                    _ => vec![string(entry)?],
+                };
+                if roots.is_empty() {
+                    return Err(format!("{}: 'root' lists no directories", entry.line));
+                }
+                route.root = roots.remove(0);
+                route.fallback_roots = roots;
+            }
+            "upload_dir" => {
+                route.upload_dir = opt_string(entry)?;
+            }
+            "verify_uploads" => {
+                route.verify_uploads = boolean(entry)?;
+            }
+            "index" => {
+                route.index = opt_string(entry)?;
+            }
+            "autoindex" => {
+                route.autoindex = boolean(entry)?;
+            }
+            "redirect" => {
+                match &entry.value.kind {
+                    NodeKind::Map(fields) => {
+                        for field in fields {
+                            match field.key.as_str() {
+                                "to" => {
+                                    route.redirect = opt_string(field)?;
+                                }
+                                "code" => {
+                                    route.redirect_code = redirect_code(field)?;
+                                }
+                                other => {
+                                    return Err(format!("{}: unknown redirect option '{}' (expected to, code)", field.line, other));
                                 }
-                                current_route = Some(default_route());
-                                let line_after_dash = if trimmed.starts_with("- ") {
-                                    &trimmed[2..]
-                                } else {
-                                    trimmed
-                                };
-                                let (k, v) = split_kv(line_after_dash);
-                                apply_route_field(current_route.as_mut().unwrap(), k, v);
-                            } else if let Some(ref mut route) = current_route {
-                                apply_route_field(route, key, value);
                             }
                         }
-                        ParseMode::General => {
-                            apply_server_field(server, key, value);
+                        if route.redirect.is_none() {
+                            return Err(format!("{}: 'redirect' needs a 'to' target", entry.line));
                         }
                     }
+                    _ => {
+                        route.redirect = opt_string(entry)?;
+                    }
                 }
             }
-
-            _ => {}
+            "return" => {
+                route.fixed_response = fixed_response(entry)?;
+            }
+            "cgi_extension" => {
+                route.cgi_extension = opt_string(entry)?;
+            }
+            "cgi_interpreter" => {
+                route.cgi_interpreter = opt_string(entry)?;
+            }
+            "cgi_cache" => {
+                route.cgi_cache = cgi_cache(entry)?;
+            }
+            "methods" => {
+                route.methods = list(entry)?;
+            }
+            "access_log" => {
+                route.access_log = boolean(entry)?;
+            }
+            "auth" => {
+                route.auth = opt_string(entry)?.map(|v| v.to_ascii_lowercase());
+            }
+            "auth_realm" => {
+                route.auth_realm = opt_string(entry)?;
+            }
+            "auth_user_file" => {
+                route.auth_user_file = opt_string(entry)?;
+            }
+            "jwt_secret" => {
+                route.jwt_secret = opt_string(entry)?;
+            }
+            "jwt_public_key" => {
+                route.jwt_public_key = opt_string(entry)?;
+            }
+            "jwt_issuer" => {
+                route.jwt_issuer = opt_string(entry)?;
+            }
+            "jwt_audience" => {
+                route.jwt_audience = opt_string(entry)?;
+            }
+            "add_headers" => {
+                route.add_headers = pairs(entry)?;
+            }
+            "sub_filter" => {
+                route.sub_filter = pairs(entry)?;
+            }
+            "sub_filter_types" => {
+                route.sub_filter_types = list(entry)?;
+            }
+            "markdown" => {
+                route.markdown = boolean(entry)?;
+            }
+            "markdown_template" => {
+                route.markdown_template = opt_string(entry)?;
+            }
+            "canary" => {
+                route.canary = canary(entry)?;
+            }
+            "csrf" => {
+                route.csrf = boolean(entry)?;
+            }
+            "websocket" => {
+                route.websocket = boolean(entry)?;
+            }
+            "sse" => {
+                route.sse = boolean(entry)?;
+            }
+            "mirror" => {
+                route.mirror = opt_string(entry)?;
+            }
+            "proxy_pass" => {
+                route.proxy_pass = match &entry.value.kind {
+                    NodeKind::Null => Vec::new(),
+                    NodeKind::Seq(_) => list(entry)?,
+                    _ => vec![string(entry)?],
+                };
+            }
+            "proxy_balance" => {
+                let value = string(entry)?;
+                route.proxy_balance = Balance::parse(&value).ok_or_else(|| invalid(entry, "round_robin or least_conn", &value))?;
+            }
+            "proxy_sticky" => {
+                route.proxy_sticky = match opt_string(entry)? {
+                    Some(value) if value != "off" =>
+                        Some(Sticky::parse(&value).ok_or_else(|| invalid(entry, "ip_hash, cookie or off", &value))?),
+                    _ => None,
+                };
+            }
+            "proxy_fail_timeout" => {
+                route.proxy_fail_timeout = duration(entry)?;
+            }
+            "proxy_forwarded" => {
+                route.proxy_forwarded = boolean(entry)?;
+            }
+            "proxy_next_upstream_tries" => {
+                route.proxy_next_upstream_tries = number(entry)?;
+            }
+            "proxy_next_upstream_timeout" => {
+                route.proxy_next_upstream_timeout = duration(entry)?;
+            }
+            "proxy_health" => {
+                route.proxy_health = proxy_health(entry)?;
+            }
+            "proxy_cache" => {
+                route.proxy_cache = proxy_cache(entry)?;
+            }
+            "handler" => {
+                route.handler = opt_string(entry)?;
+            }
+            "max_body_size" => {
+                route.max_body_size = match entry.value.kind {
+                    NodeKind::Null => None,
+                    _ => Some(size(entry)?),
+                };
+            }
+            _ => {
+                unknown_key(entry, strict)?;
+            }
         }
     }
 
-    // Finalize
-    if let Some(mut s) = current_server {
-        if let Some(r) = current_route {
-            s.routes.push(r);
-        }
-        config.servers.push(s);
+    Ok(route)
+}
+
+fn expect_map<'a>(node: &'a Node, what: &str) -> Result<&'a [MapEntry], String> {
+    match &node.kind {
+        NodeKind::Map(entries) => Ok(entries),
+        NodeKind::Null => Ok(&[]),
+        _ => Err(format!("{}: expected {} to be a mapping of 'key: value' lines", node.line, what)),
     }
+}
 
-    Ok(config)
+fn expect_entries(entry: &MapEntry) -> Result<&[MapEntry], String> {
+    match &entry.value.kind {
+        NodeKind::Map(entries) => Ok(entries),
+        NodeKind::Null => Ok(&[]),
+        _ => Err(format!("{}: '{}' expects a mapping", entry.line, entry.key)),
+    }
+}
+
+fn expect_seq(entry: &MapEntry) -> Result<&[Node], String> {
+    match &entry.value.kind {
+        NodeKind::Seq(items) => Ok(items),
+        NodeKind::Null => Ok(&[]),
+        _ => Err(format!("{}: '{}' expects a list of '- ' items", entry.line, entry.key)),
+    }
 }
 
-fn apply_server_field(server: &mut ServerConfig, key: &str, value: &str) {
-    match key {
-        "host" => {
-            server.host = value.to_string();
+fn string(entry: &MapEntry) -> Result<String, String> {
+    match &entry.value.kind {
+        NodeKind::Scalar(value) => Ok(value.clone()),
+        NodeKind::Null => Ok(String::new()),
+        _ => Err(format!("{}: '{}' expects a single value", entry.line, entry.key)),
+    }
+}
+
+fn opt_string(entry: &MapEntry) -> Result<Option<String>, String> {
+    match &entry.value.kind {
+        NodeKind::Null => Ok(None),
+        _ => string(entry).map(Some),
+    }
+}
+
+fn number<T: FromStr>(entry: &MapEntry) -> Result<T, String> {
+    let value = string(entry)?;
+    value.trim().parse().map_err(|_| invalid(entry, "a non-negative number", &value))
+}
+
+fn size(entry: &MapEntry) -> Result<usize, String> {
+    let value = string(entry)?;
+    units::parse_size(&value).ok_or_else(|| invalid(entry, "a size such as 1048576, 512K, 10M or 1G", &value))
+}
+
+fn duration(entry: &MapEntry) -> Result<Duration, String> {
+    let value = string(entry)?;
+    units::parse_duration(&value).ok_or_else(|| invalid(entry, "a duration such as 30, 500ms, 30s, 5m or 1h", &value))
+}
+
+fn redirect_code(entry: &MapEntry) -> Result<StatusCode, String> {
+    let value = string(entry)?;
+    match value.trim().parse().ok().and_then(StatusCode::from_u16) {
+        Some(code) if code.is_redirect_code() => Ok(code),
+        _ => Err(invalid(entry, "one of 301, 302, 303, 307, 308", &value)),
+    }
+}
+
+/// `return: 204`, or a map with `code` (default 200), `body` and `content_type`
+/// (default `text/plain`).
+fn fixed_response(entry: &MapEntry) -> Result<Option<FixedResponse>, String> {
+    let mut response = FixedResponse { code: StatusCode::OK, body: String::new(), content_type: "text/plain".to_string() };
+    match &entry.value.kind {
+        NodeKind::Null => {
+            return Ok(None);
         }
-        "server_name" => {
-            server.server_name = value.to_string();
+        NodeKind::Map(fields) => {
+            for field in fields {
+                match field.key.as_str() {
+                    "code" => {
+                        response.code = fixed_response_code(field)?;
+                    }
+                    "body" => {
+                        response.body = string(field)?;
+                    }
+                    "content_type" => {
+                        response.content_type = string(field)?;
+                    }
+                    other => {
+                        return Err(format!("{}: unknown return option '{}' (expected code, body, content_type)", field.line, other));
+                    }
+                }
+            }
+        }
+        _ => {
+            response.code = fixed_response_code(entry)?;
+        }
+    }
+    if response.code == StatusCode::NO_CONTENT && !response.body.is_empty() {
+        return Err(format!("{}: a 204 response cannot have a body", entry.line));
+    }
+    Ok(Some(response))
+}
+
+/// `cgi_cache: 10s`, or a map with `ttl` and `max_size` (default 1 MiB).
+fn cgi_cache(entry: &MapEntry) -> Result<Option<CgiCache>, String> {
+    let mut cache = CgiCache { ttl: Duration::ZERO, max_size: 1024 * 1024 };
+    match &entry.value.kind {
+        NodeKind::Null => {
+            return Ok(None);
+        }
+        NodeKind::Map(fields) => {
+            for field in fields {
+                match field.key.as_str() {
+                    "ttl" => {
+                        cache.ttl = duration(field)?;
+                    }
+                    "max_size" => {
+                        cache.max_size = size(field)?;
+                    }
+                    other => {
+                        return Err(format!("{}: unknown cgi_cache option '{}' (expected ttl, max_size)", field.line, other));
+                    }
+                }
+            }
+        }
+        _ => {
+            cache.ttl = duration(entry)?;
+        }
+    }
+    if cache.ttl.is_zero() {
+        return Err(format!("{}: 'cgi_cache' needs a 'ttl' above zero", entry.line));
+    }
+    Ok(Some(cache))
+}
+
+/// `proxy_cache: 10s`, or a map with `ttl` (default 0: only what the upstream
+/// allows), `force_ttl`, `max_size` (default 1 MiB), `stale` and `key`.
+fn proxy_cache(entry: &MapEntry) -> Result<Option<ProxyCache>, String> {
+    let mut cache = ProxyCache {
+        ttl: Duration::ZERO,
+        force_ttl: false,
+        max_size: 1024 * 1024,
+        stale: Duration::ZERO,
+        key: Vec::new(),
+    };
+    match &entry.value.kind {
+        NodeKind::Null => {
+            return Ok(None);
         }
-        "max_body_size" => {
-            // Explicitly parse as usize
-            server.max_body_size = value.parse::<usize>().unwrap_or(1048576);
+        NodeKind::Map(fields) => {
+            for field in fields {
+                match field.key.as_str() {
+                    "ttl" => {
+                        cache.ttl = duration(field)?;
+                    }
+                    "force_ttl" => {
+                        cache.force_ttl = boolean(field)?;
+                    }
+                    "max_size" => {
+                        cache.max_size = size(field)?;
+                    }
+                    "stale" => {
+                        cache.stale = duration(field)?;
+                    }
+                    "key" => {
+                        cache.key = list(field)?
+                            .iter()
+                            .map(|name| name.to_ascii_lowercase())
+                            .collect();
+                    }
+                    other => {
+                        return Err(format!(
+                            "{}: unknown proxy_cache option '{}' (expected ttl, force_ttl, max_size, stale, key)",
+                            field.line,
+                            other
+                        ));
+                    }
+                }
+            }
         }
-        "port" => {
-            server.port = value.to_string();
+        _ => {
+            cache.ttl = duration(entry)?;
         }
-        _ => {}
     }
+    if cache.force_ttl && cache.ttl.is_zero() {
+        return Err(format!("{}: 'proxy_cache' needs a 'ttl' above zero for 'force_ttl'", entry.line));
+    }
+    Ok(Some(cache))
 }
-fn apply_route_field(route: &mut RouteConfig, key: &str, value: &str) {
-    match key {
-        "path" => {
-            route.path = value.to_string();
+
+fn proxy_health(entry: &MapEntry) -> Result<Option<HealthCheck>, String> {
+    let mut health = HealthCheck { probe: Probe::Connect, interval: Duration::from_secs(5), fails: 2, passes: 2 };
+    let fields = match &entry.value.kind {
+        NodeKind::Null => {
+            return Ok(None);
         }
-        "root" => {
-            route.root = value.to_string();
+        NodeKind::Map(fields) => fields,
+        _ => {
+            return Err(format!("{}: 'proxy_health' must be a map (check, interval, fails, passes)", entry.line));
         }
-        "upload_dir" => {
-            route.upload_dir = Some(value.to_string());
+    };
+    for field in fields {
+        match field.key.as_str() {
+            "check" => {
+                let check = string(field)?;
+                health.probe = match check.as_str() {
+                    "tcp" => Probe::Connect,
+                    path if path.starts_with('/') => Probe::Get(path.to_string()),
+                    _ => {
+                        return Err(invalid(field, "tcp or a path starting with '/'", &check));
+                    }
+                };
+            }
+            "interval" => {
+                health.interval = duration(field)?;
+            }
+            "fails" => {
+                health.fails = number(field)?;
+            }
+            "passes" => {
+                health.passes = number(field)?;
+            }
+            other => {
+                return Err(
+                    format!("{}: unknown proxy_health option '{}' (expected check, interval, fails, passes)", field.line, other)
+                );
+            }
         }
-        "index" => {
-            route.index = Some(value.to_string());
+    }
+    if health.interval.is_zero() || health.fails == 0 || health.passes == 0 {
+        return Err(format!("{}: 'proxy_health' needs an interval, fails and passes above zero", entry.line));
+    }
+    Ok(Some(health))
+}
+
+fn fixed_response_code(entry: &MapEntry) -> Result<StatusCode, String> {
+    let value = string(entry)?;
+    match value.trim().parse().ok().and_then(StatusCode::from_u16) {
+        Some(code) if code.as_u16() < 600 && !code.is_informational() && !code.is_redirection() => Ok(code),
+        Some(code) if code.is_redirection() => Err(format!("{}: use 'redirect' for a {} response", entry.line, code)),
+        _ => Err(invalid(entry, "a 2xx, 4xx or 5xx status", &value)),
+    }
+}
+
+/// `8080`, `[8080, 8081]` or an inclusive range such as `8000-8005`.
+fn port_list(entry: &MapEntry) -> Result<Vec<String>, String> {
+    const EXPECTED: &str = "a port, a list of ports or a range such as 8000-8005";
+
+    let mut ports: Vec<String> = Vec::new();
+    for item in list(entry)? {
+        let (first, last) = item.split_once('-').unwrap_or((&item, &item));
+        let (first, last) = match (first.trim().parse::<u16>(), last.trim().parse::<u16>()) {
+            (Ok(first), Ok(last)) if first <= last => (first, last),
+            _ => {
+                return Err(invalid(entry, EXPECTED, &item));
+            }
+        };
+        for port in first..=last {
+            if ports.iter().any(|p| *p == port.to_string()) {
+                return Err(format!("{}: 'port' lists {} more than once", entry.line, port));
+            }
+            ports.push(port.to_string());
         }
-        "autoindex" => {
-            route.autoindex = value == "true";
+    }
+    if ports.is_empty() {
+        return Err(invalid(entry, EXPECTED, ""));
+    }
+    Ok(ports)
+}
+
+fn log_level(entry: &MapEntry) -> Result<Level, String> {
+    let value = string(entry)?;
+    Level::parse(&value).ok_or_else(|| invalid(entry, "error, warn, info or debug", &value))
+}
+
+fn boolean(entry: &MapEntry) -> Result<bool, String> {
+    let value = string(entry)?;
+    match value.as_str() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        other => Err(invalid(entry, "true or false", other)),
+    }
+}
+
+/// Accepts a YAML list or a single comma-separated value.
+fn list(entry: &MapEntry) -> Result<Vec<String>, String> {
+    match &entry.value.kind {
+        NodeKind::Seq(items) => {
+            let mut out = Vec::new();
+            for item in items {
+                match &item.kind {
+                    NodeKind::Scalar(value) => out.push(value.clone()),
+                    NodeKind::Null => {}
+                    _ => {
+                        return Err(format!("{}: '{}' expects a list of plain values", item.line, entry.key));
+                    }
+                }
+            }
+            Ok(out)
         }
-        "redirect" => {
-            route.redirect = Some(value.to_string());
+        _ =>
+            Ok(
+                string(entry)?
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            ),
+    }
+}
+
+type ErrorPages = (HashMap<StatusCode, String>, HashMap<StatusCode, Vec<(String, String)>>, Option<String>);
+
+/// Pages by status code, plus the `default` page for every other code. A code
+/// can map languages to pages instead; its own `default` (or else the first
+/// language) is the page for clients that accept none of them.
+fn error_pages(entry: &MapEntry) -> Result<ErrorPages, String> {
+    let mut pages = HashMap::new();
+    let mut localized = HashMap::new();
+    let mut default = None;
+    for page in expect_entries(entry)? {
+        if page.key == "default" {
+            default = Some(string(page)?);
+            continue;
         }
-        "cgi_extension" => {
-            route.cgi_extension = Some(value.to_string());
+        let code = page.key
+            .parse::<u16>()
+            .ok()
+            .and_then(StatusCode::from_u16)
+            .ok_or_else(|| format!("{}: error page key '{}' is not a status code", page.line, page.key))?;
+        if !matches!(page.value.kind, NodeKind::Map(_)) {
+            pages.insert(code, string(page)?);
+            continue;
         }
-        "cgi_interpreter" => {
-            route.cgi_interpreter = Some(value.to_string());
+
+        let mut languages = Vec::new();
+        let mut fallback = None;
+        for variant in expect_entries(page)? {
+            if variant.key == "default" {
+                fallback = Some(string(variant)?);
+            } else {
+                languages.push((variant.key.to_ascii_lowercase(), string(variant)?));
+            }
         }
-        "methods" => {
-            route.methods = parse_list(value);
+        match fallback.or_else(|| languages.first().map(|(_, path)| path.clone())) {
+            Some(path) => {
+                pages.insert(code, path);
+            }
+            None => {
+                return Err(format!("{}: error page {} lists no languages", page.line, code));
+            }
         }
-        _ => {}
+        localized.insert(code, languages);
     }
+    Ok((pages, localized, default))
 }
-fn split_kv(line: &str) -> (&str, &str) {
-    if let Some((k, v)) = line.split_once(':') {
-        (k.trim(), v.trim().trim_matches('"').trim_matches('\''))
-    } else {
-        (line.trim(), "")
+
+/// `{ header: X-Canary, value: "1", root: ./www-next }`, or `cookie:` instead of
+/// `header:` and `proxy_pass:` (one upstream or a list) instead of `root:`.
+fn canary(entry: &MapEntry) -> Result<Option<Canary>, String> {
+    if matches!(entry.value.kind, NodeKind::Null) {
+        return Ok(None);
+    }
+    let mut trigger = None;
+    let mut value = None;
+    let mut root = None;
+    let mut proxy_pass = Vec::new();
+    for field in expect_entries(entry)? {
+        match field.key.as_str() {
+            "header" => {
+                trigger = Some(CanaryTrigger::Header(string(field)?.to_ascii_lowercase()));
+            }
+            "cookie" => {
+                trigger = Some(CanaryTrigger::Cookie(string(field)?));
+            }
+            "value" => {
+                value = opt_string(field)?;
+            }
+            "root" => {
+                root = opt_string(field)?;
+            }
+            "proxy_pass" => {
+                proxy_pass = match &field.value.kind {
+                    NodeKind::Null => Vec::new(),
+                    NodeKind::Seq(_) => list(field)?,
+                    _ => vec![string(field)?],
+                };
+            }
+            other => {
+                return Err(format!(
+                    "{}: unknown canary option '{}' (expected header, cookie, value, root, proxy_pass)",
+                    field.line,
+                    other
+                ));
+            }
+        }
+    }
+    match (trigger, root.is_some(), proxy_pass.is_empty()) {
+        (Some(trigger), true, true) | (Some(trigger), false, false) => Ok(Some(Canary { trigger, value, root, proxy_pass })),
+        _ => Err(format!("{}: 'canary' needs a 'header' or 'cookie' and either a 'root' or a 'proxy_pass'", entry.line)),
     }
 }
 
-fn parse_list(value: &str) -> Vec<String> {
-    value
-        .trim_matches(|c| c == '[' || c == ']' || c == ' ')
-        .split(',')
-        .map(|s| s.trim().trim_matches('"').trim_matches('\'').to_string())
-        .filter(|s| !s.is_empty())
+fn pairs(entry: &MapEntry) -> Result<Vec<(String, String)>, String> {
+    expect_entries(entry)?
+        .iter()
+        .map(|e| Ok((e.key.clone(), string(e)?)))
         .collect()
 }
 
-fn default_server() -> ServerConfig {
+fn unknown_key(entry: &MapEntry, strict: bool) -> Result<(), String> {
+    if !strict {
+        return Ok(());
+    }
+    Err(format!("{}: unknown key '{}' (set 'strict: false' to ignore unknown keys)", entry.line, entry.key))
+}
+
+fn invalid(entry: &MapEntry, expected: &str, found: &str) -> String {
+    format!("{}: '{}' expects {}, found '{}'", entry.line, entry.key, expected, found)
+}
+
+/// Single server answering on every interface and listing `root`, for
+/// `--port` / `--root` without a config file.
+pub fn adhoc_config(port: &str, root: &str) -> Config {
+    let mut route = default_route();
+    route.root = root.to_string();
+    route.autoindex = true;
+    route.methods = vec!["GET".to_string(), "HEAD".to_string()];
+
+    let mut server = default_server();
+    server.port = port.to_string();
+    server.routes.push(route);
+
+    let mut config = default_config();
+    config.servers.push(server);
+    config
+}
+
+pub(super) fn default_config() -> Config {
+    Config {
+        servers: Vec::new(),
+        max_server_size: 10485760,
+        timeout_seconds: Duration::from_secs(30),
+        cgi_timeout: None,
+        access_log_exclude: Vec::new(),
+        maintenance_file: None,
+        maintenance_retry_after: Duration::from_secs(120),
+        maintenance_allow: vec!["/healthz".to_string()],
+        auth_nonce_timeout: Duration::from_secs(300),
+        user: None,
+        group: None,
+        chroot: None,
+        ban_threshold: 0,
+        ban_window_seconds: Duration::from_secs(60),
+        ban_duration_seconds: Duration::from_secs(600),
+        session_secret: None,
+        trusted_proxies: Vec::new(),
+        mime_types: Vec::new(),
+        default_type: None,
+        log_level: Level::Info,
+        bind_policy: BindPolicy::BestEffort,
+        server_header: Some(DEFAULT_SERVER_HEADER.to_string()),
+        error_pages: HashMap::new(),
+        localized_error_pages: HashMap::new(),
+        default_error_page: None,
+        warnings: Vec::new(),
+    }
+}
+
+pub(super) fn default_server() -> ServerConfig {
     ServerConfig {
         host: "0.0.0.0".to_string(),
         port: String::new(),
-        server_name: "localhost".to_string(),
+        resolve: HostResolution::All,
+        server_names: vec!["localhost".to_string()],
         max_body_size: 1024 * 1024,
+        https_redirect: false,
+        https_port: None,
+        proxy_protocol: ProxyProtocol::Off,
+        connect_proxy: false,
+        connect_allow: Vec::new(),
+        upload_progress: None,
+        upstream_status: None,
+        purge_allow: Vec::new(),
+        robots: Robots::AllowAll,
+        favicon: true,
+        add_headers: Vec::new(),
         error_pages: HashMap::new(),
+        localized_error_pages: HashMap::new(),
+        default_error_page: None,
         routes: Vec::new(),
     }
 }
 
-fn default_route() -> RouteConfig {
+pub(super) fn default_route() -> RouteConfig {
     RouteConfig {
         path: "/".to_string(),
         root: "./www".to_string(),
+        fallback_roots: Vec::new(),
         upload_dir: None,
+        verify_uploads: false,
         methods: Vec::new(),
         index: None,
         autoindex: false,
         redirect: None,
+        fixed_response: None,
         cgi_extension: None,
         cgi_interpreter: None,
+        cgi_cache: None,
+        access_log: true,
+        auth: None,
+        auth_realm: None,
+        auth_user_file: None,
+        jwt_secret: None,
+        jwt_public_key: None,
+        jwt_key: None,
+        jwt_issuer: None,
+        jwt_audience: None,
+        add_headers: Vec::new(),
+        sub_filter: Vec::new(),
+        sub_filter_types: vec!["text/html".to_string()],
+        markdown: false,
+        markdown_template: None,
+        canary: None,
+        csrf: false,
+        websocket: false,
+        sse: false,
+        mirror: None,
+        proxy_pass: Vec::new(),
+        proxy_balance: Balance::RoundRobin,
+        proxy_sticky: None,
+        proxy_fail_timeout: Duration::from_secs(10),
+        proxy_forwarded: false,
+        proxy_next_upstream_tries: 0,
+        proxy_next_upstream_timeout: Duration::ZERO,
+        proxy_health: None,
+        proxy_cache: None,
+        handler: None,
+        max_body_size: None,
+        redirect_code: StatusCode::MOVED_PERMANENTLY,
     }
 }
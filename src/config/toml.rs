@@ -0,0 +1,455 @@
+//! TOML reader producing the same tree as the YAML one: `[table]`,
+//! `[[array.of.tables]]`, dotted keys, basic / literal / multi-line strings,
+//! integers, floats, booleans, dates, arrays and inline tables.
+
+use crate::config::node::{ MapEntry, Node, NodeKind };
+
+struct Cursor {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+/// Parses a document; errors are `line: message`, the caller adds the file name.
+pub fn parse(content: &str) -> Result<Node, String> {
+    let mut cur = Cursor { chars: content.chars().collect(), pos: 0, line: 1 };
+    let mut root: Vec<MapEntry> = Vec::new();
+    let mut table: Vec<String> = Vec::new();
+
+    loop {
+        cur.skip_blank_lines();
+        let Some(c) = cur.peek() else {
+            break;
+        };
+        let line = cur.line;
+
+        if c == '[' {
+            cur.pos += 1;
+            let array = cur.peek() == Some('[');
+            if array {
+                cur.pos += 1;
+            }
+            let path = cur.parse_key()?;
+            cur.expect(']')?;
+            if array {
+                cur.expect(']')?;
+            }
+            cur.end_of_line()?;
+
+            let (last, parent) = path.split_last().unwrap();
+            let parent_table = table_at(&mut root, parent, line)?;
+            if array {
+                push_table(parent_table, last, line)?;
+            } else {
+                match parent_table.iter().find(|e| &e.key == last) {
+                    Some(e) if !matches!(e.value.kind, NodeKind::Map(_)) => {
+                        return Err(format!("{}: '{}' is already defined as a value", line, last));
+                    }
+                    Some(_) => {}
+                    None => parent_table.push(MapEntry {
+                        key: last.clone(),
+                        line,
+                        value: Node { line, kind: NodeKind::Map(Vec::new()) },
+                    }),
+                }
+            }
+            table = path;
+            continue;
+        }
+
+        let key = cur.parse_key()?;
+        cur.skip_spaces();
+        cur.expect('=')?;
+        cur.skip_spaces();
+        let value = cur.parse_value()?;
+        cur.end_of_line()?;
+
+        let (last, parent) = key.split_last().unwrap();
+        let mut full = table.clone();
+        full.extend_from_slice(parent);
+        let target = table_at(&mut root, &full, line)?;
+        if target.iter().any(|e| &e.key == last) {
+            return Err(format!("{}: duplicate key '{}'", line, last));
+        }
+        target.push(MapEntry { key: last.clone(), line, value });
+    }
+
+    Ok(Node { line: 1, kind: NodeKind::Map(root) })
+}
+
+/// Walks (and creates) nested tables; an array of tables resolves to its last element.
+fn table_at<'a>(
+    root: &'a mut Vec<MapEntry>,
+    path: &[String],
+    line: usize
+) -> Result<&'a mut Vec<MapEntry>, String> {
+    let mut current = root;
+    for segment in path {
+        let idx = match current.iter().position(|e| &e.key == segment) {
+            Some(idx) => idx,
+            None => {
+                current.push(MapEntry {
+                    key: segment.clone(),
+                    line,
+                    value: Node { line, kind: NodeKind::Map(Vec::new()) },
+                });
+                current.len() - 1
+            }
+        };
+        current = match &mut current[idx].value.kind {
+            NodeKind::Map(entries) => entries,
+            NodeKind::Seq(items) =>
+                match items.last_mut().map(|n| &mut n.kind) {
+                    Some(NodeKind::Map(entries)) => entries,
+                    _ => {
+                        return Err(format!("{}: '{}' is not a table", line, segment));
+                    }
+                }
+            _ => {
+                return Err(format!("{}: '{}' is already defined as a value", line, segment));
+            }
+        };
+    }
+    Ok(current)
+}
+
+fn push_table(parent: &mut Vec<MapEntry>, key: &str, line: usize) -> Result<(), String> {
+    let table = Node { line, kind: NodeKind::Map(Vec::new()) };
+    match parent.iter_mut().find(|e| e.key == key) {
+        Some(entry) =>
+            match &mut entry.value.kind {
+                NodeKind::Seq(items) => items.push(table),
+                _ => {
+                    return Err(format!("{}: '{}' is already defined and is not an array of tables", line, key));
+                }
+            }
+        None =>
+            parent.push(MapEntry {
+                key: key.to_string(),
+                line,
+                value: Node { line, kind: NodeKind::Seq(vec![table]) },
+            }),
+    }
+    Ok(())
+}
+
+impl Cursor {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, wanted: char) -> Result<(), String> {
+        self.skip_spaces();
+        match self.bump() {
+            Some(c) if c == wanted => Ok(()),
+            Some(c) => Err(format!("{}: expected '{}', found '{}'", self.line, wanted, c)),
+            None => Err(format!("{}: expected '{}' before end of file", self.line, wanted)),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while self.peek().is_some_and(|c| c != '\n') {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') | Some('\r') => {
+                    self.bump();
+                }
+                _ => {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Skips whitespace, comments and newlines inside arrays.
+    fn skip_array_space(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            if matches!(self.peek(), Some('\n') | Some('\r')) {
+                self.bump();
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.chars.get(self.pos + 1) == Some(&'\n') => Ok(()),
+            Some(c) => Err(format!("{}: unexpected '{}' after value", self.line, c)),
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_spaces();
+            let part = match self.peek() {
+                Some('"') => self.parse_basic_string()?,
+                Some('\'') => self.parse_literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(format!("{}: expected a key", self.line));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            parts.push(part);
+            self.skip_spaces();
+            if self.peek() == Some('.') {
+                self.pos += 1;
+            } else {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Node, String> {
+        let line = self.line;
+        let kind = match self.peek() {
+            Some('"') => NodeKind::Scalar(self.parse_basic_string()?),
+            Some('\'') => NodeKind::Scalar(self.parse_literal_string()?),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_array_space();
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        break;
+                    }
+                    items.push(self.parse_value()?);
+                    self.skip_array_space();
+                    match self.bump() {
+                        Some(',') => {}
+                        Some(']') => {
+                            break;
+                        }
+                        _ => {
+                            return Err(format!("{}: unterminated array", line));
+                        }
+                    }
+                }
+                NodeKind::Seq(items)
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut entries: Vec<MapEntry> = Vec::new();
+                self.skip_spaces();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        let key = self.parse_key()?;
+                        self.expect('=')?;
+                        self.skip_spaces();
+                        let value = self.parse_value()?;
+                        let (last, parent) = key.split_last().unwrap();
+                        let target = table_at(&mut entries, parent, line)?;
+                        if target.iter().any(|e| &e.key == last) {
+                            return Err(format!("{}: duplicate key '{}'", line, last));
+                        }
+                        target.push(MapEntry { key: last.clone(), line, value });
+                        self.skip_spaces();
+                        match self.bump() {
+                            Some(',') => {}
+                            Some('}') => {
+                                break;
+                            }
+                            _ => {
+                                return Err(format!("{}: unterminated inline table", line));
+                            }
+                        }
+                    }
+                }
+                NodeKind::Map(entries)
+            }
+            _ => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| !matches!(c, ',' | ']' | '}' | '#' | '\n' | '\r')) {
+                    self.pos += 1;
+                }
+                let raw: String = self.chars[start..self.pos].iter().collect();
+                let raw = raw.trim();
+                if !is_bare_value(raw) {
+                    return Err(format!("{}: invalid value '{}' (strings must be quoted)", line, raw));
+                }
+                NodeKind::Scalar(raw.replace('_', ""))
+            }
+        };
+        Ok(Node { line, kind })
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, String> {
+        let multiline = self.starts_with("\"\"\"");
+        self.pos += if multiline { 3 } else { 1 };
+        if multiline {
+            self.skip_first_newline();
+        }
+
+        let mut out = String::new();
+        loop {
+            if multiline && self.starts_with("\"\"\"") {
+                self.pos += 3;
+                return Ok(out);
+            }
+            match self.bump() {
+                None => {
+                    return Err(format!("{}: unterminated string", self.line));
+                }
+                Some('"') if !multiline => {
+                    return Ok(out);
+                }
+                Some('\n') if !multiline => {
+                    return Err(format!("{}: newline in single-line string", self.line - 1));
+                }
+                Some('\\') => {
+                    match self.bump() {
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some('r') => out.push('\r'),
+                        Some('"') => out.push('"'),
+                        Some('\\') => out.push('\\'),
+                        Some('u') => out.push(self.parse_unicode_escape(4)?),
+                        Some('U') => out.push(self.parse_unicode_escape(8)?),
+                        // Line-ending backslash trims the following whitespace
+                        Some('\n') if multiline => {
+                            while self.peek().is_some_and(|c| c.is_whitespace()) {
+                                self.bump();
+                            }
+                        }
+                        other => {
+                            return Err(format!("{}: invalid escape '\\{}'", self.line, other.unwrap_or(' ')));
+                        }
+                    }
+                }
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String, String> {
+        let multiline = self.starts_with("'''");
+        self.pos += if multiline { 3 } else { 1 };
+        if multiline {
+            self.skip_first_newline();
+        }
+
+        let mut out = String::new();
+        loop {
+            if multiline && self.starts_with("'''") {
+                self.pos += 3;
+                return Ok(out);
+            }
+            match self.bump() {
+                None => {
+                    return Err(format!("{}: unterminated string", self.line));
+                }
+                Some('\'') if !multiline => {
+                    return Ok(out);
+                }
+                Some('\n') if !multiline => {
+                    return Err(format!("{}: newline in single-line string", self.line - 1));
+                }
+                Some(c) => out.push(c),
+            }
+        }
+    }
+
+    fn parse_unicode_escape(&mut self, digits: usize) -> Result<char, String> {
+        let hex: String = self.chars.get(self.pos..self.pos + digits).unwrap_or(&[]).iter().collect();
+        self.pos += digits;
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("{}: invalid unicode escape '{}'", self.line, hex))
+    }
+
+    fn starts_with(&self, pattern: &str) -> bool {
+        pattern.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn skip_first_newline(&mut self) {
+        if self.peek() == Some('\r') {
+            self.pos += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.bump();
+        }
+    }
+}
+
+/// Booleans, numbers (with `_` separators, hex/octal/binary, inf/nan) and dates.
+fn is_bare_value(raw: &str) -> bool {
+    if matches!(raw, "true" | "false") {
+        return true;
+    }
+    let unsigned = raw.trim_start_matches(['+', '-']);
+    if matches!(unsigned, "inf" | "nan") {
+        return true;
+    }
+    if unsigned.starts_with("0x") || unsigned.starts_with("0o") || unsigned.starts_with("0b") {
+        return unsigned.len() > 2 && unsigned[2..].chars().all(|c| c.is_ascii_hexdigit() || c == '_');
+    }
+    let is_number =
+        unsigned.starts_with(|c: char| c.is_ascii_digit()) &&
+        unsigned.chars().all(|c| c.is_ascii_digit() || matches!(c, '_' | '.' | 'e' | 'E' | '+' | '-')) &&
+        unsigned.replace('_', "").parse::<f64>().is_ok();
+    is_number || is_date_time(raw)
+}
+
+/// `1979-05-27`, `07:32:00`, or both joined by `T` or a space, with optional
+/// fractional seconds and a `Z` or `+hh:mm` offset.
+fn is_date_time(raw: &str) -> bool {
+    let (date, time) = match raw.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (Some(date), Some(time)),
+        None if raw.contains(':') => (None, Some(raw)),
+        None => (Some(raw), None),
+    };
+    let shaped = |text: &str, shape: &str| {
+        text.len() == shape.len() && text.bytes().zip(shape.bytes()).all(|(c, s)| if s == b'0' { c.is_ascii_digit() } else { c == s })
+    };
+    let time_ok = |time: &str| {
+        let time = time.strip_suffix(['Z', 'z']).unwrap_or(time);
+        let time = match time.len().checked_sub(6).filter(|&at| at >= 8 && matches!(time.as_bytes()[at], b'+' | b'-')) {
+            Some(at) if shaped(&time[at + 1..], "00:00") => &time[..at],
+            _ => time,
+        };
+        let (clock, fraction) = time.split_at(time.len().min(8));
+        shaped(clock, "00:00:00") &&
+            (fraction.is_empty() || (fraction.len() > 1 && fraction.starts_with('.') && fraction[1..].bytes().all(|b| b.is_ascii_digit())))
+    };
+    date.is_none_or(|date| shaped(date, "0000-00-00")) && time.is_none_or(time_ok)
+}
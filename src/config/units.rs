@@ -0,0 +1,82 @@
+//! Human-friendly quantities in config values: sizes such as `512K` or `10M`
+//! and durations such as `500ms`, `30s` or `1h30m`.
+
+use std::time::Duration;
+
+/// Parses a byte count with an optional binary suffix: `K`, `M`, `G`
+/// (case-insensitive, optionally followed by `B` or `iB`). Plain numbers are bytes.
+pub fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, suffix) = value.split_at(split);
+    let number: usize = digits.parse().ok()?;
+
+    let unit = suffix.trim_start().to_ascii_lowercase();
+    let shift = match unit.trim_end_matches("ib").trim_end_matches('b') {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        _ => {
+            return None;
+        }
+    };
+    number.checked_mul(1usize << shift)
+}
+
+/// Parses a duration made of `<number><unit>` parts (`ms`, `s`, `m`, `h`, `d`),
+/// e.g. `500ms`, `30s`, `1m30s`. A plain number is seconds.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    if value.is_empty() {
+        return None;
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let number: u64 = rest[..split].parse().ok()?;
+        rest = &rest[split..];
+
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let part = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number.checked_mul(60)?),
+            "h" => Duration::from_secs(number.checked_mul(3600)?),
+            "d" => Duration::from_secs(number.checked_mul(86400)?),
+            _ => {
+                return None;
+            }
+        };
+        total = total.checked_add(part)?;
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+/// Inverse of `parse_duration`: the shortest `1h30m` / `500ms` style spelling.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis == 0 {
+        return "0s".to_string();
+    }
+    if !millis.is_multiple_of(1000) {
+        return format!("{}ms", millis);
+    }
+
+    let mut seconds = duration.as_secs();
+    let mut out = String::new();
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if seconds >= size {
+            out.push_str(&format!("{}{}", seconds / size, unit));
+            seconds %= size;
+        }
+    }
+    out
+}
@@ -0,0 +1,267 @@
+//! Semantic checks behind `--test-config`: the file parsed, but would the
+//! server actually be able to use it? Nothing is bound or spawned here.
+
+use crate::config::models::{ Config, ProxyProtocol, RouteConfig, ServerConfig };
+use crate::http::request::KNOWN_METHODS;
+use crate::server::forwarded::TrustedProxies;
+use crate::server::proxy::Upstream;
+use crate::vfs::MOUNT_PREFIX;
+use std::collections::HashMap;
+use std::net::{ SocketAddr, ToSocketAddrs };
+use std::os::unix::fs::PermissionsExt;
+use std::path::{ Path, PathBuf };
+
+/// Returns every problem found; an empty list means the config is usable.
+pub fn validate_config(config: &Config) -> Vec<String> {
+    check_config(config, Lookups::Resolve)
+}
+
+/// `validate_config` for a reload on the running server: upstream targets are
+/// only checked for syntax, since looking them up would stall every
+/// connection, and one briefly unresolvable should not hold back the rest.
+pub(crate) fn validate_reload(config: &Config) -> Vec<String> {
+    check_config(config, Lookups::Skip)
+}
+
+/// Whether `proxy_pass` and `mirror` host names are looked up.
+#[derive(Clone, Copy, PartialEq)]
+enum Lookups {
+    Resolve,
+    Skip,
+}
+
+fn check_config(config: &Config, lookups: Lookups) -> Vec<String> {
+    let mut problems = Vec::new();
+    let chroot = config.chroot.as_deref();
+
+    if config.servers.is_empty() {
+        problems.push("no servers are defined".to_string());
+    }
+    if let Err(e) = TrustedProxies::parse(&config.trusted_proxies) {
+        problems.push(e);
+    }
+    if let Some(dir) = chroot && !Path::new(dir).is_dir() {
+        problems.push(format!("chroot: '{}' is not a directory", dir));
+    }
+
+    problems.extend(listener_conflicts(config));
+
+    for (idx, server) in config.servers.iter().enumerate() {
+        let label = server_label(idx, server);
+
+        // Same lookup as the server: as written, then relative to the working directory
+        let pages = server.error_pages
+            .iter()
+            .map(|(code, page)| (code.to_string(), page))
+            .chain(
+                server.localized_error_pages
+                    .iter()
+                    .flat_map(|(code, languages)| {
+                        languages.iter().map(move |(language, page)| (format!("{} ({})", code, language), page))
+                    })
+            )
+            .chain(server.default_error_page.iter().map(|page| ("default".to_string(), page)));
+        for (code, page) in pages {
+            if !resolve(chroot, page).is_file() && !resolve(chroot, page.trim_start_matches('/')).is_file() {
+                problems.push(format!("{}: error page {} '{}' does not exist", label, code, page));
+            }
+        }
+
+        if let Some(path) = &server.upload_progress && !path.starts_with('/') {
+            problems.push(format!("{}: upload_progress '{}' must start with '/'", label, path));
+        }
+        if let Some(path) = &server.upstream_status && !path.starts_with('/') {
+            problems.push(format!("{}: upstream_status '{}' must start with '/'", label, path));
+        }
+        if server.proxy_protocol != ProxyProtocol::Off && config.trusted_proxies.is_empty() {
+            problems.push(format!("{}: proxy_protocol needs the balancer listed in trusted_proxies", label));
+        }
+        if let Err(e) = TrustedProxies::parse_as("purge_allow", &server.purge_allow) {
+            problems.push(format!("{}: {}", label, e));
+        }
+
+        if server.routes.is_empty() {
+            problems.push(format!("{}: no routes are defined", label));
+        }
+        for route in &server.routes {
+            check_route(&mut problems, &label, chroot, route, lookups);
+        }
+    }
+
+    problems
+}
+
+/// Listen addresses that cannot all be bound as configured: invalid
+/// addresses, a wildcard and a specific address sharing a port, and the same
+/// server name twice on one address. Every conflict is reported, not just the first.
+pub fn listener_conflicts(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // host:port -> server names already seen on that listener
+    let mut listeners: HashMap<SocketAddr, Vec<&str>> = HashMap::new();
+
+    for (idx, server) in config.servers.iter().enumerate() {
+        let label = server_label(idx, server);
+        match server.listen_addrs() {
+            Ok(addrs) => {
+                for addr in addrs {
+                    check_listener(&mut problems, &label, &mut listeners, addr, server);
+                }
+            }
+            Err(e) => problems.push(format!("{}: {}", label, e)),
+        }
+    }
+
+    problems
+}
+
+fn server_label(idx: usize, server: &ServerConfig) -> String {
+    format!("server #{} ({}:{})", idx + 1, server.host, server.port)
+}
+
+fn check_listener<'a>(
+    problems: &mut Vec<String>,
+    label: &str,
+    listeners: &mut HashMap<SocketAddr, Vec<&'a str>>,
+    addr: SocketAddr,
+    server: &'a ServerConfig
+) {
+    // A wildcard and a specific address on the same port cannot both be bound;
+    // an exact repeat shares the first listener instead
+    for other in listeners.keys() {
+        if
+            !listeners.contains_key(&addr) &&
+            other.port() == addr.port() &&
+            other.ip() != addr.ip() &&
+            (other.ip().is_unspecified() || addr.ip().is_unspecified())
+        {
+            problems.push(format!("{}: port {} is already bound by {}", label, addr.port(), other));
+        }
+    }
+
+    let names = listeners.entry(addr).or_default();
+    for name in &server.server_names {
+        if names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            problems.push(format!("{}: server_name '{}' is already used on {}", label, name, addr));
+        }
+        names.push(name);
+    }
+}
+
+fn check_route(problems: &mut Vec<String>, label: &str, chroot: Option<&str>, route: &RouteConfig, lookups: Lookups) {
+    let label = format!("{} route '{}'", label, route.path);
+
+    if !route.path.starts_with('/') {
+        problems.push(format!("{}: path must start with '/'", label));
+    }
+    for method in &route.methods {
+        if !KNOWN_METHODS.contains(&method.to_ascii_uppercase().as_str()) {
+            problems.push(format!("{}: unknown method '{}'", label, method));
+        }
+    }
+
+    // Redirect and `return` routes never touch the filesystem, and mounted trees only exist at run time
+    if route.redirect.is_none() && route.fixed_response.is_none() && !route.root.starts_with(MOUNT_PREFIX) {
+        for dir in std::iter::once(&route.root).chain(&route.fallback_roots) {
+            let root = resolve(chroot, dir);
+            if !root.is_dir() {
+                problems.push(format!("{}: root '{}' is not a directory", label, dir));
+            } else if std::fs::read_dir(&root).is_err() {
+                problems.push(format!("{}: root '{}' is not readable", label, dir));
+            }
+        }
+    }
+    if
+        !route.fallback_roots.is_empty() &&
+        (route.root.starts_with(MOUNT_PREFIX) || route.fallback_roots.iter().any(|r| r.starts_with(MOUNT_PREFIX)))
+    {
+        problems.push(format!("{}: a list of roots can only name directories, not mounted trees", label));
+    }
+
+    // Created on the first upload, so only an existing non-directory is wrong
+    if let Some(dir) = &route.upload_dir {
+        let path = resolve(chroot, dir);
+        if path.exists() && !path.is_dir() {
+            problems.push(format!("{}: upload_dir '{}' is not a directory", label, dir));
+        }
+    }
+
+    if let Some(interpreter) = &route.cgi_interpreter && !is_executable(&resolve(chroot, interpreter)) {
+        problems.push(format!("{}: cgi_interpreter '{}' is not an executable file", label, interpreter));
+    }
+
+    if route.cgi_cache.is_some() && route.cgi_extension.is_none() {
+        problems.push(format!("{}: cgi_cache needs cgi_extension", label));
+    }
+    // The script's answer would go to every client, with the first one's user and CSRF token
+    if route.cgi_cache.is_some() && (route.auth.is_some() || route.csrf) {
+        problems.push(format!("{}: cgi_cache cannot be shared on a route with auth or csrf", label));
+    }
+
+    if let Some(template) = &route.markdown_template && !resolve(chroot, template).is_file() {
+        problems.push(format!("{}: markdown_template '{}' does not exist", label, template));
+    }
+
+    if let Some(canary) = &route.canary && let Some(root) = &canary.root && !resolve(chroot, root).is_dir() {
+        problems.push(format!("{}: canary root '{}' is not a directory", label, root));
+    }
+
+    match route.auth.as_deref() {
+        None | Some("basic") | Some("digest") => {
+            if route.auth.is_some() {
+                match &route.auth_user_file {
+                    Some(file) if !resolve(chroot, file).is_file() => {
+                        problems.push(format!("{}: auth_user_file '{}' does not exist", label, file));
+                    }
+                    None => problems.push(format!("{}: auth requires auth_user_file", label)),
+                    _ => {}
+                }
+            }
+        }
+        Some("jwt") => {
+            if route.jwt_secret.is_none() && route.jwt_public_key.is_none() {
+                problems.push(format!("{}: jwt auth requires jwt_secret or jwt_public_key", label));
+            }
+            if let Some(key) = &route.jwt_public_key && !resolve(chroot, key).is_file() {
+                problems.push(format!("{}: jwt_public_key '{}' does not exist", label, key));
+            }
+        }
+        Some(other) => problems.push(format!("{}: unknown auth scheme '{}'", label, other)),
+    }
+
+    let unresolvable = |upstream: &Upstream| {
+        lookups == Lookups::Resolve && upstream.address.to_socket_addrs().map_or(true, |mut a| a.next().is_none())
+    };
+    if let Some(target) = &route.mirror {
+        match Upstream::parse_bare(target) {
+            Some(upstream) if unresolvable(&upstream) => {
+                problems.push(format!("{}: mirror '{}' does not resolve to host:port", label, target));
+            }
+            Some(_) => {}
+            None => problems.push(format!("{}: mirror '{}' is not host:port or an http:// URL", label, target)),
+        }
+    }
+
+    for target in route.proxy_pass.iter().chain(route.canary.iter().flat_map(|canary| &canary.proxy_pass)) {
+        match Upstream::parse(target) {
+            Some(upstream) if unresolvable(&upstream) => {
+                problems.push(format!("{}: proxy_pass '{}' does not resolve", label, target));
+            }
+            Some(_) => {}
+            None => problems.push(format!("{}: proxy_pass '{}' is not an http:// URL", label, target)),
+        }
+    }
+}
+
+/// Paths in the config are relative to the working directory, or to the
+/// chroot once the server has entered it.
+fn resolve(chroot: Option<&str>, path: &str) -> PathBuf {
+    match chroot {
+        Some(dir) => Path::new(dir).join(path.trim_start_matches("./").trim_start_matches('/')),
+        None => PathBuf::from(path),
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
@@ -0,0 +1,463 @@
+//! The YAML subset used by `config.yaml`: block mappings and sequences at any
+//! indentation, flow `[..]` / `{..}` collections, plain / quoted scalars,
+//! `|` and `>` block scalars, and comments. Anchors, aliases, tags and
+//! multi-document streams are rejected instead of being misread.
+
+use crate::config::node::{ MapEntry, Node, NodeKind };
+
+struct Line {
+    no: usize,
+    indent: usize,
+    text: String,
+}
+
+struct Parser<'a> {
+    raw: Vec<&'a str>,
+    lines: Vec<Line>,
+    pos: usize,
+}
+
+/// Parses a document; errors are `line: message`, the caller adds the file name.
+pub fn parse(content: &str) -> Result<Node, String> {
+    let raw: Vec<&str> = content.lines().collect();
+    let mut lines = Vec::new();
+
+    for (idx, raw_line) in raw.iter().enumerate() {
+        let no = idx + 1;
+        let body = raw_line.trim_start_matches(' ');
+        if body.starts_with('\t') {
+            return Err(format!("{}: tabs are not allowed for indentation", no));
+        }
+        let text = strip_comment(body).trim_end().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        if text == "---" && lines.is_empty() {
+            continue;
+        }
+        if text == "---" || text == "..." {
+            return Err(format!("{}: only a single YAML document is supported", no));
+        }
+        lines.push(Line { no, indent: raw_line.len() - body.len(), text });
+    }
+
+    let mut parser = Parser { raw, lines, pos: 0 };
+    if parser.lines.is_empty() {
+        return Ok(Node { line: 1, kind: NodeKind::Map(Vec::new()) });
+    }
+
+    let indent = parser.lines[0].indent;
+    let root = parser.parse_block(indent)?;
+    if let Some(line) = parser.lines.get(parser.pos) {
+        return Err(format!("{}: unexpected indentation", line.no));
+    }
+    Ok(root)
+}
+
+impl Parser<'_> {
+    fn parse_block(&mut self, indent: usize) -> Result<Node, String> {
+        let line = &self.lines[self.pos];
+        if is_seq_item(&line.text) {
+            self.parse_seq(indent)
+        } else {
+            self.parse_map(indent)
+        }
+    }
+
+    fn parse_map(&mut self, indent: usize) -> Result<Node, String> {
+        let start = self.lines[self.pos].no;
+        let mut entries: Vec<MapEntry> = Vec::new();
+
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent < indent {
+                break;
+            }
+            if line.indent > indent {
+                return Err(format!("{}: unexpected indentation", line.no));
+            }
+            if is_seq_item(&line.text) {
+                return Err(format!("{}: sequence item where a 'key: value' was expected", line.no));
+            }
+
+            let no = line.no;
+            let (key, rest) = split_mapping(&line.text).ok_or_else(||
+                format!("{}: expected 'key: value', found '{}'", no, line.text)
+            )?;
+            if entries.iter().any(|e| e.key == key) {
+                return Err(format!("{}: duplicate key '{}'", no, key));
+            }
+            self.pos += 1;
+
+            let value = self.parse_value(&rest, indent, no, true)?;
+            entries.push(MapEntry { key, line: no, value });
+        }
+
+        Ok(Node { line: start, kind: NodeKind::Map(entries) })
+    }
+
+    fn parse_seq(&mut self, indent: usize) -> Result<Node, String> {
+        let start = self.lines[self.pos].no;
+        let mut items = Vec::new();
+
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent < indent {
+                break;
+            }
+            if line.indent > indent {
+                return Err(format!("{}: unexpected indentation", line.no));
+            }
+            if !is_seq_item(&line.text) {
+                break;
+            }
+
+            let no = line.no;
+            let rest = line.text[1..].trim_start().to_string();
+            let offset = line.text.len() - rest.len();
+
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.parse_nested(indent, no, false)?);
+            } else if is_seq_item(&rest) || split_mapping(&rest).is_some() {
+                // "- key: value" opens a block collection aligned with its first key
+                let line = &mut self.lines[self.pos];
+                line.indent = indent + offset;
+                line.text = rest;
+                let inner = line.indent;
+                items.push(self.parse_block(inner)?);
+            } else {
+                self.pos += 1;
+                items.push(self.parse_value(&rest, indent, no, false)?);
+            }
+        }
+
+        Ok(Node { line: start, kind: NodeKind::Seq(items) })
+    }
+
+    /// Value after `key:` or `- `: inline, a block scalar, or a nested block.
+    fn parse_value(
+        &mut self,
+        rest: &str,
+        indent: usize,
+        no: usize,
+        in_map: bool
+    ) -> Result<Node, String> {
+        if rest.is_empty() {
+            return self.parse_nested(indent, no, in_map);
+        }
+        if rest.starts_with('|') || rest.starts_with('>') {
+            return self.parse_block_scalar(rest, indent, no);
+        }
+        if rest.starts_with('&') || rest.starts_with('*') || rest.starts_with('!') {
+            return Err(format!("{}: anchors, aliases and tags are not supported", no));
+        }
+        parse_flow(rest, no)
+    }
+
+    fn parse_nested(&mut self, indent: usize, no: usize, in_map: bool) -> Result<Node, String> {
+        match self.lines.get(self.pos) {
+            Some(next) if next.indent > indent => {
+                let inner = next.indent;
+                self.parse_block(inner)
+            }
+            // YAML allows a mapping's sequence value at the key's own indentation
+            Some(next) if in_map && next.indent == indent && is_seq_item(&next.text) => {
+                self.parse_seq(indent)
+            }
+            _ => Ok(Node { line: no, kind: NodeKind::Null }),
+        }
+    }
+
+    fn parse_block_scalar(&mut self, header: &str, indent: usize, no: usize) -> Result<Node, String> {
+        let folded = header.starts_with('>');
+        let chomp = header[1..].trim();
+        if !matches!(chomp, "" | "-" | "+") {
+            return Err(format!("{}: unsupported block scalar header '{}'", no, header));
+        }
+
+        // Block scalars keep blank and '#' lines, so read the raw text directly
+        let mut collected: Vec<&str> = Vec::new();
+        let mut block_indent = None;
+        let mut raw_idx = no;
+        while let Some(raw_line) = self.raw.get(raw_idx) {
+            let line_indent = raw_line.len() - raw_line.trim_start_matches(' ').len();
+            if raw_line.trim().is_empty() {
+                collected.push("");
+                raw_idx += 1;
+                continue;
+            }
+            if line_indent <= indent {
+                break;
+            }
+            let block = *block_indent.get_or_insert(line_indent);
+            if line_indent < block {
+                break;
+            }
+            collected.push(&raw_line[block..]);
+            raw_idx += 1;
+        }
+
+        while self.lines.get(self.pos).is_some_and(|l| l.no <= raw_idx) {
+            self.pos += 1;
+        }
+
+        let mut text = if folded {
+            let mut out = String::new();
+            for (i, part) in collected.iter().enumerate() {
+                if i > 0 {
+                    out.push(if part.is_empty() || collected[i - 1].is_empty() { '\n' } else { ' ' });
+                }
+                out.push_str(part);
+            }
+            out
+        } else {
+            collected.join("\n")
+        };
+        match chomp {
+            "-" => {
+                text = text.trim_end_matches('\n').to_string();
+            }
+            "+" => text.push('\n'),
+            _ => {
+                text = format!("{}\n", text.trim_end_matches('\n'));
+            }
+        }
+
+        Ok(Node { line: no, kind: NodeKind::Scalar(text) })
+    }
+}
+
+fn is_seq_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// Splits `key: value` at the first `: ` (or trailing `:`) outside quotes and brackets.
+fn split_mapping(text: &str) -> Option<(String, String)> {
+    let bytes = text.as_bytes();
+    if matches!(bytes.first(), Some(b'[') | Some(b'{')) {
+        return None;
+    }
+
+    let mut quote = None;
+    for (i, &b) in bytes.iter().enumerate() {
+        match (quote, b) {
+            (Some(q), _) if b == q => {
+                quote = None;
+            }
+            (Some(_), _) => {}
+            (None, b'"' | b'\'') if i == 0 => {
+                quote = Some(b);
+            }
+            (None, b':') if i + 1 == bytes.len() || bytes[i + 1] == b' ' => {
+                let raw_key = text[..i].trim();
+                let key = match parse_scalar(raw_key) {
+                    Ok(k) => k,
+                    Err(_) => {
+                        return None;
+                    }
+                };
+                return Some((key, text[i + 1..].trim().to_string()));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Removes a trailing `# comment` that is outside quotes and preceded by whitespace.
+fn strip_comment(text: &str) -> &str {
+    let bytes = text.as_bytes();
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, &b) in bytes.iter().enumerate() {
+        // `\"` and `''` do not end the string, as in `parse_scalar`
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, b) {
+            (Some(b'"'), b'\\') => {
+                escaped = true;
+            }
+            (Some(b'\''), b'\'') if bytes.get(i + 1) == Some(&b'\'') => {
+                escaped = true;
+            }
+            (Some(q), _) if b == q => {
+                quote = None;
+            }
+            (Some(_), _) => {}
+            (None, b'"' | b'\'') if i == 0 || matches!(bytes[i - 1], b' ' | b'[' | b'{' | b',' | b':') => {
+                quote = Some(b);
+            }
+            (None, b'#') if i == 0 || bytes[i - 1] == b' ' => {
+                return &text[..i];
+            }
+            _ => {}
+        }
+    }
+    text
+}
+
+fn parse_flow(text: &str, no: usize) -> Result<Node, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut pos = 0;
+    let node = parse_flow_node(&chars, &mut pos, no, false)?;
+    skip_spaces(&chars, &mut pos);
+    if pos != chars.len() {
+        return Err(format!("{}: unexpected trailing characters in '{}'", no, text));
+    }
+    Ok(node)
+}
+
+fn parse_flow_node(chars: &[char], pos: &mut usize, no: usize, nested: bool) -> Result<Node, String> {
+    skip_spaces(chars, pos);
+    match chars.get(*pos) {
+        Some('[') => {
+            *pos += 1;
+            let mut items = Vec::new();
+            loop {
+                skip_spaces(chars, pos);
+                if chars.get(*pos) == Some(&']') {
+                    *pos += 1;
+                    break;
+                }
+                items.push(parse_flow_node(chars, pos, no, true)?);
+                skip_spaces(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => {
+                        *pos += 1;
+                    }
+                    Some(']') => {
+                        *pos += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(format!("{}: unterminated flow sequence", no));
+                    }
+                }
+            }
+            Ok(Node { line: no, kind: NodeKind::Seq(items) })
+        }
+        Some('{') => {
+            *pos += 1;
+            let mut entries: Vec<MapEntry> = Vec::new();
+            loop {
+                skip_spaces(chars, pos);
+                if chars.get(*pos) == Some(&'}') {
+                    *pos += 1;
+                    break;
+                }
+                let key = match parse_flow_node(chars, pos, no, true)?.kind {
+                    NodeKind::Scalar(k) => k,
+                    _ => {
+                        return Err(format!("{}: flow mapping keys must be scalars", no));
+                    }
+                };
+                skip_spaces(chars, pos);
+                if chars.get(*pos) != Some(&':') {
+                    return Err(format!("{}: expected ':' after '{}' in flow mapping", no, key));
+                }
+                *pos += 1;
+                let value = parse_flow_node(chars, pos, no, true)?;
+                if entries.iter().any(|e| e.key == key) {
+                    return Err(format!("{}: duplicate key '{}'", no, key));
+                }
+                entries.push(MapEntry { key, line: no, value });
+                skip_spaces(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => {
+                        *pos += 1;
+                    }
+                    Some('}') => {
+                        *pos += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(format!("{}: unterminated flow mapping", no));
+                    }
+                }
+            }
+            Ok(Node { line: no, kind: NodeKind::Map(entries) })
+        }
+        Some(&q) if q == '"' || q == '\'' => {
+            let start = *pos;
+            *pos += 1;
+            while let Some(&c) = chars.get(*pos) {
+                *pos += 1;
+                if c == '\\' && q == '"' {
+                    *pos += 1;
+                } else if c == q {
+                    // '' is an escaped quote inside single quotes
+                    if q == '\'' && chars.get(*pos) == Some(&'\'') {
+                        *pos += 1;
+                        continue;
+                    }
+                    let raw: String = chars[start..*pos].iter().collect();
+                    let value = parse_scalar(&raw).map_err(|e| format!("{}: {}", no, e))?;
+                    return Ok(Node { line: no, kind: NodeKind::Scalar(value) });
+                }
+            }
+            Err(format!("{}: unterminated quoted string", no))
+        }
+        _ => {
+            let start = *pos;
+            while let Some(&c) = chars.get(*pos) {
+                if nested && matches!(c, ',' | ']' | '}') {
+                    break;
+                }
+                if nested && c == ':' && chars.get(*pos + 1).is_none_or(|n| *n == ' ') {
+                    break;
+                }
+                *pos += 1;
+            }
+            let raw: String = chars[start..*pos].iter().collect();
+            let raw = raw.trim();
+            if raw.is_empty() || raw == "~" || raw == "null" {
+                return Ok(Node { line: no, kind: NodeKind::Null });
+            }
+            Ok(Node { line: no, kind: NodeKind::Scalar(raw.to_string()) })
+        }
+    }
+}
+
+fn skip_spaces(chars: &[char], pos: &mut usize) {
+    while chars.get(*pos) == Some(&' ') {
+        *pos += 1;
+    }
+}
+
+/// Unquotes a scalar; plain scalars are returned as-is.
+fn parse_scalar(raw: &str) -> Result<String, String> {
+    if raw.len() >= 2 && raw.starts_with('\'') && raw.ends_with('\'') {
+        return Ok(raw[1..raw.len() - 1].replace("''", "'"));
+    }
+    if raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"') {
+        let mut out = String::new();
+        let mut chars = raw[1..raw.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('r') => out.push('\r'),
+                Some('0') => out.push('\0'),
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('/') => out.push('/'),
+                Some(other) => {
+                    return Err(format!("unsupported escape '\\{}'", other));
+                }
+                None => {
+                    return Err("dangling '\\' in string".to_string());
+                }
+            }
+        }
+        return Ok(out);
+    }
+    if raw.starts_with('"') || raw.starts_with('\'') {
+        return Err(format!("unterminated quoted string {}", raw));
+    }
+    Ok(raw.to_string())
+}
@@ -0,0 +1,218 @@
+use crate::crypto::blowfish_tables;
+
+const BCRYPT_ALPHABET: &[u8; 64] = b"./ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const MAGIC: &[u8; 24] = b"OrpheanBeholderScryDoubt";
+pub const DEFAULT_COST: u32 = 10;
+
+struct Blowfish {
+    p: [u32; 18],
+    s: [[u32; 256]; 4],
+}
+
+impl Blowfish {
+    fn initial() -> Self {
+        Self {
+            p: blowfish_tables::P,
+            s: blowfish_tables::S,
+        }
+    }
+
+    fn f(&self, x: u32) -> u32 {
+        let [a, b, c, d] = x.to_be_bytes();
+        (self.s[0][a as usize].wrapping_add(self.s[1][b as usize]) ^ self.s[2][c as usize]).wrapping_add(
+            self.s[3][d as usize]
+        )
+    }
+
+    fn encrypt(&self, mut l: u32, mut r: u32) -> (u32, u32) {
+        for i in 0..16 {
+            l ^= self.p[i];
+            r ^= self.f(l);
+            std::mem::swap(&mut l, &mut r);
+        }
+        std::mem::swap(&mut l, &mut r);
+        r ^= self.p[16];
+        l ^= self.p[17];
+        (l, r)
+    }
+
+    /// Blowfish key schedule, salted as described in the bcrypt paper ("ExpandKey").
+    fn expand_key(&mut self, key: &[u8], salt: Option<&[u8; 16]>) {
+        let mut key_pos = 0;
+        for i in 0..18 {
+            self.p[i] ^= stream_to_word(key, &mut key_pos);
+        }
+
+        let mut salt_pos = 0;
+        let next_salt = |pos: &mut usize| -> u32 {
+            match salt {
+                Some(s) => stream_to_word(s, pos),
+                None => 0,
+            }
+        };
+
+        let (mut l, mut r) = (0u32, 0u32);
+        for i in (0..18).step_by(2) {
+            l ^= next_salt(&mut salt_pos);
+            r ^= next_salt(&mut salt_pos);
+            (l, r) = self.encrypt(l, r);
+            self.p[i] = l;
+            self.p[i + 1] = r;
+        }
+        for sbox in 0..4 {
+            for i in (0..256).step_by(2) {
+                l ^= next_salt(&mut salt_pos);
+                r ^= next_salt(&mut salt_pos);
+                (l, r) = self.encrypt(l, r);
+                self.s[sbox][i] = l;
+                self.s[sbox][i + 1] = r;
+            }
+        }
+    }
+}
+
+fn stream_to_word(data: &[u8], pos: &mut usize) -> u32 {
+    let mut word = 0u32;
+    for _ in 0..4 {
+        word = (word << 8) | (data[*pos] as u32);
+        *pos = (*pos + 1) % data.len();
+    }
+    word
+}
+
+fn raw_hash(password: &[u8], salt: &[u8; 16], cost: u32) -> [u8; 24] {
+    // The key includes the trailing NUL and is capped at 72 bytes
+    let mut key = password.to_vec();
+    key.push(0);
+    key.truncate(72);
+
+    let mut state = Blowfish::initial();
+    state.expand_key(&key, Some(salt));
+    for _ in 0..1u64 << cost {
+        state.expand_key(&key, None);
+        state.expand_key(salt, None);
+    }
+
+    let mut ctext = [0u32; 6];
+    for (i, word) in MAGIC.chunks(4).enumerate() {
+        ctext[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for _ in 0..64 {
+        for pair in ctext.chunks_mut(2) {
+            let (l, r) = state.encrypt(pair[0], pair[1]);
+            pair[0] = l;
+            pair[1] = r;
+        }
+    }
+
+    let mut out = [0u8; 24];
+    for (i, word) in ctext.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+/// Produces a `$2b$` hash string for `password`.
+pub fn hash(password: &[u8], cost: u32, salt: &[u8; 16]) -> String {
+    let cost = cost.clamp(4, 31);
+    let digest = raw_hash(password, salt, cost);
+    format!("$2b${:02}${}{}", cost, encode(salt), encode(&digest[..23]))
+}
+
+/// Verifies `$2a$`, `$2b$`, and `$2y$` hashes.
+pub fn verify(password: &[u8], hash_str: &str) -> bool {
+    let parts: Vec<&str> = hash_str.split('$').collect();
+    if parts.len() != 4 || !matches!(parts[1], "2a" | "2b" | "2y") || parts[3].len() != 53 {
+        return false;
+    }
+
+    let cost = match parts[2].parse::<u32>() {
+        Ok(c) if (4..=31).contains(&c) => c,
+        _ => {
+            return false;
+        }
+    };
+
+    let salt_bytes = match decode(&parts[3][..22]) {
+        Some(bytes) if bytes.len() >= 16 => bytes,
+        _ => {
+            return false;
+        }
+    };
+    let mut salt = [0u8; 16];
+    salt.copy_from_slice(&salt_bytes[..16]);
+
+    let digest = raw_hash(password, &salt, cost);
+    crate::crypto::constant_time_eq(encode(&digest[..23]).as_bytes(), &parts[3].as_bytes()[22..])
+}
+
+fn encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+        for i in 0..=chunk.len() {
+            out.push(BCRYPT_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+fn decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = BCRYPT_ALPHABET.iter().position(|&a| a == c)? as u32;
+        acc = (acc << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // OpenBSD's bcrypt regression vectors
+    const VECTORS: [(&str, &str); 5] = [
+        ("", "$2a$06$DCq7YPn5Rq63x1Lad4cll.TV4S6ytwfsfvkgY8jIucDrjc8deX1s."),
+        ("a", "$2a$06$m0CrhHm10qJ3lXRY.5zDGO3rS2KdeeWLuGmsfGlMfOxih58VYVfxe"),
+        ("abc", "$2a$06$If6bvum7DFjUnE9p2uDeDu0YHzrHM6tf.iqN8.yx.jNN1ILEf7h0i"),
+        ("abcdefghijklmnopqrstuvwxyz", "$2a$06$.rCVZVOThsIa97pEDOxvGuRRgzG64bvtJ0938xuqzv18d3ZpQhstC"),
+        ("U*U", "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"),
+    ];
+
+    #[test]
+    fn matches_known_hashes() {
+        for (password, expected) in VECTORS {
+            assert!(verify(password.as_bytes(), expected), "{}", expected);
+            assert!(!verify(format!("{}!", password).as_bytes(), expected), "{}", expected);
+            // `$2b$` and `$2y$` only differ from `$2a$` for passwords over 255 bytes
+            assert!(verify(password.as_bytes(), &expected.replacen("$2a$", "$2y$", 1)));
+
+            let salt: [u8; 16] = decode(&expected[7..29]).unwrap()[..16].try_into().unwrap();
+            let cost = expected[4..6].parse().unwrap();
+            assert_eq!(hash(password.as_bytes(), cost, &salt), expected.replacen("$2a$", "$2b$", 1));
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_hashes() {
+        let (password, valid) = VECTORS[4];
+        for hash in [
+            valid.replacen("$2a$", "$2x$", 1),
+            valid.replacen("$05$", "$03$", 1),
+            valid[..valid.len() - 1].to_string(),
+            valid.replacen("CCCC", "CC!C", 1),
+            "$2a$05$".to_string(),
+        ] {
+            assert!(!verify(password.as_bytes(), &hash), "{}", hash);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::crypto::{ self, sha256::sha256 };
+
+const BLOCK_SIZE: usize = 64;
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block_key = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Vec::with_capacity(BLOCK_SIZE + message.len());
+    inner.extend(block_key.iter().map(|b| b ^ 0x36));
+    inner.extend_from_slice(message);
+    let inner_hash = sha256(&inner);
+
+    let mut outer = Vec::with_capacity(BLOCK_SIZE + 32);
+    outer.extend(block_key.iter().map(|b| b ^ 0x5c));
+    outer.extend_from_slice(&inner_hash);
+    sha256(&outer)
+}
+
+/// Appends an HMAC so the value can round-trip through a client untampered: `value.signature`.
+pub fn sign(secret: &[u8], value: &str) -> String {
+    format!("{}.{}", value, crypto::to_hex(&hmac_sha256(secret, value.as_bytes())))
+}
+
+/// Returns the original value if the signature produced by [`sign`] matches.
+pub fn verify(secret: &[u8], signed: &str) -> Option<String> {
+    let (value, signature) = signed.rsplit_once('.')?;
+    let expected = crypto::to_hex(&hmac_sha256(secret, value.as_bytes()));
+    if crypto::constant_time_eq(expected.as_bytes(), signature.as_bytes()) {
+        Some(value.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_known_macs() {
+        // RFC 4231 test case 2, then a key longer than the block, which is hashed first
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (b"Jefe", b"what do ya want for nothing?", "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            (b"key", b"The quick brown fox jumps over the lazy dog", "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"),
+            (&[b'k'; 100], b"msg", "bd56a1782c2830e8abc6ed866a57a1230661e650b84c62f7ee3accc5fa5af491"),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(crypto::to_hex(&hmac_sha256(key, message)), expected);
+        }
+    }
+
+    #[test]
+    fn signed_values_round_trip_only_untouched() {
+        let signed = sign(b"secret", "user=alice");
+        assert_eq!(verify(b"secret", &signed).as_deref(), Some("user=alice"));
+        assert_eq!(verify(b"other", &signed), None);
+        assert_eq!(verify(b"secret", &signed.replace("alice", "mallory")), None);
+        assert_eq!(verify(b"secret", "user=alice"), None);
+    }
+}
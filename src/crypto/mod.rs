@@ -0,0 +1,67 @@
+pub mod base64;
+pub mod bcrypt;
+mod blowfish_tables;
+pub mod hmac;
+pub mod md5;
+pub mod rsa;
+pub mod sha1;
+pub mod sha256;
+pub mod sha512;
+pub mod sha_crypt;
+
+use std::io;
+
+#[cfg(target_os = "linux")]
+unsafe extern "C" {
+    fn getrandom(buf: *mut u8, len: usize, flags: u32) -> isize;
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Bytes from the kernel's random source. Session, nonce and CSRF secrets come
+/// from here, so there is no weaker fallback: if the source fails, this panics
+/// (the server draws its secrets when it is built, so that happens at startup).
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    if let Err(e) = fill_random(&mut buf) {
+        panic!("cannot read the system random source: {}", e);
+    }
+    buf
+}
+
+/// `getrandom(2)` needs no file descriptor, so it still works when they run out.
+#[cfg(target_os = "linux")]
+fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = unsafe { getrandom(buf[filled..].as_mut_ptr(), buf.len() - filled, 0) };
+        if read < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        filled += read as usize;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    use std::io::Read;
+    std::fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,283 @@
+use crate::crypto::{ base64, sha256::sha256 };
+
+// DER prefix of the PKCS#1 v1.5 DigestInfo for SHA-256
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+
+pub struct RsaPublicKey {
+    modulus: Vec<u32>,
+    exponent: Vec<u32>,
+    size: usize,
+}
+
+impl RsaPublicKey {
+    /// Accepts PEM-encoded `PUBLIC KEY` (SubjectPublicKeyInfo) or `RSA PUBLIC KEY` (PKCS#1).
+    pub fn from_pem(pem: &str) -> Result<Self, String> {
+        let body: String = pem
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .map(|l| l.trim())
+            .collect();
+        let der = base64::decode(&body).ok_or("Invalid base64 in PEM key")?;
+
+        let mut outer = DerReader::new(&der);
+        let mut seq = outer.read(0x30)?;
+
+        let rsa_key = if seq.peek_tag() == Some(0x30) {
+            // SubjectPublicKeyInfo: AlgorithmIdentifier, then a BIT STRING wrapping the PKCS#1 key
+            seq.read(0x30)?;
+            let bits = seq.read(0x03)?.remaining();
+            let inner = bits.get(1..).ok_or("Empty BIT STRING in key")?;
+            DerReader::new(inner).read(0x30)?.remaining().to_vec()
+        } else {
+            seq.remaining().to_vec()
+        };
+
+        let mut key = DerReader::new(&rsa_key);
+        let modulus = key.read(0x02)?.remaining();
+        let exponent = key.read(0x02)?.remaining();
+
+        let modulus = strip_leading_zeros(modulus);
+        if modulus.len() < 128 {
+            return Err("RSA keys shorter than 1024 bits are not accepted".to_string());
+        }
+
+        Ok(Self {
+            size: modulus.len(),
+            modulus: from_be_bytes(modulus),
+            exponent: from_be_bytes(exponent),
+        })
+    }
+
+    /// RSASSA-PKCS1-v1_5 verification with SHA-256 (JWT `RS256`).
+    pub fn verify_sha256(&self, message: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != self.size {
+            return false;
+        }
+
+        let s = from_be_bytes(signature);
+        if compare(&s, &self.modulus) != std::cmp::Ordering::Less {
+            return false;
+        }
+        let em = to_be_bytes(&mod_pow(&s, &self.exponent, &self.modulus), self.size);
+
+        let hash = sha256(message);
+        let padding_len = self.size - 3 - SHA256_DIGEST_INFO.len() - hash.len();
+        let mut expected = vec![0x00, 0x01];
+        expected.extend(std::iter::repeat_n(0xff, padding_len));
+        expected.push(0x00);
+        expected.extend_from_slice(&SHA256_DIGEST_INFO);
+        expected.extend_from_slice(&hash);
+
+        crate::crypto::constant_time_eq(&em, &expected)
+    }
+}
+
+struct DerReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> DerReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    fn read(&mut self, tag: u8) -> Result<DerReader<'a>, String> {
+        if self.peek_tag() != Some(tag) {
+            return Err(format!("Unexpected DER tag, wanted 0x{:02x}", tag));
+        }
+        self.pos += 1;
+
+        let first = *self.data.get(self.pos).ok_or("Truncated DER length")?;
+        self.pos += 1;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 {
+                return Err("Unsupported DER length".to_string());
+            }
+            let bytes = self.data.get(self.pos..self.pos + count).ok_or("Truncated DER length")?;
+            self.pos += count;
+            bytes.iter().fold(0usize, |acc, b| (acc << 8) | (*b as usize))
+        };
+
+        let content = self.data.get(self.pos..self.pos + len).ok_or("Truncated DER value")?;
+        self.pos += len;
+        Ok(DerReader::new(content))
+    }
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let first = bytes
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(bytes.len());
+    &bytes[first..]
+}
+
+// Big unsigned integers as little-endian u32 limbs; just enough for modular exponentiation.
+
+fn from_be_bytes(bytes: &[u8]) -> Vec<u32> {
+    let mut limbs: Vec<u32> = bytes
+        .rchunks(4)
+        .map(|chunk| chunk.iter().fold(0u32, |acc, b| (acc << 8) | (*b as u32)))
+        .collect();
+    trim(&mut limbs);
+    limbs
+}
+
+fn to_be_bytes(limbs: &[u32], len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    for (i, limb) in limbs.iter().enumerate() {
+        for (j, byte) in limb.to_le_bytes().iter().enumerate() {
+            let idx = i * 4 + j;
+            if idx < len {
+                out[len - 1 - idx] = *byte;
+            }
+        }
+    }
+    out
+}
+
+fn trim(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+fn compare(a: &[u32], b: &[u32]) -> std::cmp::Ordering {
+    let len = a.len().max(b.len());
+    for i in (0..len).rev() {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        if x != y {
+            return x.cmp(&y);
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+fn mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let t = (x as u64) * (y as u64) + (out[i + j] as u64) + carry;
+            out[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    trim(&mut out);
+    out
+}
+
+/// Binary long division remainder; fine for the handful of products an RSA verify needs.
+fn rem(a: &[u32], m: &[u32]) -> Vec<u32> {
+    let mut r = vec![0u32; m.len() + 1];
+    for bit in (0..a.len() * 32).rev() {
+        let mut carry = (a[bit / 32] >> (bit % 32)) & 1;
+        for limb in r.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+
+        if compare(&r, m) != std::cmp::Ordering::Less {
+            let mut borrow = 0i64;
+            for (i, limb) in r.iter_mut().enumerate() {
+                let diff = (*limb as i64) - (m.get(i).copied().unwrap_or(0) as i64) - borrow;
+                if diff < 0 {
+                    *limb = (diff + (1i64 << 32)) as u32;
+                    borrow = 1;
+                } else {
+                    *limb = diff as u32;
+                    borrow = 0;
+                }
+            }
+        }
+    }
+    trim(&mut r);
+    r
+}
+
+fn mod_pow(base: &[u32], exponent: &[u32], modulus: &[u32]) -> Vec<u32> {
+    let mut result = vec![1u32];
+    let base = rem(base, modulus);
+    for bit in (0..exponent.len() * 32).rev() {
+        result = rem(&mul(&result, &result), modulus);
+        if (exponent[bit / 32] >> (bit % 32)) & 1 == 1 {
+            result = rem(&mul(&result, &base), modulus);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPKI_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDXVMgWqmpDzxhptAZnniPyXPvz
+7nk9L5zBSRhqmLKs2Imc9OgI96kXFRtU8Ei0BGmyrPWc0B7RCJQ2FzRrV4Qq9muW
+ntesJizE/Dl8dXoD7bmDCIZvfkYapsdYzUK8Ik1dsLAs+8Xe9Js7B5AR+XnCUKLU
+w1qSsvv+9cNU8WERdwIDAQAB
+-----END PUBLIC KEY-----
+";
+    // The same key as PKCS#1
+    const PKCS1_KEY: &str = "-----BEGIN RSA PUBLIC KEY-----
+MIGJAoGBANdUyBaqakPPGGm0BmeeI/Jc+/PueT0vnMFJGGqYsqzYiZz06Aj3qRcV
+G1TwSLQEabKs9ZzQHtEIlDYXNGtXhCr2a5ae16wmLMT8OXx1egPtuYMIhm9+Rhqm
+x1jNQrwiTV2wsCz7xd70mzsHkBH5ecJQotTDWpKy+/71w1TxYRF3AgMBAAE=
+-----END RSA PUBLIC KEY-----
+";
+    const SHORT_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MFwwDQYJKoZIhvcNAQEBBQADSwAwSAJBAK/kg5URm5Yh2Z34o9UErVBWL2+7yrfW
+nhVnNCCV7GPp0w6jPDSpMXIke2YkSri+vHKXfKCZhW0tgGtrz9cFE9MCAwEAAQ==
+-----END PUBLIC KEY-----
+";
+    // An RS256 JWT signed with the private half: signing input, then signature
+    const MESSAGE: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSJ9";
+    const SIGNATURE: &str = "qRHlK1Cv_YhmKGpBuq46fS8MG-gcpSecMU3j4u8dUG8dn5cDHDlZ4s34H-gS6jhxWALnGVvWw959Qe4Rp1cMWB2TNwdyHvS_eRCKJCQiuc_3SCulMaxFPYZWsa0X-1dUYmZCVmKmfJqPXJ2z1GcL0ET7ocgN7xGRu81hwCINDFA";
+
+    #[test]
+    fn verifies_a_known_signature_with_either_key_format() {
+        let signature = base64::decode_url(SIGNATURE).unwrap();
+        for pem in [SPKI_KEY, PKCS1_KEY] {
+            let key = RsaPublicKey::from_pem(pem).unwrap();
+            assert!(key.verify_sha256(MESSAGE.as_bytes(), &signature));
+        }
+    }
+
+    #[test]
+    fn refuses_altered_messages_and_signatures() {
+        let key = RsaPublicKey::from_pem(SPKI_KEY).unwrap();
+        let signature = base64::decode_url(SIGNATURE).unwrap();
+        assert!(!key.verify_sha256(b"eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJib2IifQ", &signature));
+
+        let mut flipped = signature.clone();
+        flipped[64] ^= 1;
+        assert!(!key.verify_sha256(MESSAGE.as_bytes(), &flipped));
+        assert!(!key.verify_sha256(MESSAGE.as_bytes(), &signature[1..]));
+        assert!(!key.verify_sha256(MESSAGE.as_bytes(), &[0xff; 128]));
+    }
+
+    #[test]
+    fn refuses_short_and_malformed_keys() {
+        assert!(RsaPublicKey::from_pem(SHORT_KEY).err().is_some_and(|e| e.contains("1024 bits")));
+        assert!(RsaPublicKey::from_pem("-----BEGIN PUBLIC KEY-----\nnot a key\n-----END PUBLIC KEY-----\n").is_err());
+        assert!(RsaPublicKey::from_pem("-----BEGIN PUBLIC KEY-----\nMAMCAQA=\n-----END PUBLIC KEY-----\n").is_err());
+    }
+}
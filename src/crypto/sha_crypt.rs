@@ -0,0 +1,166 @@
+use crate::crypto::sha512::sha512;
+
+const ITOA64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const DEFAULT_ROUNDS: u32 = 5000;
+const MAX_SALT_LEN: usize = 16;
+
+// Byte triples of the final digest, in the order the `$6$` encoding emits them
+const ENCODE_ORDER: [(usize, usize, usize); 21] = [
+    (0, 21, 42), (22, 43, 1), (44, 2, 23), (3, 24, 45), (25, 46, 4), (47, 5, 26), (6, 27, 48),
+    (28, 49, 7), (50, 8, 29), (9, 30, 51), (31, 52, 10), (53, 11, 32), (12, 33, 54), (34, 55, 13),
+    (56, 14, 35), (15, 36, 57), (37, 58, 16), (59, 17, 38), (18, 39, 60), (40, 61, 19), (62, 20, 41),
+];
+
+/// SHA-512 based crypt (`$6$[rounds=N$]salt$hash`), as produced by glibc and `openssl passwd -6`.
+pub fn sha512_crypt(password: &[u8], salt: &str, rounds: Option<u32>) -> String {
+    let salt = &salt.as_bytes()[..salt.len().min(MAX_SALT_LEN)];
+    let round_count = rounds.unwrap_or(DEFAULT_ROUNDS).clamp(1000, 999_999_999);
+
+    let digest_b = sha512(&[password, salt, password].concat());
+
+    let mut input_a = [password, salt].concat();
+    let mut remaining = password.len();
+    while remaining > 64 {
+        input_a.extend_from_slice(&digest_b);
+        remaining -= 64;
+    }
+    input_a.extend_from_slice(&digest_b[..remaining]);
+
+    let mut len = password.len();
+    while len > 0 {
+        if len & 1 == 1 {
+            input_a.extend_from_slice(&digest_b);
+        } else {
+            input_a.extend_from_slice(password);
+        }
+        len >>= 1;
+    }
+    let digest_a = sha512(&input_a);
+
+    let digest_dp = sha512(&password.repeat(password.len()));
+    let p_seq: Vec<u8> = digest_dp.iter().cycle().take(password.len()).copied().collect();
+
+    let digest_ds = sha512(&salt.repeat(16 + digest_a[0] as usize));
+    let s_seq = &digest_ds[..salt.len()];
+
+    let mut digest_c = digest_a;
+    for i in 0..round_count {
+        let mut input = Vec::with_capacity(64 + p_seq.len() * 2 + s_seq.len());
+        if i % 2 == 1 {
+            input.extend_from_slice(&p_seq);
+        } else {
+            input.extend_from_slice(&digest_c);
+        }
+        if i % 3 != 0 {
+            input.extend_from_slice(s_seq);
+        }
+        if i % 7 != 0 {
+            input.extend_from_slice(&p_seq);
+        }
+        if i % 2 == 1 {
+            input.extend_from_slice(&digest_c);
+        } else {
+            input.extend_from_slice(&p_seq);
+        }
+        digest_c = sha512(&input);
+    }
+
+    let mut encoded = String::with_capacity(86);
+    for (a, b, c) in ENCODE_ORDER {
+        push_b64(&mut encoded, digest_c[a], digest_c[b], digest_c[c], 4);
+    }
+    push_b64(&mut encoded, 0, 0, digest_c[63], 2);
+
+    let salt_str = String::from_utf8_lossy(salt);
+    match rounds {
+        Some(_) => format!("$6$rounds={}${}${}", round_count, salt_str, encoded),
+        None => format!("$6${}${}", salt_str, encoded),
+    }
+}
+
+/// Re-hashes `password` with the parameters embedded in `hash` and compares.
+pub fn verify_sha512_crypt(password: &[u8], hash: &str) -> bool {
+    let rest = match hash.strip_prefix("$6$") {
+        Some(r) => r,
+        None => {
+            return false;
+        }
+    };
+
+    let (rounds, rest) = match rest.strip_prefix("rounds=").and_then(|r| r.split_once('$')) {
+        Some((n, tail)) =>
+            match n.parse::<u32>() {
+                Ok(n) => (Some(n), tail),
+                Err(_) => {
+                    return false;
+                }
+            }
+        None => (None, rest),
+    };
+
+    let salt = match rest.rsplit_once('$') {
+        Some((salt, _)) => salt,
+        None => {
+            return false;
+        }
+    };
+
+    let computed = sha512_crypt(password, salt, rounds);
+    crate::crypto::constant_time_eq(computed.as_bytes(), hash.as_bytes())
+}
+
+pub fn generate_salt() -> String {
+    crate::crypto
+        ::random_bytes(MAX_SALT_LEN)
+        .iter()
+        .map(|b| ITOA64[(*b & 0x3f) as usize] as char)
+        .collect()
+}
+
+fn push_b64(out: &mut String, b2: u8, b1: u8, b0: u8, count: usize) {
+    let mut w = ((b2 as u32) << 16) | ((b1 as u32) << 8) | (b0 as u32);
+    for _ in 0..count {
+        out.push(ITOA64[(w & 0x3f) as usize] as char);
+        w >>= 6;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the SHA-crypt specification's test vectors
+    #[test]
+    fn matches_the_specification_vectors() {
+        assert_eq!(
+            sha512_crypt(b"Hello world!", "saltstring", None),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+        );
+        assert_eq!(
+            sha512_crypt(b"Hello world!", "saltstringsaltstring", Some(10000)),
+            "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v."
+        );
+        assert_eq!(
+            sha512_crypt(b"This is just a test", "toolongsaltstring", Some(5000)),
+            "$6$rounds=5000$toolongsaltstrin$lQ8jolhgVRVhY4b5pZKaysCLi0QBxGoNeKQzQ3glMhwllF7oGDZxUhx1yxdYcz/e1JSbq3y6JMxxl8audkUEm0"
+        );
+        assert_eq!(
+            sha512_crypt(b"the minimum number is still observed", "roundstoolow", Some(10)),
+            "$6$rounds=1000$roundstoolow$kUMsbe306n21p9R.FRkW3IGn.S9NPN0x50YhH1xhLsPuWGsUSklZt58jaTfF4ZEQpyUNGc0dqbpBYYBaHHrsX."
+        );
+    }
+
+    #[test]
+    fn verifies_only_the_right_password() {
+        let hash = "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.";
+        assert!(verify_sha512_crypt(b"Hello world!", hash));
+        assert!(!verify_sha512_crypt(b"Hello world?", hash));
+        assert!(!verify_sha512_crypt(b"Hello world!", &hash.replace("rounds=10000", "rounds=5000")));
+        assert!(!verify_sha512_crypt(b"Hello world!", "$5$saltstring$abc"));
+        assert!(!verify_sha512_crypt(b"Hello world!", "$6$rounds=many$salt$abc"));
+
+        let salt = generate_salt();
+        assert_eq!(salt.len(), MAX_SALT_LEN);
+        assert!(verify_sha512_crypt(b"secret", &sha512_crypt(b"secret", &salt, None)));
+    }
+}
@@ -0,0 +1,67 @@
+//! The error type of the library's fallible entry points: loading or building
+//! a configuration, binding, dropping privileges, starting CGI scripts and
+//! storing uploads. `Display` gives the one-line message the server logs.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ServerError {
+    /// The configuration is unusable as a whole: listener conflicts, an
+    /// unknown profile or format, an include that matches nothing, ...
+    Config(String),
+    /// A config file is malformed; `message` starts with the line number.
+    Parse {
+        file: String,
+        message: String,
+    },
+    /// A system call failed; `context` says what was being attempted.
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    /// A CGI or WebSocket program could not be started.
+    Cgi {
+        script: String,
+        message: String,
+    },
+}
+
+impl ServerError {
+    /// For `map_err`: `fs::write(..).map_err(ServerError::io("Cannot write x"))`.
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| ServerError::Io { context, source }
+    }
+
+    /// `Io` for the `errno` a libc call just left behind.
+    pub(crate) fn last_os_error(context: impl Into<String>) -> Self {
+        ServerError::Io { context: context.into(), source: io::Error::last_os_error() }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Config(message) => write!(f, "{}", message),
+            ServerError::Parse { file, message } => write!(f, "{}:{}", file, message),
+            ServerError::Io { context, source } => write!(f, "{}: {}", context, source),
+            ServerError::Cgi { script, message } => write!(f, "{}: {}", script, message),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for ServerError {
+    fn from(message: String) -> Self {
+        ServerError::Config(message)
+    }
+}
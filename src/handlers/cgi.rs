@@ -1,15 +1,44 @@
+use crate::error::ServerError;
+use crate::log;
 use mio::unix::pipe::{ self, Receiver };
 use std::collections::HashMap;
 use std::io::Write;
 use std::os::fd::{ FromRawFd, IntoRawFd, OwnedFd };
 use std::process::{ Child, Command, Stdio };
 
+const MAX_HEADER_ENV_VALUE: usize = 8192;
+
+/// Exports request headers as `HTTP_*` variables (RFC 3875 4.1.18), skipping anything that
+/// could poison the script environment: `Proxy` (httpoxy), names that would collide after
+/// normalization, control characters, and oversized values.
+pub fn add_header_env_vars(
+    env_vars: &mut HashMap<String, String>,
+    headers: &HashMap<String, String>
+) {
+    for (name, value) in headers {
+        // Already exported as CONTENT_TYPE / CONTENT_LENGTH, or carrying credentials
+        if matches!(name.as_str(), "content-type" | "content-length" | "authorization" | "proxy") {
+            continue;
+        }
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            continue;
+        }
+        if value.len() > MAX_HEADER_ENV_VALUE || value.chars().any(|c| c.is_control() && c != '\t') {
+            log::warning!("[CGI] Dropping unsafe header from environment: {}", name);
+            continue;
+        }
+
+        let env_name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        env_vars.insert(env_name, value.clone());
+    }
+}
+
 pub fn spawn_cgi_process(
     script_path: &str,
     interpreter: Option<&str>,
     body: &[u8],
     env_vars: HashMap<String, String>
-) -> Result<(Child, Receiver), String> {
+) -> Result<(Child, Receiver), ServerError> {
     let mut command = if let Some(interpreter_path) = interpreter {
         let mut cmd = Command::new(interpreter_path);
         cmd.arg(script_path);
@@ -18,7 +47,7 @@ pub fn spawn_cgi_process(
         Command::new(script_path)
     };
 
-    let (sender, receiver) = pipe::new().map_err(|e| format!("Failed to create CGI pipe: {}", e))?;
+    let (sender, receiver) = pipe::new().map_err(ServerError::io("Failed to create CGI pipe"))?;
     let sender_fd = sender.into_raw_fd();
     let sender_owned = unsafe { OwnedFd::from_raw_fd(sender_fd) };
 
@@ -27,13 +56,61 @@ pub fn spawn_cgi_process(
         .stdin(Stdio::piped())
         .stdout(Stdio::from(sender_owned))
         .spawn()
-        .map_err(|e| format!("Failed to execute CGI: {}", e))?;
+        .map_err(|e| ServerError::Cgi { script: script_path.to_string(), message: format!("Failed to execute CGI: {}", e) })?;
 
-    if let Some(mut stdin) = child.stdin.take() {
-        if !body.is_empty() {
-            stdin.write_all(body).map_err(|e| format!("CGI stdin write failed: {}", e))?;
-        }
+    if let Some(mut stdin) = child.stdin.take() && !body.is_empty() {
+        stdin
+            .write_all(body)
+            .map_err(|e| ServerError::Cgi { script: script_path.to_string(), message: format!("CGI stdin write failed: {}", e) })?;
     }
 
     Ok((child, receiver))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exported(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut env_vars = HashMap::new();
+        add_header_env_vars(&mut env_vars, &headers);
+        env_vars
+    }
+
+    #[test]
+    fn exports_ordinary_headers_under_http_names() {
+        let env = exported(&[("user-agent", "curl/8.0"), ("x-request-id", "abc\tdef"), ("accept", "")]);
+        assert_eq!(env.get("HTTP_USER_AGENT").map(String::as_str), Some("curl/8.0"));
+        assert_eq!(env.get("HTTP_X_REQUEST_ID").map(String::as_str), Some("abc\tdef"));
+        assert_eq!(env.get("HTTP_ACCEPT").map(String::as_str), Some(""));
+        assert_eq!(env.len(), 3);
+    }
+
+    #[test]
+    fn skips_headers_that_could_poison_the_environment() {
+        let long = "a".repeat(MAX_HEADER_ENV_VALUE + 1);
+        let env = exported(&[
+            ("proxy", "http://evil.test:3128"),
+            ("authorization", "Basic YWxpY2U6c2VjcmV0"),
+            ("content-type", "text/plain"),
+            ("content-length", "5"),
+            // Would become HTTP_X_FORWARDED_FOR next to the real one, or a name a shell cannot hold
+            ("x_forwarded_for", "10.0.0.1"),
+            ("x.y", "1"),
+            ("x y", "1"),
+            ("", "1"),
+            ("x-newline", "a\nLD_PRELOAD=/tmp/x.so"),
+            ("x-nul", "a\0b"),
+            ("x-escape", "\u{1b}[31m"),
+            ("x-long", &long),
+        ]);
+        assert!(env.is_empty(), "{:?}", env.keys().collect::<Vec<_>>());
+
+        let limit = "a".repeat(MAX_HEADER_ENV_VALUE);
+        assert!(exported(&[("x-long", &limit)]).contains_key("HTTP_X_LONG"));
+    }
+}
@@ -1 +1,2 @@
-pub  mod  cgi;
\ No newline at end of file
+pub  mod  cgi;
+pub  mod  websocket;
\ No newline at end of file
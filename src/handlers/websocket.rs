@@ -0,0 +1,44 @@
+use crate::error::ServerError;
+use mio::unix::pipe::{ self, Receiver, Sender };
+use std::collections::HashMap;
+use std::os::fd::{ FromRawFd, IntoRawFd, OwnedFd };
+use std::process::{ Child, Command, Stdio };
+
+/// Starts the long-running program behind a `websocket: true` route. Every
+/// client message is written to its stdin as one line, and every line it
+/// prints goes back to the client as one message. Both pipe ends stay
+/// non-blocking so the event loop can poll them.
+pub fn spawn_websocket_process(
+    script_path: &str,
+    interpreter: Option<&str>,
+    env_vars: HashMap<String, String>
+) -> Result<(Child, Sender, Receiver), ServerError> {
+    let mut command = if let Some(interpreter_path) = interpreter {
+        let mut cmd = Command::new(interpreter_path);
+        cmd.arg(script_path);
+        cmd
+    } else {
+        Command::new(script_path)
+    };
+
+    let (stdin_sender, stdin_receiver) = pipe::new().map_err(ServerError::io("Failed to create WebSocket pipe"))?;
+    let (stdout_sender, stdout_receiver) = pipe::new().map_err(ServerError::io("Failed to create WebSocket pipe"))?;
+
+    // The child gets blocking ends; ours stay non-blocking
+    stdin_receiver.set_nonblocking(false).map_err(ServerError::io("Failed to set up WebSocket pipe"))?;
+    stdout_sender.set_nonblocking(false).map_err(ServerError::io("Failed to set up WebSocket pipe"))?;
+    let child_stdin = unsafe { OwnedFd::from_raw_fd(stdin_receiver.into_raw_fd()) };
+    let child_stdout = unsafe { OwnedFd::from_raw_fd(stdout_sender.into_raw_fd()) };
+
+    let child = command
+        .envs(env_vars)
+        .stdin(Stdio::from(child_stdin))
+        .stdout(Stdio::from(child_stdout))
+        .spawn()
+        .map_err(|e| ServerError::Cgi {
+            script: script_path.to_string(),
+            message: format!("Failed to execute WebSocket handler: {}", e),
+        })?;
+
+    Ok((child, stdin_sender, stdout_receiver))
+}
@@ -0,0 +1,141 @@
+//! Cookies (RFC 6265): the `Cookie` request header as a map, and
+//! `Set-Cookie` values built from their parts so they are always well formed.
+//!
+//! ```
+//! use localserver::cookie::{ self, SameSite, SetCookie };
+//! use std::time::Duration;
+//!
+//! let sent = cookie::parse("theme=dark; lang=\"fr\"");
+//! assert_eq!(sent["lang"], "fr");
+//!
+//! let set = SetCookie::new("theme", "light").unwrap()
+//!     .path("/")
+//!     .max_age(Duration::from_secs(3600))
+//!     .http_only()
+//!     .same_site(SameSite::Lax);
+//! assert_eq!(set.to_string(), "theme=light; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax");
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// `name=value` pairs from a `Cookie` header. Quotes around a value are
+/// dropped; when a name is sent twice the first one, the most specific, wins.
+pub fn parse(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in header.split(';') {
+        if let Some((name, value)) = pair.trim().split_once('=') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+        }
+    }
+    cookies
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept it together with `Secure`.
+    None,
+}
+
+/// One `Set-Cookie` header value; `to_string()` writes it.
+#[derive(Clone, Debug)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<Duration>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    /// `None` when `name` is not an HTTP token or `value` holds a character
+    /// a cookie cannot carry (controls, space, `"`, `,`, `;`, `\`).
+    pub fn new(name: &str, value: &str) -> Option<Self> {
+        let token = |c: char| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c);
+        let octet = |c: char| c.is_ascii_graphic() && !"\",;\\".contains(c);
+        if name.is_empty() || !name.chars().all(token) || !value.chars().all(octet) {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        })
+    }
+
+    /// A path containing `;` or a control character is ignored.
+    pub fn path(mut self, path: &str) -> Self {
+        if path.starts_with('/') && !path.contains(|c: char| c == ';' || c.is_ascii_control()) {
+            self.path = Some(path.to_string());
+        }
+        self
+    }
+
+    /// Whole seconds; `Duration::ZERO` asks the client to delete the cookie.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// `SameSite::None` also sets `Secure`, which browsers require with it.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        if same_site == SameSite::None {
+            self.secure = true;
+        }
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
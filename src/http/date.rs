@@ -0,0 +1,88 @@
+//! HTTP dates (RFC 9110 §5.6.7): written as IMF-fixdate,
+//! `Sun, 06 Nov 1994 08:49:37 GMT`, and read in that form as well as the
+//! obsolete RFC 850 (`Sunday, 06-Nov-94 08:49:37 GMT`) and asctime
+//! (`Sun Nov  6 08:49:37 1994`) ones.
+
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// `time` to the second, as IMF-fixdate; times before 1970 are written as the epoch.
+pub fn format(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let rest = secs % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rest / 3600,
+        (rest % 3600) / 60,
+        rest % 60
+    )
+}
+
+/// Any of the three forms; `None` for anything else. The weekday is not checked.
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (_, rest) = value.split_once(' ')?;
+    let parts: Vec<&str> = rest.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        // IMF-fixdate: `06 Nov 1994 08:49:37 GMT`
+        [day, month, year, time, "GMT"] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        // RFC 850: `06-Nov-94 08:49:37 GMT`
+        [date, time, "GMT"] => {
+            let mut fields = date.split('-');
+            let (day, month, year) = (fields.next()?, fields.next()?, fields.next()?);
+            let year = year.parse::<i64>().ok()?;
+            // Two-digit years fall in 1970..2069
+            let year = if year < 70 { 2000 + year } else if year < 100 { 1900 + year } else { year };
+            (day, month, year, *time)
+        }
+        // asctime: `Nov  6 08:49:37 1994`
+        [month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => {
+            return None;
+        }
+    };
+
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let day = day.parse::<i64>().ok().filter(|day| (1..=31).contains(day))?;
+    let mut clock = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`: (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
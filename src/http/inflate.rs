@@ -0,0 +1,326 @@
+//! DEFLATE decoding (RFC 1951) with the gzip (RFC 1952) and zlib (RFC 1950)
+//! wrappers, for request bodies sent with `Content-Encoding`. Output stops at
+//! `limit` bytes, so a small body cannot expand without bound.
+
+#[derive(Debug, PartialEq)]
+pub enum InflateError {
+    /// The decoded data would be longer than the limit.
+    TooLarge,
+    /// Not valid compressed data, or its checksum does not match.
+    Invalid,
+}
+
+/// One or more concatenated gzip members.
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let mut out = Vec::new();
+    let mut rest = data;
+
+    loop {
+        let body = gzip_header_len(rest).map(|len| &rest[len..]).ok_or(InflateError::Invalid)?;
+        let start = out.len();
+        let consumed = inflate_into(body, &mut out, limit)?;
+
+        let trailer = body.get(consumed..consumed + 8).ok_or(InflateError::Invalid)?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        if crc32(&out[start..]) != crc || (out.len() - start) as u32 != size {
+            return Err(InflateError::Invalid);
+        }
+
+        rest = &body[consumed + 8..];
+        if rest.is_empty() {
+            return Ok(out);
+        }
+    }
+}
+
+/// `deflate` as the HTTP spec means it (zlib-wrapped), or the raw stream
+/// some clients send instead.
+pub fn decompress_deflate(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let wrapped = data.len() >= 2 && data[0] & 0x0f == 8 && data[1] & 0x20 == 0 && u16::from_be_bytes([data[0], data[1]]).is_multiple_of(31);
+    if !wrapped {
+        let mut out = Vec::new();
+        inflate_into(data, &mut out, limit)?;
+        return Ok(out);
+    }
+
+    let mut out = Vec::new();
+    let consumed = 2 + inflate_into(&data[2..], &mut out, limit)?;
+    let trailer = data.get(consumed..consumed + 4).ok_or(InflateError::Invalid)?;
+    if adler32(&out) != u32::from_be_bytes(trailer.try_into().unwrap()) {
+        return Err(InflateError::Invalid);
+    }
+    Ok(out)
+}
+
+/// Length of the gzip member header at the start of `data`.
+fn gzip_header_len(data: &[u8]) -> Option<usize> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if data.len() < 10 || data[0] != 0x1f || data[1] != 0x8b || data[2] != 8 {
+        return None;
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let extra = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        pos += 2 + extra;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    (pos <= data.len()).then_some(pos)
+}
+
+/// Reads bits least significant first, as DEFLATE packs them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: u32) -> Result<u32, InflateError> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(InflateError::Invalid)?;
+            self.buffer |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u32 << n) - 1);
+        self.buffer = self.buffer.checked_shr(n).unwrap_or(0);
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops the rest of the current byte; stored blocks start on a boundary.
+    fn align(&mut self) {
+        self.pos -= (self.count / 8) as usize;
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn consumed(&self) -> usize {
+        self.pos - (self.count / 8) as usize
+    }
+}
+
+/// Canonical Huffman code: how many codes of each length, and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        // More codes of some length than the code space allows
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::Invalid);
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::Invalid)
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order in which a dynamic block lists the code length code lengths.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decodes one raw DEFLATE stream onto `out`; returns how many input bytes it used.
+fn inflate_into(data: &[u8], out: &mut Vec<u8>, limit: usize) -> Result<usize, InflateError> {
+    let mut reader = BitReader { data, pos: 0, buffer: 0, count: 0 };
+
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data.get(reader.pos..reader.pos + 4).ok_or(InflateError::Invalid)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(InflateError::Invalid);
+                }
+                let start = reader.pos + 4;
+                let stored = data.get(start..start + len as usize).ok_or(InflateError::Invalid)?;
+                if out.len() + stored.len() > limit {
+                    return Err(InflateError::TooLarge);
+                }
+                out.extend_from_slice(stored);
+                reader.pos = start + stored.len();
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5u8; 30])?;
+                inflate_block(&mut reader, &literals, &distances, out, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &literals, &distances, out, limit)?;
+            }
+            _ => {
+                return Err(InflateError::Invalid);
+            }
+        }
+        if last {
+            return Ok(reader.consumed());
+        }
+    }
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(InflateError::Invalid);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &slot in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[slot] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let symbol = code_length_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or(InflateError::Invalid)?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() != literal_count + distance_count || lengths[256] == 0 {
+        return Err(InflateError::Invalid);
+    }
+
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    out: &mut Vec<u8>,
+    limit: usize
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            if out.len() >= limit {
+                return Err(InflateError::TooLarge);
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(InflateError::Invalid);
+        }
+        let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let dist_index = distances.decode(reader)? as usize;
+        if dist_index >= DIST_BASE.len() {
+            return Err(InflateError::Invalid);
+        }
+        let distance = DIST_BASE[dist_index] as usize + reader.bits(DIST_EXTRA[dist_index] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError::Invalid);
+        }
+        if out.len() + length > limit {
+            return Err(InflateError::TooLarge);
+        }
+        // Byte by byte: the copy may overlap what it is producing
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
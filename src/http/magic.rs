@@ -0,0 +1,51 @@
+//! File signatures ("magic bytes"): whether content really is the type its
+//! name or `Content-Type` claims, for routes that check their uploads.
+
+/// `Some(true)` when `data` starts the way `mime` files do, `Some(false)` when
+/// it does not, and `None` for types without a fixed signature (text, ...).
+pub fn signature_matches(mime: &str, data: &[u8]) -> Option<bool> {
+    let at = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+    let matches = match mime.split(';').next().unwrap_or("").trim() {
+        "image/png" => at(0, b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => at(0, b"\xff\xd8\xff"),
+        "image/gif" => at(0, b"GIF87a") || at(0, b"GIF89a"),
+        "image/webp" => at(0, b"RIFF") && at(8, b"WEBP"),
+        "image/avif" => at(4, b"ftypavif") || at(4, b"ftypavis"),
+        "image/bmp" => at(0, b"BM"),
+        "image/x-icon" => at(0, b"\0\0\x01\0"),
+        "font/woff" => at(0, b"wOFF"),
+        "font/woff2" => at(0, b"wOF2"),
+        "font/ttf" => at(0, b"\0\x01\0\0") || at(0, b"true"),
+        "font/otf" => at(0, b"OTTO"),
+        // An ID3 tag, or straight into an MPEG frame sync
+        "audio/mpeg" => at(0, b"ID3") || (data.len() >= 2 && data[0] == 0xff && data[1] & 0xe0 == 0xe0),
+        "audio/ogg" => at(0, b"OggS"),
+        "audio/wav" => at(0, b"RIFF") && at(8, b"WAVE"),
+        "video/mp4" => at(4, b"ftyp"),
+        "video/webm" => at(0, b"\x1a\x45\xdf\xa3"),
+        "application/pdf" => at(0, b"%PDF-"),
+        // Local file header, or the end record of an empty archive
+        "application/zip" => at(0, b"PK\x03\x04") || at(0, b"PK\x05\x06"),
+        "application/gzip" => at(0, b"\x1f\x8b"),
+        "application/x-tar" => at(257, b"ustar"),
+        "application/wasm" => at(0, b"\0asm"),
+        _ => {
+            return None;
+        }
+    };
+    Some(matches)
+}
+
+/// Native programs: ELF, Windows PE (`MZ`) and Mach-O, in either byte order.
+pub fn is_executable(data: &[u8]) -> bool {
+    const SIGNATURES: [&[u8]; 7] = [
+        b"\x7fELF",
+        b"MZ",
+        b"\xfe\xed\xfa\xce",
+        b"\xfe\xed\xfa\xcf",
+        b"\xce\xfa\xed\xfe",
+        b"\xcf\xfa\xed\xfe",
+        b"\xca\xfe\xba\xbe",
+    ];
+    SIGNATURES.iter().any(|magic| data.starts_with(magic))
+}
@@ -0,0 +1,430 @@
+//! Markdown for `markdown: true` routes: enough of CommonMark for READMEs and
+//! notes (headings, paragraphs, lists, block quotes, fenced code, rules,
+//! emphasis, code spans, links and images). HTML in the source is escaped,
+//! never passed through.
+
+const DEFAULT_TEMPLATE: &str =
+    "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<title>{{title}}</title>
+<style>
+body { max-width: 46rem; margin: 2rem auto; padding: 0 1rem; font: 16px/1.6 system-ui, sans-serif; color: #222; }
+pre { background: #f5f5f5; padding: .75rem; overflow-x: auto; }
+code { font-family: ui-monospace, monospace; font-size: .9em; }
+blockquote { margin: 0; padding-left: 1rem; border-left: 3px solid #ddd; color: #555; }
+img { max-width: 100%; }
+</style>
+</head>
+<body>
+{{content}}
+</body>
+</html>
+";
+
+/// A whole page: `source` rendered into `template` (the built-in one when
+/// `None`) in place of `{{content}}`, with `{{title}}` the first heading or
+/// `fallback_title`.
+pub fn render_page(source: &str, fallback_title: &str, template: Option<&str>) -> String {
+    let title = escape(&title(source).unwrap_or_else(|| fallback_title.to_string()));
+    let content = to_html(source);
+    // Split first so `{{title}}` inside the rendered content stays as written
+    template
+        .unwrap_or(DEFAULT_TEMPLATE)
+        .split("{{content}}")
+        .map(|part| part.replace("{{title}}", &title))
+        .collect::<Vec<_>>()
+        .join(&content)
+}
+
+/// The text of the first heading, without its markup.
+pub fn title(source: &str) -> Option<String> {
+    source
+        .lines()
+        .find_map(|line| heading(line.trim()))
+        .map(|(_, text)| text.chars().filter(|c| !matches!(c, '*' | '_' | '`')).collect())
+}
+
+/// The HTML for `source`'s blocks, without a surrounding page.
+pub fn to_html(source: &str) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut out = String::new();
+    render_blocks(&lines, &mut out);
+    out
+}
+
+fn render_blocks(lines: &[&str], out: &mut String) {
+    let mut i = 0;
+    while i < lines.len() {
+        let trimmed = lines[i].trim();
+        if trimmed.is_empty() {
+            i += 1;
+            continue;
+        }
+
+        if let Some(fence) = fence_marker(trimmed) {
+            let lang = trimmed[fence.len()..].trim();
+            let mut code = String::new();
+            i += 1;
+            while i < lines.len() && !lines[i].trim_start().starts_with(fence) {
+                code.push_str(lines[i]);
+                code.push('\n');
+                i += 1;
+            }
+            // Past the closing fence, if there is one
+            i += 1;
+            if lang.is_empty() {
+                out.push_str("<pre><code>");
+            } else {
+                out.push_str(&format!("<pre><code class=\"language-{}\">", escape(lang)));
+            }
+            out.push_str(&escape(&code));
+            out.push_str("</code></pre>\n");
+        } else if let Some((level, text)) = heading(trimmed) {
+            out.push_str(&format!("<h{0} id=\"{1}\">{2}</h{0}>\n", level, slug(text), inline(text)));
+            i += 1;
+        } else if is_rule(trimmed) {
+            out.push_str("<hr>\n");
+            i += 1;
+        } else if trimmed.starts_with('>') {
+            let mut quoted = Vec::new();
+            while i < lines.len() && lines[i].trim_start().starts_with('>') {
+                let line = &lines[i].trim_start()[1..];
+                quoted.push(line.strip_prefix(' ').unwrap_or(line));
+                i += 1;
+            }
+            out.push_str("<blockquote>\n");
+            render_blocks(&quoted, out);
+            out.push_str("</blockquote>\n");
+        } else if let Some((ordered, _)) = list_item(trimmed) {
+            i = render_list(lines, i, ordered, out);
+        } else {
+            let mut text = Vec::new();
+            while i < lines.len() && !lines[i].trim().is_empty() && (text.is_empty() || !starts_block(lines[i].trim())) {
+                text.push(lines[i].trim());
+                i += 1;
+            }
+            out.push_str(&format!("<p>{}</p>\n", inline(&text.join("\n"))));
+        }
+    }
+}
+
+/// Items of one kind starting at `start`; returns the first line after the list.
+fn render_list(lines: &[&str], start: usize, ordered: bool, out: &mut String) -> usize {
+    let tag = if ordered { "ol" } else { "ul" };
+    out.push_str(&format!("<{}>\n", tag));
+
+    let mut i = start;
+    while i < lines.len() {
+        let text = match list_item(lines[i].trim()) {
+            Some((kind, text)) if kind == ordered && indent(lines[i]) < 2 => text,
+            _ => {
+                break;
+            }
+        };
+        i += 1;
+
+        // Indented lines (and blank lines between them) belong to the item
+        let mut body: Vec<&str> = Vec::new();
+        while i < lines.len() {
+            if lines[i].trim().is_empty() {
+                match lines[i..].iter().position(|l| !l.trim().is_empty()) {
+                    Some(next) if indent(lines[i + next]) >= 2 => {
+                        body.push("");
+                        i += 1;
+                        continue;
+                    }
+                    _ => {
+                        break;
+                    }
+                }
+            }
+            if indent(lines[i]) < 2 {
+                break;
+            }
+            body.push(dedent(lines[i]));
+            i += 1;
+        }
+
+        // Plain continuation lines join the item's first line; anything else is a nested block
+        let mut first = vec![text];
+        let split = body.iter().position(|l| l.is_empty() || starts_block(l.trim())).unwrap_or(body.len());
+        first.extend(body[..split].iter().map(|l| l.trim()));
+        out.push_str("<li>");
+        out.push_str(&inline(&first.join("\n")));
+        if split < body.len() {
+            out.push('\n');
+            render_blocks(&body[split..], out);
+        }
+        out.push_str("</li>\n");
+
+        // A blank line between items keeps the list going
+        match lines[i..].iter().position(|l| !l.trim().is_empty()) {
+            Some(next) if next > 0 && list_item(lines[i + next].trim()).is_some_and(|(kind, _)| kind == ordered) => {
+                i += next;
+            }
+            _ => {}
+        }
+    }
+
+    out.push_str(&format!("</{}>\n", tag));
+    i
+}
+
+fn starts_block(trimmed: &str) -> bool {
+    fence_marker(trimmed).is_some() ||
+        heading(trimmed).is_some() ||
+        is_rule(trimmed) ||
+        trimmed.starts_with('>') ||
+        list_item(trimmed).is_some()
+}
+
+fn fence_marker(trimmed: &str) -> Option<&'static str> {
+    if trimmed.starts_with("```") {
+        Some("```")
+    } else if trimmed.starts_with("~~~") {
+        Some("~~~")
+    } else {
+        None
+    }
+}
+
+/// `## Title ##` is level 2 with text `Title`.
+fn heading(trimmed: &str) -> Option<(usize, &str)> {
+    let level = trimmed.chars().take_while(|&c| c == '#').count();
+    let rest = &trimmed[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+/// Three or more of the same `-`, `*` or `_`, spaces allowed between them.
+fn is_rule(trimmed: &str) -> bool {
+    let marks: Vec<char> = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && matches!(marks[0], '-' | '*' | '_') && marks.iter().all(|&c| c == marks[0])
+}
+
+/// `- item`, `* item`, `+ item` (unordered) or `1. item`, `1) item` (ordered).
+fn list_item(trimmed: &str) -> Option<(bool, &str)> {
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(text) = trimmed.strip_prefix(bullet) {
+            return Some((false, text.trim_start()));
+        }
+    }
+    let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+    if (1..=9).contains(&digits) {
+        let rest = &trimmed[digits..];
+        if let Some(text) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return Some((true, text.trim_start()));
+        }
+    }
+    None
+}
+
+fn indent(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+/// Drops up to four columns of indentation, the depth of one nesting level.
+fn dedent(line: &str) -> &str {
+    let mut columns = 0;
+    for (pos, c) in line.char_indices() {
+        if columns >= 4 || !(c == ' ' || c == '\t') {
+            return &line[pos..];
+        }
+        columns += if c == '\t' { 4 } else { 1 };
+    }
+    ""
+}
+
+/// Heading anchors: `Getting Started` becomes `getting-started`.
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c == ' ' || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+/// Emphasis, code spans, links, images and autolinks within one block.
+fn inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if chars.get(i + 1).is_some_and(|n| n.is_ascii_punctuation()) => {
+                out.push_str(&escape(&chars[i + 1].to_string()));
+                i += 2;
+            }
+            '`' => {
+                let run = chars[i..].iter().take_while(|&&b| b == '`').count();
+                let delimiter = vec!['`'; run];
+                match (i + run..chars.len()).find(|&j| chars[j..].starts_with(&delimiter)) {
+                    Some(end) => {
+                        let code: String = chars[i + run..end].iter().collect();
+                        out.push_str(&format!("<code>{}</code>", escape(code.trim())));
+                        i = end + run;
+                    }
+                    None => {
+                        out.push_str(&"`".repeat(run));
+                        i += run;
+                    }
+                }
+            }
+            '!' if chars.get(i + 1) == Some(&'[') => {
+                match link_at(&chars, i + 1) {
+                    Some((alt, url, next)) => {
+                        out.push_str(&format!("<img src=\"{}\" alt=\"{}\">", escape(&safe_url(&url)), escape(&alt)));
+                        i = next;
+                    }
+                    None => {
+                        out.push('!');
+                        i += 1;
+                    }
+                }
+            }
+            '[' => {
+                match link_at(&chars, i) {
+                    Some((label, url, next)) => {
+                        out.push_str(&format!("<a href=\"{}\">{}</a>", escape(&safe_url(&url)), inline(&label)));
+                        i = next;
+                    }
+                    None => {
+                        out.push('[');
+                        i += 1;
+                    }
+                }
+            }
+            '<' => {
+                let end = chars[i..].iter().position(|&b| b == '>').map(|pos| i + pos);
+                let target: Option<String> = end.map(|end| chars[i + 1..end].iter().collect());
+                match target {
+                    Some(url) if is_autolink(&url) => {
+                        out.push_str(&format!("<a href=\"{0}\">{0}</a>", escape(&url)));
+                        i = end.unwrap_or(i) + 1;
+                    }
+                    _ => {
+                        out.push_str("&lt;");
+                        i += 1;
+                    }
+                }
+            }
+            '*' | '_' => {
+                // `snake_case_names` stay as they are
+                let intraword = c == '_' && i > 0 && chars[i - 1].is_alphanumeric();
+                match emphasis_at(&chars, i) {
+                    Some((tag, inner, next)) if !intraword => {
+                        out.push_str(&format!("<{0}>{1}</{0}>", tag, inline(&inner)));
+                        i = next;
+                    }
+                    _ => {
+                        out.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            '\n' => {
+                out.push('\n');
+                i += 1;
+            }
+            _ => {
+                out.push_str(&escape(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+/// `**strong**` or `*em*` (either delimiter) opening at `start`: the tag,
+/// the text between the delimiters and the index after the closing one.
+fn emphasis_at(chars: &[char], start: usize) -> Option<(&'static str, String, usize)> {
+    let c = chars[start];
+    let strong = chars.get(start + 1) == Some(&c);
+    let (tag, width) = if strong { ("strong", 2) } else { ("em", 1) };
+    let open_end = start + width;
+    if chars.get(open_end).is_none_or(|n| n.is_whitespace()) {
+        return None;
+    }
+
+    let delimiter = vec![c; width];
+    let close = (open_end + 1..chars.len()).find(|&j| {
+        chars[j..].starts_with(&delimiter) && !chars[j - 1].is_whitespace() && (strong || chars.get(j + 1) != Some(&c))
+    })?;
+    Some((tag, chars[open_end..close].iter().collect(), close + width))
+}
+
+/// `[label](url "title")` opening at `start`: the label, the URL and the
+/// index after the closing parenthesis.
+fn link_at(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let mut depth = 0;
+    let mut close = None;
+    for (j, &c) in chars.iter().enumerate().skip(start) {
+        match c {
+            '[' => {
+                depth += 1;
+            }
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(j);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let close = close?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
+    }
+    let end = close + 2 + chars[close + 2..].iter().position(|&c| c == ')')?;
+
+    let label: String = chars[start + 1..close].iter().collect();
+    let target: String = chars[close + 2..end].iter().collect();
+    let url = target.split_whitespace().next().unwrap_or("").trim_start_matches('<').trim_end_matches('>');
+    Some((label, url.to_string(), end + 1))
+}
+
+fn is_autolink(url: &str) -> bool {
+    ["http://", "https://", "mailto:"].iter().any(|scheme| url.starts_with(scheme)) && !url.contains(char::is_whitespace)
+}
+
+/// Script URLs would run in the previewed page.
+fn safe_url(url: &str) -> String {
+    let scheme = url.trim_start().to_ascii_lowercase();
+    if scheme.starts_with("javascript:") || scheme.starts_with("vbscript:") || scheme.starts_with("data:text/html") {
+        "#".to_string()
+    } else {
+        url.to_string()
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
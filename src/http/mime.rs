@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+/// Built-in extension table; `mime_types:` in the config adds to or overrides it.
+const BUILTIN: [(&str, &str); 42] = [
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "application/javascript"),
+    ("mjs", "application/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("wasm", "application/wasm"),
+    ("webmanifest", "application/manifest+json"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("bin", "application/octet-stream"),
+    ("exe", "application/octet-stream"),
+];
+
+/// Plain text keeps the historical behaviour for unknown extensions.
+pub const DEFAULT_TYPE: &str = "text/plain";
+
+pub struct MimeTypes {
+    by_extension: HashMap<String, String>,
+    default_type: String,
+}
+
+impl MimeTypes {
+    /// `overrides` are `(extension, type)` pairs; a leading dot on the extension is optional.
+    pub fn new(overrides: &[(String, String)], default_type: Option<&str>) -> Self {
+        let mut by_extension: HashMap<String, String> = BUILTIN.iter()
+            .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+            .collect();
+        for (ext, mime) in overrides {
+            by_extension.insert(ext.trim_start_matches('.').to_ascii_lowercase(), mime.clone());
+        }
+
+        Self {
+            by_extension,
+            default_type: default_type.unwrap_or(DEFAULT_TYPE).to_string(),
+        }
+    }
+
+    pub fn lookup(&self, path: &str) -> &str {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        file_name
+            .rsplit_once('.')
+            .and_then(|(_, ext)| self.by_extension.get(&ext.to_ascii_lowercase()))
+            .map_or(&self.default_type, |mime| mime)
+    }
+}
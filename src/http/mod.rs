@@ -1 +1,9 @@
-pub  mod  request;
\ No newline at end of file
+pub  mod  cookie;
+pub  mod  date;
+pub  mod  inflate;
+pub  mod  magic;
+pub  mod  markdown;
+pub  mod  mime;
+pub  mod  request;
+pub  mod  status;
+pub  mod  websocket;
\ No newline at end of file
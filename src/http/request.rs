@@ -1,20 +1,55 @@
+use crate::http::cookie;
+use crate::http::status::StatusCode;
 use std::collections::HashMap;
 
+pub const MAX_REQUEST_LINE: usize = 8192;
+const MAX_METHOD_LEN: usize = 32;
+
+/// Methods the server implements; anything else is a 501, and route
+/// `methods` lists may only name these.
+pub const KNOWN_METHODS: [&str; 10] = [
+    "GET",
+    "HEAD",
+    "POST",
+    "PUT",
+    "DELETE",
+    "PATCH",
+    "OPTIONS",
+    "TRACE",
+    "CONNECT",
+    "PURGE",
+];
+
 pub struct HttpRequest {
     pub method: String,
     pub uri: String,
     pub headers: HashMap<String, String>,
+    /// From the `Cookie` header.
+    pub cookies: HashMap<String, String>,
     pub body: Vec<u8>,
 }
 
 #[derive(Debug)]
 pub struct UploadedFile {
     pub file_name: String,
+    /// The part's own `Content-Type`, when it sent one.
+    pub content_type: Option<String>,
     pub data: Vec<u8>,
 }
 
 pub struct MultipartForm {
     pub files: Vec<UploadedFile>,
+    pub fields: Vec<(String, String)>,
+}
+
+impl MultipartForm {
+    /// Value of a plain (non-file) form field.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
 }
 
 impl HttpRequest {
@@ -52,29 +87,112 @@ impl HttpRequest {
             body_slice[..content_length].to_vec()
         };
 
+        let cookies = headers.get("cookie").map(|header| cookie::parse(header)).unwrap_or_default();
         Some(HttpRequest {
             method,
             uri,
             headers,
+            cookies,
             body,
         })
     }
 
-    pub fn is_complete(buf: &[u8]) -> bool {
-        if let Some(header_end) = Self::find_header_end(buf) {
-            let header_bytes = &buf[..header_end];
-            let body_slice = &buf[header_end..];
+    /// Checks the request line at the start of `buf` once it is complete.
+    /// `Err` carries the status to reject with: 400 for malformed lines, 414 for
+    /// over-long ones, 505 for versions other than HTTP/1.x.
+    pub fn validate_request_line(buf: &[u8]) -> Result<(), StatusCode> {
+        let line_end = match buf.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => pos,
+            None if buf.len() > MAX_REQUEST_LINE => {
+                return Err(StatusCode::URI_TOO_LONG);
+            }
+            None => {
+                return Ok(());
+            }
+        };
+        if line_end > MAX_REQUEST_LINE {
+            return Err(StatusCode::URI_TOO_LONG);
+        }
+
+        let line = &buf[..line_end];
+        let mut parts = line.split(|&b| b == b' ');
+        let (Some(method), Some(target), Some(version), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return Err(StatusCode::BAD_REQUEST);
+        };
 
-            if let Some(headers) = Self::parse_headers_map(header_bytes) {
-                if Self::is_chunked_transfer(&headers) {
-                    return Self::decode_chunked_body(body_slice).is_some();
-                }
+        if method.is_empty() || method.len() > MAX_METHOD_LEN || !method.iter().all(|&b| Self::is_tchar(b)) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        // CONNECT is the only method that takes the authority form (`host:port`)
+        let authority_form = !target.contains(&b'/') && target.contains(&b':');
+        let target_ok =
+            !target.is_empty() &&
+            target.iter().all(|&b| (0x21..0x7f).contains(&b)) &&
+            (if method == b"CONNECT" {
+                authority_form
+            } else {
+                target[0] == b'/' ||
+                    target == b"*" ||
+                    target.starts_with(b"http://") ||
+                    target.starts_with(b"https://")
+            });
+        if !target_ok {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        match version {
+            [b'H', b'T', b'T', b'P', b'/', b'1', b'.', minor] if minor.is_ascii_digit() => {}
+            [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if
+                major.is_ascii_digit() &&
+                minor.is_ascii_digit()
+            => {
+                return Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+            }
+            _ => {
+                return Err(StatusCode::BAD_REQUEST);
             }
+        }
 
-            let content_length = Self::get_content_length(header_bytes).unwrap_or(0);
-            return body_slice.len() >= content_length;
+        // NUL bytes are never legitimate anywhere in the header section
+        let head_end = Self::find_header_end(buf).unwrap_or(buf.len());
+        if buf[..head_end].contains(&0) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        // Well-formed but unknown (`BREW /`), as opposed to a route excluding it (405)
+        if !KNOWN_METHODS.iter().any(|known| known.as_bytes() == method) {
+            return Err(StatusCode::NOT_IMPLEMENTED);
+        }
+
+        Ok(())
+    }
+
+    fn is_tchar(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    }
+
+    /// Length of the request at the start of `buf` once all of it has
+    /// arrived; anything after it belongs to the next, pipelined request.
+    pub fn complete_len(buf: &[u8]) -> Option<usize> {
+        let header_end = Self::find_header_end(buf)?;
+        let header_bytes = &buf[..header_end];
+        let body_slice = &buf[header_end..];
+
+        if
+            let Some(headers) = Self::parse_headers_map(header_bytes) &&
+            Self::is_chunked_transfer(&headers)
+        {
+            return Self::decode_chunked_body(body_slice).map(|(_, used)| header_end + used);
         }
-        false
+
+        let content_length = Self::get_content_length(header_bytes).unwrap_or(0);
+        (body_slice.len() >= content_length).then_some(header_end + content_length)
     }
 
     fn find_header_end(buf: &[u8]) -> Option<usize> {
@@ -87,8 +205,8 @@ impl HttpRequest {
         let header_str = std::str::from_utf8(header_bytes).ok()?;
         for line in header_str.lines() {
             let line_lower = line.to_lowercase();
-            if line_lower.starts_with("content-length:") {
-                return line_lower["content-length:".len()..].trim().parse().ok();
+            if let Some(value) = line_lower.strip_prefix("content-length:") {
+                return value.trim().parse().ok();
             }
         }
         None
@@ -171,6 +289,7 @@ impl HttpRequest {
         let boundary_bytes = format!("--{}", boundary).into_bytes();
 
         let mut files = Vec::new();
+        let mut fields = Vec::new();
         let mut current_pos = 0;
 
         while let Some(start_pos) = Self::find_bytes(body, &boundary_bytes, current_pos) {
@@ -184,16 +303,21 @@ impl HttpRequest {
             };
 
             let part_data = &body[part_search_start..end_pos];
-            if let Some(file) = Self::parse_multipart_part(part_data) {
-                files.push(file);
+            if let Some((name, file_name, content_type, data)) = Self::parse_multipart_part(part_data) {
+                if file_name.is_empty() {
+                    fields.push((name, String::from_utf8_lossy(&data).to_string()));
+                } else {
+                    files.push(UploadedFile { file_name, content_type, data });
+                }
             }
             current_pos = end_pos;
         }
 
-        Some(MultipartForm { files })
+        Some(MultipartForm { files, fields })
     }
 
-    fn parse_multipart_part(part_data: &[u8]) -> Option<UploadedFile> {
+    /// Returns `(name, filename, content type, data)`; `filename` is empty for plain form fields.
+    fn parse_multipart_part(part_data: &[u8]) -> Option<(String, String, Option<String>, Vec<u8>)> {
         let data = if part_data.starts_with(b"\r\n") { &part_data[2..] } else { part_data };
 
         let header_end = Self::find_header_end(data)?;
@@ -207,28 +331,29 @@ impl HttpRequest {
         };
 
         let header_str = std::str::from_utf8(header_bytes).ok()?;
+        let mut name = String::new();
         let mut file_name = String::new();
+        let mut content_type = None;
         for line in header_str.lines() {
-            if line.to_lowercase().starts_with("content-disposition:") {
-                if
-                    let Some(f) = line
-                        .split("filename=\"")
-                        .nth(1)
-                        .and_then(|s| s.split('"').next())
-                {
-                    file_name = f.to_string();
-                }
+            if let Some((key, value)) = line.split_once(':') && key.trim().eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.trim().to_string());
+            }
+            if !line.to_lowercase().starts_with("content-disposition:") {
+                continue;
+            }
+            if let Some(f) = line.split("filename=\"").nth(1).and_then(|s| s.split('"').next()) {
+                file_name = f.to_string();
+            }
+            if let Some(n) = line.split(" name=\"").nth(1).and_then(|s| s.split('"').next()) {
+                name = n.to_string();
             }
         }
 
-        if file_name.is_empty() {
+        if name.is_empty() && file_name.is_empty() {
             return None;
         }
 
-        Some(UploadedFile {
-            file_name,
-            data: actual_file_data.to_vec(),
-        })
+        Some((name, file_name, content_type, actual_file_data.to_vec()))
     }
 
     fn find_bytes(haystack: &[u8], needle: &[u8], start: usize) -> Option<usize> {
@@ -239,3 +364,103 @@ impl HttpRequest {
             .map(|pos| pos + start)
     }
 }
+
+/// Index of the tag in `available` (lowercase) that `Accept-Language` ranks
+/// highest. A range takes its own tag and the ones under it (`en` takes
+/// `en-gb`), then its shorter prefixes (`fr-ch` takes `fr`); `*` or no match
+/// leaves the choice to the caller's default.
+pub fn preferred_language(accept_language: &str, available: &[&str]) -> Option<usize> {
+    let mut ranges: Vec<(String, f32)> = accept_language
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let weight = match parts.find_map(|p| p.trim().strip_prefix("q=")) {
+                Some(q) => q.trim().parse::<f32>().ok()?,
+                None => 1.0,
+            };
+            (!range.is_empty() && weight > 0.0).then_some((range, weight))
+        })
+        .collect();
+    // Stable, so equal weights keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    for (range, _) in &ranges {
+        if range == "*" {
+            break;
+        }
+        let under = format!("{}-", range);
+        if let Some(idx) = available.iter().position(|tag| tag == range || tag.starts_with(&under)) {
+            return Some(idx);
+        }
+        let mut prefix = range.as_str();
+        while let Some((shorter, _)) = prefix.rsplit_once('-') {
+            prefix = shorter;
+            if let Some(idx) = available.iter().position(|tag| *tag == prefix) {
+                return Some(idx);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(line: &[u8]) -> Result<(), StatusCode> {
+        HttpRequest::validate_request_line(line)
+    }
+
+    #[test]
+    fn accepts_well_formed_request_lines() {
+        for line in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"GET /a/b?c=d&e=%20 HTTP/1.0\r\nHost: x\r\n\r\n",
+            b"OPTIONS * HTTP/1.1\r\n",
+            b"GET http://example.test/a HTTP/1.1\r\n",
+            b"CONNECT example.test:443 HTTP/1.1\r\n",
+            // Not complete yet, so nothing to judge
+            b"GET /still-arriv",
+        ] {
+            assert_eq!(check(line), Ok(()), "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_request_lines_with_400() {
+        for line in [
+            &b"NONSENSE\r\n"[..],
+            b"GET  / HTTP/1.1\r\n",
+            b"GET / HTTP/1.1 extra\r\n",
+            b"GET\t/ HTTP/1.1\r\n",
+            b"G(T / HTTP/1.1\r\n",
+            b"GET relative HTTP/1.1\r\n",
+            b"GET /a\x7fb HTTP/1.1\r\n",
+            b"GET /a\x01b HTTP/1.1\r\n",
+            b"GET / http/1.1\r\n",
+            b"GET / HTTP/1\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\0b\r\n\r\n",
+            b"CONNECT /path HTTP/1.1\r\n",
+        ] {
+            assert_eq!(check(line), Err(StatusCode::BAD_REQUEST), "{}", String::from_utf8_lossy(line));
+        }
+        let long_method = format!("{} / HTTP/1.1\r\n", "A".repeat(MAX_METHOD_LEN + 1));
+        assert_eq!(check(long_method.as_bytes()), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn refuses_long_lines_other_versions_and_unknown_methods() {
+        let long = format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_REQUEST_LINE));
+        assert_eq!(check(long.as_bytes()), Err(StatusCode::URI_TOO_LONG));
+        // Too long already, before the line ends
+        assert_eq!(check(&long.as_bytes()[..MAX_REQUEST_LINE + 1]), Err(StatusCode::URI_TOO_LONG));
+        let limit = format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_REQUEST_LINE - 14));
+        assert_eq!(limit.len(), MAX_REQUEST_LINE + 2);
+        assert_eq!(check(limit.as_bytes()), Ok(()));
+
+        assert_eq!(check(b"GET / HTTP/2.0\r\n"), Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED));
+        assert_eq!(check(b"GET / HTTP/0.9\r\n"), Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED));
+        assert_eq!(check(b"BREW /pot HTTP/1.1\r\n"), Err(StatusCode::NOT_IMPLEMENTED));
+    }
+}
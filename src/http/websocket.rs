@@ -0,0 +1,146 @@
+use crate::crypto::{ base64, sha1::sha1 };
+use crate::http::status::StatusCode;
+use std::collections::HashMap;
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+pub fn is_upgrade_request(headers: &HashMap<String, String>) -> bool {
+    headers
+        .get("upgrade")
+        .is_some_and(|v| v.split(',').any(|p| p.trim().eq_ignore_ascii_case("websocket")))
+}
+
+/// Validates the client half of the RFC 6455 opening handshake and returns the
+/// `Sec-WebSocket-Accept` value. 426 means the version is unsupported.
+pub fn accept_key(method: &str, headers: &HashMap<String, String>) -> Result<String, StatusCode> {
+    if method != "GET" {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let connection_upgrade = headers
+        .get("connection")
+        .is_some_and(|v| v.split(',').any(|p| p.trim().eq_ignore_ascii_case("upgrade")));
+    if !connection_upgrade {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if headers.get("sec-websocket-version").map(|v| v.trim()) != Some("13") {
+        return Err(StatusCode::UPGRADE_REQUIRED);
+    }
+
+    let key = headers.get("sec-websocket-key").ok_or(StatusCode::BAD_REQUEST)?.trim();
+    if base64::decode(key).is_none_or(|raw| raw.len() != 16) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(base64::encode(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes())))
+}
+
+pub fn handshake_response(accept: &str, extra_headers: Vec<(String, String)>, server_header: Option<&str>) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+        accept
+    );
+    for (key, value) in extra_headers {
+        response.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    if let Some(server) = server_header {
+        response.push_str(&format!("Server: {}\r\n", server));
+    }
+    response.push_str("\r\n");
+    response.into_bytes()
+}
+
+/// Decodes one client frame from the front of `buf`. `Ok(None)` means more bytes
+/// are needed; `Err` carries the close code to fail the connection with.
+pub fn decode_frame(buf: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    if buf[0] & 0x70 != 0 || !matches!(opcode, 0x0..=0x2 | 0x8..=0xa) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    // Clients must mask every frame (RFC 6455 5.1)
+    if buf[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(raw), 10)
+        }
+        n => (n as u64, 2),
+    };
+
+    if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    if len > max_payload as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    Ok(Some((Frame { fin, opcode, payload }, pos + len)))
+}
+
+/// Server frames are sent unfragmented and unmasked.
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | opcode);
+    if payload.len() < 126 {
+        out.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+pub fn close_frame(code: u16) -> Vec<u8> {
+    encode_frame(OPCODE_CLOSE, &code.to_be_bytes())
+}
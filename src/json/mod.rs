@@ -0,0 +1,5 @@
+pub mod parser;
+pub mod writer;
+
+pub use parser::{ parse, JsonValue };
+pub use writer::to_string_pretty;
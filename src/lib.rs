@@ -0,0 +1,36 @@
+//! An HTTP/1.1 server on a single-threaded `mio` event loop. The binary in
+//! `main.rs` is a thin wrapper; other programs can embed the server directly:
+//!
+//! ```no_run
+//! let config = localserver::config::parse_config("config.yaml", None, None).unwrap();
+//! let mut server = localserver::Server::from_config(config);
+//! server.bind().unwrap();
+//!
+//! let shutdown = server.shutdown_signal();
+//! let trigger = shutdown.clone();
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(std::time::Duration::from_secs(60));
+//!     trigger.trigger();
+//! });
+//! server.run_until(&shutdown);
+//! ```
+
+mod auth;
+pub mod config;
+mod crypto;
+mod error;
+mod handlers;
+mod http;
+mod json;
+pub mod log;
+pub mod server;
+pub mod testing;
+pub mod vfs;
+
+pub use auth::htpasswd::run_passwd_command;
+pub use error::ServerError;
+pub use http::cookie;
+pub use http::date;
+pub use http::markdown;
+pub use http::status::StatusCode;
+pub use server::{ Server, ShutdownSignal };
@@ -1,25 +1,118 @@
-mod config;
-mod server;
-mod http;
-mod handlers;
+mod cli;
+mod scaffold;
 
-use crate::server::Server;
+use localserver::{ config, log, Server };
 
 fn main() {
-    let cfg = match config::parse_config("config.yaml") {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("passwd") {
+        std::process::exit(localserver::run_passwd_command(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("init") {
+        std::process::exit(scaffold::run_init_command(&args[2..]));
+    }
+
+    let options = match cli::parse_args(&args[1..]) {
+        Ok(cli::Action::Serve(options)) => options,
+        Ok(cli::Action::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Ok(cli::Action::Version) => {
+            println!("localserver {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let cfg = match options.load_config() {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Fatal Config Error: {}", e);
-            return;
+            std::process::exit(1);
+        }
+    };
+    log::set_level(cfg.log_level);
+    for warning in cfg.warnings.iter().filter(|_| log::enabled(log::Level::Warn)) {
+        eprintln!("[Config] Warning: {}", warning);
+    }
+
+    if let Some(format) = &options.dump_config {
+        match config::dump_config(&cfg, format) {
+            Ok(dump) => print!("{}", dump),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
+    if options.test_config {
+        let source = options.source();
+        let problems = config::validate_config(&cfg);
+        for problem in &problems {
+            eprintln!("[Config] {}", problem);
+        }
+        if !problems.is_empty() {
+            eprintln!("{}: test failed ({} problem(s))", source, problems.len());
+            std::process::exit(1);
+        }
+        println!("{}: syntax is ok, test is successful", source);
+        return;
+    }
+
+    // A reload re-reads the file by its path, which a chroot would hide
+    let watch_path = match (options.watch, options.config_path()) {
+        (false, _) => None,
+        (true, _) if cfg.chroot.is_some() => {
+            eprintln!("--watch cannot be combined with chroot");
+            std::process::exit(2);
+        }
+        (true, Some(path)) => Some(std::path::PathBuf::from(path)),
+        (true, None) => {
+            eprintln!("--watch needs a configuration file");
+            std::process::exit(2);
         }
     };
 
-    let mut server = Server::new(cfg);
+    let mut server = Server::from_config(cfg);
 
     if let Err(e) = server.bind() {
         eprintln!("[Fatal] {}", e);
-        return;
+        std::process::exit(1);
+    }
+
+    if options.dev && let Err(e) = server.enable_live_reload() {
+        eprintln!("[Fatal] {}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(body_bytes) = options.trace_http {
+        server.enable_http_trace(body_bytes);
+    }
+
+    if
+        let Some(path) = watch_path &&
+        let Err(e) = server.watch_config(&path, Box::new(move || options.load_config()))
+    {
+        eprintln!("[Fatal] {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = server.stop_on_signals() {
+        eprintln!("[Fatal] {}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = server.drop_privileges() {
+        eprintln!("[Fatal] {}", e);
+        std::process::exit(1);
     }
 
     server.run();
 }
+
@@ -0,0 +1,173 @@
+//! `localserver init [--force] [dir]`: writes a starter config, a `www/` tree
+//! with an index page and error pages, and an example CGI script.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const CONFIG: &str = r#"# localserver starter configuration. Validate changes with `localserver -t`.
+
+# Whole-request cap (headers + body); K/M/G suffixes are accepted
+max_server_size: 10M
+# Idle connections and CGI scripts are cut off after this long
+timeout_seconds: 30s
+
+servers:
+  - host: 127.0.0.1
+    port: 8080
+    server_name: localhost
+    max_body_size: 1M
+
+    error_pages:
+      400: ./www/errors/400.html
+      403: ./www/errors/403.html
+      404: ./www/errors/404.html
+      405: ./www/errors/405.html
+      413: ./www/errors/413.html
+      500: ./www/errors/500.html
+
+    routes:
+      # Static site
+      - path: /
+        root: ./www
+        index: index.html
+        methods: [GET, HEAD]
+
+      # Files POSTed here land in ./www/uploads and can be fetched or DELETEd again
+      - path: /uploads
+        root: ./www
+        upload_dir: ./www/uploads
+        methods: [GET, POST, DELETE]
+        max_body_size: 50M
+
+      # Scripts ending in .py run through python3
+      - path: /cgi-bin
+        root: ./www/cgi-bin
+        cgi_extension: .py
+        cgi_interpreter: /usr/bin/python3
+        methods: [GET, POST]
+"#;
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>localserver</title>
+</head>
+<body>
+  <h1>It works!</h1>
+  <p>Edit <code>www/index.html</code> or <code>config.yaml</code> to get started.</p>
+  <ul>
+    <li><a href="/cgi-bin/hello.py?name=localserver">Run the example CGI script</a></li>
+    <li><a href="/uploads/">Browse uploads</a></li>
+  </ul>
+  <form action="/uploads" method="post" enctype="multipart/form-data">
+    <input type="file" name="file">
+    <button type="submit">Upload</button>
+  </form>
+</body>
+</html>
+"#;
+
+const HELLO_CGI: &str = r#"#!/usr/bin/env python3
+import html
+import os
+from urllib.parse import parse_qs
+
+query = parse_qs(os.environ.get("QUERY_STRING", ""))
+name = query.get("name", ["world"])[0]
+
+print("Content-Type: text/html")
+print()
+print("<h1>Hello, {}!</h1>".format(html.escape(name)))
+print("<p>{} request, query string: <code>{}</code></p>".format(
+    html.escape(os.environ.get("REQUEST_METHOD", "?")),
+    html.escape(os.environ.get("QUERY_STRING", ""))))
+"#;
+
+const ERROR_PAGES: [(u16, &str); 6] = [
+    (400, "Bad Request"),
+    (403, "Forbidden"),
+    (404, "Not Found"),
+    (405, "Method Not Allowed"),
+    (413, "Payload Too Large"),
+    (500, "Internal Server Error"),
+];
+
+pub fn run_init_command(args: &[String]) -> i32 {
+    let mut force = false;
+    let mut target = None;
+
+    for arg in args {
+        match arg.as_str() {
+            "--force" | "-f" => {
+                force = true;
+            }
+            other if target.is_none() && !other.starts_with('-') => {
+                target = Some(other.to_string());
+            }
+            other => {
+                eprintln!("Unexpected argument: {}", other);
+                eprintln!("Usage: localserver init [--force] [dir]");
+                return 2;
+            }
+        }
+    }
+
+    let dir = Path::new(target.as_deref().unwrap_or("."));
+    match scaffold(dir, force) {
+        Ok(()) => {
+            println!("Start it with: cd {} && localserver", dir.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("init: {}", e);
+            1
+        }
+    }
+}
+
+fn scaffold(dir: &Path, force: bool) -> Result<(), String> {
+    let mut files: Vec<(String, String, bool)> = vec![
+        ("config.yaml".to_string(), CONFIG.to_string(), false),
+        ("www/index.html".to_string(), INDEX.to_string(), false),
+        ("www/cgi-bin/hello.py".to_string(), HELLO_CGI.to_string(), true)
+    ];
+    for (code, reason) in ERROR_PAGES {
+        files.push((format!("www/errors/{}.html", code), error_page(code, reason), false));
+    }
+
+    // Check everything first so a refusal leaves the directory untouched
+    if !force {
+        let existing: Vec<&str> = files
+            .iter()
+            .map(|(path, _, _)| path.as_str())
+            .filter(|path| dir.join(path).exists())
+            .collect();
+        if !existing.is_empty() {
+            return Err(format!("{} already exist(s); use --force to overwrite", existing.join(", ")));
+        }
+    }
+
+    fs::create_dir_all(dir.join("www/uploads")).map_err(|e| e.to_string())?;
+    for (path, content, executable) in &files {
+        let full = dir.join(path);
+        if let Some(parent) = full.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+        }
+        fs::write(&full, content).map_err(|e| format!("{}: {}", full.display(), e))?;
+        if *executable {
+            fs::set_permissions(&full, fs::Permissions::from_mode(0o755)).map_err(|e| e.to_string())?;
+        }
+        println!("  created {}", full.display());
+    }
+    Ok(())
+}
+
+fn error_page(code: u16, reason: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0} {1}</title></head>\n<body>\n  <h1>{0} {1}</h1>\n  <p><a href=\"/\">Back to the home page</a></p>\n</body>\n</html>\n",
+        code,
+        reason
+    )
+}
@@ -0,0 +1,63 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::IpAddr;
+use std::time::{ Duration, Instant };
+
+/// fail2ban-style tracking: clients producing too many 4xx responses within
+/// `window` are refused at accept time for `duration`.
+pub struct BanList {
+    threshold: usize,
+    window: Duration,
+    duration: Duration,
+    strikes: HashMap<IpAddr, VecDeque<Instant>>,
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    pub fn new(threshold: usize, window: Duration, duration: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            duration,
+            strikes: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.banned.get(&ip).is_some_and(|until| now < *until)
+    }
+
+    /// Records one client error; returns true when this strike triggers a ban.
+    pub fn record_error(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+
+        let strikes = self.strikes.entry(ip).or_default();
+        strikes.push_back(now);
+        while strikes.front().is_some_and(|t| now.duration_since(*t) > self.window) {
+            strikes.pop_front();
+        }
+
+        if strikes.len() < self.threshold {
+            return false;
+        }
+
+        self.strikes.remove(&ip);
+        self.banned.insert(ip, now + self.duration);
+        true
+    }
+
+    /// Applies new limits on reload without forgetting current strikes and bans.
+    pub fn configure(&mut self, threshold: usize, window: Duration, duration: Duration) {
+        self.threshold = threshold;
+        self.window = window;
+        self.duration = duration;
+    }
+
+    pub fn prune(&mut self, now: Instant) {
+        self.banned.retain(|_, until| now < *until);
+        let window = self.window;
+        self.strikes.retain(|_, strikes| strikes.back().is_some_and(|t| now.duration_since(*t) <= window));
+    }
+}
@@ -0,0 +1,182 @@
+//! `cgi_cache:` on a route: successful GET responses of its CGI scripts are
+//! kept for `ttl`, keyed by path and query, and identical requests that arrive
+//! while the script runs wait for its answer instead of starting it again.
+//! `proxy_cache:` keeps upstream answers here as well, for as long as they
+//! allow, and may hand out a stale one while a fresh copy is fetched.
+//! `PURGE` from a `purge_allow` address drops copies before they expire.
+
+use mio::Token;
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+
+/// A response of one route (`server_idx:route path:root or upstreams`) for one `path?query`.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct CacheKey {
+    pub(crate) route: String,
+    pub(crate) request: String,
+}
+
+/// A request waiting for the script another request started, with what it
+/// needs to run the script itself if no cacheable answer comes back.
+pub(crate) struct Waiter {
+    pub(crate) token: Token,
+    pub(crate) script_path: String,
+    pub(crate) interpreter: Option<String>,
+    pub(crate) env_vars: HashMap<String, String>,
+}
+
+struct Entry {
+    response: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+    /// Until then an expired entry may still be served while it is refetched.
+    stale_until: Instant,
+}
+
+#[derive(Default)]
+struct RouteCache {
+    entries: HashMap<String, Entry>,
+    size: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct ResponseCache {
+    routes: HashMap<String, RouteCache>,
+    in_flight: HashMap<CacheKey, Vec<Waiter>>,
+}
+
+impl ResponseCache {
+    pub(crate) fn get(&self, key: &CacheKey, now: Instant) -> Option<Vec<u8>> {
+        let entry = self.routes.get(&key.route)?.entries.get(&key.request)?;
+        (now < entry.expires).then(|| entry.response.clone())
+    }
+
+    /// An expired entry still inside its stale window.
+    pub(crate) fn get_stale(&self, key: &CacheKey, now: Instant) -> Option<Vec<u8>> {
+        let entry = self.routes.get(&key.route)?.entries.get(&key.request)?;
+        (entry.expires <= now && now < entry.stale_until).then(|| entry.response.clone())
+    }
+
+    /// Keeps `response` for `fresh`, and `stale` beyond that for `get_stale`.
+    /// Oldest entries make room; a response bigger than the whole cache is not kept.
+    pub(crate) fn insert(
+        &mut self,
+        key: &CacheKey,
+        response: Vec<u8>,
+        max_size: usize,
+        fresh: Duration,
+        stale: Duration,
+        now: Instant
+    ) {
+        if response.len() > max_size {
+            return;
+        }
+        let route = self.routes.entry(key.route.clone()).or_default();
+        route.entries.remove(&key.request);
+        route.entries.retain(|_, entry| now < entry.stale_until);
+        route.size = route.entries.values().map(|entry| entry.response.len()).sum();
+        while route.size + response.len() > max_size {
+            let oldest = match route.entries.iter().min_by_key(|(_, entry)| entry.stored) {
+                Some((request, _)) => request.clone(),
+                None => {
+                    break;
+                }
+            };
+            if let Some(entry) = route.entries.remove(&oldest) {
+                route.size -= entry.response.len();
+            }
+        }
+        route.size += response.len();
+        let expires = now + fresh;
+        route.entries.insert(key.request.clone(), Entry { response, stored: now, expires, stale_until: expires + stale });
+    }
+
+    pub(crate) fn prune(&mut self, now: Instant) {
+        for route in self.routes.values_mut() {
+            route.entries.retain(|_, entry| now < entry.stale_until);
+            route.size = route.entries.values().map(|entry| entry.response.len()).sum();
+        }
+        self.routes.retain(|_, route| !route.entries.is_empty());
+    }
+
+    /// Drops every copy a server block keeps of `path`, whatever its query or
+    /// key headers; a `path` ending in `*` drops every path it starts. Returns
+    /// how many were dropped.
+    pub(crate) fn purge(&mut self, server_idx: usize, path: &str) -> usize {
+        let block = format!("{}:", server_idx);
+        let matches = |request: &str| {
+            let request_path = request.split(['?', '\n']).next().unwrap_or("");
+            match path.strip_suffix('*') {
+                Some(prefix) => request_path.starts_with(prefix),
+                None => request_path == path,
+            }
+        };
+        let mut purged = 0;
+        for (name, route) in self.routes.iter_mut() {
+            if !name.starts_with(&block) {
+                continue;
+            }
+            let before = route.entries.len();
+            route.entries.retain(|request, _| !matches(request));
+            purged += before - route.entries.len();
+            route.size = route.entries.values().map(|entry| entry.response.len()).sum();
+        }
+        self.routes.retain(|_, route| !route.entries.is_empty());
+        purged
+    }
+
+    /// Whether a script for `key` is already running; if so `waiter` queues behind it.
+    pub(crate) fn join(&mut self, key: &CacheKey, waiter: Waiter) -> Result<(), Waiter> {
+        match self.in_flight.get_mut(key) {
+            Some(waiters) => {
+                waiters.push(waiter);
+                Ok(())
+            }
+            None => Err(waiter),
+        }
+    }
+
+    /// Marks `key` as being fetched by the request that runs its script.
+    pub(crate) fn lead(&mut self, key: &CacheKey) {
+        self.in_flight.insert(key.clone(), Vec::new());
+    }
+
+    pub(crate) fn is_in_flight(&self, key: &CacheKey) -> bool {
+        self.in_flight.contains_key(key)
+    }
+
+    pub(crate) fn release(&mut self, key: &CacheKey) -> Vec<Waiter> {
+        self.in_flight.remove(key).unwrap_or_default()
+    }
+
+    /// A waiting client went away; it must not be answered later.
+    pub(crate) fn forget(&mut self, token: Token) {
+        for waiters in self.in_flight.values_mut() {
+            waiters.retain(|waiter| waiter.token != token);
+        }
+    }
+}
+
+/// Only a plain 200 that is the same for everyone: no cookie, nothing
+/// `Cache-Control` keeps out of shared caches.
+pub(crate) fn cacheable(response: &[u8]) -> bool {
+    let head = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => String::from_utf8_lossy(&response[..pos]).to_ascii_lowercase(),
+        None => {
+            return false;
+        }
+    };
+    let mut lines = head.lines();
+    if lines.next().and_then(|status| status.split(' ').nth(1)) != Some("200") {
+        return false;
+    }
+    lines.all(|line| {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        match name.trim() {
+            "set-cookie" => false,
+            "cache-control" =>
+                !value.split(',').any(|directive| matches!(directive.trim(), "no-store" | "no-cache" | "private")),
+            _ => true,
+        }
+    })
+}
@@ -0,0 +1,60 @@
+//! The server's idea of "now" for timeouts, bans and sessions. Real servers
+//! use `SystemClock`; tests swap in a `ManualClock` with `Server::set_clock`
+//! and move time forward themselves:
+//!
+//! ```no_run
+//! # let mut server: localserver::Server = unimplemented!();
+//! use localserver::server::clock::ManualClock;
+//! use std::time::Duration;
+//!
+//! let clock = ManualClock::new();
+//! server.set_clock(clock.clone());
+//! // ... open a connection and send half a request ...
+//! clock.advance(Duration::from_secs(31)); // the next loop turn answers 408
+//! ```
+
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Stands still until `advance` is called. Clones share the same time, so a
+/// test can keep one while the server (on its own thread) holds another.
+#[derive(Clone)]
+pub struct ManualClock {
+    start: Instant,
+    offset_nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), offset_nanos: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.offset_nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_nanos(self.offset_nanos.load(Ordering::SeqCst))
+    }
+}
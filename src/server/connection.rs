@@ -1,40 +1,109 @@
+use std::io::Read;
+use std::net::{ IpAddr, SocketAddr };
 use std::time::Instant;
+use crate::config::models::ProxyProtocol;
+use crate::server::sendfile::FileBody;
 use mio::net::TcpStream;
+use mio::Token;
 
 #[derive(Debug, PartialEq)]
 pub enum ConnectionState {
     ReadRequest,
     CgiPending,
     WriteResponse,
+    /// Upgraded by a `websocket: true` route; frames flow both ways until close.
+    WebSocket,
+    /// `sse: true` route: handler output is forwarded as it arrives.
+    Streaming,
+    /// CONNECT tunnel: bytes are relayed to and from an upstream socket.
+    Tunnel,
+    /// `proxy_pass` route: the upstream's response is relayed as it arrives.
+    Proxy,
+    /// An early error was answered; the rest of the declared body is read and
+    /// thrown away so closing does not reset the response out from under the client.
+    Draining,
 }
 
 pub struct Connection {
     pub stream: TcpStream,
+    /// TCP peer as accepted; behind a proxy this is the proxy.
+    pub peer: SocketAddr,
 
     pub state: ConnectionState,
     pub read_buffer: Vec<u8>,
     pub write_buffer: Vec<u8>,
     pub bytes_written: usize,
+    /// Rest of a streamed response body, read into `write_buffer` once it has been sent.
+    pub body_stream: Option<Box<dyn Read>>,
+    /// Rest of a file body going out with `sendfile`, ahead of `body_stream`.
+    pub(crate) body_file: Option<FileBody>,
     pub response_headers: Vec<(String, String)>,
     pub last_activity: Instant,
+    pub opened_at: Instant,
+    /// When the first byte of the request arrived.
+    pub request_started: Option<Instant>,
     pub server_idx: usize,
+    /// Listener the connection arrived on, so a reload can re-point `server_idx`.
+    pub listener: Token,
+    /// Shown to clients in error pages (`{{request_id}}`) and to handlers.
+    pub request_id: String,
     pub request_complete: bool,
+    /// Bytes of `read_buffer` the current request takes up once complete.
+    pub request_len: usize,
+    /// The client sent `Connection: close` with the current request.
+    pub close_requested: bool,
+    /// The response being written leaves the connection open for the next request.
+    pub keep_alive: bool,
+    /// Body bytes still to discard after an early error; 0 closes right after the response.
+    pub drain_remaining: usize,
+    pub request_method: String,
+    pub request_uri: String,
+    /// For picking a localized error page.
+    pub accept_language: String,
+    /// `X-Upload-ID` of a body being tracked for `upload_progress`.
+    pub upload_id: Option<String>,
+    pub access_log: bool,
+    /// Real client address resolved through `trusted_proxies`; `None` means the TCP peer.
+    pub client_ip: Option<IpAddr>,
+    /// Still expecting a PROXY protocol preamble; reset to `Off` once handled.
+    pub proxy_protocol: ProxyProtocol,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, server_idx: usize) -> Self {
+    pub fn new(stream: TcpStream, peer: SocketAddr, listener: Token, server_idx: usize, now: Instant) -> Self {
         Self {
             stream,
+            peer,
             state: ConnectionState::ReadRequest,
             read_buffer: Vec::with_capacity(8192),
             write_buffer: Vec::new(),
             bytes_written: 0,
+            body_stream: None,
+            body_file: None,
             response_headers: Vec::new(),
-            last_activity: Instant::now(),
+            last_activity: now,
+            opened_at: now,
+            request_started: None,
             server_idx,
+            listener,
+            request_id: String::new(),
             request_complete: false,
+            request_len: 0,
+            close_requested: false,
+            keep_alive: false,
+            drain_remaining: 0,
+            request_method: String::new(),
+            request_uri: String::new(),
+            accept_language: String::new(),
+            upload_id: None,
+            access_log: true,
+            client_ip: None,
+            proxy_protocol: ProxyProtocol::Off,
         }
     }
 
-
+    /// Another request already arrived behind the one being answered.
+    pub fn pipelined(&self) -> bool {
+        self.request_complete && !self.close_requested && self.read_buffer.len() > self.request_len
+    }
 }
@@ -0,0 +1,80 @@
+//! `sub_filter` routes: string replacements applied to the body of complete
+//! responses whose `Content-Type` is listed in `sub_filter_types`.
+
+/// `response` with every `(from, to)` rule applied in order and its
+/// `Content-Length` updated. Compressed, chunked and non-UTF-8 bodies, and
+/// content types not in `types`, are passed through untouched.
+pub(crate) fn apply(response: Vec<u8>, rules: &[(String, String)], types: &[String]) -> Vec<u8> {
+    let split = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => {
+            return response;
+        }
+    };
+    let head = match std::str::from_utf8(&response[..split]) {
+        Ok(head) => head,
+        Err(_) => {
+            return response;
+        }
+    };
+
+    let mut content_type = None;
+    for line in head.split("\r\n").skip(1) {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => {
+                continue;
+            }
+        };
+        if name.eq_ignore_ascii_case("content-encoding") || name.eq_ignore_ascii_case("transfer-encoding") {
+            return response;
+        }
+        if name.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value);
+        }
+    }
+    let media_type = content_type.and_then(|ct| ct.split(';').next()).unwrap_or("").trim();
+    if !types.iter().any(|t| type_matches(media_type, t)) {
+        return response;
+    }
+
+    let body = match std::str::from_utf8(&response[split + 4..]) {
+        Ok(body) => body,
+        Err(_) => {
+            return response;
+        }
+    };
+    let mut filtered = body.to_string();
+    for (from, to) in rules {
+        if !from.is_empty() {
+            filtered = filtered.replace(from.as_str(), to);
+        }
+    }
+    if filtered == body {
+        return response;
+    }
+
+    let mut out = Vec::with_capacity(split + filtered.len() + 4);
+    for (i, line) in head.split("\r\n").enumerate() {
+        if i > 0 {
+            out.extend_from_slice(b"\r\n");
+        }
+        match line.split_once(':') {
+            Some((name, _)) if i > 0 && name.trim().eq_ignore_ascii_case("content-length") => {
+                out.extend_from_slice(format!("{}: {}", name, filtered.len()).as_bytes());
+            }
+            _ => out.extend_from_slice(line.as_bytes()),
+        }
+    }
+    out.extend_from_slice(b"\r\n\r\n");
+    out.extend_from_slice(filtered.as_bytes());
+    out
+}
+
+/// `text/html`, or `text/*` for a whole family.
+fn type_matches(media_type: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => media_type.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+        None => media_type.eq_ignore_ascii_case(pattern),
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// `trusted_proxies` entries: single addresses or CIDR ranges whose
+/// X-Forwarded-For / Forwarded headers are believed.
+#[derive(Default)]
+pub struct TrustedProxies {
+    nets: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        Self::parse_as("trusted_proxies", entries)
+    }
+
+    /// The same address and range syntax for another config key, named in errors.
+    pub fn parse_as(key: &str, entries: &[String]) -> Result<Self, String> {
+        let mut nets = Vec::new();
+        for entry in entries {
+            let (addr, prefix) = match entry.split_once('/') {
+                Some((a, p)) => (a, Some(p)),
+                None => (entry.as_str(), None),
+            };
+            let ip: IpAddr = addr
+                .trim()
+                .parse()
+                .map_err(|_| format!("{}: invalid address '{}'", key, entry))?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let bits = match prefix {
+                Some(p) =>
+                    p
+                        .trim()
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|b| *b <= max)
+                        .ok_or_else(|| format!("{}: invalid prefix in '{}'", key, entry))?,
+                None => max,
+            };
+            nets.push((ip, bits));
+        }
+        Ok(Self { nets })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|(net, bits)| Self::in_net(ip, *net, *bits))
+    }
+
+    /// Walks the forwarding chain right to left and returns the first hop that
+    /// is not a trusted proxy. Untrusted peers are never allowed to spoof it.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HashMap<String, String>) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let chain = match Self::forwarded_chain(headers) {
+            Some(chain) if !chain.is_empty() => chain,
+            _ => {
+                return peer;
+            }
+        };
+
+        for ip in chain.iter().rev() {
+            if !self.contains(*ip) {
+                return *ip;
+            }
+        }
+        chain[0]
+    }
+
+    /// Prefers RFC 7239 `Forwarded: for=` and falls back to `X-Forwarded-For`.
+    fn forwarded_chain(headers: &HashMap<String, String>) -> Option<Vec<IpAddr>> {
+        if let Some(value) = headers.get("forwarded") {
+            let mut chain = Vec::new();
+            for element in value.split(',') {
+                for pair in element.split(';') {
+                    if
+                        let Some((k, v)) = pair.trim().split_once('=') &&
+                        k.eq_ignore_ascii_case("for")
+                    {
+                        chain.push(Self::parse_node(v)?);
+                    }
+                }
+            }
+            return Some(chain);
+        }
+
+        headers
+            .get("x-forwarded-for")
+            .map(|value| value.split(',').filter_map(Self::parse_node).collect())
+    }
+
+    /// Accepts `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:80"` and bare IPv6.
+    fn parse_node(node: &str) -> Option<IpAddr> {
+        let node = node.trim().trim_matches('"');
+        if let Ok(ip) = node.parse() {
+            return Some(ip);
+        }
+        if let Some(rest) = node.strip_prefix('[') {
+            return rest.split(']').next()?.parse().ok();
+        }
+        node.rsplit_once(':').and_then(|(host, _)| host.parse().ok())
+    }
+
+    fn in_net(ip: IpAddr, net: IpAddr, bits: u8) -> bool {
+        match (ip, net) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = if bits == 0 { 0 } else { u32::MAX << (32 - bits) };
+                (u32::from(a) & mask) == (u32::from(b) & mask)
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = if bits == 0 { 0 } else { u128::MAX << (128 - bits) };
+                (u128::from(a) & mask) == (u128::from(b) & mask)
+            }
+            _ => false,
+        }
+    }
+}
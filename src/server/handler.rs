@@ -0,0 +1,564 @@
+//! The built-in handlers, tried in order once the middlewares have run.
+
+use crate::config::models::Sticky;
+use crate::http::date;
+use crate::http::markdown;
+use crate::http::request::{ HttpRequest, UploadedFile };
+use crate::http::status::StatusCode;
+use crate::http::websocket;
+use crate::log;
+use crate::server::cgi_cache::{ CacheKey, Waiter };
+use crate::server::pipeline::{ Flow, Handler, Request, Response };
+use crate::server::proxy::{ self, Forward, Upstream };
+use crate::server::{ Server, SESSION_COOKIE };
+use mio::Token;
+use std::collections::HashMap;
+use std::path::{ Component, Path, PathBuf };
+use std::time::{ Duration, UNIX_EPOCH };
+
+/// `proxy_pass` routes: the request goes to one of the upstream servers, whose
+/// answer is relayed to the client as it arrives.
+pub struct Proxy;
+
+impl Handler for Proxy {
+    fn handle(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let route = match &request.route {
+            Some(route) if !route.proxy_pass.is_empty() => route,
+            _ => {
+                return Flow::Next;
+            }
+        };
+
+        let mut forward = Forward {
+            method: request.method.clone(),
+            uri: request.uri.clone(),
+            route_path: route.path.clone(),
+            headers: std::mem::take(&mut request.headers),
+            peer: request.context.peer.ip(),
+            body: std::mem::take(&mut request.body),
+            upstreams: route.proxy_pass.iter().filter_map(|target| Upstream::parse(target)).collect(),
+            balance: route.proxy_balance,
+            fail_timeout: route.proxy_fail_timeout,
+            forwarded: route.proxy_forwarded,
+            tries: route.proxy_next_upstream_tries,
+            try_budget: route.proxy_next_upstream_timeout,
+            started: request.context.started,
+            tried: Vec::new(),
+            affinity: None,
+            sticky_cookie: route.proxy_sticky == Some(Sticky::Cookie),
+            cache: None,
+        };
+        let cookie = request.cookies.get(proxy::STICKY_COOKIE).map(String::as_str);
+        forward.affinity = route.proxy_sticky.and_then(|sticky| {
+            proxy::affinity(sticky, request.context.client_ip, cookie, &forward.upstreams)
+        });
+
+        // Only body-less GETs that are the same for everyone; a canary's
+        // upstreams keep answers of their own
+        let result = match &route.proxy_cache {
+            Some(cache) if
+                forward.method == "GET" &&
+                forward.body.is_empty() &&
+                !forward.headers.contains_key("authorization") &&
+                !proxy::is_upgrade(&forward.method, &forward.headers)
+            => {
+                let mut request_key = format!("{}?{}", request.path, request.query);
+                for name in &cache.key {
+                    let value = forward.headers.get(name).map_or("", String::as_str);
+                    request_key.push_str(&format!("\n{}: {}", name, value));
+                }
+                let key = CacheKey {
+                    route: format!("{}:{}:{}", request.server_idx, route.path, route.proxy_pass.join(" ")),
+                    request: request_key,
+                };
+                server.start_cached_proxy(token, forward, key, cache.clone())
+            }
+            _ => server.start_proxy(token, forward),
+        };
+        match result {
+            Ok(()) => Flow::Done,
+            Err(code) => Flow::Error(code),
+        }
+    }
+}
+
+/// POST to a non-CGI path stores the body (multipart or raw) in the upload directory.
+pub struct Upload;
+
+impl Handler for Upload {
+    fn handle(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        let route = match &request.route {
+            Some(route) if request.method == "POST" && !request.is_cgi() => route,
+            _ => {
+                return Flow::Next;
+            }
+        };
+
+        let upload_path = match &route.upload_dir {
+            Some(upload_dir) => PathBuf::from(upload_dir),
+            // A mounted tree is read-only, so there is nowhere to fall back to
+            None if request.is_mounted() => {
+                return Flow::Next;
+            }
+            None => PathBuf::from(&route.root).join("uploads"),
+        };
+
+        let form = HttpRequest::parse_multipart(&request.headers, &request.body);
+        if
+            let Some(expected) = &request.csrf_token &&
+            !Server::csrf_token_submitted(expected, &request.headers, form.as_ref())
+        {
+            return Flow::Error(StatusCode::FORBIDDEN);
+        }
+
+        let upload_performed = match form {
+            Some(form) => {
+                let allowed = |file: &UploadedFile| {
+                    server.upload_content_allowed(&file.file_name, file.content_type.as_deref(), &file.data)
+                };
+                if route.verify_uploads && !form.files.iter().all(allowed) {
+                    return Flow::Error(StatusCode::UNPROCESSABLE_CONTENT);
+                }
+                server.handle_multipart_upload(form, &upload_path).is_ok()
+            }
+            None if !request.body.is_empty() => {
+                let filename = Server::extract_raw_upload_filename(&request.path, &route.path, &request.headers);
+                let declared = request.headers.get("content-type").map(String::as_str);
+                if route.verify_uploads && !server.upload_content_allowed(&filename, declared, &request.body) {
+                    return Flow::Error(StatusCode::UNPROCESSABLE_CONTENT);
+                }
+                server.handle_raw_upload(&request.body, &upload_path, &filename).is_ok()
+            }
+            None => false,
+        };
+
+        // A failed or empty upload falls through to the file at that path
+        if upload_performed {
+            Flow::Reply(Response::new(StatusCode::CREATED, "text/plain", "Upload Successful"))
+        } else {
+            Flow::Next
+        }
+    }
+}
+
+/// DELETE removes a file from the upload directory, or else from the root;
+/// directories and paths that lead outside them are refused.
+pub struct Delete;
+
+impl Handler for Delete {
+    fn handle(&self, _server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        let (route, full_path) = match (&request.route, &request.fs_path) {
+            (Some(route), Some(path)) if request.method == "DELETE" => (route, path),
+            _ => {
+                return Flow::Next;
+            }
+        };
+
+        let relative_path = request.path.strip_prefix(&route.path).unwrap_or("").trim_start_matches('/');
+        if Path::new(relative_path).components().any(|c| c == Component::ParentDir) {
+            return Flow::Error(StatusCode::FORBIDDEN);
+        }
+
+        // Where the file may be, and the directory it has to stay inside
+        let mut candidates: Vec<(PathBuf, &str)> = Vec::new();
+        if let Some(upload_dir) = &route.upload_dir && !relative_path.is_empty() {
+            candidates.push((PathBuf::from(upload_dir).join(relative_path), upload_dir));
+        }
+        if !request.is_mounted() {
+            candidates.push((full_path.clone(), &route.root));
+        }
+        if candidates.is_empty() {
+            return Flow::Error(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let (target, base) = match candidates.into_iter().find(|(path, _)| path.symlink_metadata().is_ok()) {
+            Some(found) => found,
+            None => {
+                return Flow::Error(StatusCode::NOT_FOUND);
+            }
+        };
+        if target.is_dir() {
+            return Flow::Error(StatusCode::FORBIDDEN);
+        }
+
+        // A symlinked directory on the way must not lead outside `base`
+        let inside = match (target.parent().map(std::fs::canonicalize), std::fs::canonicalize(base)) {
+            (Some(Ok(parent)), Ok(base)) => parent.starts_with(base),
+            _ => false,
+        };
+        if !inside {
+            return Flow::Error(StatusCode::FORBIDDEN);
+        }
+
+        match std::fs::remove_file(&target) {
+            Ok(_) => Flow::Reply(Response::new(StatusCode::NO_CONTENT, "text/plain", "")),
+            Err(_) => Flow::Error(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// A directory is redirected to its slash-terminated URL, then served through
+/// its `index` file, listed with `autoindex`, or refused.
+pub struct Directory;
+
+impl Handler for Directory {
+    fn handle(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let (route, full_path) = match (&request.route, &mut request.fs_path) {
+            (Some(route), Some(path)) if request.vfs.is_dir(path) => (route, path),
+            _ => {
+                return Flow::Next;
+            }
+        };
+
+        // Relative links in the index or listing resolve against the slash-terminated URL
+        if !request.path.ends_with('/') {
+            let mut location = format!("{}/", request.path);
+            if !request.query.is_empty() {
+                location = format!("{}?{}", location, request.query);
+            }
+            server.send_redirect_response(token, &location, StatusCode::MOVED_PERMANENTLY);
+            return Flow::Done;
+        }
+
+        if let Some(index_file) = &route.index {
+            // Later handlers serve or run the index file
+            full_path.push(index_file);
+            Flow::Next
+        } else if route.autoindex {
+            let listing = Server::build_autoindex_listing(&request.path, request.vfs.as_ref(), full_path);
+            Flow::Reply(Response::new(StatusCode::OK, "text/html", listing))
+        } else {
+            Flow::Error(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/// CGI scripts (by `cgi_extension`) and `websocket: true` upgrades.
+pub struct Cgi;
+
+impl Handler for Cgi {
+    fn handle(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let (route, full_path) = match (&request.route, &request.fs_path) {
+            (Some(route), Some(path)) => (route, path),
+            _ => {
+                return Flow::Next;
+            }
+        };
+        let is_websocket = route.websocket && websocket::is_upgrade_request(&request.headers);
+        if !request.is_cgi() && !is_websocket {
+            return Flow::Next;
+        }
+        if request.is_mounted() {
+            // Scripts have to be real files to be executed
+            return Flow::Error(StatusCode::NOT_FOUND);
+        }
+        if !full_path.exists() {
+            return Flow::Error(StatusCode::NOT_FOUND);
+        }
+
+        let script_path = std::fs::canonicalize(full_path).unwrap_or(full_path.clone());
+        let script_path_str = script_path.to_string_lossy().to_string();
+
+        let mut env_vars = HashMap::new();
+        env_vars.insert("REQUEST_METHOD".to_string(), request.method.clone());
+        env_vars.insert("SCRIPT_FILENAME".to_string(), script_path_str.clone());
+        env_vars.insert("PATH_INFO".to_string(), script_path_str.clone());
+        env_vars.insert("QUERY_STRING".to_string(), request.query.clone());
+        env_vars.insert("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string());
+        env_vars.insert("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string());
+        env_vars.insert("REMOTE_ADDR".to_string(), request.context.client_ip.to_string());
+        // The port is only the client's own when nothing sits in between
+        if request.context.client_ip == request.context.peer.ip() {
+            env_vars.insert("REMOTE_PORT".to_string(), request.context.peer.port().to_string());
+        }
+        if let Some(identity) = request.identity.take() {
+            env_vars.insert("AUTH_TYPE".to_string(), identity.auth_type.to_string());
+            if !identity.user.is_empty() {
+                env_vars.insert("REMOTE_USER".to_string(), identity.user);
+            }
+            env_vars.extend(identity.env);
+        }
+        if let Some(csrf) = &request.csrf_token {
+            env_vars.insert("CSRF_TOKEN".to_string(), csrf.clone());
+        }
+
+        // Pass headers to CGI
+        crate::handlers::cgi::add_header_env_vars(&mut env_vars, &request.headers);
+        if let Some(ct) = request.headers.get("content-type") {
+            env_vars.insert("CONTENT_TYPE".to_string(), ct.clone());
+        }
+        let content_length = request.headers
+            .get("content-length")
+            .cloned()
+            .unwrap_or_else(|| request.body.len().to_string());
+        env_vars.insert("CONTENT_LENGTH".to_string(), content_length);
+
+        let interpreter = route.cgi_interpreter.as_deref();
+        if is_websocket {
+            server.start_websocket(token, &request.method, &request.headers, &script_path_str, interpreter, env_vars);
+            return Flow::Done;
+        }
+
+        // Only body-less GETs from nobody in particular are the same request
+        // every time: the env above carries the user and the CSRF token. The
+        // root keeps a canary's scripts apart from the stable ones
+        let personal =
+            route.auth.is_some() ||
+            route.jwt_secret.is_some() ||
+            route.jwt_public_key.is_some() ||
+            route.csrf ||
+            request.headers.contains_key("authorization") ||
+            request.cookies.contains_key(SESSION_COOKIE);
+        if
+            let Some(settings) = route.cgi_cache &&
+            request.method == "GET" &&
+            request.body.is_empty() &&
+            !route.sse &&
+            !personal
+        {
+            let key = CacheKey {
+                route: format!("{}:{}:{}", request.server_idx, route.path, route.root),
+                request: format!("{}?{}", request.path, request.query),
+            };
+            let waiter = Waiter {
+                token,
+                script_path: script_path_str,
+                interpreter: interpreter.map(str::to_string),
+                env_vars,
+            };
+            return match server.start_cached_cgi(token, key, settings, waiter) {
+                Ok(()) => Flow::Done,
+                Err(e) => {
+                    log::error!("[CGI Error] {}", e);
+                    Flow::Error(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            };
+        }
+
+        match server.start_cgi_process(token, &script_path_str, interpreter, &request.body, env_vars, route.sse) {
+            Ok(()) => Flow::Done,
+            Err(e) => {
+                log::error!("[CGI Error] {}", e);
+                Flow::Error(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// `.md` files on `markdown: true` routes, rendered into an HTML page. The
+/// template is read on every request so edits to it show up at once.
+pub struct Markdown;
+
+impl Handler for Markdown {
+    fn handle(&self, _server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        let (route, full_path) = match (&request.route, &request.fs_path) {
+            (Some(route), Some(path)) if route.markdown && path.extension().is_some_and(|ext| ext == "md") =>
+                (route, path),
+            _ => {
+                return Flow::Next;
+            }
+        };
+        if request.method != "GET" && request.method != "HEAD" {
+            return Flow::Next;
+        }
+
+        let source = match request.vfs.read(full_path) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(_) => {
+                return Flow::Error(StatusCode::NOT_FOUND);
+            }
+        };
+        let template = match &route.markdown_template {
+            Some(file) =>
+                match std::fs::read_to_string(file) {
+                    Ok(template) => Some(template),
+                    Err(e) => {
+                        log::error!("[Markdown] Cannot read template {}: {}", file, e);
+                        return Flow::Error(StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                }
+            None => None,
+        };
+
+        let fallback_title = full_path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let page = markdown::render_page(&source, &fallback_title, template.as_deref());
+        Flow::Reply(Response::new(StatusCode::OK, "text/html; charset=utf-8", page))
+    }
+}
+
+/// Browsers and crawlers ask every site for these; the root's own file wins.
+const FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// `/favicon.ico` and `/robots.txt` missing from the root get the built-in
+/// ones (`favicon`, `robots`) instead of a 404.
+pub struct BuiltinFiles;
+
+impl Handler for BuiltinFiles {
+    fn handle(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Flow::Next;
+        }
+        if request.fs_path.as_ref().is_some_and(|path| request.vfs.exists(path)) {
+            return Flow::Next;
+        }
+
+        let server_cfg = &server.config.servers[request.server_idx];
+        match request.path.as_str() {
+            "/favicon.ico" if server_cfg.favicon => Flow::Reply(Response::new(StatusCode::OK, "image/x-icon", FAVICON)),
+            "/robots.txt" =>
+                match server_cfg.robots.body() {
+                    Some(body) => Flow::Reply(Response::new(StatusCode::OK, "text/plain", body)),
+                    None => Flow::Next,
+                }
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Compressed copies a file may have next to it, in order of preference:
+/// `index.html.br`, then `index.html.gz`.
+const SIDECARS: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+/// Everything else is a file under the route's root, or its compressed
+/// sidecar when the client accepts that encoding.
+pub struct StaticFile;
+
+impl Handler for StaticFile {
+    fn handle(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let full_path = match &request.fs_path {
+            Some(path) => path,
+            None => {
+                return Flow::Error(StatusCode::NOT_FOUND);
+            }
+        };
+
+        let mime = server.mime_types.lookup(full_path.to_str().unwrap_or("")).to_string();
+        // A page that gets the session's CSRF token is not the same for everyone
+        let personalized = request.csrf_token.is_some() && mime.starts_with("text/html");
+        // Whole seconds, as the header carries them
+        let modified = request.vfs
+            .metadata(full_path)
+            .and_then(|meta| meta.modified)
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| UNIX_EPOCH + Duration::from_secs(since.as_secs()))
+            .filter(|_| !personalized);
+
+        // Substitutions need the plain text, so only untouched files have sidecars
+        let rewritten = personalized || request.route.as_ref().is_some_and(|route| !route.sub_filter.is_empty());
+        let sidecars: Vec<(&str, PathBuf)> = SIDECARS
+            .iter()
+            .filter(|_| !rewritten)
+            .map(|(coding, ext)| {
+                let mut path = full_path.clone().into_os_string();
+                path.push(ext);
+                (*coding, PathBuf::from(path))
+            })
+            .filter(|(_, path)| request.vfs.metadata(path).is_some_and(|meta| !meta.is_dir))
+            .collect();
+        let accept_encoding = request.headers.get("accept-encoding").map_or("", String::as_str);
+        let sidecar = sidecars.iter().find(|(coding, _)| accepts_encoding(accept_encoding, coding));
+
+        let mut headers = Vec::new();
+        if !sidecars.is_empty() {
+            // The same URL answers with different bytes depending on the header
+            headers.push(vary(server, token, "Accept-Encoding"));
+        }
+        if let Some(modified) = modified {
+            headers.push(("Last-Modified".to_string(), date::format(modified)));
+        }
+
+        if let Some(modified) = modified && (request.method == "GET" || request.method == "HEAD") {
+            let since = request.headers.get("if-modified-since").and_then(|value| date::parse(value));
+            // If-None-Match, when sent, decides alone (RFC 9110 §13.1.3)
+            if since.is_some_and(|since| since >= modified) && !request.headers.contains_key("if-none-match") {
+                let mut response = Response::new(StatusCode::NOT_MODIFIED, &mime, Vec::new());
+                response.headers = headers;
+                return Flow::Reply(response);
+            }
+        }
+
+        let path = match sidecar {
+            Some((coding, path)) => {
+                headers.push(("Content-Encoding".to_string(), coding.to_string()));
+                path
+            }
+            None => full_path,
+        };
+
+        // Bodies that get rewritten on the way out are read whole, the rest as they are sent
+        let buffered = rewritten || (server.live_reload.is_some() && mime.starts_with("text/html"));
+        if !buffered && let Some(meta) = request.vfs.metadata(path) && !meta.is_dir {
+            let mut response = if request.method == "HEAD" {
+                let mut response = Response::new(StatusCode::OK, &mime, Vec::new());
+                response.headers.push(("Content-Length".to_string(), meta.len.to_string()));
+                response
+            } else if !request.is_mounted() {
+                // A real file on disk: the kernel can send it straight from the page cache
+                match std::fs::File::open(path) {
+                    Ok(file) => Response::file(StatusCode::OK, &mime, file, meta.len),
+                    Err(_) => {
+                        return Flow::Error(StatusCode::NOT_FOUND);
+                    }
+                }
+            } else {
+                match request.vfs.stream(path) {
+                    Ok(reader) => Response::streamed(StatusCode::OK, &mime, reader, meta.len),
+                    Err(_) => {
+                        return Flow::Error(StatusCode::NOT_FOUND);
+                    }
+                }
+            };
+            response.headers.append(&mut headers);
+            return Flow::Reply(response);
+        }
+
+        match request.vfs.read(path) {
+            Ok(mut content) => {
+                if let Some(csrf) = &request.csrf_token && personalized {
+                    // Forms on CSRF-protected routes embed the token as `{{csrf_token}}`
+                    content = String::from_utf8_lossy(&content)
+                        .replace("{{csrf_token}}", csrf)
+                        .into_bytes();
+                }
+                let mut response = Response::new(StatusCode::OK, &mime, content);
+                response.headers = headers;
+                Flow::Reply(response)
+            }
+            Err(_) => Flow::Error(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+/// Whether `Accept-Encoding` allows `coding`: named, or covered by `*`,
+/// with a `q` above 0.
+fn accepts_encoding(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let allowed = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .all(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+        if name.eq_ignore_ascii_case(coding) {
+            return allowed;
+        }
+        if name == "*" {
+            wildcard = allowed;
+        }
+    }
+    wildcard
+}
+
+/// A `Vary` header naming `field` along with whatever an earlier stage
+/// already listed for this response.
+fn vary(server: &Server, token: Token, field: &str) -> (String, String) {
+    let earlier = server.connections
+        .get(&token)
+        .and_then(|conn| conn.response_headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("vary")))
+        .map(|(_, value)| value.clone());
+    match earlier {
+        Some(value) if !value.split(',').any(|name| name.trim().eq_ignore_ascii_case(field)) =>
+            ("Vary".to_string(), format!("{}, {}", value, field)),
+        Some(value) => ("Vary".to_string(), value),
+        None => ("Vary".to_string(), field.to_string()),
+    }
+}
@@ -0,0 +1,120 @@
+//! Callbacks an embedder attaches at fixed points of a connection's life,
+//! through `Server::hooks_mut()`. Unlike middlewares they never see the
+//! server itself, only what happened:
+//!
+//! ```no_run
+//! # let mut server: localserver::Server = unimplemented!();
+//! use localserver::StatusCode;
+//!
+//! let hooks = server.hooks_mut();
+//! hooks.on_request(|request| {
+//!     if request.path.starts_with("/admin") && !request.context.client_ip.is_loopback() {
+//!         return Some(StatusCode::FORBIDDEN);
+//!     }
+//!     None
+//! });
+//! hooks.on_response(|response| response.add_header("X-Served-By", "edge-1"));
+//! ```
+
+use crate::http::status::StatusCode;
+use crate::server::pipeline::Request;
+use std::net::{ IpAddr, SocketAddr };
+use std::time::Duration;
+
+type AcceptHook = Box<dyn FnMut(SocketAddr) -> bool>;
+type RequestHook = Box<dyn FnMut(&mut Request) -> Option<StatusCode>>;
+type ResponseHook = Box<dyn FnMut(&mut ResponseEvent)>;
+type CloseHook = Box<dyn FnMut(&CloseEvent)>;
+
+#[derive(Default)]
+pub struct Hooks {
+    accept: Vec<AcceptHook>,
+    request: Vec<RequestHook>,
+    response: Vec<ResponseHook>,
+    close: Vec<CloseHook>,
+}
+
+/// A response about to be queued.
+pub struct ResponseEvent<'a> {
+    /// `None` when the status line could not be read (a raw CGI reply, ...).
+    pub status: Option<StatusCode>,
+    /// Empty when the request never got that far (a malformed request line, ...).
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub client_ip: IpAddr,
+    /// Since the first request byte.
+    pub elapsed: Duration,
+    /// Serialized size, before any headers added here.
+    pub size: usize,
+    added_headers: Vec<(String, String)>,
+}
+
+impl ResponseEvent<'_> {
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.added_headers.push((name.to_string(), value.to_string()));
+    }
+}
+
+/// A connection that has just been closed.
+pub struct CloseEvent {
+    pub peer: SocketAddr,
+    pub client_ip: IpAddr,
+    /// Since the connection was accepted.
+    pub duration: Duration,
+}
+
+impl Hooks {
+    /// Runs for each accepted connection; returning false closes it at once.
+    pub fn on_accept(&mut self, hook: impl FnMut(SocketAddr) -> bool + 'static) {
+        self.accept.push(Box::new(hook));
+    }
+
+    /// Runs once the client address is known, before routing. The request may
+    /// be changed; returning a status answers with that error page instead.
+    pub fn on_request(&mut self, hook: impl FnMut(&mut Request) -> Option<StatusCode> + 'static) {
+        self.request.push(Box::new(hook));
+    }
+
+    /// Runs for every response just before it is queued, CGI output included.
+    pub fn on_response(&mut self, hook: impl FnMut(&mut ResponseEvent) + 'static) {
+        self.response.push(Box::new(hook));
+    }
+
+    pub fn on_close(&mut self, hook: impl FnMut(&CloseEvent) + 'static) {
+        self.close.push(Box::new(hook));
+    }
+
+    pub(crate) fn accept(&mut self, peer: SocketAddr) -> bool {
+        self.accept.iter_mut().all(|hook| hook(peer))
+    }
+
+    pub(crate) fn request(&mut self, request: &mut Request) -> Option<StatusCode> {
+        self.request.iter_mut().find_map(|hook| hook(request))
+    }
+
+    /// The headers the hooks added.
+    pub(crate) fn response(
+        &mut self,
+        status: Option<StatusCode>,
+        method: &str,
+        uri: &str,
+        client_ip: IpAddr,
+        elapsed: Duration,
+        size: usize
+    ) -> Vec<(String, String)> {
+        if self.response.is_empty() {
+            return Vec::new();
+        }
+        let mut event = ResponseEvent { status, method, uri, client_ip, elapsed, size, added_headers: Vec::new() };
+        for hook in &mut self.response {
+            hook(&mut event);
+        }
+        event.added_headers
+    }
+
+    pub(crate) fn close(&mut self, event: &CloseEvent) {
+        for hook in &mut self.close {
+            hook(event);
+        }
+    }
+}
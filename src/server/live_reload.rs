@@ -0,0 +1,31 @@
+//! `--dev`: every disk route root is watched, and HTML pages get a script that
+//! listens on an event stream and reloads the page when something changes.
+
+use crate::server::filter;
+use crate::server::watch::TreeWatcher;
+use mio::Token;
+use std::time::{ Duration, Instant };
+
+/// The event stream the injected script connects to.
+pub const EVENTS_PATH: &str = "/__livereload";
+
+/// Editors write a file in several steps; one reload once they stop.
+pub(crate) const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// `EventSource` reconnects on its own, so a restarted server is picked up too.
+const SCRIPT: &str =
+    "<script>new EventSource(\"/__livereload\").onmessage = function() { location.reload(); };</script>";
+
+pub(crate) struct LiveReload {
+    pub(crate) watcher: TreeWatcher,
+    pub(crate) token: Token,
+    /// Clients holding the event stream open.
+    pub(crate) clients: Vec<Token>,
+    pub(crate) due: Option<Instant>,
+}
+
+/// `response` with the reload script before `</body>` when it is an HTML page.
+pub(crate) fn inject(response: Vec<u8>) -> Vec<u8> {
+    let rules = [("</body>".to_string(), format!("{}</body>", SCRIPT))];
+    filter::apply(response, &rules, &["text/html".to_string()])
+}
@@ -0,0 +1,474 @@
+//! The built-in middlewares, in pipeline order (see `Pipeline::standard`).
+
+use crate::config::models::CanaryTrigger;
+use crate::http::inflate::{ self, InflateError };
+use crate::http::status::StatusCode;
+use crate::server::pipeline::{ Flow, Middleware, Request, Response };
+use crate::server::{ filter, live_reload };
+use crate::server::router::{ self, Target };
+use crate::server::Server;
+use mio::Token;
+use crate::log;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Resolves the real client behind trusted proxies and rejects it if banned.
+pub struct ClientAddress;
+
+impl Middleware for ClientAddress {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if server.resolve_client_ip(token, &request.headers) {
+            return Flow::Done;
+        }
+        if let Some(ip) = server.connections.get(&token).and_then(|conn| conn.client_ip) {
+            request.context.client_ip = ip;
+        }
+        Flow::Next
+    }
+}
+
+/// The embedder's `on_request` hooks, which may veto the request.
+pub struct RequestHooks;
+
+impl Middleware for RequestHooks {
+    fn before(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        match server.hooks.request(request) {
+            Some(status) => Flow::Error(status),
+            None => Flow::Next,
+        }
+    }
+}
+
+/// While `maintenance_file` exists, 503 with `Retry-After` for everything
+/// but the `maintenance_allow` paths. Checked per request, so creating or
+/// removing the file takes effect at once and open connections are untouched.
+pub struct Maintenance;
+
+impl Middleware for Maintenance {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let config = &server.config;
+        let active = config.maintenance_file.as_ref().is_some_and(|file| Path::new(file).exists());
+        if !active || config.maintenance_allow.iter().any(|pattern| router::path_matches_pattern(&request.path, pattern)) {
+            return Flow::Next;
+        }
+        let retry_after = config.maintenance_retry_after.as_secs().to_string();
+        server.apply_configured_headers(token, &[("Retry-After".to_string(), retry_after)]);
+        Flow::Error(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+/// `--dev`: serves the reload event stream and adds the script listening to
+/// it to HTML pages.
+pub struct LiveReload;
+
+impl Middleware for LiveReload {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if server.live_reload.is_none() || request.path != live_reload::EVENTS_PATH {
+            return Flow::Next;
+        }
+        if request.method != "GET" {
+            return Flow::Error(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        server.start_live_reload_stream(token);
+        Flow::Done
+    }
+
+    fn after(&self, server: &mut Server, token: Token, response: &mut Vec<u8>) {
+        let is_head = server.connections.get(&token).is_none_or(|conn| conn.request_method == "HEAD");
+        if server.live_reload.is_some() && !is_head {
+            *response = live_reload::inject(std::mem::take(response));
+        }
+    }
+}
+
+/// `CONNECT host:port` becomes a TCP tunnel before any HTTP handling.
+pub struct ConnectTunnel;
+
+impl Middleware for ConnectTunnel {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if request.method != "CONNECT" {
+            return Flow::Next;
+        }
+        server.start_tunnel(token, request.server_idx, &request.uri);
+        Flow::Done
+    }
+}
+
+/// The server block's `add_headers`.
+pub struct ServerHeaders;
+
+impl Middleware for ServerHeaders {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let headers = server.config.servers[request.server_idx].add_headers.clone();
+        server.apply_configured_headers(token, &headers);
+        Flow::Next
+    }
+}
+
+/// The block's `upload_progress` path: `?id=<X-Upload-ID>` gets that upload's progress.
+pub struct UploadProgress;
+
+impl Middleware for UploadProgress {
+    fn before(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        if server.config.servers[request.server_idx].upload_progress.as_ref() != Some(&request.path) {
+            return Flow::Next;
+        }
+        if request.method != "GET" && request.method != "HEAD" {
+            return Flow::Error(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let id = match request.query.split('&').find_map(|pair| pair.strip_prefix("id=")) {
+            Some(id) if !id.is_empty() => id,
+            _ => {
+                return Flow::Error(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        let mut response = Response::new(StatusCode::OK, "application/json", server.upload_progress.report(id));
+        response.headers.push(("Cache-Control".to_string(), "no-store".to_string()));
+        Flow::Reply(response)
+    }
+}
+
+/// The block's `upstream_status` path: the health of every `proxy_pass` upstream.
+pub struct UpstreamStatus;
+
+impl Middleware for UpstreamStatus {
+    fn before(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        if server.config.servers[request.server_idx].upstream_status.as_ref() != Some(&request.path) {
+            return Flow::Next;
+        }
+        if request.method != "GET" && request.method != "HEAD" {
+            return Flow::Error(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let mut response = Response::new(StatusCode::OK, "application/json", server.upstream_report());
+        response.headers.push(("Cache-Control".to_string(), "no-store".to_string()));
+        Flow::Reply(response)
+    }
+}
+
+/// `PURGE <path>` drops the block's cached copies of `path` (every path under
+/// it for `<prefix>*`), for `purge_allow` addresses only.
+pub struct Purge;
+
+impl Middleware for Purge {
+    fn before(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        if request.method != "PURGE" {
+            return Flow::Next;
+        }
+        if server.config.servers[request.server_idx].purge_allow.is_empty() {
+            return Flow::Error(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        if !server.purge_allow[request.server_idx].contains(request.context.client_ip) {
+            return Flow::Error(StatusCode::FORBIDDEN);
+        }
+
+        let purged = server.cgi_cache.purge(request.server_idx, &request.path);
+        log::info!("[Cache] {} purged {} cached response(s) of {}", request.context.client_ip, purged, request.path);
+        let mut response = Response::new(StatusCode::OK, "application/json", format!("{{\"purged\":{}}}", purged));
+        response.headers.push(("Cache-Control".to_string(), "no-store".to_string()));
+        Flow::Reply(response)
+    }
+}
+
+pub struct HttpsRedirect;
+
+impl Middleware for HttpsRedirect {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if !server.config.servers[request.server_idx].https_redirect {
+            return Flow::Next;
+        }
+        let location = server.build_https_location(request.server_idx, &request.headers, &request.uri);
+        server.send_redirect_response(token, &location, StatusCode::MOVED_PERMANENTLY);
+        Flow::Done
+    }
+}
+
+/// Issues or refreshes the signed `SESSION_ID` cookie.
+pub struct Session;
+
+impl Middleware for Session {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        server.attach_session_cookie(token, &request.cookies);
+        Flow::Next
+    }
+}
+
+/// Files saved by an upload route are served back from its `upload_dir`.
+pub struct UploadedFiles;
+
+impl Middleware for UploadedFiles {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if request.method == "GET" && server.try_serve_upload_file(token, request.server_idx, &request.path) {
+            Flow::Done
+        } else {
+            Flow::Next
+        }
+    }
+}
+
+/// Picks the longest matching route and the file it maps the path to.
+pub struct RouteLookup;
+
+impl Middleware for RouteLookup {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let server_cfg = &server.config.servers[request.server_idx];
+        let mut route = match router::find_route(server_cfg, &request.path) {
+            Some(r) => r.clone(),
+            None => {
+                return Flow::Error(StatusCode::NOT_FOUND);
+            }
+        };
+
+        // Both variants share a URL, so caches must keep them apart
+        if let Some(canary) = &route.canary {
+            let vary = match &canary.trigger {
+                CanaryTrigger::Header(name) => name.clone(),
+                CanaryTrigger::Cookie(_) => "Cookie".to_string(),
+            };
+            server.apply_configured_headers(token, &[("Vary".to_string(), vary)]);
+        }
+        if let Some(canary) = router::canary(&route, &request.headers).cloned() {
+            match canary.root {
+                Some(root) => {
+                    route.root = root;
+                    route.fallback_roots.clear();
+                    route.proxy_pass.clear();
+                }
+                None => route.proxy_pass = canary.proxy_pass,
+            }
+        }
+
+        if !route.access_log && let Some(conn) = server.connections.get_mut(&token) {
+            conn.access_log = false;
+        }
+        server.apply_configured_headers(token, &route.add_headers);
+
+        let fs_path = match router::resolve(&route, &request.path) {
+            // Reads fall through to the first root that has the file; writes stay in `root`
+            Target::Disk(path) if
+                !path.exists() &&
+                !route.fallback_roots.is_empty() &&
+                matches!(request.method.as_str(), "GET" | "HEAD")
+            => {
+                router::fallbacks(&route, &request.path)
+                    .into_iter()
+                    .find(|candidate| candidate.exists())
+                    .unwrap_or(path)
+            }
+            Target::Disk(path) => path,
+            Target::Mounted { name, path } => {
+                match server.mounts.get(name) {
+                    Some(vfs) => request.vfs = Rc::clone(vfs),
+                    None => {
+                        log::error!("[Route] Root '{}' is not mounted", route.root);
+                        return Flow::Error(StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                }
+                path
+            }
+        };
+        request.fs_path = Some(fs_path);
+        request.route = Some(route);
+        Flow::Next
+    }
+}
+
+/// Routes with `redirect` answer before authentication.
+pub struct RouteRedirect;
+
+impl Middleware for RouteRedirect {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let route = match &request.route {
+            Some(route) => route,
+            None => {
+                return Flow::Next;
+            }
+        };
+        let target = match &route.redirect {
+            Some(target) => target,
+            None => {
+                return Flow::Next;
+            }
+        };
+        let location = router::expand_redirect_target(target, &route.path, &request.path, &request.query, &request.headers);
+        server.send_redirect_response(token, &location, route.redirect_code);
+        Flow::Done
+    }
+}
+
+/// Routes with `return` answer with their configured response, also before authentication.
+pub struct RouteReturn;
+
+impl Middleware for RouteReturn {
+    fn before(&self, _server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        let fixed = match request.route.as_ref().and_then(|route| route.fixed_response.as_ref()) {
+            Some(fixed) => fixed,
+            None => {
+                return Flow::Next;
+            }
+        };
+        let mut response = Response::new(fixed.code, &fixed.content_type, fixed.body.as_bytes());
+        if request.method == "HEAD" {
+            response.headers.push(("Content-Length".to_string(), fixed.body.len().to_string()));
+            response.body.clear();
+        }
+        Flow::Reply(response)
+    }
+}
+
+/// Basic, Digest or JWT authentication when the route sets `auth`.
+pub struct Auth;
+
+impl Middleware for Auth {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        let route = match &request.route {
+            Some(route) => route,
+            None => {
+                return Flow::Next;
+            }
+        };
+        let kind = match route.auth.as_deref() {
+            Some(kind) => kind,
+            None => {
+                return Flow::Next;
+            }
+        };
+        // The challenge or rejection has already been sent when this fails
+        match server.authenticate(token, kind, route, &request.method, &request.uri, &request.headers) {
+            Some(identity) => {
+                request.identity = Some(identity);
+                Flow::Next
+            }
+            None => Flow::Done,
+        }
+    }
+}
+
+/// 405 with `Allow` for methods outside the route's `methods` (an empty list allows all).
+pub struct AllowedMethods;
+
+impl Middleware for AllowedMethods {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        match &request.route {
+            Some(route) if !router::method_allowed(route, &request.method) => {
+                server.apply_configured_headers(token, &[("Allow".to_string(), route.methods.join(", "))]);
+                Flow::Error(StatusCode::METHOD_NOT_ALLOWED)
+            }
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// `Content-Encoding: gzip` / `deflate` request bodies are decoded before CSRF
+/// checks, uploads and CGI see them; the decoded size counts against the body limit.
+pub struct BodyDecoding;
+
+impl Middleware for BodyDecoding {
+    fn before(&self, server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        let codings = match request.headers.get("content-encoding") {
+            Some(value) if !request.body.is_empty() => value.to_ascii_lowercase(),
+            _ => {
+                return Flow::Next;
+            }
+        };
+        let server_cfg = &server.config.servers[request.server_idx];
+        let limit = Server::route_body_limit(&server.config, server_cfg, request.route.as_ref());
+
+        // Listed in the order they were applied, so undone last to first
+        let mut body = std::mem::take(&mut request.body);
+        for coding in codings.split(',').map(str::trim).rev() {
+            let decoded = match coding {
+                "" | "identity" => {
+                    continue;
+                }
+                "gzip" | "x-gzip" => inflate::gunzip(&body, limit),
+                "deflate" => inflate::decompress_deflate(&body, limit),
+                _ => {
+                    return Flow::Error(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                }
+            };
+            body = match decoded {
+                Ok(decoded) => decoded,
+                Err(InflateError::TooLarge) => {
+                    return Flow::Error(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                Err(InflateError::Invalid) => {
+                    return Flow::Error(StatusCode::BAD_REQUEST);
+                }
+            };
+        }
+
+        // Handlers and CGI scripts see a plain body of its real length
+        request.headers.remove("content-encoding");
+        request.headers.insert("content-length".to_string(), body.len().to_string());
+        request.body = body;
+        Flow::Next
+    }
+}
+
+/// Issues the token that `csrf: true` routes embed in forms and check on POST.
+pub struct Csrf;
+
+impl Middleware for Csrf {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if request.route.as_ref().is_some_and(|r| r.csrf) {
+            request.csrf_token = Some(server.issue_csrf_token(token, &request.cookies));
+        }
+        Flow::Next
+    }
+}
+
+/// Replays the request to the route's `mirror` upstream.
+pub struct Mirror;
+
+impl Middleware for Mirror {
+    fn before(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow {
+        if let Some(route) = &request.route && let Some(target) = &route.mirror {
+            server.start_mirror(token, target, &route.path);
+        }
+        Flow::Next
+    }
+}
+
+/// Rewrites response bodies on routes with `sub_filter`.
+pub struct ResponseFilter;
+
+impl Middleware for ResponseFilter {
+    fn after(&self, server: &mut Server, token: Token, response: &mut Vec<u8>) {
+        let conn = match server.connections.get(&token) {
+            Some(conn) if conn.request_method != "HEAD" => conn,
+            _ => {
+                return;
+            }
+        };
+        let server_cfg = match server.config.servers.get(conn.server_idx) {
+            Some(server_cfg) => server_cfg,
+            None => {
+                return;
+            }
+        };
+        let path = conn.request_uri.split('?').next().unwrap_or("");
+        if let Some(route) = router::find_route(server_cfg, path) && !route.sub_filter.is_empty() {
+            *response = filter::apply(std::mem::take(response), &route.sub_filter, &route.sub_filter_types);
+        }
+    }
+}
+
+/// One `[Access]` line per response unless the route or `access_log_exclude` opts out.
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn after(&self, server: &mut Server, token: Token, response: &mut Vec<u8>) {
+        server.log_access(token, response);
+    }
+}
+
+/// Counts 4xx answers per client and bans repeat offenders (`ban_threshold`).
+pub struct ClientErrorBan;
+
+impl Middleware for ClientErrorBan {
+    fn after(&self, server: &mut Server, token: Token, response: &mut Vec<u8>) {
+        server.track_client_error(token, response);
+    }
+}
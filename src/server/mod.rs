@@ -1,20 +1,80 @@
+mod ban;
+mod cgi_cache;
+pub mod clock;
 pub mod connection;
-use crate::config::models::Config;
+mod filter;
+pub mod forwarded;
+mod handler;
+pub mod hooks;
+pub mod live_reload;
+mod middleware;
+pub mod pipeline;
+mod proxy_protocol;
+pub mod router;
+mod sendfile;
+mod privileges;
+pub mod proxy;
+mod resolve;
+mod signals;
+pub mod trace;
+pub mod upload_progress;
+mod watch;
+use crate::auth::{ htpasswd, jwt, AuthIdentity, DigestAuth, DigestOutcome, UserFiles };
+use crate::config::models::{ BindPolicy, CgiCache, Config, ProxyProtocol, RouteConfig, ServerConfig };
+use crate::crypto::{ self, hmac };
+use crate::error::ServerError;
 use crate::handlers::cgi::spawn_cgi_process;
+use crate::handlers::websocket::spawn_websocket_process;
+use crate::http::cookie::{ SameSite, SetCookie };
+use crate::http::magic;
+use crate::http::mime::MimeTypes;
+use crate::http::status::StatusCode;
+use crate::http::websocket;
+use crate::log;
+use crate::server::ban::BanList;
+use crate::server::cgi_cache::{ CacheKey, ResponseCache, Waiter };
+use crate::server::clock::{ Clock, SystemClock };
 use crate::server::connection::{ Connection, ConnectionState };
-
-use mio::net::{ TcpListener };
-use mio::unix::{ pipe::Receiver, SourceFd };
-use mio::{ Interest, Poll, Token };
+use crate::server::forwarded::TrustedProxies;
+use crate::server::hooks::{ CloseEvent, Hooks };
+use crate::server::live_reload::LiveReload;
+use crate::server::trace::Direction;
+use crate::server::upload_progress::UploadTracker;
+use crate::server::pipeline::{ Flow, Pipeline, Request, RequestContext };
+use crate::server::proxy::{ Balancer, Mirror, Probes, Proxied, Tunnel };
+use crate::server::proxy_protocol::Preamble;
+use crate::server::resolve::Resolver;
+use crate::server::sendfile::Progress;
+use crate::server::watch::{ ConfigWatcher, TreeWatcher };
+use crate::vfs::{ DiskFs, Vfs, MOUNT_PREFIX };
+
+use mio::net::TcpListener;
+use mio::unix::{ pipe::{ Receiver, Sender }, SourceFd };
+use mio::{ Interest, Poll, Token, Waker };
 use std::collections::HashMap;
 use std::io::{ self, Read, Write };
+use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
-use std::path::Path;
-use std::time::Duration;
-use std::time::Instant;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
 use std::time::{ SystemTime, UNIX_EPOCH };
 
-const SERVER_TOKEN_MAX: usize = 100; // Assume max 100 server blocks
+// Fixed tokens; every other one (listeners, clients, CGI pipes, upstreams)
+// comes from `allocate_token` and is never reused, so kinds cannot collide
+const WAKE_TOKEN: Token = Token(0);
+const WATCH_TOKEN: Token = Token(1);
+const FIRST_DYNAMIC_TOKEN: usize = 2;
+// Editors often write a file in several steps; wait for them to settle
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+/// Larger unread bodies are not worth receiving just to close cleanly.
+const MAX_DRAIN: usize = 1024 * 1024;
+/// How much of a streamed body is read into memory at a time.
+const STREAM_CHUNK: usize = 64 * 1024;
+/// The name of the signed cookie `Session` issues.
+pub const SESSION_COOKIE: &str = "SESSION_ID";
 
 pub struct Server {
     poll: Poll,
@@ -22,12 +82,81 @@ pub struct Server {
     connections: HashMap<Token, Connection>,
     pending_cgi: HashMap<Token, PendingCgi>,
     cgi_token_to_client: HashMap<Token, Token>,
+    websockets: HashMap<Token, WebSocketSession>,
+    ws_token_to_client: HashMap<Token, Token>,
+    tunnels: HashMap<Token, Tunnel>,
+    tunnel_token_to_client: HashMap<Token, Token>,
+    mirrors: HashMap<Token, Mirror>,
+    proxies: HashMap<Token, Proxied>,
+    proxy_token_to_client: HashMap<Token, Token>,
+    balancer: Balancer,
+    probes: Probes,
+    resolver: Resolver,
     sessions: HashMap<String, Instant>,
     next_session_id: u64,
+    /// Request ids are this random prefix and a counter, so they stay unique across restarts.
+    request_id_prefix: String,
+    next_request_id: u64,
+    session_secret: Vec<u8>,
+    digest_auth: DigestAuth,
+    user_files: UserFiles,
+    ban_list: BanList,
+    trusted_proxies: TrustedProxies,
+    /// Each server block's `purge_allow`, parsed once per configuration.
+    purge_allow: Vec<TrustedProxies>,
+    mime_types: MimeTypes,
     config: Config,
+    reload: Option<ConfigReload>,
+    live_reload: Option<LiveReload>,
+    upload_progress: UploadTracker,
+    cgi_cache: ResponseCache,
+    /// `--trace-http`: how many body bytes to show; `None` when not tracing.
+    http_trace: Option<usize>,
+    shutdown: ShutdownSignal,
+    pipeline: Rc<Pipeline>,
+    hooks: Hooks,
+    /// Trees mounted with `mount`, by name.
+    mounts: HashMap<String, Rc<dyn Vfs>>,
+    disk: Rc<dyn Vfs>,
+    clock: Box<dyn Clock>,
     next_token: usize,
 }
 
+/// Stops `Server::run_until` from another thread; cheap to clone.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    triggered: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownSignal {
+    /// The event loop returns after finishing the events it is handling.
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
+
+/// `--watch` state: the inotify watch, how to load the config again, and
+/// when the next reload is due once changes stop arriving.
+struct ConfigReload {
+    watcher: ConfigWatcher,
+    loader: Box<dyn Fn() -> Result<Config, ServerError>>,
+    due: Option<Instant>,
+}
+
+/// What the bytes at the front of a connection's buffer amount to so far.
+enum Examined {
+    Incomplete,
+    Complete,
+    Reject(StatusCode),
+    Drop,
+}
+
 struct ListenerEntry {
     listener: TcpListener,
     server_idx: usize,
@@ -39,100 +168,562 @@ struct PendingCgi {
     output: Vec<u8>,
     io_token: Token,
     started_at: Instant,
+    // `sse` routes: output goes to the client as it arrives instead of after exit
+    streaming: bool,
+    // `cgi_cache` routes: where the response is kept, and who is waiting for it
+    cache: Option<(CacheKey, CgiCache)>,
+}
+
+struct WebSocketSession {
+    child: std::process::Child,
+    stdin: Option<Sender>,
+    stdout: Receiver,
+    stdin_token: Token,
+    stdout_token: Token,
+    to_process: Vec<u8>,
+    from_process: Vec<u8>,
+    message: Vec<u8>,
+    // Set while a fragmented message is being reassembled
+    message_opcode: Option<u8>,
+    max_message: usize,
+    closing: bool,
 }
 
 impl Server {
-    pub fn new(config: Config) -> Self {
+    pub fn from_config(config: Config) -> Self {
+        let poll = Poll::new().expect("Failed to create mio poll");
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE_TOKEN).expect("Failed to create mio waker"));
+
         Self {
-            poll: Poll::new().expect("Failed to create mio poll"),
+            poll,
             listeners: HashMap::new(),
             connections: HashMap::new(),
             pending_cgi: HashMap::new(),
             cgi_token_to_client: HashMap::new(),
+            websockets: HashMap::new(),
+            ws_token_to_client: HashMap::new(),
+            tunnels: HashMap::new(),
+            tunnel_token_to_client: HashMap::new(),
+            mirrors: HashMap::new(),
+            proxies: HashMap::new(),
+            proxy_token_to_client: HashMap::new(),
+            balancer: Balancer::default(),
+            probes: Probes::default(),
+            resolver: Resolver::new(Arc::clone(&waker)),
             sessions: HashMap::new(),
             next_session_id: 1,
+            request_id_prefix: crypto::to_hex(&crypto::random_bytes(4)),
+            next_request_id: 1,
+            session_secret: config.session_secret
+                .as_ref()
+                .map(|s| s.as_bytes().to_vec())
+                .unwrap_or_else(|| crate::crypto::random_bytes(32)),
+            digest_auth: DigestAuth::new(config.auth_nonce_timeout.as_secs()),
+            user_files: UserFiles::new(),
+            ban_list: BanList::new(
+                config.ban_threshold,
+                config.ban_window_seconds,
+                config.ban_duration_seconds
+            ),
+            mime_types: MimeTypes::new(&config.mime_types, config.default_type.as_deref()),
+            trusted_proxies: TrustedProxies::parse(&config.trusted_proxies).unwrap_or_else(|e| {
+                log::warning!("[Config] {}; forwarded client addresses will be ignored", e);
+                TrustedProxies::default()
+            }),
+            purge_allow: Self::purge_allow(&config),
             config,
-            next_token: SERVER_TOKEN_MAX,
+            reload: None,
+            live_reload: None,
+            upload_progress: UploadTracker::default(),
+            cgi_cache: ResponseCache::default(),
+            http_trace: None,
+            shutdown: ShutdownSignal {
+                triggered: Arc::new(AtomicBool::new(false)),
+                waker,
+            },
+            pipeline: Rc::new(Pipeline::standard()),
+            hooks: Hooks::default(),
+            mounts: HashMap::new(),
+            disk: Rc::new(DiskFs::default()),
+            clock: Box::new(SystemClock),
+            next_token: FIRST_DYNAMIC_TOKEN,
         }
     }
 
-    pub fn bind(&mut self) -> Result<(), String> {
+    /// Binds every listen address. With `bind_policy: strict` any failure is
+    /// an error; otherwise it is one only when nothing could be bound.
+    pub fn bind(&mut self) -> Result<(), ServerError> {
         let mut bound_addrs: HashMap<String, Token> = HashMap::new();
+        let mut failed: Vec<String> = Vec::new();
+        // Blocks on port 0 get whatever port the kernel picked
+        let mut picked_ports: Vec<(String, u16)> = Vec::new();
 
-        for (idx, s_cfg) in self.config.servers.iter().enumerate() {
+        let mut blocks: Vec<(String, bool, Vec<SocketAddr>)> = Vec::new();
+        for s_cfg in &self.config.servers {
             let addr_str = format!("{}:{}", s_cfg.host, s_cfg.port);
+            let addrs = s_cfg
+                .listen_addrs()
+                .map_err(|e| ServerError::Config(format!("Invalid address {}: {}", addr_str, e)))?;
+            blocks.push((addr_str, s_cfg.port == "0", addrs));
+        }
+        for (idx, (addr_str, any_port, addrs)) in blocks.into_iter().enumerate() {
             if bound_addrs.contains_key(&addr_str) {
                 continue;
             }
 
-            let addr = addr_str.parse().map_err(|e| format!("Invalid address: {}", e))?;
+            // A name with several addresses on port 0 gets the same free port on each
+            let mut picked: Option<u16> = None;
+            for mut addr in addrs {
+                if let Some(port) = picked {
+                    addr.set_port(port);
+                }
+                // Another block's host already resolved to it; that block serves it
+                if !any_port && self.listeners.values().any(|entry| entry.listener.local_addr().ok() == Some(addr)) {
+                    continue;
+                }
+                match TcpListener::bind(addr) {
+                    Ok(mut listener) => {
+                        let token = self.allocate_token();
+
+                        self.poll
+                            .registry()
+                            .register(&mut listener, token, Interest::READABLE)
+                            .map_err(ServerError::io(format!("Failed to register {}", addr)))?;
+
+                        self.listeners.insert(token, ListenerEntry {
+                            listener,
+                            server_idx: idx,
+                        });
+                        let bound = self.listeners[&token].listener.local_addr().unwrap_or(addr);
+                        if any_port && picked.is_none() {
+                            picked = Some(bound.port());
+                            picked_ports.push((addr_str.clone(), bound.port()));
+                        }
+                        bound_addrs.entry(addr_str.clone()).or_insert(token);
+                        log::info!("[Setup] Bound to http://{}", bound);
+                    }
+                    Err(e) => {
+                        log::error!("[Setup] Failed to bind {}: {}", addr, e);
+                        failed.push(format!("{} ({})", addr, e));
+                    }
+                }
+            }
+        }
+
+        for server in &mut self.config.servers {
+            let addr_str = format!("{}:{}", server.host, server.port);
+            if let Some((_, port)) = picked_ports.iter().find(|(a, _)| *a == addr_str) {
+                server.port = port.to_string();
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+        let summary = format!(
+            "bound {} of {} listen addresses; failed: {}",
+            self.listeners.len(),
+            self.listeners.len() + failed.len(),
+            failed.join(", ")
+        );
+        if self.listeners.is_empty() {
+            return Err(ServerError::Config(format!("No ports could be bound: {}", failed.join(", "))));
+        }
+        if self.config.bind_policy == BindPolicy::Strict {
+            for (_, mut entry) in self.listeners.drain() {
+                let _ = self.poll.registry().deregister(&mut entry.listener);
+            }
+            return Err(ServerError::Config(format!("Strict bind policy: {}", summary)));
+        }
+        log::warning!("[Setup] Serving anyway ({}); use bind_policy: strict to refuse", summary);
+        Ok(())
+    }
+
+    /// The bound listen addresses, in server block order; useful after binding port 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let mut entries: Vec<&ListenerEntry> = self.listeners.values().collect();
+        entries.sort_by_key(|entry| entry.server_idx);
+        entries
+            .iter()
+            .filter_map(|entry| entry.listener.local_addr().ok())
+            .collect()
+    }
+
+    pub fn drop_privileges(&self) -> Result<(), ServerError> {
+        privileges::drop_privileges(
+            self.config.user.as_deref(),
+            self.config.group.as_deref(),
+            self.config.chroot.as_deref()
+        )
+    }
+
+    /// SIGINT and SIGTERM make `run` return after closing every connection and
+    /// reaping every child, as `shutdown_signal().trigger()` would.
+    pub fn stop_on_signals(&self) -> Result<(), ServerError> {
+        signals::install(self.shutdown_signal())
+    }
 
-            match TcpListener::bind(addr) {
-                Ok(mut listener) => {
-                    let token = Token(idx);
+    /// Logs the raw requests and responses of every connection, with up to
+    /// `body_bytes` of each body (0 for heads only).
+    pub fn enable_http_trace(&mut self, body_bytes: usize) {
+        self.http_trace = Some(body_bytes);
+    }
 
-                    self.poll
-                        .registry()
-                        .register(&mut listener, token, Interest::READABLE)
-                        .map_err(|e| e.to_string())?;
+    /// Reloads the configuration through `loader` whenever `path` changes.
+    pub fn watch_config(
+        &mut self,
+        path: &Path,
+        loader: Box<dyn Fn() -> Result<Config, ServerError>>
+    ) -> Result<(), ServerError> {
+        let watcher = ConfigWatcher::new(path)?;
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&watcher.as_raw_fd()), WATCH_TOKEN, Interest::READABLE)
+            .map_err(ServerError::io("Cannot watch the configuration"))?;
+        self.reload = Some(ConfigReload { watcher, loader, due: None });
+        log::info!("[Setup] Watching {} for changes", path.display());
+        Ok(())
+    }
 
-                    self.listeners.insert(token, ListenerEntry {
-                        listener,
-                        server_idx: idx,
-                    });
-                    bound_addrs.insert(addr_str.clone(), token);
-                    println!("[Setup] Bound to http://{}", addr);
+    /// `--dev`: watches every disk route root and reloads the browser pages
+    /// served from them (HTML responses get a script that listens on
+    /// `live_reload::EVENTS_PATH`) whenever a file underneath changes.
+    pub fn enable_live_reload(&mut self) -> Result<(), ServerError> {
+        let mut roots: Vec<PathBuf> = Vec::new();
+        for route in self.config.servers.iter().flat_map(|s| &s.routes) {
+            let canary = route.canary.iter().filter_map(|canary| canary.root.as_ref());
+            for dir in std::iter::once(&route.root).chain(&route.fallback_roots).chain(canary) {
+                let root = PathBuf::from(dir);
+                if !dir.starts_with(MOUNT_PREFIX) && root.is_dir() && !roots.contains(&root) {
+                    roots.push(root);
                 }
-                Err(e) => eprintln!("[Setup] Failed to bind {}: {}", addr, e),
             }
         }
 
-        if self.listeners.is_empty() {
-            return Err("No ports could be bound".into());
+        let watcher = TreeWatcher::new(roots)?;
+        let token = self.allocate_token();
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&watcher.as_raw_fd()), token, Interest::READABLE)
+            .map_err(ServerError::io("Cannot watch the route roots"))?;
+        for root in watcher.roots() {
+            log::info!("[Dev] Reloading pages when {} changes", root.display());
         }
+        self.live_reload = Some(LiveReload { watcher, token, clients: Vec::new(), due: None });
         Ok(())
     }
 
+    /// The request pipeline, for adding middlewares or handlers before `run`.
+    pub fn pipeline_mut(&mut self) -> &mut Pipeline {
+        Rc::get_mut(&mut self.pipeline).expect("the pipeline cannot change while requests are in flight")
+    }
+
+    /// Serves routes with `root: "vfs:<name>"` from `vfs` instead of a directory.
+    pub fn mount(&mut self, name: &str, vfs: Box<dyn Vfs>) {
+        self.mounts.insert(name.to_string(), Rc::from(vfs));
+    }
+
+    /// Replaces the time source behind idle, CGI and ban timeouts and sessions.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// A token no other registered source has ever used.
+    fn allocate_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Callbacks for accepted connections, requests, responses and closes.
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    /// A handle that makes `run_until` return; every call shares the same signal.
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+
+    /// Serves until `shutdown_signal()` is triggered.
     pub fn run(&mut self) {
+        let shutdown = self.shutdown_signal();
+        self.run_until(&shutdown);
+    }
+
+    /// Serves until `shutdown` is triggered, then closes every connection.
+    pub fn run_until(&mut self, shutdown: &ShutdownSignal) {
         let mut events = mio::Events::with_capacity(1024);
 
-        println!("\n[Reactor] Mio event loop started...");
-        loop {
-            if
-                let Err(e) = self.poll.poll(
-                    &mut events,
-                    Some(std::time::Duration::from_millis(1000))
-                )
-            {
-                eprintln!("Mio poll error: {}", e);
+        self.warn_missing_registrations();
+        log::info!("\n[Reactor] Mio event loop started...");
+        self.check_upstream_health();
+        while !shutdown.is_triggered() {
+            // Wake up in time for a pending reload
+            let mut timeout = Duration::from_millis(1000);
+            let reload_due = self.reload.as_ref().and_then(|r| r.due);
+            let live_reload_due = self.live_reload.as_ref().and_then(|l| l.due);
+            for due in reload_due.into_iter().chain(live_reload_due) {
+                timeout = timeout.min(due.saturating_duration_since(Instant::now()));
+            }
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                // A signal landing mid-poll; the loop condition sees a shutdown it asked for
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    log::error!("Mio poll error: {}", e);
+                }
                 continue;
             }
 
             for event in events.iter() {
                 let token = event.token();
 
-                if self.listeners.contains_key(&token) {
+                if token == WAKE_TOKEN {
+                    continue;
+                } else if token == WATCH_TOKEN {
+                    if let Some(reload) = &mut self.reload && reload.watcher.changed() {
+                        reload.due = Some(Instant::now() + RELOAD_DEBOUNCE);
+                    }
+                } else if let Some(live) = &mut self.live_reload && live.token == token {
+                    if live.watcher.changed() {
+                        live.due = Some(Instant::now() + live_reload::DEBOUNCE);
+                    }
+                } else if self.listeners.contains_key(&token) {
                     self.accept_connection(token);
                 } else if self.cgi_token_to_client.contains_key(&token) {
                     self.handle_cgi_event(token, event);
+                } else if self.ws_token_to_client.contains_key(&token) {
+                    self.handle_websocket_process_event(token, event);
+                } else if self.tunnel_token_to_client.contains_key(&token) {
+                    self.handle_tunnel_upstream_event(token, event);
+                } else if self.proxy_token_to_client.contains_key(&token) {
+                    self.handle_proxy_upstream_event(token, event);
+                } else if self.mirrors.contains_key(&token) {
+                    self.handle_mirror_event(token, event);
+                } else if self.probes.contains(token) {
+                    self.handle_probe_event(token, event);
                 } else {
                     self.handle_client_event(token, event);
                 }
             }
+            self.check_lookups();
             self.check_cgi_progress();
             self.check_cgi_timeouts();
             self.check_timeouts();
+            self.check_upstream_health();
+            self.check_config_reload();
+            self.check_live_reload();
         }
+
+        let open: Vec<Token> = self.connections.keys().copied().collect();
+        for token in open {
+            self.close_connection(token);
+        }
+        self.stop_children();
+        log::info!("[Reactor] Stopped");
     }
 
-    fn handle_client_event(&mut self, token: Token, event: &mio::event::Event) {
-        if let Some(conn) = self.connections.get(&token) {
-            if conn.state == ConnectionState::CgiPending {
-                if event.is_read_closed() || event.is_write_closed() {
-                    self.close_connection(token);
+    /// Kills and reaps every CGI script and WebSocket handler still running.
+    fn stop_children(&mut self) {
+        let children = self.pending_cgi
+            .drain()
+            .map(|(_, pending)| pending.child)
+            .chain(self.websockets.drain().map(|(_, ws)| ws.child));
+        for mut child in children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        self.cgi_token_to_client.clear();
+        self.ws_token_to_client.clear();
+    }
+
+    /// `handler:` and `vfs:` names are only known once the embedder has registered them.
+    fn warn_missing_registrations(&self) {
+        for server in &self.config.servers {
+            for route in &server.routes {
+                if let Some(name) = &route.handler && !self.pipeline.has_handler(name) {
+                    log::warning!("[Setup] Route '{}' uses handler '{}', which is not registered", route.path, name);
+                }
+                if let Some(name) = route.root.strip_prefix(MOUNT_PREFIX) && !self.mounts.contains_key(name) {
+                    log::warning!("[Setup] Route '{}' uses root '{}', which is not mounted", route.path, route.root);
                 }
+            }
+        }
+    }
+
+    /// Tells every open event stream to reload once the changes have settled.
+    fn check_live_reload(&mut self) {
+        let live = match &mut self.live_reload {
+            Some(live) if live.due.is_some_and(|due| due <= Instant::now()) => live,
+            _ => {
+                return;
+            }
+        };
+        live.due = None;
+
+        let connections = &self.connections;
+        live.clients.retain(|client| connections.contains_key(client));
+        let clients = live.clients.clone();
+        log::info!("[Dev] Files changed; reloading {} page(s)", clients.len());
+        for client in clients {
+            self.queue_stream_bytes(client, b"data: reload\n\n");
+        }
+    }
+
+    fn check_config_reload(&mut self) {
+        let reload = match &mut self.reload {
+            Some(reload) if reload.due.is_some_and(|due| due <= Instant::now()) => reload,
+            _ => {
                 return;
             }
+        };
+        reload.due = None;
+
+        // Anything wrong leaves the running configuration untouched
+        let result = (reload.loader)().and_then(|config| {
+            let problems = crate::config::validate_reload(&config);
+            if problems.is_empty() {
+                Ok(config)
+            } else {
+                Err(ServerError::Config(problems.join("\n  ")))
+            }
+        });
+        let result = result.inspect(|config| {
+            for warning in &config.warnings {
+                log::warning!("[Reload] Warning: {}", warning);
+            }
+        });
+        match result.and_then(|config| self.apply_config(config)) {
+            Ok(()) => {
+                log::info!("[Reload] Configuration reloaded ({} server(s))", self.config.servers.len());
+                self.warn_missing_registrations();
+            }
+            Err(e) => log::warning!("[Reload] Keeping the current configuration:\n  {}", e),
+        }
+    }
+
+    /// Switches to a validated configuration. New listeners are bound before
+    /// anything changes, so a failed bind rolls back to the running setup.
+    fn apply_config(&mut self, config: Config) -> Result<(), ServerError> {
+        // Each listen address is served by its first server block by default
+        let mut wanted: Vec<(SocketAddr, usize)> = Vec::new();
+        for (idx, s_cfg) in config.servers.iter().enumerate() {
+            let addrs = s_cfg
+                .listen_addrs()
+                .map_err(|e| ServerError::Config(format!("Invalid address {}:{}: {}", s_cfg.host, s_cfg.port, e)))?;
+            for addr in addrs {
+                if !wanted.iter().any(|(a, _)| *a == addr) {
+                    wanted.push((addr, idx));
+                }
+            }
+        }
+
+        let current: Vec<(Token, SocketAddr)> = self.listeners
+            .iter()
+            .filter_map(|(&token, entry)| entry.listener.local_addr().ok().map(|addr| (token, addr)))
+            .collect();
+
+        let mut added: Vec<(Token, TcpListener, usize)> = Vec::new();
+        for (addr, idx) in &wanted {
+            if current.iter().any(|(_, a)| a == addr) {
+                continue;
+            }
+            let token = self.allocate_token();
+            // Dropping `added` on error closes whatever was bound so far
+            let listener = TcpListener::bind(*addr).map_err(ServerError::io(format!("Failed to bind {}", addr)))?;
+            added.push((token, listener, *idx));
+        }
+
+        for (token, addr) in current {
+            match wanted.iter().find(|(a, _)| *a == addr) {
+                Some((_, idx)) => {
+                    if let Some(entry) = self.listeners.get_mut(&token) {
+                        entry.server_idx = *idx;
+                    }
+                }
+                None => {
+                    if let Some(mut entry) = self.listeners.remove(&token) {
+                        let _ = self.poll.registry().deregister(&mut entry.listener);
+                        log::info!("[Reload] Stopped listening on {}", addr);
+                    }
+                }
+            }
+        }
+        for (token, mut listener, idx) in added {
+            if let Err(e) = self.poll.registry().register(&mut listener, token, Interest::READABLE) {
+                log::error!("[Reload] Failed to register listener: {}", e);
+                continue;
+            }
+            if let Ok(addr) = listener.local_addr() {
+                log::info!("[Reload] Bound to http://{}", addr);
+            }
+            self.listeners.insert(token, ListenerEntry { listener, server_idx: idx });
+        }
+
+        // Server indexes shift when blocks are added or removed; the next request re-selects by Host
+        let orphaned: Vec<Token> = self.connections
+            .iter()
+            .filter(|(_, conn)| !self.listeners.contains_key(&conn.listener))
+            .map(|(&t, _)| t)
+            .collect();
+        for t in orphaned {
+            self.close_connection(t);
+        }
+        for conn in self.connections.values_mut() {
+            if let Some(entry) = self.listeners.get(&conn.listener) {
+                conn.server_idx = entry.server_idx;
+            }
+        }
+
+        if config.user != self.config.user || config.group != self.config.group || config.chroot != self.config.chroot {
+            log::warning!("[Reload] user, group and chroot changes take effect after a restart");
+        }
+        if let Some(secret) = &config.session_secret {
+            self.session_secret = secret.as_bytes().to_vec();
+        }
+        self.digest_auth.set_nonce_lifetime(config.auth_nonce_timeout.as_secs());
+        self.ban_list.configure(config.ban_threshold, config.ban_window_seconds, config.ban_duration_seconds);
+        self.mime_types = MimeTypes::new(&config.mime_types, config.default_type.as_deref());
+        self.trusted_proxies = TrustedProxies::parse(&config.trusted_proxies).unwrap_or_default();
+        self.purge_allow = Self::purge_allow(&config);
+        log::set_level(config.log_level);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Loading the config already rejected entries that do not parse.
+    fn purge_allow(config: &Config) -> Vec<TrustedProxies> {
+        config.servers
+            .iter()
+            .map(|server| TrustedProxies::parse_as("purge_allow", &server.purge_allow).unwrap_or_default())
+            .collect()
+    }
+
+    fn handle_client_event(&mut self, token: Token, event: &mio::event::Event) {
+        if
+            let Some(conn) = self.connections.get(&token) &&
+            conn.state == ConnectionState::CgiPending
+        {
+            if event.is_read_closed() || event.is_write_closed() {
+                self.close_connection(token);
+            }
+            return;
+        }
+
+        if
+            let Some(conn) = self.connections.get(&token) &&
+            matches!(conn.state, ConnectionState::Streaming | ConnectionState::Proxy)
+        {
+            // Anything the client sends on an event stream or during a proxied answer is ignored
+            if event.is_read_closed() || event.is_write_closed() {
+                self.close_connection(token);
+            } else if event.is_writable() {
+                self.write_to_client(token);
+            }
+            return;
         }
 
         // Handle Reading
@@ -152,73 +743,71 @@ impl Server {
     }
 
     fn read_from_client(&mut self, token: Token) {
+        let now = self.now();
         let server_idx = match self.connections.get(&token) {
-            Some(c) => c.server_idx,
-            None => {
+            Some(c) if c.state == ConnectionState::WebSocket => {
+                self.read_websocket_frames(token);
                 return;
             }
-        };
-
-        let effective_body_limit = self.config.servers
-            .get(server_idx)
-            .map(|s| s.max_body_size.min(self.config.max_server_size))
-            .unwrap_or(self.config.max_server_size);
-
-        let conn = match self.connections.get_mut(&token) {
-            Some(c) => c,
+            Some(c) if c.state == ConnectionState::Tunnel => {
+                self.read_tunnel_client(token);
+                return;
+            }
+            Some(c) if c.state == ConnectionState::Draining => {
+                self.drain_client(token);
+                return;
+            }
+            Some(c) => c.server_idx,
             None => {
                 return;
             }
         };
 
         let mut buf = [0u8; 4096];
-        let mut oversized = false;
-        let mut should_process = false;
-
         loop {
+            let conn = match self.connections.get_mut(&token) {
+                Some(c) => c,
+                None => {
+                    return;
+                }
+            };
+            // Pipelined requests wait behind the one being answered, within the head limit
+            if conn.request_complete && conn.read_buffer.len() - conn.request_len > self.config.max_server_size {
+                return;
+            }
+
             match conn.stream.read(&mut buf) {
                 Ok(0) => {
                     self.close_connection(token);
                     return;
                 }
                 Ok(n) => {
-                    conn.read_buffer.extend_from_slice(&buf[..n]);
-                    conn.last_activity = std::time::Instant::now();
-
-                    if conn.read_buffer.len() > self.config.max_server_size {
-                        oversized = true;
-                        break;
+                    if conn.request_started.is_none() {
+                        conn.request_started = Some(now);
                     }
-
-                    if let Some(header_end) = Self::find_header_end(&conn.read_buffer) {
-                        if
-                            let Some(content_length) = Self::extract_content_length(
-                                &conn.read_buffer[..header_end]
-                            )
-                        {
-                            if content_length > effective_body_limit {
-                                oversized = true;
-                                break;
-                            }
-                        }
-
-                        let current_body_len = conn.read_buffer.len().saturating_sub(header_end);
-                        if current_body_len > effective_body_limit {
-                            oversized = true;
-                            break;
+                    conn.read_buffer.extend_from_slice(&buf[..n]);
+                    conn.last_activity = now;
+                    if let Some(body_limit) = self.http_trace {
+                        let len = conn.read_buffer.len();
+                        for line in trace::describe(token.0, Direction::Received, &conn.read_buffer, len - n..len, body_limit) {
+                            println!("{}", line);
                         }
                     }
+                    if conn.request_complete {
+                        continue;
+                    }
 
-                    // Use our refactored helper to check if the full request (Header + Body) is here
-                    if crate::http::request::HttpRequest::is_complete(&conn.read_buffer) {
-                        conn.request_complete = true;
-                        should_process = true;
-                        break; // Exit the read loop to start processing
+                    match self.examine_request(token, server_idx) {
+                        Examined::Incomplete => {}
+                        outcome => {
+                            self.act_on_request(token, outcome);
+                            return;
+                        }
                     }
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     // OS buffer is empty for now, wait for the next EPOLLIN notification
-                    break;
+                    return;
                 }
                 Err(_) => {
                     // Real socket error
@@ -227,258 +816,461 @@ impl Server {
                 }
             }
         }
-
-        if oversized {
-            self.send_error(token, 413);
-            return;
-        }
-
-        if should_process {
-            self.process_request(token);
-        }
     }
 
-    fn write_to_client(&mut self, token: Token) {
+    /// Looks at what has arrived of the request at the front of the buffer:
+    /// limits, the PROXY preamble, the request line and whether it is whole.
+    fn examine_request(&mut self, token: Token, server_idx: usize) -> Examined {
         let conn = match self.connections.get_mut(&token) {
             Some(c) => c,
             None => {
-                return;
+                return Examined::Incomplete;
             }
         };
+        if conn.read_buffer.is_empty() {
+            return Examined::Incomplete;
+        }
 
-        while conn.bytes_written < conn.write_buffer.len() {
-            let to_write = &conn.write_buffer[conn.bytes_written..];
+        // Past the head, the (possibly larger) per-route limit applies instead
+        if conn.read_buffer.len() > self.config.max_server_size && Self::find_header_end(&conn.read_buffer).is_none() {
+            return Examined::Reject(StatusCode::PAYLOAD_TOO_LARGE);
+        }
 
-            match conn.stream.write(to_write) {
-                Ok(n) => {
-                    conn.bytes_written += n;
-                    conn.last_activity = std::time::Instant::now();
+        if conn.proxy_protocol != ProxyProtocol::Off {
+            match proxy_protocol::parse_preamble(&conn.read_buffer) {
+                Preamble::Incomplete => {
+                    return Examined::Incomplete;
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    return;
+                Preamble::Parsed { len, source } => {
+                    // Only a trusted balancer may say who the client is; anyone
+                    // else could pose as an allowed or unbanned address
+                    let trusted = self.trusted_proxies.contains(conn.peer.ip());
+                    if !trusted && conn.proxy_protocol == ProxyProtocol::Required {
+                        log::warning!("[Network] Dropping PROXY preamble from untrusted peer {}", conn.peer.ip());
+                        return Examined::Drop;
+                    }
+                    conn.read_buffer.drain(..len);
+                    if trusted {
+                        conn.client_ip = source.or(conn.client_ip);
+                    }
                 }
-                Err(_) => {
-                    self.close_connection(token);
-                    return;
+                Preamble::Absent if conn.proxy_protocol == ProxyProtocol::Optional => {}
+                Preamble::Absent | Preamble::Invalid => {
+                    // Not worth an HTTP answer: the peer is not speaking our protocol
+                    return Examined::Drop;
                 }
             }
+            conn.proxy_protocol = ProxyProtocol::Off;
+            if conn.read_buffer.is_empty() {
+                return Examined::Incomplete;
+            }
         }
 
-        println!("[Network] Response sent to Token {:?}", token);
+        if let Err(code) = crate::http::request::HttpRequest::validate_request_line(&conn.read_buffer) {
+            return Examined::Reject(code);
+        }
 
-        self.close_connection(token);
-    }
+        if let Some(header_end) = Self::find_header_end(&conn.read_buffer) {
+            let head = &conn.read_buffer[..header_end];
+            if conn.upload_id.is_none() && let Some(id) = Self::upload_id(&self.config, server_idx, head) {
+                self.upload_progress.start(&id, Self::extract_content_length(head));
+                conn.upload_id = Some(id);
+            }
+            if let Some(id) = &conn.upload_id {
+                self.upload_progress.update(id, conn.read_buffer.len() - header_end);
+            }
 
-    fn process_request(&mut self, token: Token) {
-        // --- 1. DATA EXTRACTION ---
-        let (method, uri, headers, body, mut server_idx) = {
-            let conn = match self.connections.get(&token) {
-                Some(c) => c,
-                None => {
-                    return;
+            let effective_body_limit = Self::body_limit(&self.config, server_idx, &conn.read_buffer[..header_end]);
+            if
+                let Some(content_length) = Self::extract_content_length(&conn.read_buffer[..header_end]) &&
+                content_length > effective_body_limit
+            {
+                // The end of the body is known: read it after answering if it is small enough
+                let unread = content_length.saturating_sub(conn.read_buffer.len() - header_end);
+                if unread <= MAX_DRAIN {
+                    conn.drain_remaining = unread;
                 }
-            };
-            let idx = conn.server_idx;
-            match crate::http::request::HttpRequest::parse(&conn.read_buffer) {
-                Some(req) => (req.method, req.uri, req.headers, req.body, idx),
-                None =>
-                    (
-                        "".to_string(),
-                        "".to_string(),
-                        std::collections::HashMap::new(),
-                        Vec::new(),
-                        999,
-                    ),
+                return Examined::Reject(StatusCode::PAYLOAD_TOO_LARGE);
             }
-        };
 
-        if server_idx == 999 {
-            self.send_error(token, 400);
-            return;
-        }
-
-        server_idx = self.select_server_for_request(server_idx, &headers);
-        if let Some(conn) = self.connections.get_mut(&token) {
-            conn.server_idx = server_idx;
+            // A chunked body has no known end, so this one is closed right away
+            let current_body_len = conn.read_buffer.len().saturating_sub(header_end);
+            if current_body_len > effective_body_limit {
+                return Examined::Reject(StatusCode::PAYLOAD_TOO_LARGE);
+            }
         }
-        self.attach_session_cookie(token, &headers);
-
-        let (path_only, query_string) = match uri.split_once('?') {
-            Some((p, q)) => (p.to_string(), q.to_string()),
-            None => (uri.clone(), String::new()),
-        };
 
-        if method == "GET" {
-            if self.try_serve_upload_file(token, server_idx, &path_only) {
-                return;
+        match crate::http::request::HttpRequest::complete_len(&conn.read_buffer) {
+            Some(len) => {
+                conn.request_complete = true;
+                conn.request_len = len;
+                Examined::Complete
             }
+            None => Examined::Incomplete,
         }
+    }
 
-        // --- 2. CONFIG & ROUTE LOOKUP ---
-        let server_cfg = &self.config.servers[server_idx];
-        let route = match
-            server_cfg.routes
-                .iter()
-                .filter(|r| Self::path_matches_route(&path_only, &r.path))
-                .max_by_key(|r| r.path.len())
-        {
-            Some(r) => r.clone(),
-            None => {
-                self.send_error(token, 404);
+    /// Reads whatever else the client has sent so far, up to the pipelining
+    /// limit: a request that came in a later read than the one being answered,
+    /// or while a CGI script ran, is then seen before the connection's fate is
+    /// decided.
+    fn read_queued(&mut self, token: Token) {
+        let now = self.now();
+        let conn = match self.connections.get_mut(&token) {
+            // A tunnel or WebSocket that failed to open has taken over what the client sent
+            Some(c) if
+                c.request_complete &&
+                !c.close_requested &&
+                !matches!(c.state, ConnectionState::Tunnel | ConnectionState::WebSocket)
+            => c,
+            _ => {
                 return;
             }
         };
 
-        if let Some(target) = &route.redirect {
-            self.send_redirect_response(token, target, 301);
-            return;
-        }
-
-        // --- 3. METHOD VALIDATION ---
-        if !route.methods.contains(&method) && !route.methods.is_empty() {
-            self.send_error(token, 405);
-            return;
-        }
-
-        // --- 4. CONVENTION-BASED UPLOAD LOGIC ---
-        // Rule: If it's POST/PUT and NOT a CGI script, treat it as an upload
-        let is_cgi = route.cgi_extension.as_ref().map_or(false, |ext| path_only.ends_with(ext));
-
-        if method == "POST" && !is_cgi {
-            let upload_path = route.upload_dir
-                .as_ref()
-                .map(std::path::PathBuf::from)
-                .unwrap_or_else(|| {
-                    let mut fallback = std::path::PathBuf::from(&route.root);
-                    fallback.push("uploads");
-                    fallback
-                });
-
-            let mut upload_performed = false;
-
-            if let Some(form) = crate::http::request::HttpRequest::parse_multipart(&headers, &body) {
-                if self.handle_multipart_upload(form, &upload_path).is_ok() {
-                    upload_performed = true;
+        let mut buf = [0u8; 4096];
+        while conn.read_buffer.len() - conn.request_len <= self.config.max_server_size {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    // The client hung up: this answer is the last
+                    conn.close_requested = true;
+                    return;
+                }
+                Ok(n) => {
+                    conn.read_buffer.extend_from_slice(&buf[..n]);
+                    conn.last_activity = now;
+                    if let Some(body_limit) = self.http_trace {
+                        let len = conn.read_buffer.len();
+                        for line in trace::describe(token.0, Direction::Received, &conn.read_buffer, len - n..len, body_limit) {
+                            println!("{}", line);
+                        }
+                    }
                 }
-            } else if !body.is_empty() {
-                let filename = Self::extract_raw_upload_filename(&path_only, &route.path, &headers);
-                if self.handle_raw_upload(&body, &upload_path, &filename).is_ok() {
-                    upload_performed = true;
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                // Nothing more for now; a real error shows up again when writing
+                Err(_) => {
+                    return;
                 }
             }
+        }
+    }
 
-            if upload_performed {
-                self.send_text_response(token, 201, "Upload Successful", "text/plain");
+    fn act_on_request(&mut self, token: Token, outcome: Examined) {
+        match outcome {
+            Examined::Incomplete => {}
+            Examined::Complete => self.process_request(token),
+            Examined::Reject(code) => self.send_error(token, code),
+            Examined::Drop => self.close_connection(token),
+        }
+    }
+
+    /// The response to a pipelined request is out: the next one queued behind
+    /// it starts over as if it had arrived on a fresh connection.
+    fn start_next_request(&mut self, token: Token) {
+        let now = self.now();
+        let request_id = format!("{}-{:06}", self.request_id_prefix, self.next_request_id);
+        self.next_request_id += 1;
+        let conn = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => {
                 return;
             }
+        };
+        // Requests pick their block by Host again, starting from the listener's
+        let server_idx = self.listeners.get(&conn.listener).map_or(conn.server_idx, |entry| entry.server_idx);
+
+        conn.read_buffer.drain(..conn.request_len);
+        conn.request_len = 0;
+        conn.request_complete = false;
+        conn.keep_alive = false;
+        conn.close_requested = false;
+        conn.write_buffer.clear();
+        conn.bytes_written = 0;
+        conn.response_headers.clear();
+        conn.request_started = Some(now);
+        conn.request_id = request_id;
+        conn.server_idx = server_idx;
+        conn.request_method.clear();
+        conn.request_uri.clear();
+        conn.accept_language.clear();
+        conn.upload_id = None;
+        conn.access_log = true;
+        conn.state = ConnectionState::ReadRequest;
+        conn.last_activity = now;
+        if let Err(e) = self.poll.registry().reregister(&mut conn.stream, token, Interest::READABLE) {
+            log::error!("[Mio] Failed to reregister token {:?}: {}", token, e);
+            self.close_connection(token);
+            return;
         }
 
-        // --- 5. PATH RESOLUTION ---
-        let relative_path = path_only.strip_prefix(&route.path).unwrap_or("");
-        let mut full_path = std::path::PathBuf::from(&route.root);
-        full_path.push(relative_path.trim_start_matches('/'));
+        match self.examine_request(token, server_idx) {
+            Examined::Incomplete => self.read_from_client(token),
+            outcome => self.act_on_request(token, outcome),
+        }
+    }
 
-        if method == "DELETE" {
-            let delete_target = if let Some(upload_dir) = &route.upload_dir {
-                let rel = relative_path.trim_start_matches('/');
-                if rel.is_empty() {
-                    full_path.clone()
-                } else {
-                    let mut path = std::path::PathBuf::from(upload_dir);
-                    path.push(rel);
-                    path
+    fn write_to_client(&mut self, token: Token) {
+        let now = self.now();
+        let conn = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => {
+                return;
+            }
+        };
+
+        loop {
+            while conn.bytes_written < conn.write_buffer.len() {
+                let to_write = &conn.write_buffer[conn.bytes_written..];
+
+                match conn.stream.write(to_write) {
+                    Ok(n) => {
+                        conn.bytes_written += n;
+                        conn.last_activity = now;
+                    }
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        return;
+                    }
+                    Err(_) => {
+                        self.close_connection(token);
+                        return;
+                    }
+                }
+            }
+
+            if let Some(body) = conn.body_file.as_mut() {
+                match body.send(&conn.stream) {
+                    Progress::Done => conn.body_file = None,
+                    Progress::WouldBlock => {
+                        conn.last_activity = now;
+                        return;
+                    }
+                    Progress::Unsupported => {
+                        let body = conn.body_file.take().expect("checked above");
+                        match body.into_reader() {
+                            Ok(reader) => conn.body_stream = Some(reader),
+                            Err(e) => {
+                                log::error!("[Network] Reading the body for Token {:?} failed: {}", token, e);
+                                self.close_connection(token);
+                                return;
+                            }
+                        }
+                    }
+                    Progress::Failed(e) => {
+                        log::debug!("[Network] sendfile to Token {:?} failed: {}", token, e);
+                        self.close_connection(token);
+                        return;
+                    }
+                }
+                conn.last_activity = now;
+                continue;
+            }
+
+            // A streamed body takes the buffer's place one chunk at a time
+            let source = match conn.body_stream.as_mut() {
+                Some(source) => source,
+                None => {
+                    break;
                 }
-            } else {
-                full_path.clone()
             };
+            conn.write_buffer.resize(STREAM_CHUNK, 0);
+            conn.bytes_written = 0;
+            match source.read(&mut conn.write_buffer) {
+                Ok(0) => {
+                    conn.write_buffer.clear();
+                    conn.body_stream = None;
+                }
+                Ok(n) => conn.write_buffer.truncate(n),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => conn.write_buffer.clear(),
+                Err(e) => {
+                    log::error!("[Network] Reading the body for Token {:?} failed: {}", token, e);
+                    self.close_connection(token);
+                    return;
+                }
+            }
+        }
 
-            if !delete_target.exists() {
-                self.send_error(token, 404);
-            } else if delete_target.is_dir() {
-                self.send_error(token, 403);
+        if
+            matches!(
+                conn.state,
+                ConnectionState::WebSocket | ConnectionState::Streaming | ConnectionState::Tunnel | ConnectionState::Proxy
+            )
+        {
+            conn.write_buffer.clear();
+            conn.bytes_written = 0;
+            let closing = match conn.state {
+                ConnectionState::WebSocket => self.websockets.get(&token).is_none_or(|ws| ws.closing),
+                ConnectionState::Tunnel => self.tunnels.get(&token).is_none_or(|t| t.closing),
+                ConnectionState::Proxy => !self.proxies.contains_key(&token),
+                _ =>
+                    !self.pending_cgi.contains_key(&token) &&
+                        !self.live_reload.as_ref().is_some_and(|live| live.clients.contains(&token)),
+            };
+            if closing {
+                self.close_connection(token);
+            } else if
+                let Err(e) = self.poll
+                    .registry()
+                    .reregister(&mut conn.stream, token, Interest::READABLE)
+            {
+                log::error!("[Mio] Failed to reregister token {:?}: {}", token, e);
+                self.close_connection(token);
             } else {
-                match std::fs::remove_file(&delete_target) {
-                    Ok(_) => self.send_text_response(token, 200, "Deleted", "text/plain"),
-                    Err(_) => self.send_error(token, 500),
-                }
+                // The client caught up, so a relay that stopped reading its upstream carries on
+                self.resume_upstream(token);
             }
             return;
         }
 
-        // --- 6. DIRECTORY HANDLING ---
-        if full_path.is_dir() {
-            if let Some(index_file) = &route.index {
-                full_path.push(index_file);
-            } else if route.autoindex {
-                let listing = self.build_autoindex_listing(&path_only, &full_path);
-                self.send_text_response(token, 200, &listing, "text/html");
-                return;
-            } else {
-                self.send_error(token, 403);
-                return;
+        log::debug!("[Network] Response sent to Token {:?}", token);
+
+        if conn.keep_alive {
+            self.start_next_request(token);
+            return;
+        }
+
+        // Requests that reached the pipeline were read whole; only early errors leave a body behind
+        if conn.drain_remaining > 0 {
+            conn.state = ConnectionState::Draining;
+            let _ = conn.stream.shutdown(std::net::Shutdown::Write);
+            if
+                let Err(e) = self.poll
+                    .registry()
+                    .reregister(&mut conn.stream, token, Interest::READABLE)
+            {
+                log::error!("[Mio] Failed to reregister token {:?}: {}", token, e);
+                self.close_connection(token);
             }
+            return;
         }
 
-        // --- 7. CGI EXECUTION ---
-        if is_cgi {
-            if !full_path.exists() {
-                self.send_error(token, 404);
+        self.close_connection(token);
+    }
+
+    /// Discards what is left of a rejected body, closing once it has all
+    /// arrived, the client hangs up, or the idle timeout fires.
+    fn drain_client(&mut self, token: Token) {
+        let now = self.now();
+        let conn = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => {
                 return;
             }
+        };
 
-            let script_path = std::fs::canonicalize(&full_path).unwrap_or(full_path.clone());
-            let script_path_str = script_path.to_string_lossy().to_string();
-
-            let mut env_vars = std::collections::HashMap::new();
-            env_vars.insert("REQUEST_METHOD".to_string(), method.clone());
-            env_vars.insert("SCRIPT_FILENAME".to_string(), script_path_str.clone());
-            env_vars.insert("PATH_INFO".to_string(), script_path_str.clone());
-            env_vars.insert("QUERY_STRING".to_string(), query_string);
-            env_vars.insert("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string());
-            env_vars.insert("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string());
+        let mut buf = [0u8; 8192];
+        loop {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    break;
+                }
+                Ok(n) => {
+                    conn.last_activity = now;
+                    conn.drain_remaining = conn.drain_remaining.saturating_sub(n);
+                    if conn.drain_remaining == 0 {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return;
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(_) => {
+                    break;
+                }
+            }
+        }
+        self.close_connection(token);
+    }
 
-            // Pass headers to CGI
-            if let Some(ct) = headers.get("content-type") {
-                env_vars.insert("CONTENT_TYPE".to_string(), ct.clone());
+    fn process_request(&mut self, token: Token) {
+        let (parsed, default_idx) = match self.connections.get(&token) {
+            Some(conn) => (crate::http::request::HttpRequest::parse(&conn.read_buffer), conn.server_idx),
+            None => {
+                return;
             }
-            if let Some(cl) = headers.get("content-length") {
-                env_vars.insert("CONTENT_LENGTH".to_string(), cl.clone());
-            } else {
-                env_vars.insert("CONTENT_LENGTH".to_string(), body.len().to_string());
+        };
+        let parsed = match parsed {
+            Some(req) => req,
+            None => {
+                self.send_error(token, StatusCode::BAD_REQUEST);
+                return;
             }
+        };
 
-            if
-                let Err(e) = self.start_cgi_process(
-                    token,
-                    &script_path_str,
-                    route.cgi_interpreter.as_deref(),
-                    &body,
-                    env_vars
-                )
-            {
-                eprintln!("[CGI Error] {}", e);
-                self.send_error(token, 500);
-            }
-            return;
+        let server_idx = self.select_server_for_request(default_idx, &parsed.headers);
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.server_idx = server_idx;
+            conn.request_method = parsed.method.clone();
+            conn.request_uri = parsed.uri.clone();
+            conn.accept_language = parsed.headers.get("accept-language").cloned().unwrap_or_default();
+            conn.close_requested = parsed.headers
+                .get("connection")
+                .is_some_and(|value| value.split(',').any(|option| option.trim().eq_ignore_ascii_case("close")));
         }
 
-        // --- 8. STATIC FILE SERVING ---
-        match std::fs::read(&full_path) {
-            Ok(content) => {
-                let mime = Self::get_mime_type(full_path.to_str().unwrap_or(""));
-                self.send_bytes_response(token, 200, content, mime);
+        let (path, query) = match parsed.uri.split_once('?') {
+            Some((p, q)) => (p.to_string(), q.to_string()),
+            None => (parsed.uri.clone(), String::new()),
+        };
+        let context = match self.connections.get(&token) {
+            Some(conn) =>
+                RequestContext {
+                    request_id: conn.request_id.clone(),
+                    peer: conn.peer,
+                    client_ip: conn.client_ip.unwrap_or(conn.peer.ip()),
+                    started: conn.request_started.unwrap_or(self.now()),
+                },
+            None => {
+                return;
+            }
+        };
+        let mut request = Request {
+            method: parsed.method,
+            uri: parsed.uri,
+            path,
+            query,
+            headers: parsed.headers,
+            cookies: parsed.cookies,
+            body: parsed.body,
+            server_idx,
+            context,
+            route: None,
+            vfs: Rc::clone(&self.disk),
+            fs_path: None,
+            csrf_token: None,
+            identity: None,
+        };
+
+        let pipeline = Rc::clone(&self.pipeline);
+        pipeline.run(self, token, &mut request);
+    }
+
+    /// Sends what a pipeline stage decided.
+    fn finish(&mut self, token: Token, flow: Flow) {
+        match flow {
+            Flow::Next | Flow::Done => {}
+            Flow::Error(code) => self.send_error(token, code),
+            Flow::Reply(response) => {
+                self.apply_configured_headers(token, &response.headers);
+                if let Some(conn) = self.connections.get_mut(&token) {
+                    conn.body_stream = response.stream;
+                    conn.body_file = response.file;
+                }
+                self.send_bytes_response(token, response.status, response.body, &response.content_type);
             }
-            Err(_) => self.send_error(token, 404),
         }
     }
-    fn send_error(&mut self, token: Token, code: u16) {
-        let status_text = Self::reason_phrase(code);
+
+    fn send_error(&mut self, token: Token, code: StatusCode) {
+        let status_text = code.reason();
 
         // 1. Determine which server config we are using
-        let server_idx = self.connections
+        let (server_idx, accept_language) = self.connections
             .get(&token)
-            .map(|c| c.server_idx)
-            .unwrap_or(0);
+            .map(|c| (c.server_idx, c.accept_language.clone()))
+            .unwrap_or_default();
         let server_cfg = &self.config.servers[server_idx];
 
         // 2. Default Fallback Body
@@ -492,8 +1284,24 @@ impl Server {
             status_text
         );
 
-        // 3. Try to find the custom error page from YAML
-        if let Some(custom_path) = server_cfg.error_pages.get(&code) {
+        // 3. Try to find the custom error page from YAML, in the client's language if there is one
+        let localized = server_cfg.localized_error_pages.get(&code);
+        let language = localized.and_then(|languages| {
+            let tags: Vec<&str> = languages
+                .iter()
+                .map(|(tag, _)| tag.as_str())
+                .collect();
+            crate::http::request::preferred_language(&accept_language, &tags).map(|idx| &languages[idx])
+        });
+        let page = language
+            .map(|(_, path)| path)
+            .or(server_cfg.error_pages.get(&code))
+            .or(server_cfg.default_error_page.as_ref());
+        if let Some(custom_path) = page {
+            let (request_path, request_id) = match self.connections.get(&token) {
+                Some(conn) => (conn.request_uri.split('?').next().unwrap_or("").to_string(), conn.request_id.clone()),
+                None => (String::new(), String::new()),
+            };
             let candidate_paths = [
                 custom_path.clone(),
                 custom_path.trim_start_matches('/').to_string(),
@@ -505,7 +1313,7 @@ impl Server {
             for candidate in candidate_paths {
                 match std::fs::read_to_string(&candidate) {
                     Ok(content) => {
-                        body = content;
+                        body = Self::render_error_page(&content, code, &request_path, &request_id);
                         loaded = true;
                         break;
                     }
@@ -515,24 +1323,55 @@ impl Server {
                 }
             }
 
-            if !loaded {
-                if let Some(e) = last_error {
-                    eprintln!(
-                        "[Config] Custom error page {} defined but could not be read: {}",
-                        custom_path,
-                        e
-                    );
-                }
+            if !loaded && let Some(e) = last_error {
+                log::warning!(
+                    "[Config] Custom error page {} defined but could not be read: {}",
+                    custom_path,
+                    e
+                );
             }
         }
 
+        if localized.is_some() {
+            let mut headers = vec![("Vary".to_string(), "Accept-Language".to_string())];
+            if let Some((tag, _)) = language {
+                headers.push(("Content-Language".to_string(), tag.clone()));
+            }
+            self.apply_configured_headers(token, &headers);
+        }
         self.send_text_response(token, code, &body, "text/html");
     }
 
+    /// Fills in `{{code}}`, `{{reason}}`, `{{request_path}}` and `{{request_id}}`,
+    /// so one file can serve every status.
+    fn render_error_page(template: &str, code: StatusCode, request_path: &str, request_id: &str) -> String {
+        template
+            .replace("{{code}}", &code.to_string())
+            .replace("{{reason}}", code.reason())
+            .replace("{{request_path}}", &Self::escape_html(request_path))
+            .replace("{{request_id}}", request_id)
+    }
+
+    /// The request path comes from the client; keep it from injecting markup.
+    fn escape_html(text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&#39;"),
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
     fn send_text_response(
         &mut self,
         token: Token,
-        status_code: u16,
+        status_code: StatusCode,
         body: &str,
         content_type: &str
     ) {
@@ -542,7 +1381,7 @@ impl Server {
     fn send_bytes_response(
         &mut self,
         token: Token,
-        status_code: u16,
+        status_code: StatusCode,
         body: Vec<u8>,
         content_type: &str
     ) {
@@ -550,21 +1389,52 @@ impl Server {
         if let Some(conn) = self.connections.get_mut(&token) {
             headers.append(&mut conn.response_headers);
         }
-        let response = Self::build_http_response(
-            status_code,
-            Self::reason_phrase(status_code),
-            headers,
-            &body,
-            true
-        );
+        let server_header = self.config.server_header.as_deref();
+        let response = Self::build_http_response(status_code, status_code.reason(), headers, &body, true, server_header);
         self.finalize_response(token, response);
     }
 
-    fn finalize_response(&mut self, token: Token, response_bytes: Vec<u8>) {
+    fn finalize_response(&mut self, token: Token, mut response_bytes: Vec<u8>) {
+        let now = self.now();
+        if let Some(conn) = self.connections.get(&token) {
+            let added = self.hooks.response(
+                Self::response_status(&response_bytes),
+                &conn.request_method,
+                &conn.request_uri,
+                conn.client_ip.unwrap_or(conn.peer.ip()),
+                conn.request_started.map(|started| self.now().saturating_duration_since(started)).unwrap_or_default(),
+                Self::response_size(conn, &response_bytes)
+            );
+            response_bytes = Self::insert_headers(response_bytes, added);
+        }
+
+        let pipeline = Rc::clone(&self.pipeline);
+        pipeline.after(self, token, &mut response_bytes);
+
+        // Another request is queued behind this one: answer it on the same connection
+        if let Some(kept) = Self::keep_alive(&response_bytes) {
+            self.read_queued(token);
+            if let Some(conn) = self.connections.get_mut(&token) && conn.pipelined() {
+                response_bytes = kept;
+                conn.keep_alive = true;
+            }
+        }
+
+        if let Some(body_limit) = self.http_trace {
+            let len = response_bytes.len();
+            for line in trace::describe(token.0, Direction::Sent, &response_bytes, 0..len, body_limit) {
+                println!("{}", line);
+            }
+        }
+
         if let Some(conn) = self.connections.get_mut(&token) {
+            if let Some(id) = &conn.upload_id {
+                let ok = Self::response_status(&response_bytes).is_some_and(|s| s.is_success() || s.is_redirection());
+                self.upload_progress.finish(id, ok, now);
+            }
             conn.write_buffer = response_bytes;
             conn.state = ConnectionState::WriteResponse;
-            conn.last_activity = std::time::Instant::now();
+            conn.last_activity = now;
 
             // Switch MIO from waiting for READ to waiting for WRITE
             if
@@ -572,38 +1442,167 @@ impl Server {
                     .registry()
                     .reregister(&mut conn.stream, token, mio::Interest::WRITABLE)
             {
-                eprintln!("[Mio] Failed to reregister token {:?}: {}", token, e);
+                log::error!("[Mio] Failed to reregister token {:?}: {}", token, e);
                 self.close_connection(token);
             }
         }
     }
 
+    fn log_access(&self, token: Token, response_bytes: &[u8]) {
+        let conn = match self.connections.get(&token) {
+            Some(c) => c,
+            None => {
+                return;
+            }
+        };
+
+        if !conn.access_log || self.is_access_log_excluded(&conn.request_uri) {
+            return;
+        }
+
+        let status = Self::response_status(response_bytes)
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let peer = match conn.client_ip {
+            Some(ip) => ip.to_string(),
+            None => conn.peer.to_string(),
+        };
+        let request_line = if conn.request_method.is_empty() {
+            "-".to_string()
+        } else {
+            format!("{} {}", conn.request_method, conn.request_uri)
+        };
+
+        // Time from the first request byte until the response is queued
+        let latency = conn.request_started
+            .map(|started| format!("{:.1}ms", self.now().saturating_duration_since(started).as_secs_f64() * 1000.0))
+            .unwrap_or_else(|| "-".to_string());
+
+        log::info!("[Access] {} \"{}\" {} {} {}", peer, request_line, status, Self::response_size(conn, response_bytes), latency);
+    }
+
+    fn track_client_error(&mut self, token: Token, response_bytes: &[u8]) {
+        if !Self::response_status(response_bytes).is_some_and(|s| s.is_client_error()) {
+            return;
+        }
+
+        let ip = match self.connections.get(&token) {
+            Some(conn) => conn.client_ip.unwrap_or(conn.peer.ip()),
+            None => {
+                return;
+            }
+        };
+
+        if self.ban_list.record_error(ip, self.now()) {
+            log::warning!(
+                "[Ban] {} banned for {}s after repeated client errors",
+                ip,
+                self.config.ban_duration_seconds.as_secs()
+            );
+        }
+    }
+
+    /// Records the real client behind a PROXY protocol balancer and/or trusted proxies.
+    /// Returns true if that client is banned and the request was already rejected.
+    fn resolve_client_ip(
+        &mut self,
+        token: Token,
+        headers: &std::collections::HashMap<String, String>
+    ) -> bool {
+        let (tcp_peer, peer) = match self.connections.get(&token) {
+            Some(conn) => (conn.peer.ip(), conn.client_ip.unwrap_or(conn.peer.ip())),
+            None => {
+                return false;
+            }
+        };
+
+        let client = self.trusted_proxies.client_ip(peer, headers);
+        if client == tcp_peer {
+            return false;
+        }
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.client_ip = Some(client);
+        }
+
+        // Bans are normally enforced at accept, which only sees the proxy's address
+        if self.ban_list.is_banned(client, self.now()) {
+            self.send_error(token, StatusCode::FORBIDDEN);
+            return true;
+        }
+        false
+    }
+
+    /// Bytes the response puts on the wire, a streamed body included.
+    fn response_size(conn: &Connection, response_bytes: &[u8]) -> usize {
+        if conn.body_stream.is_none() && conn.body_file.is_none() {
+            return response_bytes.len();
+        }
+        let head_end = response_bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap_or(response_bytes.len());
+        let streamed = String::from_utf8_lossy(&response_bytes[..head_end])
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        response_bytes.len() + streamed
+    }
+
+    fn response_status(response_bytes: &[u8]) -> Option<StatusCode> {
+        response_bytes
+            .split(|&b| b == b' ')
+            .nth(1)
+            .and_then(|s| std::str::from_utf8(s).ok())
+            .and_then(|s| s.parse().ok())
+            .and_then(StatusCode::from_u16)
+    }
+
+    fn is_access_log_excluded(&self, uri: &str) -> bool {
+        let path = uri.split('?').next().unwrap_or(uri);
+        self.config.access_log_exclude.iter().any(|pattern| router::path_matches_pattern(path, pattern))
+    }
+
     fn check_timeouts(&mut self) {
-        let now = Instant::now();
-        let timeout = std::time::Duration::from_secs(self.config.timeout_seconds);
+        let now = self.now();
+        self.upload_progress.prune(now);
+        self.cgi_cache.prune(now);
+        let timeout = self.config.timeout_seconds;
         let to_remove: Vec<Token> = self.connections
             .iter()
             .filter(|(_, conn)| {
                 conn.state != ConnectionState::CgiPending &&
+                    conn.state != ConnectionState::WebSocket &&
+                    conn.state != ConnectionState::Streaming &&
+                    conn.state != ConnectionState::Proxy &&
                     now.duration_since(conn.last_activity) > timeout
             })
             .map(|(&t, _)| t)
             .collect();
 
         for t in to_remove {
-            self.close_connection(t);
+            // A client that stalled halfway through its request is told why
+            let partial = self.connections
+                .get(&t)
+                .is_some_and(|conn| conn.state == ConnectionState::ReadRequest && conn.request_started.is_some());
+            if partial {
+                self.send_error(t, StatusCode::REQUEST_TIMEOUT);
+            } else {
+                self.close_connection(t);
+            }
         }
 
+        self.check_upstream_timeouts(now, timeout);
+
         self.sessions.retain(|_, last_seen| now.duration_since(*last_seen) <= timeout);
+        self.ban_list.prune(now);
     }
 
     fn check_cgi_timeouts(&mut self) {
-        let timeout = Duration::from_secs(self.config.timeout_seconds);
-        let now = Instant::now();
+        let timeout = self.config.cgi_timeout.unwrap_or(self.config.timeout_seconds);
+        let now = self.now();
 
         let timed_out: Vec<Token> = self.pending_cgi
             .iter()
-            .filter(|(_, pending)| now.duration_since(pending.started_at) > timeout)
+            .filter(|(_, pending)| !pending.streaming && now.duration_since(pending.started_at) > timeout)
             .map(|(&client_token, _)| client_token)
             .collect();
 
@@ -611,9 +1610,10 @@ impl Server {
             if let Some(mut pending) = self.remove_pending_cgi(client_token) {
                 let _ = pending.child.kill();
                 let _ = pending.child.wait();
+                self.release_cgi_waiters(pending.cache, Some(StatusCode::GATEWAY_TIMEOUT));
             }
             if self.connections.contains_key(&client_token) {
-                self.send_error(client_token, 504);
+                self.send_error(client_token, StatusCode::GATEWAY_TIMEOUT);
             }
         }
     }
@@ -623,14 +1623,22 @@ impl Server {
 
         loop {
             match self.listeners.get_mut(&server_token).unwrap().listener.accept() {
-                Ok((mut stream, _)) => {
-                    let token = Token(self.next_token);
-                    self.next_token += 1;
+                Ok((mut stream, addr)) => {
+                    // Dropping the stream closes it without reading anything
+                    if self.ban_list.is_banned(addr.ip(), self.now()) || !self.hooks.accept(addr) {
+                        continue;
+                    }
+
+                    let token = self.allocate_token();
 
                     self.poll.registry().register(&mut stream, token, Interest::READABLE).ok();
 
-                    self.connections.insert(token, Connection::new(stream, server_idx));
-                    println!("[Network] New client Token {:?}", token);
+                    let mut conn = Connection::new(stream, addr, server_token, server_idx, self.now());
+                    conn.request_id = format!("{}-{:06}", self.request_id_prefix, self.next_request_id);
+                    self.next_request_id += 1;
+                    conn.proxy_protocol = self.config.servers[server_idx].proxy_protocol;
+                    self.connections.insert(token, conn);
+                    log::debug!("[Network] New client Token {:?}", token);
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     break;
@@ -642,146 +1650,656 @@ impl Server {
         }
     }
 
-    fn close_connection(&mut self, token: Token) {
-        if let Some(mut pending) = self.remove_pending_cgi(token) {
-            let _ = pending.child.kill();
-            let _ = pending.child.wait();
+    fn close_connection(&mut self, token: Token) {
+        if let Some(mut pending) = self.remove_pending_cgi(token) {
+            let _ = pending.child.kill();
+            let _ = pending.child.wait();
+            self.release_cgi_waiters(pending.cache, None);
+        }
+        self.cgi_cache.forget(token);
+        if let Some(mut ws) = self.remove_websocket(token) {
+            let _ = ws.child.kill();
+            let _ = ws.child.wait();
+        }
+        self.remove_tunnel(token);
+        self.remove_proxy(token);
+        if let Some(mut conn) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut conn.stream);
+            // Cut off before a reply: the upload failed
+            if let Some(id) = &conn.upload_id {
+                self.upload_progress.finish(id, false, self.clock.now());
+            }
+            self.hooks.close(
+                &(CloseEvent {
+                    peer: conn.peer,
+                    client_ip: conn.client_ip.unwrap_or(conn.peer.ip()),
+                    duration: self.now().saturating_duration_since(conn.opened_at),
+                })
+            );
+        }
+    }
+
+    fn handle_cgi_event(&mut self, cgi_token: Token, event: &mio::event::Event) {
+        let client_token = match self.cgi_token_to_client.get(&cgi_token) {
+            Some(token) => *token,
+            None => {
+                return;
+            }
+        };
+
+        if event.is_readable() || event.is_read_closed() {
+            self.poll_cgi_process(client_token);
+        }
+    }
+
+    fn start_cgi_process(
+        &mut self,
+        client_token: Token,
+        script_path: &str,
+        interpreter: Option<&str>,
+        body: &[u8],
+        env_vars: std::collections::HashMap<String, String>,
+        streaming: bool
+    ) -> Result<(), ServerError> {
+        let now = self.now();
+        let (child, stdout) = spawn_cgi_process(script_path, interpreter, body, env_vars)?;
+
+        let io_token = self.allocate_token();
+
+        self.register_raw_fd(stdout.as_raw_fd(), io_token, Interest::READABLE)?;
+
+        self.pending_cgi.insert(client_token, PendingCgi {
+            child,
+            stdout,
+            output: Vec::new(),
+            io_token,
+            started_at: self.now(),
+            streaming,
+            cache: None,
+        });
+        self.cgi_token_to_client.insert(io_token, client_token);
+
+        if let Some(conn) = self.connections.get_mut(&client_token) {
+            conn.state = if streaming { ConnectionState::Streaming } else { ConnectionState::CgiPending };
+            conn.last_activity = now;
+        }
+
+        if streaming {
+            // Headers go out at once so the client sees the stream open before the first event
+            let extra_headers = match self.connections.get_mut(&client_token) {
+                Some(conn) => std::mem::take(&mut conn.response_headers),
+                None => Vec::new(),
+            };
+            let head = Self::event_stream_head(extra_headers, self.config.server_header.as_deref());
+            self.log_access(client_token, &head);
+            self.queue_stream_bytes(client_token, &head);
+        }
+
+        Ok(())
+    }
+
+    /// `cgi_cache` routes: a fresh copy is sent at once, a request identical to
+    /// one whose script is running waits for it, anything else runs the script.
+    pub(crate) fn start_cached_cgi(
+        &mut self,
+        client_token: Token,
+        key: CacheKey,
+        settings: CgiCache,
+        waiter: Waiter
+    ) -> Result<(), ServerError> {
+        if let Some(response) = self.cgi_cache.get(&key, self.now()) {
+            self.send_cached_response(client_token, response, "HIT");
+            return Ok(());
+        }
+        let waiter = match self.cgi_cache.join(&key, waiter) {
+            Ok(()) => {
+                if let Some(conn) = self.connections.get_mut(&client_token) {
+                    conn.state = ConnectionState::CgiPending;
+                }
+                return Ok(());
+            }
+            Err(waiter) => waiter,
+        };
+
+        self.start_cgi_process(client_token, &waiter.script_path, waiter.interpreter.as_deref(), &[], waiter.env_vars, false)?;
+        self.cgi_cache.lead(&key);
+        if let Some(pending) = self.pending_cgi.get_mut(&client_token) {
+            pending.cache = Some((key, settings));
+        }
+        Ok(())
+    }
+
+    /// A copy from the response cache, marked `X-Cache: HIT` (or `STALE`).
+    fn send_cached_response(&mut self, token: Token, response: Vec<u8>, cache_status: &str) {
+        self.apply_configured_headers(token, &[("X-Cache".to_string(), cache_status.to_string())]);
+        let response_bytes = self.apply_connection_headers_to_raw_response(token, response);
+        self.finalize_response(token, response_bytes);
+    }
+
+    /// Answers the requests that waited for a cached route's script: with the
+    /// copy it stored, with the same error, or else by running it for each of them.
+    fn release_cgi_waiters(&mut self, cache: Option<(CacheKey, CgiCache)>, failure: Option<StatusCode>) {
+        let key = match cache {
+            Some((key, _)) => key,
+            None => {
+                return;
+            }
+        };
+        let cached = self.cgi_cache.get(&key, self.now());
+        for waiter in self.cgi_cache.release(&key) {
+            if !self.connections.contains_key(&waiter.token) {
+                continue;
+            }
+            if let Some(code) = failure {
+                self.send_error(waiter.token, code);
+            } else if let Some(response) = &cached {
+                self.send_cached_response(waiter.token, response.clone(), "HIT");
+            } else if
+                let Err(e) = self.start_cgi_process(
+                    waiter.token,
+                    &waiter.script_path,
+                    waiter.interpreter.as_deref(),
+                    &[],
+                    waiter.env_vars,
+                    false
+                )
+            {
+                log::error!("[CGI Error] {}", e);
+                self.send_error(waiter.token, StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    fn poll_cgi_process(&mut self, client_token: Token) {
+        let now = self.now();
+        let mut should_finalize = false;
+        let mut process_error = None;
+        let mut streaming = false;
+        let mut streamed = Vec::new();
+
+        {
+            let pending = match self.pending_cgi.get_mut(&client_token) {
+                Some(p) => p,
+                None => {
+                    return;
+                }
+            };
+
+            let mut buf = [0u8; 8192];
+            loop {
+                match pending.stdout.read(&mut buf) {
+                    Ok(0) => {
+                        break;
+                    }
+                    Ok(n) => {
+                        pending.output.extend_from_slice(&buf[..n]);
+                        if let Some(conn) = self.connections.get_mut(&client_token) {
+                            conn.last_activity = now;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(e) => {
+                        process_error = Some(format!("CGI stdout read failed: {}", e));
+                        break;
+                    }
+                }
+            }
+
+            if pending.streaming {
+                streaming = true;
+                streamed = std::mem::take(&mut pending.output);
+            }
+
+            if process_error.is_none() {
+                match pending.child.try_wait() {
+                    Ok(Some(_)) => {
+                        should_finalize = true;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        process_error = Some(format!("CGI process check failed: {}", e));
+                    }
+                }
+            }
+        }
+
+        if !streamed.is_empty() {
+            self.queue_stream_bytes(client_token, &streamed);
+        }
+
+        if let Some(err) = process_error {
+            log::error!("[CGI Error] {}", err);
+            if let Some(pending) = self.remove_pending_cgi(client_token) {
+                self.release_cgi_waiters(pending.cache, Some(StatusCode::INTERNAL_SERVER_ERROR));
+            }
+            if streaming {
+                // Headers are already out; all that is left is to end the stream
+                self.close_connection(client_token);
+            } else if self.connections.contains_key(&client_token) {
+                self.send_error(client_token, StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            return;
+        }
+
+        if streaming {
+            if should_finalize && let Some(mut pending) = self.remove_pending_cgi(client_token) {
+                let _ = pending.child.wait();
+                // Wakes write_to_client, which closes once the buffer is drained
+                self.queue_stream_bytes(client_token, &[]);
+            }
+            return;
+        }
+
+        if should_finalize && let Some(mut pending) = self.remove_pending_cgi(client_token) {
+            let _ = pending.child.wait();
+            let response = Self::build_cgi_response(&pending.output, self.config.server_header.as_deref());
+            if let Some((key, settings)) = &pending.cache {
+                if cgi_cache::cacheable(&response) {
+                    self.cgi_cache.insert(key, response.clone(), settings.max_size, settings.ttl, Duration::ZERO, now);
+                }
+                self.apply_configured_headers(client_token, &[("X-Cache".to_string(), "MISS".to_string())]);
+            }
+            self.release_cgi_waiters(pending.cache, None);
+            if self.connections.contains_key(&client_token) {
+                let response_bytes = self.apply_connection_headers_to_raw_response(client_token, response);
+                self.finalize_response(client_token, response_bytes);
+            }
+        }
+    }
+
+    fn check_cgi_progress(&mut self) {
+        let pending_tokens: Vec<Token> = self.pending_cgi.keys().copied().collect();
+        for client_token in pending_tokens {
+            self.poll_cgi_process(client_token);
+        }
+    }
+
+    fn remove_pending_cgi(&mut self, client_token: Token) -> Option<PendingCgi> {
+        let pending = self.pending_cgi.remove(&client_token)?;
+        self.cgi_token_to_client.remove(&pending.io_token);
+        let _ = self.deregister_raw_fd(pending.stdout.as_raw_fd());
+        Some(pending)
+    }
+
+    fn start_websocket(
+        &mut self,
+        token: Token,
+        method: &str,
+        headers: &std::collections::HashMap<String, String>,
+        script_path: &str,
+        interpreter: Option<&str>,
+        env_vars: std::collections::HashMap<String, String>
+    ) {
+        let now = self.now();
+        let accept = match websocket::accept_key(method, headers) {
+            Ok(accept) => accept,
+            Err(code) => {
+                if code == StatusCode::UPGRADE_REQUIRED && let Some(conn) = self.connections.get_mut(&token) {
+                    conn.response_headers.push(("Sec-WebSocket-Version".to_string(), "13".to_string()));
+                }
+                self.send_error(token, code);
+                return;
+            }
+        };
+
+        let (child, stdin, stdout) = match spawn_websocket_process(script_path, interpreter, env_vars) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                log::error!("[WebSocket Error] {}", e);
+                self.send_error(token, StatusCode::INTERNAL_SERVER_ERROR);
+                return;
+            }
+        };
+
+        let stdin_token = self.allocate_token();
+        let stdout_token = self.allocate_token();
+
+        let mut ws = WebSocketSession {
+            child,
+            stdin: Some(stdin),
+            stdout,
+            stdin_token,
+            stdout_token,
+            to_process: Vec::new(),
+            from_process: Vec::new(),
+            message: Vec::new(),
+            message_opcode: None,
+            max_message: self.config.servers
+                .get(self.connections.get(&token).map(|c| c.server_idx).unwrap_or(0))
+                .map(|s| s.max_body_size.min(self.config.max_server_size))
+                .unwrap_or(self.config.max_server_size),
+            closing: false,
+        };
+
+        let registered = self
+            .register_raw_fd(ws.stdout.as_raw_fd(), stdout_token, Interest::READABLE)
+            .and_then(|_| {
+                let stdin_fd = ws.stdin.as_ref().map(|s| s.as_raw_fd()).unwrap_or(-1);
+                self.register_raw_fd(stdin_fd, stdin_token, Interest::WRITABLE)
+            });
+        if let Err(e) = registered {
+            log::error!("[WebSocket Error] {}", e);
+            let _ = self.deregister_raw_fd(ws.stdout.as_raw_fd());
+            let _ = ws.child.kill();
+            let _ = ws.child.wait();
+            self.send_error(token, StatusCode::INTERNAL_SERVER_ERROR);
+            return;
+        }
+
+        self.websockets.insert(token, ws);
+        self.ws_token_to_client.insert(stdin_token, token);
+        self.ws_token_to_client.insert(stdout_token, token);
+
+        let extra_headers = match self.connections.get_mut(&token) {
+            Some(conn) => std::mem::take(&mut conn.response_headers),
+            None => Vec::new(),
+        };
+        let response = websocket::handshake_response(&accept, extra_headers, self.config.server_header.as_deref());
+        self.log_access(token, &response);
+
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.read_buffer.clear();
+            conn.state = ConnectionState::WebSocket;
+            conn.last_activity = now;
+        }
+        self.queue_stream_bytes(token, &response);
+    }
+
+    fn read_websocket_frames(&mut self, token: Token) {
+        let now = self.now();
+        let max_message = match self.websockets.get(&token) {
+            Some(ws) => ws.max_message,
+            None => {
+                self.close_connection(token);
+                return;
+            }
+        };
+
+        let mut peer_gone = false;
+        if let Some(conn) = self.connections.get_mut(&token) {
+            let mut buf = [0u8; 4096];
+            loop {
+                match conn.stream.read(&mut buf) {
+                    Ok(0) => {
+                        peer_gone = true;
+                        break;
+                    }
+                    Ok(n) => {
+                        conn.read_buffer.extend_from_slice(&buf[..n]);
+                        conn.last_activity = now;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(_) => {
+                        peer_gone = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if peer_gone {
+            self.close_connection(token);
+            return;
+        }
+
+        loop {
+            let decoded = match self.connections.get_mut(&token) {
+                Some(conn) =>
+                    match websocket::decode_frame(&conn.read_buffer, max_message) {
+                        Ok(Some((frame, used))) => {
+                            conn.read_buffer.drain(..used);
+                            Ok(Some(frame))
+                        }
+                        Ok(None) => Ok(None),
+                        Err(code) => Err(code),
+                    }
+                None => {
+                    return;
+                }
+            };
+
+            match decoded {
+                Ok(Some(frame)) => self.handle_websocket_frame(token, frame),
+                Ok(None) => {
+                    return;
+                }
+                Err(code) => {
+                    self.fail_websocket(token, code);
+                    return;
+                }
+            }
+
+            if self.websockets.get(&token).is_none_or(|ws| ws.closing) {
+                return;
+            }
+        }
+    }
+
+    fn handle_websocket_frame(&mut self, token: Token, frame: websocket::Frame) {
+        let ws = match self.websockets.get_mut(&token) {
+            Some(ws) => ws,
+            None => {
+                return;
+            }
+        };
+
+        match frame.opcode {
+            websocket::OPCODE_PING => {
+                let pong = websocket::encode_frame(websocket::OPCODE_PONG, &frame.payload);
+                self.queue_stream_bytes(token, &pong);
+            }
+            websocket::OPCODE_PONG => {}
+            websocket::OPCODE_CLOSE => {
+                let code = match frame.payload.as_slice() {
+                    [hi, lo, ..] => u16::from_be_bytes([*hi, *lo]),
+                    _ => websocket::CLOSE_NORMAL,
+                };
+                self.fail_websocket(token, code);
+            }
+            websocket::OPCODE_TEXT | websocket::OPCODE_BINARY => {
+                if ws.message_opcode.is_some() {
+                    self.fail_websocket(token, websocket::CLOSE_PROTOCOL_ERROR);
+                } else if frame.fin {
+                    self.deliver_websocket_message(token, frame.payload);
+                } else {
+                    ws.message_opcode = Some(frame.opcode);
+                    ws.message = frame.payload;
+                }
+            }
+            websocket::OPCODE_CONTINUATION => {
+                if ws.message_opcode.is_none() {
+                    self.fail_websocket(token, websocket::CLOSE_PROTOCOL_ERROR);
+                    return;
+                }
+                ws.message.extend_from_slice(&frame.payload);
+                if ws.message.len() > ws.max_message {
+                    self.fail_websocket(token, websocket::CLOSE_TOO_BIG);
+                } else if frame.fin {
+                    ws.message_opcode = None;
+                    let message = std::mem::take(&mut ws.message);
+                    self.deliver_websocket_message(token, message);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// One message per line on the handler's stdin.
+    fn deliver_websocket_message(&mut self, token: Token, mut message: Vec<u8>) {
+        if let Some(ws) = self.websockets.get_mut(&token) {
+            message.push(b'\n');
+            ws.to_process.extend_from_slice(&message);
+        }
+        self.flush_websocket_input(token);
+    }
+
+    fn flush_websocket_input(&mut self, token: Token) {
+        let mut process_gone = false;
+        if let Some(ws) = self.websockets.get_mut(&token) && let Some(stdin) = ws.stdin.as_mut() {
+            while !ws.to_process.is_empty() {
+                match stdin.write(&ws.to_process) {
+                    Ok(n) => {
+                        ws.to_process.drain(..n);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(_) => {
+                        process_gone = true;
+                        break;
+                    }
+                }
+            }
         }
-        if let Some(mut conn) = self.connections.remove(&token) {
-            let _ = self.poll.registry().deregister(&mut conn.stream);
+
+        if process_gone {
+            self.fail_websocket(token, websocket::CLOSE_NORMAL);
         }
     }
 
-    fn handle_cgi_event(&mut self, cgi_token: Token, event: &mio::event::Event) {
-        let client_token = match self.cgi_token_to_client.get(&cgi_token) {
+    fn handle_websocket_process_event(&mut self, io_token: Token, event: &mio::event::Event) {
+        let client_token = match self.ws_token_to_client.get(&io_token) {
             Some(token) => *token,
             None => {
                 return;
             }
         };
 
-        if event.is_readable() || event.is_read_closed() {
-            self.poll_cgi_process(client_token);
-        }
-    }
-
-    fn start_cgi_process(
-        &mut self,
-        client_token: Token,
-        script_path: &str,
-        interpreter: Option<&str>,
-        body: &[u8],
-        env_vars: std::collections::HashMap<String, String>
-    ) -> Result<(), String> {
-        let (child, stdout) = spawn_cgi_process(script_path, interpreter, body, env_vars)?;
-
-        let io_token = Token(self.next_token);
-        self.next_token += 1;
-
-        self.register_raw_fd(stdout.as_raw_fd(), io_token, Interest::READABLE)?;
-
-        self.pending_cgi.insert(client_token, PendingCgi {
-            child,
-            stdout,
-            output: Vec::new(),
-            io_token,
-            started_at: Instant::now(),
-        });
-        self.cgi_token_to_client.insert(io_token, client_token);
-
-        if let Some(conn) = self.connections.get_mut(&client_token) {
-            conn.state = ConnectionState::CgiPending;
-            conn.last_activity = Instant::now();
+        let is_stdin = self.websockets.get(&client_token).is_some_and(|ws| ws.stdin_token == io_token);
+        if is_stdin {
+            if event.is_writable() || event.is_error() {
+                self.flush_websocket_input(client_token);
+            }
+        } else if event.is_readable() || event.is_read_closed() {
+            self.read_websocket_process_output(client_token);
         }
-
-        Ok(())
     }
 
-    fn poll_cgi_process(&mut self, client_token: Token) {
-        let mut should_finalize = false;
-        let mut process_error = None;
-
-        {
-            let pending = match self.pending_cgi.get_mut(&client_token) {
-                Some(p) => p,
-                None => {
-                    return;
-                }
-            };
+    fn read_websocket_process_output(&mut self, client_token: Token) {
+        let mut frames = Vec::new();
+        let mut finished = false;
 
+        if let Some(ws) = self.websockets.get_mut(&client_token) {
             let mut buf = [0u8; 8192];
             loop {
-                match pending.stdout.read(&mut buf) {
+                match ws.stdout.read(&mut buf) {
                     Ok(0) => {
+                        finished = true;
                         break;
                     }
-                    Ok(n) => {
-                        pending.output.extend_from_slice(&buf[..n]);
-                        if let Some(conn) = self.connections.get_mut(&client_token) {
-                            conn.last_activity = Instant::now();
-                        }
-                    }
+                    Ok(n) => ws.from_process.extend_from_slice(&buf[..n]),
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         break;
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
                         continue;
                     }
-                    Err(e) => {
-                        process_error = Some(format!("CGI stdout read failed: {}", e));
+                    Err(_) => {
+                        finished = true;
                         break;
                     }
                 }
             }
 
-            if process_error.is_none() {
-                match pending.child.try_wait() {
-                    Ok(Some(_)) => {
-                        should_finalize = true;
-                    }
-                    Ok(None) => {}
-                    Err(e) => {
-                        process_error = Some(format!("CGI process check failed: {}", e));
-                    }
-                }
+            while let Some(pos) = ws.from_process.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = ws.from_process.drain(..=pos).collect();
+                frames.push(line[..line.len() - 1].to_vec());
+            }
+            if finished && !ws.from_process.is_empty() {
+                frames.push(std::mem::take(&mut ws.from_process));
             }
         }
 
-        if let Some(err) = process_error {
-            eprintln!("[CGI Error] {}", err);
-            let _ = self.remove_pending_cgi(client_token);
-            if self.connections.contains_key(&client_token) {
-                self.send_error(client_token, 500);
-            }
-            return;
+        for payload in frames {
+            let opcode = if std::str::from_utf8(&payload).is_ok() {
+                websocket::OPCODE_TEXT
+            } else {
+                websocket::OPCODE_BINARY
+            };
+            self.queue_stream_bytes(client_token, &websocket::encode_frame(opcode, &payload));
         }
 
-        if should_finalize {
-            if let Some(mut pending) = self.remove_pending_cgi(client_token) {
-                let _ = pending.child.wait();
-                if self.connections.contains_key(&client_token) {
-                    let response_bytes = self.apply_connection_headers_to_raw_response(
-                        client_token,
-                        Self::build_cgi_response(&pending.output)
-                    );
-                    self.finalize_response(client_token, response_bytes);
+        if finished {
+            self.fail_websocket(client_token, websocket::CLOSE_NORMAL);
+        }
+    }
+
+    /// Sends a close frame and tears the connection down once it is flushed.
+    fn fail_websocket(&mut self, token: Token, code: u16) {
+        match self.websockets.get_mut(&token) {
+            Some(ws) if !ws.closing => {
+                ws.closing = true;
+                // Closing stdin lets the handler see EOF and exit on its own
+                if let Some(stdin) = ws.stdin.take() {
+                    let _ = self.deregister_raw_fd(stdin.as_raw_fd());
                 }
             }
+            _ => {
+                return;
+            }
         }
+        self.queue_stream_bytes(token, &websocket::close_frame(code));
     }
 
-    fn check_cgi_progress(&mut self) {
-        let pending_tokens: Vec<Token> = self.pending_cgi.keys().copied().collect();
-        for client_token in pending_tokens {
-            self.poll_cgi_process(client_token);
+    fn queue_stream_bytes(&mut self, token: Token, bytes: &[u8]) {
+        let now = self.now();
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.write_buffer.extend_from_slice(bytes);
+            conn.last_activity = now;
+            if
+                let Err(e) = self.poll
+                    .registry()
+                    .reregister(&mut conn.stream, token, Interest::READABLE | Interest::WRITABLE)
+            {
+                log::error!("[Mio] Failed to reregister token {:?}: {}", token, e);
+                self.close_connection(token);
+            }
         }
     }
 
-    fn remove_pending_cgi(&mut self, client_token: Token) -> Option<PendingCgi> {
-        let pending = self.pending_cgi.remove(&client_token)?;
-        self.cgi_token_to_client.remove(&pending.io_token);
-        let _ = self.deregister_raw_fd(pending.stdout.as_raw_fd());
-        Some(pending)
+    fn remove_websocket(&mut self, client_token: Token) -> Option<WebSocketSession> {
+        let ws = self.websockets.remove(&client_token)?;
+        self.ws_token_to_client.remove(&ws.stdin_token);
+        self.ws_token_to_client.remove(&ws.stdout_token);
+        if let Some(stdin) = ws.stdin.as_ref() {
+            let _ = self.deregister_raw_fd(stdin.as_raw_fd());
+        }
+        let _ = self.deregister_raw_fd(ws.stdout.as_raw_fd());
+        Some(ws)
+    }
+
+    /// `--dev`: holds the connection open as an event stream until the next reload.
+    fn start_live_reload_stream(&mut self, token: Token) {
+        let now = self.now();
+        let extra_headers = match self.connections.get_mut(&token) {
+            Some(conn) => {
+                conn.state = ConnectionState::Streaming;
+                conn.last_activity = now;
+                std::mem::take(&mut conn.response_headers)
+            }
+            None => {
+                return;
+            }
+        };
+        if let Some(live) = &mut self.live_reload {
+            live.clients.push(token);
+        }
+        let head = Self::event_stream_head(extra_headers, self.config.server_header.as_deref());
+        self.log_access(token, &head);
+        self.queue_stream_bytes(token, &head);
     }
 
     fn register_raw_fd(
@@ -789,20 +2307,20 @@ impl Server {
         raw_fd: std::os::fd::RawFd,
         token: Token,
         interest: Interest
-    ) -> Result<(), String> {
+    ) -> Result<(), ServerError> {
         let mut source = SourceFd(&raw_fd);
         self.poll
             .registry()
             .register(&mut source, token, interest)
-            .map_err(|e| e.to_string())
+            .map_err(ServerError::io("Failed to register pipe"))
     }
 
-    fn deregister_raw_fd(&self, raw_fd: std::os::fd::RawFd) -> Result<(), String> {
+    fn deregister_raw_fd(&self, raw_fd: std::os::fd::RawFd) -> Result<(), ServerError> {
         let mut source = SourceFd(&raw_fd);
         self.poll
             .registry()
             .deregister(&mut source)
-            .map_err(|e| e.to_string())
+            .map_err(ServerError::io("Failed to deregister pipe"))
     }
 
     fn find_header_end(buf: &[u8]) -> Option<usize> {
@@ -814,10 +2332,11 @@ impl Server {
     fn extract_content_length(header_bytes: &[u8]) -> Option<usize> {
         let header = std::str::from_utf8(header_bytes).ok()?;
         for line in header.lines() {
-            if let Some((key, value)) = line.split_once(':') {
-                if key.trim().eq_ignore_ascii_case("content-length") {
-                    return value.trim().parse::<usize>().ok();
-                }
+            if
+                let Some((key, value)) = line.split_once(':') &&
+                key.trim().eq_ignore_ascii_case("content-length")
+            {
+                return value.trim().parse::<usize>().ok();
             }
         }
         None
@@ -828,10 +2347,11 @@ impl Server {
         route_path: &str,
         headers: &std::collections::HashMap<String, String>
     ) -> String {
-        if let Some(disposition) = headers.get("content-disposition") {
-            if let Some(filename) = Self::extract_filename_from_disposition(disposition) {
-                return filename;
-            }
+        if
+            let Some(disposition) = headers.get("content-disposition") &&
+            let Some(filename) = Self::extract_filename_from_disposition(disposition)
+        {
+            return filename;
         }
 
         let request_name = Path::new(request_path)
@@ -846,10 +2366,8 @@ impl Server {
             .map(|s| s.trim())
             .filter(|s| !s.is_empty());
 
-        if let Some(name) = request_name {
-            if route_name.map_or(true, |route_segment| route_segment != name) {
-                return name.to_string();
-            }
+        if let Some(name) = request_name && route_name != Some(name) {
+            return name.to_string();
         }
 
         let ts = SystemTime::now()
@@ -873,14 +2391,6 @@ impl Server {
         None
     }
 
-    fn path_matches_route(path: &str, route_path: &str) -> bool {
-        if route_path == "/" {
-            return path.starts_with('/');
-        }
-
-        path == route_path || path.starts_with(&format!("{}/", route_path.trim_end_matches('/')))
-    }
-
     fn try_serve_upload_file(&mut self, token: Token, server_idx: usize, path_only: &str) -> bool {
         let server_cfg = &self.config.servers[server_idx];
 
@@ -910,7 +2420,7 @@ impl Server {
 
             let mut matched_prefix: Option<&str> = None;
             for prefix in [upload_prefix.as_str(), route.path.as_str()] {
-                if Self::path_matches_route(path_only, prefix) {
+                if router::path_matches_route(path_only, prefix) {
                     matched_prefix = Some(prefix);
                     break;
                 }
@@ -929,17 +2439,17 @@ impl Server {
             full_path.push(relative);
 
             if full_path.is_dir() {
-                self.send_error(token, 403);
+                self.send_error(token, StatusCode::FORBIDDEN);
                 return true;
             }
 
             match std::fs::read(&full_path) {
                 Ok(content) => {
-                    let mime = Self::get_mime_type(full_path.to_str().unwrap_or(""));
-                    self.send_bytes_response(token, 200, content, mime);
+                    let mime = self.mime_types.lookup(full_path.to_str().unwrap_or("")).to_string();
+                    self.send_bytes_response(token, StatusCode::OK, content, &mime);
                 }
                 Err(_) => {
-                    self.send_error(token, 404);
+                    self.send_error(token, StatusCode::NOT_FOUND);
                 }
             }
             return true;
@@ -948,19 +2458,7 @@ impl Server {
         false
     }
 
-    fn get_mime_type(path: &str) -> &str {
-        if path.ends_with(".html") {
-            "text/html"
-        } else if path.ends_with(".css") {
-            "text/css"
-        } else if path.ends_with(".js") {
-            "application/javascript"
-        } else {
-            "text/plain"
-        }
-    }
-
-    fn build_cgi_response(output: &[u8]) -> Vec<u8> {
+    fn build_cgi_response(output: &[u8], server_header: Option<&str>) -> Vec<u8> {
         if output.starts_with(b"HTTP/") {
             return output.to_vec();
         }
@@ -975,31 +2473,30 @@ impl Server {
             (&[][..], output)
         };
 
-        let mut status_code = 200u16;
-        let mut status_text = "OK".to_string();
+        let mut status_code = StatusCode::OK;
+        let mut status_text = None;
         let mut headers: Vec<(String, String)> = Vec::new();
 
-        if !header_part.is_empty() {
-            if let Ok(header_str) = std::str::from_utf8(header_part) {
-                for line in header_str.lines() {
-                    if line.trim().is_empty() {
-                        continue;
-                    }
-                    if let Some((key, value)) = line.split_once(':') {
-                        if key.trim().eq_ignore_ascii_case("status") {
-                            let status_val = value.trim();
-                            let mut parts = status_val.splitn(2, ' ');
-                            if let Some(code_str) = parts.next() {
-                                if let Ok(code) = code_str.parse::<u16>() {
-                                    status_code = code;
-                                }
-                            }
-                            if let Some(text) = parts.next() {
-                                status_text = text.trim().to_string();
-                            }
-                        } else {
-                            headers.push((key.trim().to_string(), value.trim().to_string()));
+        if !header_part.is_empty() && let Ok(header_str) = std::str::from_utf8(header_part) {
+            for line in header_str.lines() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Some((key, value)) = line.split_once(':') {
+                    if key.trim().eq_ignore_ascii_case("status") {
+                        let status_val = value.trim();
+                        let mut parts = status_val.splitn(2, ' ');
+                        if
+                            let Some(code_str) = parts.next() &&
+                            let Some(code) = code_str.parse().ok().and_then(StatusCode::from_u16)
+                        {
+                            status_code = code;
                         }
+                        if let Some(text) = parts.next() {
+                            status_text = Some(text.trim().to_string());
+                        }
+                    } else {
+                        headers.push((key.trim().to_string(), value.trim().to_string()));
                     }
                 }
             }
@@ -1024,15 +2521,33 @@ impl Server {
             headers.push(("Content-Length".to_string(), body_part.len().to_string()));
         }
 
-        Self::build_http_response(status_code, &status_text, headers, body_part, true)
+        // `Status: 404` without a reason gets the standard one
+        let status_text = status_text.unwrap_or_else(|| status_code.reason().to_string());
+        Self::build_http_response(status_code, &status_text, headers, body_part, true, server_header)
+    }
+
+    fn event_stream_head(extra_headers: Vec<(String, String)>, server_header: Option<&str>) -> Vec<u8> {
+        let mut head = String::from(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nX-Accel-Buffering: no\r\n"
+        );
+        for (key, value) in extra_headers {
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        // No Content-Length: the stream ends when the handler exits and the connection closes
+        if let Some(server) = server_header {
+            head.push_str(&format!("Server: {}\r\n", server));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
     }
 
     fn build_http_response(
-        status_code: u16,
+        status_code: StatusCode,
         status_text: &str,
         headers: Vec<(String, String)>,
         body: &[u8],
-        close_connection: bool
+        close_connection: bool,
+        server_header: Option<&str>
     ) -> Vec<u8> {
         let mut has_content_length = false;
         let mut header_lines = String::new();
@@ -1044,11 +2559,14 @@ impl Server {
             header_lines.push_str(&format!("{}: {}\r\n", key, value));
         }
 
-        if !has_content_length {
+        // A 204 has no body and a 304 stands for one the client already has
+        if !has_content_length && status_code != StatusCode::NO_CONTENT && status_code != StatusCode::NOT_MODIFIED {
             header_lines.push_str(&format!("Content-Length: {}\r\n", body.len()));
         }
 
-        header_lines.push_str("Server: LocalServer\r\n");
+        if let Some(server) = server_header {
+            header_lines.push_str(&format!("Server: {}\r\n", server));
+        }
         header_lines.push_str(
             if close_connection {
                 "Connection: close\r\n"
@@ -1067,83 +2585,247 @@ impl Server {
         response
     }
 
-    fn reason_phrase(status_code: u16) -> &'static str {
-        match status_code {
-            200 => "OK",
-            201 => "Created",
-            301 => "Moved Permanently",
-            400 => "Bad Request",
-            403 => "Forbidden",
-            404 => "Not Found",
-            405 => "Method Not Allowed",
-            413 => "Payload Too Large",
-            500 => "Internal Server Error",
-            501 => "Not Implemented",
-            504 => "Gateway Timeout",
-            _ => "Internal Server Error",
-        }
-    }
-
     fn select_server_for_request(
         &self,
         default_idx: usize,
         headers: &std::collections::HashMap<String, String>
     ) -> usize {
-        let host_header = match headers.get("host") {
-            Some(h) => h,
+        router::select_server(&self.config, default_idx, headers.get("host").map(|h| h.as_str()))
+    }
+
+    /// Body limit for a request whose head has arrived: the matched route's
+    /// `max_body_size` when set, otherwise the server's capped by `max_server_size`.
+    fn body_limit(config: &Config, default_idx: usize, head: &[u8]) -> usize {
+        let head = std::str::from_utf8(head).unwrap_or("");
+        let mut lines = head.lines();
+        let path_only = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .map(|uri| uri.split('?').next().unwrap_or(uri))
+            .unwrap_or("/");
+        let host = lines.find_map(|line| {
+            line.split_once(':')
+                .filter(|(key, _)| key.trim().eq_ignore_ascii_case("host"))
+                .map(|(_, value)| value.trim())
+        });
+
+        let server_cfg = match config.servers.get(router::select_server(config, default_idx, host)) {
+            Some(s) => s,
             None => {
-                return default_idx;
+                return config.max_server_size;
             }
         };
 
-        let host_name = host_header.split(':').next().unwrap_or(host_header).trim();
-        let default_cfg = &self.config.servers[default_idx];
+        Self::route_body_limit(config, server_cfg, router::find_route(server_cfg, path_only))
+    }
 
-        for (idx, cfg) in self.config.servers.iter().enumerate() {
-            if cfg.host == default_cfg.host && cfg.port == default_cfg.port {
-                if
-                    cfg.server_name.eq_ignore_ascii_case(host_name) ||
-                    cfg.host.eq_ignore_ascii_case(host_name)
-                {
-                    return idx;
-                }
-            }
-        }
+    /// `X-Upload-ID` from a request head, when the block it is for reports upload progress.
+    fn upload_id(config: &Config, default_idx: usize, head: &[u8]) -> Option<String> {
+        let head = std::str::from_utf8(head).ok()?;
+        let header = |name: &str| {
+            head.lines().find_map(|line| {
+                line.split_once(':')
+                    .filter(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.trim())
+            })
+        };
+
+        let server_cfg = config.servers.get(router::select_server(config, default_idx, header("host")))?;
+        server_cfg.upload_progress.as_ref()?;
+        // Ids are kept in memory, so a client cannot make them arbitrarily long
+        let id = header(upload_progress::ID_HEADER)?;
+        (!id.is_empty() && id.len() <= 128).then(|| id.to_string())
+    }
 
-        default_idx
+    /// The route's `max_body_size` when set, otherwise the server's capped by `max_server_size`.
+    fn route_body_limit(config: &Config, server_cfg: &ServerConfig, route: Option<&RouteConfig>) -> usize {
+        route.and_then(|r| r.max_body_size).unwrap_or(server_cfg.max_body_size.min(config.max_server_size))
     }
 
-    fn send_redirect_response(&mut self, token: Token, location: &str, status_code: u16) {
+    fn send_redirect_response(&mut self, token: Token, location: &str, status_code: StatusCode) {
         let mut headers = vec![("Location".to_string(), location.to_string())];
         if let Some(conn) = self.connections.get_mut(&token) {
             headers.append(&mut conn.response_headers);
         }
 
-        let response = Self::build_http_response(
-            status_code,
-            Self::reason_phrase(status_code),
-            headers,
-            &[],
-            true
-        );
+        let server_header = self.config.server_header.as_deref();
+        let response = Self::build_http_response(status_code, status_code.reason(), headers, &[], true, server_header);
         self.finalize_response(token, response);
     }
 
-    fn build_autoindex_listing(&self, request_path: &str, dir_path: &std::path::Path) -> String {
-        let mut items: Vec<String> = Vec::new();
+    /// Runs the route's `auth` scheme. On failure the 401 (or 500 for a misconfigured
+    /// scheme) has already been sent and `None` is returned.
+    fn authenticate(
+        &mut self,
+        token: Token,
+        kind: &str,
+        route: &RouteConfig,
+        method: &str,
+        uri: &str,
+        headers: &std::collections::HashMap<String, String>
+    ) -> Option<AuthIdentity> {
+        let (auth_type, user) = match kind {
+            "digest" => ("Digest", self.authorize_digest(token, route, method, uri, headers)?),
+            "basic" => ("Basic", self.authorize_basic(token, route, headers)?),
+            "jwt" => {
+                return self.authorize_jwt(token, route, headers);
+            }
+            other => {
+                log::warning!("[Config] Unknown auth scheme '{}' on route {}", other, route.path);
+                self.send_error(token, StatusCode::INTERNAL_SERVER_ERROR);
+                return None;
+            }
+        };
+        Some(AuthIdentity { auth_type, user, env: Vec::new() })
+    }
 
-        if let Ok(entries) = std::fs::read_dir(dir_path) {
-            for entry in entries.flatten() {
-                if let Some(name) = entry.file_name().to_str() {
-                    let suffix = if entry.path().is_dir() { "/" } else { "" };
-                    let base = request_path.trim_end_matches('/');
-                    let href = if base.is_empty() {
-                        format!("/{}{}", name, suffix)
-                    } else {
-                        format!("{}/{}{}", base, name, suffix)
-                    };
-                    items.push(format!("<li><a href=\"{}\">{}{}</a></li>", href, name, suffix));
+    fn authorize_jwt(
+        &mut self,
+        token: Token,
+        route: &RouteConfig,
+        headers: &std::collections::HashMap<String, String>
+    ) -> Option<AuthIdentity> {
+        let realm = route.auth_realm.as_deref().unwrap_or("localserver");
+        let settings = jwt::JwtSettings {
+            secret: route.jwt_secret.as_deref(),
+            public_key: route.jwt_key.as_deref(),
+            issuer: route.jwt_issuer.as_deref(),
+            audience: route.jwt_audience.as_deref(),
+        };
+
+        let bearer = headers
+            .get("authorization")
+            .and_then(|v| v.trim().strip_prefix("Bearer "))
+            .map(|t| t.trim());
+
+        let challenge = match bearer.map(|t| jwt::verify(t, &settings)) {
+            Some(Ok(claims)) => {
+                let user = claims
+                    .get("sub")
+                    .and_then(|s| s.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                return Some(AuthIdentity {
+                    auth_type: "Bearer",
+                    user,
+                    env: jwt::claims_env(&claims),
+                });
+            }
+            Some(Err(reason)) => {
+                log::info!("[Auth] Rejected bearer token on {}: {}", route.path, reason);
+                format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm)
+            }
+            None => format!("Bearer realm=\"{}\"", realm),
+        };
+
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.response_headers.push(("WWW-Authenticate".to_string(), challenge));
+        }
+        self.send_error(token, StatusCode::UNAUTHORIZED);
+        None
+    }
+
+    fn authorize_digest(
+        &mut self,
+        token: Token,
+        route: &RouteConfig,
+        method: &str,
+        uri: &str,
+        headers: &std::collections::HashMap<String, String>
+    ) -> Option<String> {
+        let realm = route.auth_realm.as_deref().unwrap_or("localserver");
+        let user_file = route.auth_user_file.as_deref().unwrap_or("");
+
+        let outcome = self.digest_auth.verify(
+            headers.get("authorization").map(|v| v.as_str()),
+            method,
+            uri,
+            realm,
+            user_file
+        );
+
+        match outcome {
+            DigestOutcome::Authorized(user) => Some(user),
+            DigestOutcome::Challenge { stale } => {
+                let mut challenge = self.digest_auth.challenge_headers(realm, stale);
+                if let Some(conn) = self.connections.get_mut(&token) {
+                    conn.response_headers.append(&mut challenge);
                 }
+                self.send_error(token, StatusCode::UNAUTHORIZED);
+                None
+            }
+        }
+    }
+
+    fn authorize_basic(
+        &mut self,
+        token: Token,
+        route: &RouteConfig,
+        headers: &std::collections::HashMap<String, String>
+    ) -> Option<String> {
+        let realm = route.auth_realm.as_deref().unwrap_or("localserver");
+        let user_file = route.auth_user_file.as_deref().unwrap_or("");
+
+        if
+            let Some((user, password)) = headers
+                .get("authorization")
+                .and_then(|v| htpasswd::parse_basic_credentials(v)) &&
+            self.user_files.verify(user_file, &user, &password)
+        {
+            return Some(user);
+        }
+
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.response_headers.push((
+                "WWW-Authenticate".to_string(),
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+            ));
+        }
+        self.send_error(token, StatusCode::UNAUTHORIZED);
+        None
+    }
+
+    fn build_https_location(
+        &self,
+        server_idx: usize,
+        headers: &std::collections::HashMap<String, String>,
+        uri: &str
+    ) -> String {
+        let server_cfg = &self.config.servers[server_idx];
+        let requested = headers.get("host").map(|host| {
+            let host = host.trim();
+            match host.rsplit_once(':') {
+                Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+                _ => host,
+            }
+        });
+        // Only a name the block answers to is echoed, so a forged Host cannot
+        // send clients (or a cache) elsewhere
+        let host_name = requested
+            .filter(|name| !name.is_empty() && router::is_own_name(server_cfg, name))
+            .or_else(|| server_cfg.server_names.iter().map(String::as_str).find(|name| !name.contains('*')))
+            .unwrap_or(&server_cfg.host);
+
+        match server_cfg.https_port.as_deref() {
+            Some(port) if port != "443" => format!("https://{}:{}{}", host_name, port, uri),
+            _ => format!("https://{}{}", host_name, uri),
+        }
+    }
+
+    fn build_autoindex_listing(request_path: &str, vfs: &dyn Vfs, dir_path: &Path) -> String {
+        let mut items: Vec<String> = Vec::new();
+
+        if let Ok(entries) = vfs.read_dir(dir_path) {
+            for entry in entries {
+                let name = entry.name;
+                let suffix = if entry.is_dir { "/" } else { "" };
+                let base = request_path.trim_end_matches('/');
+                let href = if base.is_empty() {
+                    format!("/{}{}", name, suffix)
+                } else {
+                    format!("{}/{}{}", base, name, suffix)
+                };
+                items.push(format!("<li><a href=\"{}\">{}{}</a></li>", href, name, suffix));
             }
         }
 
@@ -1156,13 +2838,14 @@ impl Server {
         )
     }
 
-    fn attach_session_cookie(
-        &mut self,
-        token: Token,
-        headers: &std::collections::HashMap<String, String>
-    ) {
-        if let Some(sid) = headers.get("cookie").and_then(|v| Self::extract_session_id(v.as_str())) {
-            self.sessions.insert(sid, Instant::now());
+    fn attach_session_cookie(&mut self, token: Token, cookies: &HashMap<String, String>) {
+        // Only IDs this server issued (and signed) are accepted; anything else gets a fresh session
+        if
+            let Some(sid) = cookies
+                .get(SESSION_COOKIE)
+                .and_then(|signed| hmac::verify(&self.session_secret, signed))
+        {
+            self.sessions.insert(sid, self.now());
             return;
         }
 
@@ -1172,27 +2855,50 @@ impl Server {
             .unwrap_or(0);
         let sid = format!("s{}-{}", self.next_session_id, now_ms);
         self.next_session_id += 1;
-        self.sessions.insert(sid.clone(), Instant::now());
+        self.sessions.insert(sid.clone(), self.now());
 
-        if let Some(conn) = self.connections.get_mut(&token) {
-            conn.response_headers.push((
-                "Set-Cookie".to_string(),
-                format!("SESSION_ID={}; Path=/; HttpOnly", sid),
-            ));
+        let signed = hmac::sign(&self.session_secret, &sid);
+        if let Some(conn) = self.connections.get_mut(&token) && let Some(cookie) = SetCookie::new(SESSION_COOKIE, &signed) {
+            conn.response_headers.push(("Set-Cookie".to_string(), cookie.path("/").http_only().to_string()));
+        }
+    }
+
+    /// Returns the signed CSRF token from the client's cookie, issuing a fresh one if it is missing or forged.
+    fn issue_csrf_token(&mut self, token: Token, cookies: &HashMap<String, String>) -> String {
+        if let Some(existing) = cookies.get("CSRF_TOKEN") && hmac::verify(&self.session_secret, existing).is_some() {
+            return existing.clone();
+        }
+
+        let signed = hmac::sign(&self.session_secret, &crypto::to_hex(&crypto::random_bytes(16)));
+        if let Some(conn) = self.connections.get_mut(&token) && let Some(cookie) = SetCookie::new("CSRF_TOKEN", &signed) {
+            conn.response_headers.push(("Set-Cookie".to_string(), cookie.path("/").same_site(SameSite::Strict).to_string()));
         }
+        signed
+    }
+
+    /// Double-submit check: the `csrf_token` form field or `X-CSRF-Token` header must echo the cookie.
+    fn csrf_token_submitted(
+        expected: &str,
+        headers: &std::collections::HashMap<String, String>,
+        form: Option<&crate::http::request::MultipartForm>
+    ) -> bool {
+        let submitted = form
+            .and_then(|f| f.field("csrf_token"))
+            .or(headers.get("x-csrf-token").map(|v| v.as_str()));
+        submitted.is_some_and(|s| crypto::constant_time_eq(s.trim().as_bytes(), expected.as_bytes()))
     }
 
-    fn extract_session_id(cookie_header: &str) -> Option<String> {
-        for part in cookie_header.split(';') {
-            let kv = part.trim();
-            if let Some(value) = kv.strip_prefix("SESSION_ID=") {
-                let clean = value.trim();
-                if !clean.is_empty() {
-                    return Some(clean.to_string());
+    /// Queues `add_headers` from the config; a route entry replaces a server
+    /// entry of the same name. `Set-Cookie` is added to, never replaced.
+    fn apply_configured_headers(&mut self, token: Token, headers: &[(String, String)]) {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            for (name, value) in headers {
+                if !name.eq_ignore_ascii_case("set-cookie") {
+                    conn.response_headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
                 }
+                conn.response_headers.push((name.clone(), value.clone()));
             }
         }
-        None
     }
 
     fn apply_connection_headers_to_raw_response(
@@ -1204,7 +2910,31 @@ impl Server {
             Some(conn) => std::mem::take(&mut conn.response_headers),
             None => Vec::new(),
         };
+        Self::insert_headers(response_bytes, extra_headers)
+    }
+
+    /// `response` with `Connection: keep-alive` instead of `close`, when the
+    /// client can tell where its body ends; `None` when it cannot.
+    fn keep_alive(response: &[u8]) -> Option<Vec<u8>> {
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n")? + 2;
+        let head = std::str::from_utf8(&response[..head_end]).ok()?;
+        let status = Self::response_status(response)?;
+        let framed = status == StatusCode::NO_CONTENT ||
+            status == StatusCode::NOT_MODIFIED ||
+            head.lines().any(|line| line.to_ascii_lowercase().starts_with("content-length:"));
+        if !framed || head.lines().any(|line| line.to_ascii_lowercase().starts_with("transfer-encoding:")) {
+            return None;
+        }
+        let pos = head.find("\r\nConnection: close\r\n")?;
+        let mut kept = Vec::with_capacity(response.len() + 5);
+        kept.extend_from_slice(&response[..pos]);
+        kept.extend_from_slice(b"\r\nConnection: keep-alive\r\n");
+        kept.extend_from_slice(&response[pos + "\r\nConnection: close\r\n".len()..]);
+        Some(kept)
+    }
 
+    /// Adds header lines at the end of a serialized response's head.
+    fn insert_headers(response_bytes: Vec<u8>, extra_headers: Vec<(String, String)>) -> Vec<u8> {
         if extra_headers.is_empty() {
             return response_bytes;
         }
@@ -1233,41 +2963,69 @@ impl Server {
         &self,
         form: crate::http::request::MultipartForm,
         upload_dir: &std::path::Path
-    ) -> Result<(), String> {
+    ) -> Result<(), ServerError> {
         // Convention: Create the "uploads" folder if it doesn't exist inside the root
         if !upload_dir.exists() {
-            std::fs::create_dir_all(upload_dir).map_err(|e| e.to_string())?;
+            std::fs::create_dir_all(upload_dir).map_err(ServerError::io(upload_dir.display().to_string()))?;
         }
 
         for file in form.files {
             let safe_name = std::path::Path
                 ::new(&file.file_name)
                 .file_name()
-                .ok_or("Invalid filename")?;
+                .ok_or_else(|| ServerError::Io {
+                    context: file.file_name.clone(),
+                    source: io::Error::new(io::ErrorKind::InvalidInput, "Invalid filename"),
+                })?;
             let mut dest = upload_dir.to_path_buf();
             dest.push(safe_name);
 
-            std::fs::write(&dest, &file.data).map_err(|e| e.to_string())?;
-            println!("[Upload] Multipart saved to: {:?}", dest);
+            std::fs::write(&dest, &file.data).map_err(ServerError::io(dest.display().to_string()))?;
+            log::debug!("[Upload] Multipart saved to: {:?}", dest);
         }
         Ok(())
     }
 
+    /// `verify_uploads`: every type the upload claims, by its extension or its
+    /// `Content-Type`, must match its signature, and a native program is only
+    /// accepted under a name that makes it opaque binary data.
+    fn upload_content_allowed(&self, file_name: &str, declared: Option<&str>, data: &[u8]) -> bool {
+        let implied = self.mime_types.lookup(file_name);
+        let mut claimed = [Some(implied), declared].into_iter().flatten();
+        if let Some(mime) = claimed.find(|mime| magic::signature_matches(mime, data) == Some(false)) {
+            log::warning!("[Upload] '{}' does not look like {}", file_name, mime);
+            return false;
+        }
+        if magic::is_executable(data) && implied != "application/octet-stream" {
+            log::warning!("[Upload] '{}' is a program posing as {}", file_name, implied);
+            return false;
+        }
+        true
+    }
+
     fn handle_raw_upload(
         &self,
         body: &[u8],
         upload_dir: &std::path::Path,
         filename: &str
-    ) -> Result<(), String> {
+    ) -> Result<(), ServerError> {
         if !upload_dir.exists() {
-            std::fs::create_dir_all(upload_dir).map_err(|e| e.to_string())?;
+            std::fs::create_dir_all(upload_dir).map_err(ServerError::io(upload_dir.display().to_string()))?;
         }
 
         let mut dest = upload_dir.to_path_buf();
         dest.push(filename);
 
-        std::fs::write(&dest, body).map_err(|e| e.to_string())?;
-        println!("[Upload] Raw Body saved to: {:?}", dest);
+        std::fs::write(&dest, body).map_err(ServerError::io(dest.display().to_string()))?;
+        log::debug!("[Upload] Raw Body saved to: {:?}", dest);
         Ok(())
     }
 }
+
+/// Also runs when a panic unwinds out of the event loop, so no CGI script or
+/// WebSocket handler outlives the server that started it.
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop_children();
+    }
+}
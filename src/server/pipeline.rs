@@ -0,0 +1,280 @@
+//! How a parsed request becomes a response. `process_request` builds a
+//! `Request` and hands it to the `Pipeline`: every middleware's `before` runs
+//! in order, then the handlers are tried in order until one answers. Each
+//! response is passed through the middlewares' `after` hooks just before it is
+//! queued, whether it came from a handler, an error page or a CGI script.
+
+use crate::auth::AuthIdentity;
+use crate::config::models::RouteConfig;
+use crate::http::cookie::SetCookie;
+use crate::http::status::StatusCode;
+use crate::log;
+use crate::server::sendfile::FileBody;
+use crate::server::{ handler, middleware, Server };
+use crate::vfs::{ Vfs, MOUNT_PREFIX };
+use mio::Token;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::{ IpAddr, SocketAddr };
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{ Duration, Instant };
+
+/// One request as it moves through the pipeline. Middlewares fill in the
+/// later fields (`route`, `fs_path`, ...) for the stages after them.
+pub struct Request {
+    pub method: String,
+    /// Path and query string as sent.
+    pub uri: String,
+    pub path: String,
+    pub query: String,
+    /// Lowercase names.
+    pub headers: HashMap<String, String>,
+    /// From the `Cookie` header, see `cookie::parse`.
+    pub cookies: HashMap<String, String>,
+    pub body: Vec<u8>,
+    /// Server block chosen by the Host header.
+    pub server_idx: usize,
+    pub context: RequestContext,
+    /// Set by route lookup; `None` before it runs.
+    pub route: Option<RouteConfig>,
+    /// Where the route's files live: the real filesystem, or a tree mounted
+    /// with `Server::mount` for `root: "vfs:<name>"`.
+    pub vfs: Rc<dyn Vfs>,
+    /// File the request maps to, as a path within `vfs`.
+    pub fs_path: Option<PathBuf>,
+    pub csrf_token: Option<String>,
+    pub(crate) identity: Option<AuthIdentity>,
+}
+
+impl Request {
+    /// True when the route runs this path as a CGI script.
+    pub fn is_cgi(&self) -> bool {
+        self.route
+            .as_ref()
+            .and_then(|r| r.cgi_extension.as_ref())
+            .is_some_and(|ext| self.path.ends_with(ext))
+    }
+
+    /// True when the route's files come from a mounted tree rather than a directory.
+    pub fn is_mounted(&self) -> bool {
+        self.route.as_ref().is_some_and(|r| r.root.starts_with(MOUNT_PREFIX))
+    }
+}
+
+/// Who sent the request and when, for logging, timing and access rules.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    /// Unique per request; error pages can show it as `{{request_id}}`.
+    pub request_id: String,
+    /// TCP peer of the connection; behind a proxy this is the proxy.
+    pub peer: SocketAddr,
+    /// The real client: from the PROXY protocol or `trusted_proxies` once the
+    /// client address middleware has run, else the peer's address.
+    pub client_ip: IpAddr,
+    /// When the first byte of the request arrived, on the server's clock.
+    pub started: Instant,
+}
+
+impl RequestContext {
+    /// Time since `started` on the server's clock, so a test clock moves it too.
+    pub fn elapsed(&self, server: &Server) -> Duration {
+        server.now().saturating_duration_since(self.started)
+    }
+}
+
+/// A complete response produced without touching the connection.
+pub struct Response {
+    pub status: StatusCode,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Sent after `body` a chunk at a time as the client takes it, so it is
+    /// never held in memory whole; `after` hooks only see what comes before it.
+    pub stream: Option<Box<dyn Read>>,
+    /// A file on disk sent after `body` with `sendfile`, see `Response::file`.
+    pub(crate) file: Option<FileBody>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self { status, content_type: content_type.to_string(), headers: Vec::new(), body: body.into(), stream: None, file: None }
+    }
+
+    /// A body of `len` bytes read from `reader` while it is sent; anything past `len` is left unread.
+    pub fn streamed(status: StatusCode, content_type: &str, reader: Box<dyn Read>, len: u64) -> Self {
+        let mut response = Self::new(status, content_type, Vec::new());
+        response.headers.push(("Content-Length".to_string(), len.to_string()));
+        response.stream = Some(Box::new(reader.take(len)));
+        response
+    }
+
+    /// The first `len` bytes of `file`, copied to the socket by the kernel
+    /// where it can (`sendfile`) and streamed otherwise.
+    pub fn file(status: StatusCode, content_type: &str, file: File, len: u64) -> Self {
+        let mut response = Self::new(status, content_type, Vec::new());
+        response.headers.push(("Content-Length".to_string(), len.to_string()));
+        response.file = Some(FileBody::new(file, len));
+        response
+    }
+
+    /// Adds a `Set-Cookie` header; several cookies each get their own.
+    pub fn set_cookie(&mut self, cookie: &SetCookie) {
+        self.headers.push(("Set-Cookie".to_string(), cookie.to_string()));
+    }
+}
+
+/// What a stage decided.
+pub enum Flow {
+    /// Carry on with the next stage.
+    Next,
+    /// Answer with this status and the server's error page.
+    Error(StatusCode),
+    Reply(Response),
+    /// The stage already answered or took the connection over (CGI, tunnel, ...).
+    Done,
+}
+
+/// Produces the answer for a request, or `Flow::Next` when it is not for this handler.
+pub trait Handler {
+    fn handle(&self, server: &mut Server, token: Token, request: &mut Request) -> Flow;
+}
+
+/// Cross-cutting behavior around the handlers.
+pub trait Middleware {
+    /// Runs before the handlers; anything but `Flow::Next` ends the request here.
+    fn before(&self, _server: &mut Server, _token: Token, _request: &mut Request) -> Flow {
+        Flow::Next
+    }
+
+    /// Sees every serialized response for the connection just before it is queued.
+    fn after(&self, _server: &mut Server, _token: Token, _response: &mut Vec<u8>) {}
+}
+
+/// `Pipeline::register_fn` wraps a closure in this.
+struct FnHandler<F>(F);
+
+impl<F: Fn(&Request) -> Response> Handler for FnHandler<F> {
+    fn handle(&self, _server: &mut Server, _token: Token, request: &mut Request) -> Flow {
+        Flow::Reply((self.0)(request))
+    }
+}
+
+pub struct Pipeline {
+    middlewares: Vec<Box<dyn Middleware>>,
+    handlers: Vec<Box<dyn Handler>>,
+    /// Handlers that routes select by name with `handler:`.
+    named: HashMap<String, Box<dyn Handler>>,
+}
+
+impl Pipeline {
+    /// The built-in stages, in the order the server has always applied them.
+    pub fn standard() -> Self {
+        Self {
+            middlewares: vec![
+                Box::new(middleware::ClientAddress),
+                Box::new(middleware::RequestHooks),
+                Box::new(middleware::Maintenance),
+                Box::new(middleware::LiveReload),
+                Box::new(middleware::ConnectTunnel),
+                Box::new(middleware::ServerHeaders),
+                Box::new(middleware::UploadProgress),
+                Box::new(middleware::UpstreamStatus),
+                Box::new(middleware::Purge),
+                Box::new(middleware::HttpsRedirect),
+                Box::new(middleware::Session),
+                Box::new(middleware::UploadedFiles),
+                Box::new(middleware::RouteLookup),
+                Box::new(middleware::RouteRedirect),
+                Box::new(middleware::RouteReturn),
+                Box::new(middleware::Auth),
+                Box::new(middleware::AllowedMethods),
+                Box::new(middleware::BodyDecoding),
+                Box::new(middleware::Csrf),
+                Box::new(middleware::Mirror),
+                Box::new(middleware::ResponseFilter),
+                Box::new(middleware::AccessLog),
+                Box::new(middleware::ClientErrorBan)
+            ],
+            handlers: vec![
+                Box::new(handler::Proxy),
+                Box::new(handler::Upload),
+                Box::new(handler::Delete),
+                Box::new(handler::Directory),
+                Box::new(handler::Cgi),
+                Box::new(handler::Markdown),
+                Box::new(handler::BuiltinFiles),
+                Box::new(handler::StaticFile)
+            ],
+            named: HashMap::new(),
+        }
+    }
+
+    /// Appended after the built-in middlewares, so routing and auth have already run.
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Tried before the built-in handlers.
+    pub fn add_handler(&mut self, handler: Box<dyn Handler>) {
+        self.handlers.insert(0, handler);
+    }
+
+    /// Makes `name` available to routes as `handler: name`. The route's
+    /// middlewares (auth, allowed methods, ...) still run first; `Flow::Next`
+    /// from the handler falls through to the built-in ones.
+    pub fn register_handler(&mut self, name: &str, handler: Box<dyn Handler>) {
+        self.named.insert(name.to_string(), handler);
+    }
+
+    /// `register_handler` for an endpoint that only needs the request.
+    pub fn register_fn(&mut self, name: &str, handler: impl Fn(&Request) -> Response + 'static) {
+        self.register_handler(name, Box::new(FnHandler(handler)));
+    }
+
+    pub fn has_handler(&self, name: &str) -> bool {
+        self.named.contains_key(name)
+    }
+
+    pub(crate) fn run(&self, server: &mut Server, token: Token, request: &mut Request) {
+        for middleware in &self.middlewares {
+            match middleware.before(server, token, request) {
+                Flow::Next => {}
+                flow => {
+                    server.finish(token, flow);
+                    return;
+                }
+            }
+        }
+        if let Some(name) = request.route.as_ref().and_then(|r| r.handler.clone()) {
+            let flow = match self.named.get(&name) {
+                Some(handler) => handler.handle(server, token, request),
+                None => {
+                    log::error!("[Handler] Route '{}' uses unregistered handler '{}'", request.path, name);
+                    Flow::Error(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            };
+            if !matches!(flow, Flow::Next) {
+                server.finish(token, flow);
+                return;
+            }
+        }
+        for handler in &self.handlers {
+            match handler.handle(server, token, request) {
+                Flow::Next => {}
+                flow => {
+                    server.finish(token, flow);
+                    return;
+                }
+            }
+        }
+        server.send_error(token, StatusCode::NOT_FOUND);
+    }
+
+    pub(crate) fn after(&self, server: &mut Server, token: Token, response: &mut Vec<u8>) {
+        for middleware in &self.middlewares {
+            middleware.after(server, token, response);
+        }
+    }
+}
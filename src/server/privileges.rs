@@ -0,0 +1,105 @@
+use crate::error::ServerError;
+use crate::log;
+
+unsafe extern "C" {
+    fn chroot(path: *const std::ffi::c_char) -> i32;
+    fn setuid(uid: u32) -> i32;
+    fn setgid(gid: u32) -> i32;
+    fn setgroups(size: usize, list: *const u32) -> i32;
+    fn getuid() -> u32;
+    fn getgid() -> u32;
+}
+
+/// Confines the process to `dir`: afterwards every static, upload, and CGI path
+/// (including relative ones, which now start from the jail root) resolves inside it.
+fn enter_chroot(dir: &str) -> Result<(), ServerError> {
+    let path = std::ffi::CString::new(dir).map_err(|_| ServerError::Config(format!("Invalid chroot path: {}", dir)))?;
+    if unsafe { chroot(path.as_ptr()) } != 0 {
+        return Err(ServerError::last_os_error(format!("chroot({}) failed", dir)));
+    }
+    std::env::set_current_dir("/").map_err(ServerError::io("chdir into chroot failed"))?;
+
+    log::info!("[Setup] Jailed to {}", dir);
+    Ok(())
+}
+
+/// Optionally jails the process, then switches it to `user`/`group` (names or numeric ids).
+/// Must run after the listeners are bound and before any request is served.
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    chroot_dir: Option<&str>
+) -> Result<(), ServerError> {
+    if user.is_none() && group.is_none() && chroot_dir.is_none() {
+        return Ok(());
+    }
+
+    // Names are resolved first: /etc/passwd is usually not reachable from inside the jail
+    let (uid, primary_gid) = match user {
+        Some(name) => {
+            let (uid, gid) = lookup_user(name)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(name) => Some(lookup_group(name)?),
+        None => primary_gid,
+    };
+
+    if let Some(dir) = chroot_dir {
+        enter_chroot(dir)?;
+    }
+
+    // Order matters: supplementary groups and gid can only be changed while still root
+    if let Some(gid) = gid {
+        if unsafe { getuid() } == 0 && unsafe { setgroups(1, &gid) } != 0 {
+            return Err(ServerError::last_os_error("setgroups failed"));
+        }
+        if unsafe { setgid(gid) } != 0 {
+            return Err(ServerError::last_os_error(format!("setgid({}) failed", gid)));
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { setuid(uid) } != 0 {
+            return Err(ServerError::last_os_error(format!("setuid({}) failed", uid)));
+        }
+        if uid != 0 && unsafe { setuid(0) } == 0 {
+            return Err(ServerError::Config("Privilege drop is reversible, refusing to continue".to_string()));
+        }
+    }
+
+    log::info!("[Setup] Running as uid {} gid {}", unsafe { getuid() }, unsafe { getgid() });
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<(u32, u32), String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        if fields[0] == name || fields[2] == name {
+            let uid = fields[2].parse().map_err(|_| format!("Bad uid for user {}", name))?;
+            let gid = fields[3].parse().map_err(|_| format!("Bad gid for user {}", name))?;
+            return Ok((uid, gid));
+        }
+    }
+
+    // Numeric ids without a passwd entry keep their own number as primary group
+    name.parse::<u32>()
+        .map(|uid| (uid, uid))
+        .map_err(|_| format!("Unknown user: {}", name))
+}
+
+fn lookup_group(name: &str) -> Result<u32, String> {
+    let groups = std::fs::read_to_string("/etc/group").unwrap_or_default();
+    for line in groups.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() >= 3 && (fields[0] == name || fields[2] == name) {
+            return fields[2].parse().map_err(|_| format!("Bad gid for group {}", name));
+        }
+    }
+    name.parse::<u32>().map_err(|_| format!("Unknown group: {}", name))
+}
@@ -0,0 +1,1685 @@
+//! `proxy_pass` routes: which upstream server a request goes to, how it is
+//! rewritten for it, and how the upstream's response head is rewritten for the
+//! client. Below those, the event-loop side of every upstream socket the server
+//! opens: proxied requests, CONNECT tunnels and mirrors.
+
+use crate::config::models::{ Balance, HealthCheck, Probe, ProxyCache, RouteConfig, Sticky };
+use crate::crypto;
+use crate::http::cookie::{ SameSite, SetCookie };
+use crate::http::date;
+use crate::http::status::StatusCode;
+use crate::http::websocket;
+use crate::log;
+use crate::server::cgi_cache::{ self, CacheKey };
+use crate::server::connection::ConnectionState;
+use crate::server::resolve::Lookup;
+use crate::server::Server;
+use mio::net::TcpStream;
+use mio::{ Interest, Token };
+use std::collections::HashMap;
+use std::io::{ self, Read, Write };
+use std::net::{ IpAddr, SocketAddr };
+use std::time::{ Duration, Instant, SystemTime };
+
+/// Larger upstream response heads are refused with 502.
+pub const MAX_HEAD: usize = 64 * 1024;
+
+/// A relay stops reading one side while this much is still waiting to be
+/// written to the other, and carries on once it has all gone out.
+const QUEUE_LIMIT: usize = 256 * 1024;
+
+/// Methods that may be sent again after an upstream took them and failed (RFC 9110 §9.2.2).
+const IDEMPOTENT: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+/// Headers that describe one connection rather than the message (RFC 9110 §7.6.1).
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A `proxy_pass` target, `http://host:port/prefix`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upstream {
+    /// As written, for the `Host` header.
+    pub host: String,
+    /// `host:port` to connect to; port 80 when none is given.
+    pub address: String,
+    /// Replaces the route's path in the request URI; empty keeps the URI as sent.
+    pub path: String,
+}
+
+impl Upstream {
+    /// `None` unless the target is `http://` followed by a host.
+    pub fn parse(target: &str) -> Option<Self> {
+        let rest = target.strip_prefix("http://")?;
+        let (host, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '@' || c == '?' || c == '#') {
+            return None;
+        }
+        let has_port = match host.rsplit_once(':') {
+            Some((_, port)) => !port.contains(']'),
+            None => false,
+        };
+        let address = if has_port { host.to_string() } else { format!("{}:80", host) };
+        Some(Self { host: host.to_string(), address, path: path.to_string() })
+    }
+
+    /// Like `parse`, but also takes a bare `host:port[/prefix]`, as `mirror` does.
+    pub fn parse_bare(target: &str) -> Option<Self> {
+        match target.starts_with("http://") {
+            true => Self::parse(target),
+            false => Self::parse(&format!("http://{}", target)),
+        }
+    }
+
+    /// The URI to ask the upstream for: `uri` with the route's path swapped for
+    /// the target's, so `/api` → `http://b:8080/v1` sends `/api/users` as `/v1/users`.
+    pub fn uri(&self, uri: &str, route_path: &str) -> String {
+        if self.path.is_empty() {
+            return uri.to_string();
+        }
+        let rest = uri.strip_prefix(route_path).unwrap_or(uri);
+        match (self.path.ends_with('/'), rest.starts_with('/')) {
+            (true, true) => format!("{}{}", self.path, &rest[1..]),
+            (false, false) if !rest.is_empty() && !rest.starts_with('?') => format!("{}/{}", self.path, rest),
+            _ => format!("{}{}", self.path, rest),
+        }
+    }
+
+    /// What the `proxy_sticky: cookie` cookie holds for this upstream: stable
+    /// across restarts and reorderings, without giving the address away.
+    pub fn id(&self) -> String {
+        crypto::to_hex(&crypto::sha256::sha256(self.address.as_bytes())[..8])
+    }
+}
+
+/// A route's `proxy_pass` upstreams, then its `canary` ones.
+fn route_targets(route: &RouteConfig) -> impl Iterator<Item = &String> {
+    route.proxy_pass.iter().chain(route.canary.iter().flat_map(|canary| &canary.proxy_pass))
+}
+
+/// The name of the `proxy_sticky: cookie` cookie.
+pub const STICKY_COOKIE: &str = "UPSTREAM_ID";
+
+/// Index of the upstream `sticky` keeps this client on: the one its cookie
+/// names, or one picked by its address.
+pub fn affinity(sticky: Sticky, client: IpAddr, cookie: Option<&str>, upstreams: &[Upstream]) -> Option<usize> {
+    match sticky {
+        Sticky::IpHash => {
+            let octets = match client {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            let hash = crypto::sha256::sha256(&octets);
+            let mut first = [0u8; 8];
+            first.copy_from_slice(&hash[..8]);
+            u64::from_be_bytes(first).checked_rem(upstreams.len() as u64).map(|idx| idx as usize)
+        }
+        Sticky::Cookie => {
+            let cookie = cookie?;
+            upstreams.iter().position(|upstream| upstream.id() == cookie)
+        }
+    }
+}
+
+/// Spreads requests over a route's upstreams and remembers which failed
+/// recently or fail their health checks. Upstreams are known by address, so
+/// routes sharing one also share its count of requests in flight and its
+/// failures.
+#[derive(Default)]
+pub struct Balancer {
+    /// Where round-robin carries on, per list of upstreams.
+    turns: HashMap<Vec<String>, usize>,
+    active: HashMap<String, usize>,
+    down_until: HashMap<String, Instant>,
+    health: HashMap<String, Health>,
+}
+
+/// Probe results of a `proxy_health` upstream: its state, and how many probes
+/// in a row went the other way.
+#[derive(Default)]
+struct Health {
+    unhealthy: bool,
+    streak: usize,
+}
+
+impl Balancer {
+    /// Index of the upstream for the next attempt, leaving out those in `tried`
+    /// and, unless every one left is down, those still cooling off after a
+    /// failure. `None` once all of them were tried.
+    pub fn pick(&mut self, upstreams: &[Upstream], balance: Balance, tried: &[usize], now: Instant) -> Option<usize> {
+        let left: Vec<usize> = (0..upstreams.len()).filter(|idx| !tried.contains(idx)).collect();
+        let up: Vec<usize> = left
+            .iter()
+            .copied()
+            .filter(|&idx| !self.is_down(&upstreams[idx].address, now))
+            .collect();
+        let mut candidates = if up.is_empty() { left } else { up };
+
+        // In turn from where the last pick left off, so ties rotate as well
+        let key: Vec<String> = upstreams.iter().map(|upstream| upstream.address.clone()).collect();
+        let count = upstreams.len();
+        let turn = self.turns.get(&key).copied().unwrap_or(0);
+        candidates.sort_by_key(|&idx| (idx + count - turn) % count);
+        let chosen = match balance {
+            Balance::RoundRobin => *candidates.first()?,
+            Balance::LeastConnections => *candidates.iter().min_by_key(|&&idx| self.active(&upstreams[idx].address))?,
+        };
+        self.turns.insert(key, (chosen + 1) % count);
+        Some(chosen)
+    }
+
+    /// Requests in flight to `address`.
+    pub fn active(&self, address: &str) -> usize {
+        self.active.get(address).copied().unwrap_or(0)
+    }
+
+    /// Cooling off after a failure, or failing its health checks.
+    pub fn is_down(&self, address: &str, now: Instant) -> bool {
+        self.is_cooling_off(address, now) || !self.is_healthy(address)
+    }
+
+    pub fn is_cooling_off(&self, address: &str, now: Instant) -> bool {
+        self.down_until.get(address).is_some_and(|until| *until > now)
+    }
+
+    /// Upstreams are healthy until `check.fails` probes in a row fail.
+    pub fn is_healthy(&self, address: &str) -> bool {
+        self.health.get(address).is_none_or(|health| !health.unhealthy)
+    }
+
+    /// Records a health probe of `address`. Returns its new state when this
+    /// probe changed it: after `check.fails` failures in a row for a healthy
+    /// upstream, `check.passes` successes for an unhealthy one.
+    pub fn probed(&mut self, address: &str, ok: bool, check: &HealthCheck) -> Option<bool> {
+        let health = self.health.entry(address.to_string()).or_default();
+        if ok != health.unhealthy {
+            health.streak = 0;
+            return None;
+        }
+        health.streak += 1;
+        let needed = if health.unhealthy { check.passes } else { check.fails };
+        if health.streak < needed {
+            return None;
+        }
+        health.unhealthy = !health.unhealthy;
+        health.streak = 0;
+        Some(!health.unhealthy)
+    }
+
+    /// `{"upstreams":[{"address":"a:80","healthy":true,"cooling_off":false,"active":0}]}`.
+    pub fn report(&self, addresses: &[String], now: Instant) -> String {
+        let upstreams: Vec<String> = addresses
+            .iter()
+            .map(|address| {
+                format!(
+                    "{{\"address\":\"{}\",\"healthy\":{},\"cooling_off\":{},\"active\":{}}}",
+                    address.replace('\\', "\\\\").replace('"', "\\\""),
+                    self.is_healthy(address),
+                    self.is_cooling_off(address, now),
+                    self.active(address)
+                )
+            })
+            .collect();
+        format!("{{\"upstreams\":[{}]}}", upstreams.join(","))
+    }
+
+    pub fn opened(&mut self, address: &str) {
+        *self.active.entry(address.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn closed(&mut self, address: &str) {
+        if let Some(count) = self.active.get_mut(address) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Skips `address` for `cooldown` from `now`.
+    pub fn failed(&mut self, address: &str, now: Instant, cooldown: Duration) {
+        self.down_until.insert(address.to_string(), now + cooldown);
+    }
+}
+
+/// A request on its way to one of the route's upstreams; an attempt that fails
+/// before any answer comes back is retried on the next one not yet tried.
+pub(crate) struct Forward {
+    pub method: String,
+    pub uri: String,
+    pub route_path: String,
+    pub headers: HashMap<String, String>,
+    pub peer: IpAddr,
+    pub body: Vec<u8>,
+    pub upstreams: Vec<Upstream>,
+    pub balance: Balance,
+    pub fail_timeout: Duration,
+    /// Send a `Forwarded` header as well as the `X-Forwarded-*` ones.
+    pub forwarded: bool,
+    /// Upstreams to try at most; 0 tries each of them once.
+    pub tries: usize,
+    /// How long after `started` a failed attempt may still be retried; zero for no limit.
+    pub try_budget: Duration,
+    /// When the request arrived, on the server's clock.
+    pub started: Instant,
+    /// Indexes into `upstreams`.
+    pub tried: Vec<usize>,
+    /// The upstream `proxy_sticky` keeps the client on, tried first while it is up.
+    pub affinity: Option<usize>,
+    /// Tell the client which upstream answered in the `UPSTREAM_ID` cookie.
+    pub sticky_cookie: bool,
+    /// Where a `proxy_cache` route keeps the answer.
+    pub cache: Option<(CacheKey, ProxyCache)>,
+}
+
+impl Forward {
+    /// The whole request as `upstream` gets it.
+    pub fn message(&self, upstream: &Upstream) -> Vec<u8> {
+        let uri = upstream.uri(&self.uri, &self.route_path);
+        let mut message = request_head(
+            &self.method,
+            &uri,
+            &self.headers,
+            upstream,
+            self.peer,
+            self.body.len(),
+            self.forwarded
+        );
+        message.extend_from_slice(&self.body);
+        message
+    }
+}
+
+/// Hop-by-hop headers, and those the `Connection` header names, are dropped.
+fn forwardable<'a>(headers: impl Iterator<Item = (&'a str, &'a str)> + Clone) -> Vec<(&'a str, &'a str)> {
+    let named: Vec<String> = headers
+        .clone()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect();
+    headers
+        .filter(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !HOP_BY_HOP.contains(&name.as_str()) && !named.contains(&name)
+        })
+        .collect()
+}
+
+/// A WebSocket opening handshake, which is passed upstream with its `Upgrade`
+/// so the connection can become a tunnel once the upstream agrees.
+pub fn is_upgrade(method: &str, headers: &HashMap<String, String>) -> bool {
+    let connection_upgrade = headers
+        .get("connection")
+        .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+    method == "GET" && connection_upgrade && websocket::is_upgrade_request(headers)
+}
+
+/// The head of the request sent upstream. It is HTTP/1.0, so the response is
+/// never chunked and ends when the upstream closes; a WebSocket handshake
+/// (`is_upgrade`) goes as HTTP/1.1 and keeps its `Upgrade`. `Host` names the upstream
+/// and the client's own goes in `X-Forwarded-Host`; `peer` is appended to
+/// `X-Forwarded-For`, and with `rfc7239` to `Forwarded` as well. The body,
+/// already de-chunked, follows with its length.
+pub fn request_head(
+    method: &str,
+    uri: &str,
+    headers: &HashMap<String, String>,
+    upstream: &Upstream,
+    peer: IpAddr,
+    body_len: usize,
+    rfc7239: bool
+) -> Vec<u8> {
+    let mut passed: Vec<(&str, &str)> = forwardable(headers.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .into_iter()
+        .filter(|(name, _)| {
+            !matches!(*name, "host" | "content-length" | "x-forwarded-for" | "x-forwarded-host" | "x-forwarded-proto")
+        })
+        .filter(|(name, _)| !rfc7239 || *name != "forwarded")
+        .collect();
+    passed.sort();
+
+    let upgrade = is_upgrade(method, headers);
+    let version = if upgrade { "HTTP/1.1" } else { "HTTP/1.0" };
+    let mut head = format!("{} {} {}\r\nHost: {}\r\n", method, uri, version, upstream.host);
+    for (name, value) in passed {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if rfc7239 {
+        let node = match peer {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        let mut element = format!("for={};proto=http", forwarded_value(&node));
+        if let Some(host) = headers.get("host") {
+            element.push_str(&format!(";host={}", forwarded_value(host)));
+        }
+        match headers.get("forwarded") {
+            Some(chain) => head.push_str(&format!("Forwarded: {}, {}\r\n", chain, element)),
+            None => head.push_str(&format!("Forwarded: {}\r\n", element)),
+        }
+    }
+    let forwarded_for = match headers.get("x-forwarded-for") {
+        Some(chain) => format!("{}, {}", chain, peer),
+        None => peer.to_string(),
+    };
+    head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    if let Some(host) = headers.get("host") {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+    head.push_str("X-Forwarded-Proto: http\r\n");
+    if body_len > 0 || headers.contains_key("content-length") {
+        head.push_str(&format!("Content-Length: {}\r\n", body_len));
+    }
+    match upgrade {
+        true => head.push_str("Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n"),
+        false => head.push_str("Connection: close\r\n\r\n"),
+    }
+    head.into_bytes()
+}
+
+/// The request exactly as the client sent it, framing and all, for a `mirror`
+/// target; only the URI in the request line changes, as for `proxy_pass`.
+pub fn mirror_request(raw: &[u8], upstream: &Upstream, route_path: &str) -> Vec<u8> {
+    let line_end = match raw.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None => {
+            return raw.to_vec();
+        }
+    };
+    let line = String::from_utf8_lossy(&raw[..line_end]);
+    let mut parts = line.splitn(3, ' ');
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(version)) => (method, uri, version),
+        _ => {
+            return raw.to_vec();
+        }
+    };
+    let mut message = format!("{} {} {}", method, upstream.uri(uri, route_path), version).into_bytes();
+    message.extend_from_slice(&raw[line_end..]);
+    message
+}
+
+/// A `Forwarded` parameter value: a token as it is, anything else quoted.
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    match token {
+        true => value.to_string(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+/// How long the upstream says its answer stays fresh: `s-maxage`, else
+/// `max-age`, else `Expires` counted from its `Date` (or `now` without one).
+/// `None` when it says nothing.
+pub fn freshness(head: &[u8], now: SystemTime) -> Option<Duration> {
+    let head = std::str::from_utf8(head).ok()?;
+    let header = |wanted: &str| {
+        head.split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.trim())
+    };
+    let directive = |wanted: &str| {
+        header("cache-control")?
+            .split(',')
+            .filter_map(|directive| directive.trim().split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+            .and_then(|(_, value)| value.trim().trim_matches('"').parse::<u64>().ok())
+    };
+    if let Some(seconds) = directive("s-maxage").or_else(|| directive("max-age")) {
+        return Some(Duration::from_secs(seconds));
+    }
+    // An `Expires` that does not parse means already expired
+    let expires = header("expires")?;
+    let sent = header("date").and_then(date::parse).unwrap_or(now);
+    Some(date::parse(expires).map_or(Duration::ZERO, |expires| expires.duration_since(sent).unwrap_or_default()))
+}
+
+/// What a `proxy_cache` route keeps of a whole upstream answer: the head as
+/// clients get it, with a `Content-Length` when the upstream closed instead,
+/// and the body; then how long it stays fresh and how long after that it may
+/// still be served stale. `None` when it may not be kept: not a 200, private
+/// to one client (see `cgi_cache`), varying on a header outside `cache.key`,
+/// cut short, or with no lifetime.
+pub fn cache_entry(
+    response: &[u8],
+    cache: &ProxyCache,
+    server_header: Option<&str>,
+    now: SystemTime
+) -> Option<(Vec<u8>, Duration, Duration)> {
+    let len = head_len(response)?;
+    if !cgi_cache::cacheable(response) {
+        return None;
+    }
+    let head = std::str::from_utf8(&response[..len]).ok()?;
+    let headers: Vec<(String, &str)> = head
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    let header = |wanted: &str| headers.iter().find(|(name, _)| name == wanted).map(|(_, value)| *value);
+
+    let vary_covered = headers
+        .iter()
+        .filter(|(name, _)| name == "vary")
+        .flat_map(|(_, value)| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .all(|name| cache.key.contains(&name));
+    if !vary_covered {
+        return None;
+    }
+    let fresh = match cache.force_ttl {
+        true => cache.ttl,
+        false => freshness(&response[..len], now).unwrap_or(cache.ttl),
+    };
+    if fresh.is_zero() {
+        return None;
+    }
+    let stale = header("cache-control")
+        .and_then(|value| {
+            value
+                .split(',')
+                .filter_map(|directive| directive.trim().split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("stale-while-revalidate"))
+                .and_then(|(_, seconds)| seconds.trim().parse::<u64>().ok())
+        })
+        .map_or(cache.stale, Duration::from_secs);
+
+    let body = &response[len..];
+    let mut copy = response_head(&response[..len], &[], server_header)?;
+    match (header("content-length"), header("transfer-encoding")) {
+        (Some(length), _) if length.parse::<usize>().ok() != Some(body.len()) => {
+            return None;
+        }
+        (None, None) => {
+            let closing = b"Connection: close\r\n\r\n".len();
+            copy.truncate(copy.len() - closing);
+            copy.extend_from_slice(format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()).as_bytes());
+        }
+        _ => {}
+    }
+    copy.extend_from_slice(body);
+    Some((copy, fresh, stale))
+}
+
+/// Length of the response head at the front of `buf`, blank line included.
+pub fn head_len(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+/// The upstream's head as sent to the client: HTTP/1.1, hop-by-hop headers
+/// dropped, `extra` in place of same-name ones (`Set-Cookie` is added to), the
+/// server's own `Server` header, and `Connection: close`. `Transfer-Encoding`
+/// stays, since the body is relayed with the framing it came with. `None` when
+/// the status line is not HTTP.
+pub fn response_head(head: &[u8], extra: &[(String, String)], server_header: Option<&str>) -> Option<Vec<u8>> {
+    rewrite_head(head, extra, server_header, "Connection: close\r\n")
+}
+
+/// The upstream's `101 Switching Protocols` for a WebSocket handshake, rewritten
+/// like `response_head` but keeping the connection open for the tunnel.
+pub fn upgrade_head(head: &[u8], extra: &[(String, String)], server_header: Option<&str>) -> Option<Vec<u8>> {
+    rewrite_head(head, extra, server_header, "Connection: Upgrade\r\nUpgrade: websocket\r\n")
+}
+
+fn rewrite_head(head: &[u8], extra: &[(String, String)], server_header: Option<&str>, connection: &str) -> Option<Vec<u8>> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+    let status_line = lines.next()?;
+    let (version, status) = status_line.split_once(' ')?;
+    let (code, reason) = status.split_once(' ').unwrap_or((status, ""));
+    if !version.starts_with("HTTP/1.") || code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let transfer_encoding = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding")).copied();
+    let replaced = |name: &str| {
+        !name.eq_ignore_ascii_case("set-cookie") && extra.iter().any(|(n, _)| n.eq_ignore_ascii_case(name))
+    };
+
+    let mut out = format!("HTTP/1.1 {} {}\r\n", code, reason);
+    for (name, value) in forwardable(headers.iter().copied()).into_iter().chain(transfer_encoding) {
+        if !name.eq_ignore_ascii_case("server") && !replaced(name) {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    for (name, value) in extra {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(server) = server_header {
+        out.push_str(&format!("Server: {}\r\n", server));
+    }
+    out.push_str(connection);
+    out.push_str("\r\n");
+    Some(out.into_bytes())
+}
+
+pub(crate) struct Tunnel {
+    /// `None` while the target's name is being looked up.
+    upstream: Option<TcpStream>,
+    upstream_token: Token,
+    established: bool,
+    /// Client bytes not yet written upstream, starting with any that came
+    /// in behind the CONNECT head.
+    to_upstream: Vec<u8>,
+    /// The upstream hung up; the client goes once it has the rest.
+    pub closing: bool,
+    /// Upstream reads stopped at `QUEUE_LIMIT` bytes queued for the client.
+    upstream_paused: bool,
+    /// Client reads stopped at `QUEUE_LIMIT` bytes in `to_upstream`.
+    client_paused: bool,
+}
+
+/// Fire-and-forget copy of a request sent to a route's `mirror` upstream.
+pub(crate) struct Mirror {
+    /// `None` while the target's name is being looked up.
+    stream: Option<TcpStream>,
+    request: Vec<u8>,
+    written: usize,
+    started_at: Instant,
+}
+
+/// A `proxy_pass` request: the upstream socket, the request still to be sent
+/// to it, and the start of its response until the head is complete.
+pub(crate) struct Proxied {
+    forward: Forward,
+    /// The upstream in `forward.upstreams` this attempt went to.
+    upstream_idx: usize,
+    /// `None` while the upstream's name is being looked up.
+    upstream: Option<TcpStream>,
+    upstream_token: Token,
+    connected: bool,
+    request: Vec<u8>,
+    written: usize,
+    head: Vec<u8>,
+    head_sent: bool,
+    /// Reads stopped at `QUEUE_LIMIT`.
+    paused: bool,
+    last_activity: Instant,
+    /// The answer as received, for `proxy_cache`; dropped once it outgrows the cache.
+    captured: Option<Vec<u8>>,
+}
+
+/// `proxy_health` probes in flight, and when each upstream address is due for
+/// its next one.
+#[derive(Default)]
+pub(crate) struct Probes {
+    running: HashMap<Token, HealthProbe>,
+    due: HashMap<String, Instant>,
+}
+
+impl Probes {
+    pub fn contains(&self, token: Token) -> bool {
+        self.running.contains_key(&token)
+    }
+}
+
+struct HealthProbe {
+    address: String,
+    check: HealthCheck,
+    /// `None` while the upstream's name is being looked up.
+    stream: Option<TcpStream>,
+    connected: bool,
+    /// Empty for a plain connection check.
+    request: Vec<u8>,
+    written: usize,
+    response: Vec<u8>,
+    started: Instant,
+}
+
+impl Server {
+    /// Forward-proxy CONNECT: opens a TCP connection to an allowed `host:port` and
+    /// relays bytes both ways once it is up. The connection is made when the
+    /// target's name has been looked up, off the event loop.
+    pub(crate) fn start_tunnel(&mut self, token: Token, server_idx: usize, authority: &str) {
+        let server_cfg = &self.config.servers[server_idx];
+        if !server_cfg.connect_proxy {
+            self.send_error(token, StatusCode::METHOD_NOT_ALLOWED);
+            return;
+        }
+        if !Self::connect_allowed(authority, &server_cfg.connect_allow) {
+            self.send_error(token, StatusCode::FORBIDDEN);
+            return;
+        }
+
+        let early = match self.open_tunnel(token) {
+            Some(early) => early,
+            None => {
+                return;
+            }
+        };
+
+        let upstream_token = self.allocate_token();
+        self.tunnels.insert(token, Tunnel {
+            upstream: None,
+            upstream_token,
+            established: false,
+            to_upstream: early,
+            closing: false,
+            upstream_paused: false,
+            client_paused: false,
+        });
+        self.tunnel_token_to_client.insert(upstream_token, token);
+
+        if let Lookup::Ready(addr) = self.resolver.lookup(upstream_token, authority) {
+            self.connect_tunnel(upstream_token, Some(addr));
+        }
+    }
+
+    /// Turns the client's connection into its end of a tunnel. Returns what it
+    /// sent behind the request head, which goes upstream first.
+    fn open_tunnel(&mut self, token: Token) -> Option<Vec<u8>> {
+        let now = self.now();
+        let conn = self.connections.get_mut(&token)?;
+        let early = conn.read_buffer.split_off(conn.request_len.min(conn.read_buffer.len()));
+        conn.read_buffer.clear();
+        conn.state = ConnectionState::Tunnel;
+        conn.last_activity = now;
+        let _ = self.poll.registry().reregister(&mut conn.stream, token, Interest::READABLE);
+        Some(early)
+    }
+
+    fn connect_tunnel(&mut self, upstream_token: Token, addr: Option<SocketAddr>) {
+        let client_token = match self.tunnel_token_to_client.get(&upstream_token) {
+            Some(token) => *token,
+            None => {
+                return;
+            }
+        };
+
+        let opened = match addr {
+            Some(addr) => self.open_upstream(addr, upstream_token).map_err(|e| e.to_string()),
+            None => Err("no address".to_string()),
+        };
+        match opened {
+            Ok(upstream) => {
+                if let Some(tunnel) = self.tunnels.get_mut(&client_token) {
+                    tunnel.upstream = Some(upstream);
+                }
+            }
+            Err(e) => {
+                log::warning!("[Tunnel] Connect for Token {:?} failed: {}", client_token, e);
+                self.remove_tunnel(client_token);
+                self.send_error(client_token, StatusCode::BAD_GATEWAY);
+            }
+        }
+    }
+
+    fn connect_allowed(authority: &str, allow: &[String]) -> bool {
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) => (h.trim_start_matches('[').trim_end_matches(']'), p),
+            None => {
+                return false;
+            }
+        };
+
+        allow.iter().any(|entry| {
+            let (allow_host, allow_port) = match entry.rsplit_once(':') {
+                Some((h, p)) => (h.trim_start_matches('[').trim_end_matches(']'), p),
+                None => (entry.as_str(), "*"),
+            };
+            let host_ok =
+                allow_host == "*" ||
+                allow_host.eq_ignore_ascii_case(host) ||
+                allow_host
+                    .strip_prefix("*.")
+                    .is_some_and(|suffix| {
+                        host.len() > suffix.len() + 1 &&
+                            host.to_ascii_lowercase().ends_with(&format!(".{}", suffix.to_ascii_lowercase()))
+                    });
+            host_ok && (allow_port == "*" || allow_port == port)
+        })
+    }
+
+
+    pub(crate) fn handle_tunnel_upstream_event(&mut self, upstream_token: Token, event: &mio::event::Event) {
+        let client_token = match self.tunnel_token_to_client.get(&upstream_token) {
+            Some(token) => *token,
+            None => {
+                return;
+            }
+        };
+
+        let established = self.tunnels.get(&client_token).is_some_and(|t| t.established);
+        if !established {
+            if !event.is_writable() && !event.is_error() && !event.is_write_closed() {
+                return;
+            }
+            let connected = self.tunnels
+                .get(&client_token)
+                .and_then(|t| t.upstream.as_ref())
+                .is_some_and(|upstream| matches!(upstream.take_error(), Ok(None)) && upstream.peer_addr().is_ok());
+            if !connected {
+                self.remove_tunnel(client_token);
+                self.send_error(client_token, StatusCode::BAD_GATEWAY);
+                return;
+            }
+
+            if let Some(tunnel) = self.tunnels.get_mut(&client_token) {
+                tunnel.established = true;
+            }
+            let response = b"HTTP/1.1 200 Connection Established\r\n\r\n".to_vec();
+            self.log_access(client_token, &response);
+            self.queue_stream_bytes(client_token, &response);
+        }
+
+        if event.is_writable() {
+            self.flush_tunnel_upstream(client_token);
+        }
+        if event.is_readable() || event.is_read_closed() {
+            self.read_tunnel_upstream(client_token);
+        }
+    }
+
+    fn read_tunnel_upstream(&mut self, client_token: Token) {
+        let now = self.now();
+        let room = self.client_room(client_token);
+        let mut data = Vec::new();
+        let mut finished = false;
+
+        if let Some(tunnel) = self.tunnels.get_mut(&client_token) && let Some(upstream) = tunnel.upstream.as_mut() {
+            let mut buf = [0u8; 8192];
+            loop {
+                if data.len() >= room {
+                    tunnel.upstream_paused = true;
+                    break;
+                }
+                match upstream.read(&mut buf) {
+                    Ok(0) => {
+                        finished = true;
+                        break;
+                    }
+                    Ok(n) => data.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(_) => {
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+            if finished {
+                tunnel.closing = true;
+            }
+        }
+
+        if !data.is_empty() && let Some(conn) = self.connections.get_mut(&client_token) {
+            // A client only listening, as on a WebSocket, is not idle
+            conn.last_activity = now;
+        }
+        if !data.is_empty() || finished {
+            // An empty push still wakes write_to_client so a closed tunnel gets torn down
+            self.queue_stream_bytes(client_token, &data);
+        }
+    }
+
+    pub(crate) fn read_tunnel_client(&mut self, token: Token) {
+        let now = self.now();
+        let room = match self.tunnels.get(&token) {
+            Some(tunnel) => QUEUE_LIMIT.saturating_sub(tunnel.to_upstream.len()),
+            None => {
+                return;
+            }
+        };
+        let mut peer_gone = false;
+        let mut data = Vec::new();
+
+        if let Some(conn) = self.connections.get_mut(&token) {
+            let mut buf = [0u8; 8192];
+            loop {
+                if data.len() >= room {
+                    break;
+                }
+                match conn.stream.read(&mut buf) {
+                    Ok(0) => {
+                        peer_gone = true;
+                        break;
+                    }
+                    Ok(n) => {
+                        data.extend_from_slice(&buf[..n]);
+                        conn.last_activity = now;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(_) => {
+                        peer_gone = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(tunnel) = self.tunnels.get_mut(&token) {
+            tunnel.client_paused = data.len() >= room;
+            tunnel.to_upstream.extend_from_slice(&data);
+        }
+        self.flush_tunnel_upstream(token);
+
+        if peer_gone {
+            self.close_connection(token);
+        }
+    }
+
+    /// Writes what the client sent on to the upstream; once it has all gone,
+    /// a client whose reads paused is read again.
+    fn flush_tunnel_upstream(&mut self, client_token: Token) {
+        let mut failed = false;
+        let mut resume_client = false;
+        if
+            let Some(tunnel) = self.tunnels.get_mut(&client_token) &&
+            tunnel.established &&
+            let Some(upstream) = tunnel.upstream.as_mut()
+        {
+            while !tunnel.to_upstream.is_empty() {
+                match upstream.write(&tunnel.to_upstream) {
+                    Ok(n) => {
+                        tunnel.to_upstream.drain(..n);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(_) => {
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if tunnel.client_paused && tunnel.to_upstream.is_empty() {
+                tunnel.client_paused = false;
+                resume_client = true;
+            }
+        }
+
+        if failed {
+            self.close_connection(client_token);
+        } else if resume_client && let Some(conn) = self.connections.get_mut(&client_token) {
+            // Re-arming reports the bytes the client is still holding
+            let mut interest = Interest::READABLE;
+            if conn.write_buffer.len() > conn.bytes_written {
+                interest |= Interest::WRITABLE;
+            }
+            let _ = self.poll.registry().reregister(&mut conn.stream, client_token, interest);
+        }
+    }
+
+    /// Replays the request to the shadow upstream once its name has been looked
+    /// up; the response is read and discarded.
+    pub(crate) fn start_mirror(&mut self, token: Token, target: &str, route_path: &str) {
+        let upstream = match Upstream::parse_bare(target) {
+            Some(upstream) => upstream,
+            None => {
+                log::warning!("[Mirror] '{}' is not a mirror target", target);
+                return;
+            }
+        };
+        let request = match self.connections.get(&token) {
+            Some(conn) => {
+                let raw = &conn.read_buffer[..conn.request_len.min(conn.read_buffer.len())];
+                mirror_request(raw, &upstream, route_path)
+            }
+            None => {
+                return;
+            }
+        };
+
+        let mirror_token = self.allocate_token();
+        self.mirrors.insert(mirror_token, Mirror {
+            stream: None,
+            request,
+            written: 0,
+            started_at: self.now(),
+        });
+        if let Lookup::Ready(addr) = self.resolver.lookup(mirror_token, &upstream.address) {
+            self.connect_mirror(mirror_token, Some(addr));
+        }
+    }
+
+    fn connect_mirror(&mut self, token: Token, addr: Option<SocketAddr>) {
+        match addr.and_then(|addr| self.open_upstream(addr, token).ok()) {
+            Some(stream) => {
+                if let Some(mirror) = self.mirrors.get_mut(&token) {
+                    mirror.stream = Some(stream);
+                }
+            }
+            None => {
+                log::warning!("[Mirror] Could not reach the mirror for Token {:?}", token);
+                self.close_mirror(token);
+            }
+        }
+    }
+
+    pub(crate) fn handle_mirror_event(&mut self, token: Token, event: &mio::event::Event) {
+        let mut done = event.is_error();
+
+        if let Some(mirror) = self.mirrors.get_mut(&token) && let Some(stream) = mirror.stream.as_mut() {
+            if event.is_writable() {
+                while mirror.written < mirror.request.len() {
+                    match stream.write(&mirror.request[mirror.written..]) {
+                        Ok(n) => {
+                            mirror.written += n;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            break;
+                        }
+                        Err(_) => {
+                            done = true;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if event.is_readable() || event.is_read_closed() {
+                let mut buf = [0u8; 8192];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => {
+                            done = true;
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            break;
+                        }
+                        Err(_) => {
+                            done = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if done {
+            self.close_mirror(token);
+        }
+    }
+
+    pub(crate) fn close_mirror(&mut self, token: Token) {
+        if let Some(mirror) = self.mirrors.remove(&token) && let Some(mut stream) = mirror.stream {
+            let _ = self.poll.registry().deregister(&mut stream);
+        }
+    }
+
+    /// `proxy_pass`: connects to the upstream the balancer picks, once its name
+    /// has been looked up, and sends it the request when the connection is up;
+    /// one that cannot be reached is marked down and the next is tried.
+    pub(crate) fn start_proxy(&mut self, token: Token, mut forward: Forward) -> Result<(), StatusCode> {
+        let now = self.now();
+        let upstream_token = self.allocate_token();
+        let (upstream_idx, upstream) = loop {
+            if forward.tries > 0 && forward.tried.len() >= forward.tries {
+                return Err(StatusCode::BAD_GATEWAY);
+            }
+            let sticky = forward.affinity.filter(|&idx| {
+                forward.tried.is_empty() && !self.balancer.is_down(&forward.upstreams[idx].address, now)
+            });
+            let idx = match sticky.or_else(|| self.balancer.pick(&forward.upstreams, forward.balance, &forward.tried, now)) {
+                Some(idx) => idx,
+                None => {
+                    return Err(StatusCode::BAD_GATEWAY);
+                }
+            };
+            forward.tried.push(idx);
+            let address = &forward.upstreams[idx].address;
+            let addr = match self.resolver.lookup(upstream_token, address) {
+                Lookup::Ready(addr) => addr,
+                Lookup::Pending => {
+                    break (idx, None);
+                }
+            };
+            match self.open_upstream(addr, upstream_token) {
+                Ok(stream) => {
+                    break (idx, Some(stream));
+                }
+                Err(e) => {
+                    log::warning!("[Proxy] Connect to {} failed: {}", address, e);
+                    self.balancer.failed(address, now, forward.fail_timeout);
+                }
+            }
+        };
+
+        let request = forward.message(&forward.upstreams[upstream_idx]);
+        self.balancer.opened(&forward.upstreams[upstream_idx].address);
+        let captured = forward.cache.as_ref().map(|_| Vec::new());
+        self.proxies.insert(token, Proxied {
+            forward,
+            upstream_idx,
+            upstream,
+            upstream_token,
+            connected: false,
+            request,
+            written: 0,
+            head: Vec::new(),
+            head_sent: false,
+            paused: false,
+            last_activity: now,
+            captured,
+        });
+        self.proxy_token_to_client.insert(upstream_token, token);
+
+        if let Some(conn) = self.connections.get_mut(&token) {
+            conn.state = ConnectionState::Proxy;
+            conn.last_activity = now;
+            let _ = self.poll.registry().reregister(&mut conn.stream, token, Interest::READABLE);
+        }
+        Ok(())
+    }
+
+    /// The looked-up upstream of a proxied request; one that cannot be reached
+    /// fails over like a refused connection.
+    fn connect_proxy(&mut self, upstream_token: Token, addr: Option<SocketAddr>) {
+        let client_token = match self.proxy_token_to_client.get(&upstream_token) {
+            Some(token) => *token,
+            None => {
+                return;
+            }
+        };
+        let opened = match addr {
+            Some(addr) => self.open_upstream(addr, upstream_token).map_err(|e| e.to_string()),
+            None => Err("no address".to_string()),
+        };
+        match opened {
+            Ok(stream) => {
+                if let Some(proxied) = self.proxies.get_mut(&client_token) {
+                    proxied.upstream = Some(stream);
+                }
+            }
+            Err(e) => {
+                log::warning!("[Proxy] Connect for Token {:?} failed: {}", client_token, e);
+                self.fail_proxy(client_token, StatusCode::BAD_GATEWAY);
+            }
+        }
+    }
+
+    /// Starts a non-blocking connection to `addr`, registered under `token`.
+    fn open_upstream(&self, addr: SocketAddr, token: Token) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(addr)?;
+        self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(stream)
+    }
+
+    pub(crate) fn handle_proxy_upstream_event(&mut self, upstream_token: Token, event: &mio::event::Event) {
+        let client_token = match self.proxy_token_to_client.get(&upstream_token) {
+            Some(token) => *token,
+            None => {
+                return;
+            }
+        };
+        let now = self.now();
+        let room = self.client_room(client_token);
+        let mut received = Vec::new();
+        let mut finished = false;
+        let mut failed = false;
+
+        if let Some(proxied) = self.proxies.get_mut(&client_token) && let Some(upstream) = proxied.upstream.as_mut() {
+            if !proxied.connected {
+                if !event.is_writable() && !event.is_error() && !event.is_write_closed() {
+                    return;
+                }
+                proxied.connected = matches!(upstream.take_error(), Ok(None)) && upstream.peer_addr().is_ok();
+                failed = !proxied.connected;
+            }
+
+            if !failed && event.is_writable() {
+                while proxied.written < proxied.request.len() {
+                    match upstream.write(&proxied.request[proxied.written..]) {
+                        Ok(n) => {
+                            proxied.written += n;
+                            proxied.last_activity = now;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            break;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            continue;
+                        }
+                        Err(_) => {
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if !failed && (event.is_readable() || event.is_read_closed()) {
+                let mut buf = [0u8; 8192];
+                loop {
+                    if received.len() >= room {
+                        proxied.paused = true;
+                        break;
+                    }
+                    match upstream.read(&mut buf) {
+                        Ok(0) => {
+                            finished = true;
+                            break;
+                        }
+                        Ok(n) => {
+                            received.extend_from_slice(&buf[..n]);
+                            proxied.last_activity = now;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            break;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            continue;
+                        }
+                        Err(_) => {
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if failed {
+            self.fail_proxy(client_token, StatusCode::BAD_GATEWAY);
+            return;
+        }
+        if
+            let Some(proxied) = self.proxies.get_mut(&client_token) &&
+            let Some((_, cache)) = &proxied.forward.cache &&
+            let Some(captured) = proxied.captured.as_mut()
+        {
+            match captured.len() + received.len() > cache.max_size {
+                true => proxied.captured = None,
+                false => captured.extend_from_slice(&received),
+            }
+        }
+        let head_sent = self.proxies.get(&client_token).is_some_and(|p| p.head_sent);
+
+        if !head_sent && (!received.is_empty() || finished) {
+            self.relay_proxy_head(client_token, received, finished);
+        } else if !received.is_empty() || finished {
+            if finished {
+                self.finish_proxy(client_token);
+            }
+            // An empty push still wakes write_to_client so the connection gets closed
+            self.queue_stream_bytes(client_token, &received);
+        }
+    }
+
+    /// Collects the upstream's response head; once it is whole, sends it
+    /// rewritten for the client together with whatever body came with it.
+    fn relay_proxy_head(&mut self, client_token: Token, received: Vec<u8>, finished: bool) {
+        let (buffered, head_len) = match self.proxies.get_mut(&client_token) {
+            Some(proxied) => {
+                proxied.head.extend_from_slice(&received);
+                match head_len(&proxied.head) {
+                    Some(len) => (std::mem::take(&mut proxied.head), Some(len)),
+                    None if !finished && proxied.head.len() <= MAX_HEAD => {
+                        return;
+                    }
+                    None => (Vec::new(), None),
+                }
+            }
+            None => {
+                return;
+            }
+        };
+
+        if let Some(proxied) = self.proxies.get(&client_token) &&
+            proxied.forward.sticky_cookie &&
+            proxied.forward.affinity != Some(proxied.upstream_idx) &&
+            let Some(cookie) = SetCookie::new(STICKY_COOKIE, &proxied.forward.upstreams[proxied.upstream_idx].id()) &&
+            let Some(conn) = self.connections.get_mut(&client_token)
+        {
+            let cookie = cookie.path(&proxied.forward.route_path).http_only().same_site(SameSite::Lax);
+            conn.response_headers.push(("Set-Cookie".to_string(), cookie.to_string()));
+        }
+        let upgrading =
+            buffered.starts_with(b"HTTP/1.") &&
+            buffered.get(9..13) == Some(b"101 ") &&
+            self.proxies.get(&client_token).is_some_and(|p| is_upgrade(&p.forward.method, &p.forward.headers));
+        let rewrite = if upgrading { upgrade_head } else { response_head };
+        let extra_headers = self.connections
+            .get(&client_token)
+            .map(|conn| conn.response_headers.as_slice())
+            .unwrap_or_default();
+        let head = head_len.and_then(|len| rewrite(&buffered[..len], extra_headers, self.config.server_header.as_deref()));
+        let (mut bytes, head_len) = match (head, head_len) {
+            (Some(head), Some(len)) => (head, len),
+            _ => {
+                log::warning!("[Proxy] Upstream for Token {:?} sent no valid response head", client_token);
+                self.fail_proxy(client_token, StatusCode::BAD_GATEWAY);
+                return;
+            }
+        };
+        if let Some(conn) = self.connections.get_mut(&client_token) {
+            conn.response_headers.clear();
+        }
+        if let Some(proxied) = self.proxies.get_mut(&client_token) {
+            proxied.head_sent = true;
+        }
+
+        self.log_access(client_token, &bytes);
+        bytes.extend_from_slice(&buffered[head_len..]);
+        if upgrading {
+            self.upgrade_proxy(client_token, &bytes, finished);
+            return;
+        }
+        if finished {
+            self.finish_proxy(client_token);
+        }
+        self.queue_stream_bytes(client_token, &bytes);
+    }
+
+    /// The upstream accepted a WebSocket handshake, so the proxied request
+    /// becomes a tunnel over the same upstream connection and its token.
+    fn upgrade_proxy(&mut self, client_token: Token, response: &[u8], finished: bool) {
+        let mut proxied = match self.proxies.remove(&client_token) {
+            Some(proxied) => proxied,
+            None => {
+                return;
+            }
+        };
+        self.proxy_token_to_client.remove(&proxied.upstream_token);
+        self.balancer.closed(&proxied.forward.upstreams[proxied.upstream_idx].address);
+        let early = match self.open_tunnel(client_token) {
+            Some(early) => early,
+            None => {
+                if let Some(upstream) = proxied.upstream.as_mut() {
+                    let _ = self.poll.registry().deregister(upstream);
+                }
+                return;
+            }
+        };
+
+        self.tunnels.insert(client_token, Tunnel {
+            upstream: proxied.upstream.take(),
+            upstream_token: proxied.upstream_token,
+            established: true,
+            to_upstream: early,
+            closing: finished,
+            upstream_paused: proxied.paused,
+            client_paused: false,
+        });
+        self.tunnel_token_to_client.insert(proxied.upstream_token, client_token);
+        self.queue_stream_bytes(client_token, response);
+        self.flush_tunnel_upstream(client_token);
+    }
+
+    /// The upstream failed, so it is skipped for the route's `proxy_fail_timeout`.
+    /// Until it has sent anything back, the request goes to the next upstream
+    /// while the route's tries and time budget last; one that is not idempotent
+    /// only when its connection never came up. Otherwise the client gets `code`
+    /// (504 once the budget is spent), or is cut off once the answer has started.
+    pub(crate) fn fail_proxy(&mut self, client_token: Token, code: StatusCode) {
+        let now = self.now();
+        let proxied = match self.remove_proxy(client_token) {
+            Some(proxied) => proxied,
+            None => {
+                return;
+            }
+        };
+        let forward = proxied.forward;
+        self.balancer.failed(&forward.upstreams[proxied.upstream_idx].address, now, forward.fail_timeout);
+
+        let replayable = !proxied.connected || IDEMPOTENT.contains(&forward.method.as_str());
+        let out_of_time =
+            !forward.try_budget.is_zero() && now.saturating_duration_since(forward.started) >= forward.try_budget;
+        if proxied.head_sent {
+            self.close_connection(client_token);
+        } else if !proxied.head.is_empty() || !replayable {
+            self.send_error(client_token, code);
+        } else if out_of_time {
+            self.send_error(client_token, StatusCode::GATEWAY_TIMEOUT);
+        } else if self.start_proxy(client_token, forward).is_err() {
+            self.send_error(client_token, code);
+        }
+    }
+
+    /// The upstream sent its whole answer; a `proxy_cache` route keeps a copy.
+    fn finish_proxy(&mut self, client_token: Token) {
+        let now = self.now();
+        let proxied = match self.remove_proxy(client_token) {
+            Some(proxied) => proxied,
+            None => {
+                return;
+            }
+        };
+        if
+            let Some((key, cache)) = &proxied.forward.cache &&
+            let Some(captured) = &proxied.captured &&
+            let Some((copy, fresh, stale)) =
+                cache_entry(captured, cache, self.config.server_header.as_deref(), SystemTime::now())
+        {
+            self.cgi_cache.insert(key, copy, cache.max_size, fresh, stale, now);
+        }
+    }
+
+    /// `proxy_cache` routes: a fresh copy is sent at once. A stale one within
+    /// its window is sent too, while one request in the background fetches
+    /// the next copy; anything else goes upstream and is kept on the way back.
+    pub(crate) fn start_cached_proxy(
+        &mut self,
+        token: Token,
+        mut forward: Forward,
+        key: CacheKey,
+        cache: ProxyCache
+    ) -> Result<(), StatusCode> {
+        let now = self.now();
+        if let Some(response) = self.cgi_cache.get(&key, now) {
+            self.send_cached_response(token, response, "HIT");
+            return Ok(());
+        }
+        if let Some(response) = self.cgi_cache.get_stale(&key, now) {
+            self.send_cached_response(token, response, "STALE");
+            if !self.cgi_cache.is_in_flight(&key) {
+                self.cgi_cache.lead(&key);
+                forward.cache = Some((key.clone(), cache));
+                // No client is waiting on this token, so nothing is relayed anywhere
+                let refresh = self.allocate_token();
+                if self.start_proxy(refresh, forward).is_err() {
+                    self.cgi_cache.release(&key);
+                }
+            }
+            return Ok(());
+        }
+
+        self.apply_configured_headers(token, &[("X-Cache".to_string(), "MISS".to_string())]);
+        forward.cache = Some((key, cache));
+        self.start_proxy(token, forward)
+    }
+
+    pub(crate) fn remove_proxy(&mut self, client_token: Token) -> Option<Proxied> {
+        let mut proxied = self.proxies.remove(&client_token)?;
+        self.proxy_token_to_client.remove(&proxied.upstream_token);
+        if let Some((key, _)) = &proxied.forward.cache {
+            self.cgi_cache.release(key);
+        }
+        if let Some(upstream) = proxied.upstream.as_mut() {
+            let _ = self.poll.registry().deregister(upstream);
+        }
+        self.balancer.closed(&proxied.forward.upstreams[proxied.upstream_idx].address);
+        Some(proxied)
+    }
+
+    /// How much more may be queued for the client before upstream reads pause.
+    /// A background `proxy_cache` refresh has no client to wait for.
+    fn client_room(&self, client_token: Token) -> usize {
+        match self.connections.get(&client_token) {
+            Some(conn) => QUEUE_LIMIT.saturating_sub(conn.write_buffer.len().saturating_sub(conn.bytes_written)),
+            None => usize::MAX,
+        }
+    }
+
+    /// Re-arms the upstream of a relay whose reads paused, once the client's
+    /// queue has been written out; the upstream's pending data is reported again.
+    pub(crate) fn resume_upstream(&mut self, client_token: Token) {
+        let now = self.now();
+        let registry = self.poll.registry();
+        if
+            let Some(tunnel) = self.tunnels.get_mut(&client_token) &&
+            tunnel.upstream_paused &&
+            let Some(upstream) = tunnel.upstream.as_mut()
+        {
+            tunnel.upstream_paused = false;
+            let _ = registry.reregister(upstream, tunnel.upstream_token, Interest::READABLE | Interest::WRITABLE);
+        }
+        if
+            let Some(proxied) = self.proxies.get_mut(&client_token) &&
+            proxied.paused &&
+            let Some(upstream) = proxied.upstream.as_mut()
+        {
+            proxied.paused = false;
+            proxied.last_activity = now;
+            let _ = registry.reregister(upstream, proxied.upstream_token, Interest::READABLE | Interest::WRITABLE);
+        }
+    }
+
+    pub(crate) fn remove_tunnel(&mut self, client_token: Token) -> Option<Tunnel> {
+        let mut tunnel = self.tunnels.remove(&client_token)?;
+        self.tunnel_token_to_client.remove(&tunnel.upstream_token);
+        if let Some(upstream) = tunnel.upstream.as_mut() {
+            let _ = self.poll.registry().deregister(upstream);
+        }
+        Some(tunnel)
+    }
+
+    /// Connects the tunnels, proxied requests, mirrors and health probes whose
+    /// upstreams have been looked up since the last pass.
+    pub(crate) fn check_lookups(&mut self) {
+        for (token, addr) in self.resolver.answers() {
+            if self.tunnel_token_to_client.contains_key(&token) {
+                self.connect_tunnel(token, addr);
+            } else if self.proxy_token_to_client.contains_key(&token) {
+                self.connect_proxy(token, addr);
+            } else if self.mirrors.contains_key(&token) {
+                self.connect_mirror(token, addr);
+            } else if self.probes.contains(token) {
+                self.connect_probe(token, addr);
+            }
+        }
+    }
+
+    /// Mirrors are dropped after `timeout`. An upstream that stops sending gets
+    /// 504, or is cut off if it had started answering.
+    pub(crate) fn check_upstream_timeouts(&mut self, now: Instant, timeout: Duration) {
+        let stale_mirrors: Vec<Token> = self.mirrors
+            .iter()
+            .filter(|(_, mirror)| now.duration_since(mirror.started_at) > timeout)
+            .map(|(&t, _)| t)
+            .collect();
+        for t in stale_mirrors {
+            self.close_mirror(t);
+        }
+
+        let stale_proxies: Vec<Token> = self.proxies
+            .iter()
+            .filter(|(_, proxied)| now.duration_since(proxied.last_activity) > timeout)
+            .map(|(&t, _)| t)
+            .collect();
+        for t in stale_proxies {
+            self.fail_proxy(t, StatusCode::GATEWAY_TIMEOUT);
+        }
+    }
+
+    /// Starts the health probes that are due, one per upstream address at a
+    /// time, every `interval` of the first route that checks it. A probe still
+    /// running after its interval has failed.
+    pub(crate) fn check_upstream_health(&mut self) {
+        let now = self.now();
+        let stale: Vec<Token> = self.probes.running
+            .iter()
+            .filter(|(_, probe)| now.duration_since(probe.started) >= probe.check.interval)
+            .map(|(&t, _)| t)
+            .collect();
+        for t in stale {
+            self.finish_probe(t, false);
+        }
+
+        let mut due: Vec<(Upstream, HealthCheck)> = Vec::new();
+        let checked = self.config.servers
+            .iter()
+            .flat_map(|server| &server.routes)
+            .filter_map(|route| route.proxy_health.as_ref().map(|check| (route, check)));
+        for (route, check) in checked {
+            for upstream in route_targets(route).filter_map(|target| Upstream::parse(target)) {
+                let address = &upstream.address;
+                let waiting =
+                    self.probes.running.values().any(|probe| &probe.address == address) ||
+                    self.probes.due.get(address).is_some_and(|at| now < *at) ||
+                    due.iter().any(|(other, _)| &other.address == address);
+                if !waiting {
+                    due.push((upstream, check.clone()));
+                }
+            }
+        }
+        for (upstream, check) in due {
+            self.start_probe(upstream, check, now);
+        }
+    }
+
+    fn start_probe(&mut self, upstream: Upstream, check: HealthCheck, now: Instant) {
+        self.probes.due.insert(upstream.address.clone(), now + check.interval);
+        let request = match &check.probe {
+            Probe::Connect => Vec::new(),
+            Probe::Get(path) =>
+                format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, upstream.host).into_bytes(),
+        };
+        let token = self.allocate_token();
+        self.probes.running.insert(token, HealthProbe {
+            address: upstream.address.clone(),
+            check,
+            stream: None,
+            connected: false,
+            request,
+            written: 0,
+            response: Vec::new(),
+            started: now,
+        });
+        if let Lookup::Ready(addr) = self.resolver.lookup(token, &upstream.address) {
+            self.connect_probe(token, Some(addr));
+        }
+    }
+
+    fn connect_probe(&mut self, token: Token, addr: Option<SocketAddr>) {
+        match addr.and_then(|addr| self.open_upstream(addr, token).ok()) {
+            Some(stream) => {
+                if let Some(probe) = self.probes.running.get_mut(&token) {
+                    probe.stream = Some(stream);
+                }
+            }
+            None => self.finish_probe(token, false),
+        }
+    }
+
+    /// A connection check passes once connected; a `GET` check on the status
+    /// line of the answer.
+    pub(crate) fn handle_probe_event(&mut self, token: Token, event: &mio::event::Event) {
+        let mut passed = None;
+
+        if let Some(probe) = self.probes.running.get_mut(&token) && let Some(stream) = probe.stream.as_mut() {
+            if !probe.connected {
+                if !event.is_writable() && !event.is_error() && !event.is_write_closed() {
+                    return;
+                }
+                probe.connected = matches!(stream.take_error(), Ok(None)) && stream.peer_addr().is_ok();
+                if !probe.connected || probe.request.is_empty() {
+                    passed = Some(probe.connected);
+                }
+            }
+
+            if passed.is_none() && event.is_writable() {
+                while probe.written < probe.request.len() {
+                    match stream.write(&probe.request[probe.written..]) {
+                        Ok(n) => {
+                            probe.written += n;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            break;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            continue;
+                        }
+                        Err(_) => {
+                            passed = Some(false);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if passed.is_none() && (event.is_readable() || event.is_read_closed()) {
+                let mut buf = [0u8; 1024];
+                let mut finished = false;
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => {
+                            finished = true;
+                            break;
+                        }
+                        Ok(n) => probe.response.extend_from_slice(&buf[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            break;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            continue;
+                        }
+                        Err(_) => {
+                            finished = true;
+                            break;
+                        }
+                    }
+                }
+                let status_line = probe.response
+                    .windows(2)
+                    .position(|w| w == b"\r\n")
+                    .map(|end| String::from_utf8_lossy(&probe.response[..end]).to_string());
+                passed = match status_line {
+                    Some(line) => {
+                        let code = line.split(' ').nth(1).and_then(|code| code.parse::<u16>().ok());
+                        Some(line.starts_with("HTTP/1.") && code.is_some_and(|code| (200..400).contains(&code)))
+                    }
+                    None if finished => Some(false),
+                    None => None,
+                };
+            }
+        }
+
+        if let Some(ok) = passed {
+            self.finish_probe(token, ok);
+        }
+    }
+
+    fn finish_probe(&mut self, token: Token, ok: bool) {
+        let mut probe = match self.probes.running.remove(&token) {
+            Some(probe) => probe,
+            None => {
+                return;
+            }
+        };
+        if let Some(stream) = probe.stream.as_mut() {
+            let _ = self.poll.registry().deregister(stream);
+        }
+        match self.balancer.probed(&probe.address, ok, &probe.check) {
+            Some(true) => log::info!("[Proxy] Upstream {} passes its health checks again", probe.address),
+            Some(false) => log::warning!("[Proxy] Upstream {} fails its health checks", probe.address),
+            None => {}
+        }
+    }
+
+    /// The `upstream_status` report: every `proxy_pass` upstream in the config,
+    /// canary ones included.
+    pub(crate) fn upstream_report(&self) -> String {
+        let mut addresses: Vec<String> = Vec::new();
+        let targets = self.config.servers
+            .iter()
+            .flat_map(|server| &server.routes)
+            .flat_map(route_targets);
+        for upstream in targets.filter_map(|target| Upstream::parse(target)) {
+            if !addresses.contains(&upstream.address) {
+                addresses.push(upstream.address);
+            }
+        }
+        self.balancer.report(&addresses, self.now())
+    }
+}
@@ -0,0 +1,114 @@
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+pub enum Preamble {
+    /// Could still become a preamble; wait for more bytes.
+    Incomplete,
+    /// The connection starts with something else (plain HTTP).
+    Absent,
+    /// `len` bytes to strip; `source` is `None` for LOCAL / UNKNOWN connections.
+    Parsed {
+        len: usize,
+        source: Option<IpAddr>,
+    },
+    Invalid,
+}
+
+/// Parses a HAProxy PROXY protocol v1 (text) or v2 (binary) header.
+pub fn parse_preamble(buf: &[u8]) -> Preamble {
+    if is_prefix(buf, V2_SIGNATURE) {
+        return if buf.len() < V2_SIGNATURE.len() { Preamble::Incomplete } else { parse_v2(buf) };
+    }
+    if is_prefix(buf, V1_PREFIX) {
+        return if buf.len() < V1_PREFIX.len() { Preamble::Incomplete } else { parse_v1(buf) };
+    }
+    Preamble::Absent
+}
+
+/// True when `buf` and `pattern` agree on their common length.
+fn is_prefix(buf: &[u8], pattern: &[u8]) -> bool {
+    let n = buf.len().min(pattern.len());
+    buf[..n] == pattern[..n]
+}
+
+fn parse_v1(buf: &[u8]) -> Preamble {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None => {
+            return if buf.len() >= V1_MAX_LEN { Preamble::Invalid } else { Preamble::Incomplete };
+        }
+    };
+    if end + 2 > V1_MAX_LEN {
+        return Preamble::Invalid;
+    }
+
+    let line = match std::str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => {
+            return Preamble::Invalid;
+        }
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    let len = end + 2;
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Preamble::Parsed { len, source: None },
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let source = src.parse::<IpAddr>();
+            let valid =
+                source.as_ref().is_ok_and(|ip| ip.is_ipv4() == (*proto == "TCP4")) &&
+                dst.parse::<IpAddr>().is_ok() &&
+                sport.parse::<u16>().is_ok() &&
+                dport.parse::<u16>().is_ok();
+            match source {
+                Ok(ip) if valid => Preamble::Parsed { len, source: Some(ip) },
+                _ => Preamble::Invalid,
+            }
+        }
+        _ => Preamble::Invalid,
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Preamble {
+    if buf.len() < 16 {
+        return Preamble::Incomplete;
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    if version != 2 || command > 1 {
+        return Preamble::Invalid;
+    }
+
+    let addr_len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let len = 16 + addr_len;
+    if buf.len() < len {
+        return Preamble::Incomplete;
+    }
+
+    // LOCAL: health checks from the balancer itself, keep the real peer
+    if command == 0 {
+        return Preamble::Parsed { len, source: None };
+    }
+
+    let addrs = &buf[16..len];
+    let source = match buf[13] >> 4 {
+        0x1 if addrs.len() >= 12 => {
+            Some(IpAddr::V4(Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3])))
+        }
+        0x2 if addrs.len() >= 36 => {
+            let mut raw = [0u8; 16];
+            raw.copy_from_slice(&addrs[..16]);
+            Some(IpAddr::V6(Ipv6Addr::from(raw)))
+        }
+        0x0 => None,
+        _ => {
+            return Preamble::Invalid;
+        }
+    };
+
+    Preamble::Parsed { len, source }
+}
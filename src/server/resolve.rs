@@ -0,0 +1,49 @@
+//! Host name lookups off the event loop. An address that is already an IP is
+//! answered at once; a name is looked up on a thread of its own and the answer
+//! comes back through a channel, with the poll waker set off to collect it.
+
+use mio::{ Token, Waker };
+use std::net::{ SocketAddr, ToSocketAddrs };
+use std::sync::mpsc::{ self, Receiver, Sender };
+use std::sync::Arc;
+
+pub struct Resolver {
+    waker: Arc<Waker>,
+    sender: Sender<(Token, Option<SocketAddr>)>,
+    answers: Receiver<(Token, Option<SocketAddr>)>,
+}
+
+/// What `Resolver::lookup` has for `host:port` right away.
+pub enum Lookup {
+    Ready(SocketAddr),
+    /// Comes back from `Resolver::answers` under the token it was asked with.
+    Pending,
+}
+
+impl Resolver {
+    pub fn new(waker: Arc<Waker>) -> Self {
+        let (sender, answers) = mpsc::channel();
+        Self { waker, sender, answers }
+    }
+
+    pub fn lookup(&self, token: Token, address: &str) -> Lookup {
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            return Lookup::Ready(addr);
+        }
+        let address = address.to_string();
+        let sender = self.sender.clone();
+        let waker = Arc::clone(&self.waker);
+        std::thread::spawn(move || {
+            let addr = address.to_socket_addrs().ok().and_then(|mut addrs| addrs.next());
+            if sender.send((token, addr)).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+        Lookup::Pending
+    }
+
+    /// Lookups finished since the last call; `None` for names that did not resolve.
+    pub fn answers(&self) -> Vec<(Token, Option<SocketAddr>)> {
+        self.answers.try_iter().collect()
+    }
+}
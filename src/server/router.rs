@@ -0,0 +1,309 @@
+//! Which server block and route answer a request, and what the route maps
+//! the path to. Everything here is a pure function of the configuration and
+//! the request line, so matching rules can be checked without a server:
+//!
+//! ```no_run
+//! # let server_cfg: localserver::config::models::ServerConfig = unimplemented!();
+//! # let headers = std::collections::HashMap::new();
+//! use localserver::server::router::{ self, RouteDecision };
+//!
+//! match router::decide(&server_cfg, "GET", "/images/logo.png", "", &headers) {
+//!     RouteDecision::Serve { route, target } => println!("{} serves {:?}", route.path, target),
+//!     RouteDecision::Redirect { location, .. } => println!("redirect to {}", location),
+//!     RouteDecision::Fixed { response, .. } => println!("{} from the config", response.code),
+//!     RouteDecision::MethodNotAllowed { .. } => println!("405"),
+//!     RouteDecision::NotFound => println!("404"),
+//! }
+//! ```
+//!
+//! The pipeline runs these steps as separate middlewares so authentication
+//! sits between the redirect and the method check; `decide` is the answer
+//! for a request that passes authentication.
+
+use crate::config::models::{ Canary, CanaryTrigger, Config, FixedResponse, RouteConfig, ServerConfig };
+use crate::http::cookie;
+use crate::http::status::StatusCode;
+use crate::vfs::MOUNT_PREFIX;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub enum RouteDecision<'a> {
+    /// No route prefix matches the path.
+    NotFound,
+    /// The route has `redirect`; `location` has its variables filled in.
+    Redirect {
+        route: &'a RouteConfig,
+        location: String,
+        code: StatusCode,
+    },
+    /// The route has `return`, which answers whatever the method.
+    Fixed {
+        route: &'a RouteConfig,
+        response: &'a FixedResponse,
+    },
+    /// The method is not in the route's `methods`.
+    MethodNotAllowed {
+        route: &'a RouteConfig,
+    },
+    /// Handled by the route: a named handler, CGI, an upload or a file under `target`.
+    Serve {
+        route: &'a RouteConfig,
+        target: Target<'a>,
+    },
+}
+
+/// Where a route finds the file a path maps to.
+#[derive(Debug, PartialEq)]
+pub enum Target<'a> {
+    /// The route's `root` directory joined with the rest of the path.
+    Disk(PathBuf),
+    /// A path inside the tree mounted as `name` (`root: "vfs:<name>"`).
+    Mounted {
+        name: &'a str,
+        path: PathBuf,
+    },
+}
+
+/// The whole routing answer for one request, in the order the pipeline checks it.
+pub fn decide<'a>(
+    server_cfg: &'a ServerConfig,
+    method: &str,
+    path_only: &str,
+    query: &str,
+    headers: &HashMap<String, String>
+) -> RouteDecision<'a> {
+    let route = match find_route(server_cfg, path_only) {
+        Some(route) => route,
+        None => {
+            return RouteDecision::NotFound;
+        }
+    };
+    if let Some(target) = &route.redirect {
+        let location = expand_redirect_target(target, &route.path, path_only, query, headers);
+        return RouteDecision::Redirect { route, location, code: route.redirect_code };
+    }
+    if let Some(response) = &route.fixed_response {
+        return RouteDecision::Fixed { route, response };
+    }
+    if !method_allowed(route, method) {
+        return RouteDecision::MethodNotAllowed { route };
+    }
+    let target = match canary_root(route, headers) {
+        Some(root) => Target::Disk(PathBuf::from(root).join(relative_path(route, path_only))),
+        None => resolve(route, path_only),
+    };
+    RouteDecision::Serve { route, target }
+}
+
+/// The block on `default_idx`'s listener whose name matches the `Host`
+/// header, in nginx's order: an exact name, the longest `*.example.com`, the
+/// longest `www.example.*`, else the listener's default block.
+pub fn select_server(config: &Config, default_idx: usize, host_header: Option<&str>) -> usize {
+    let host_header = match host_header {
+        Some(h) => h,
+        None => {
+            return default_idx;
+        }
+    };
+
+    let host_name = host_header.split(':').next().unwrap_or(host_header).trim();
+    let default_cfg = &config.servers[default_idx];
+
+    let mut best_leading: Option<(usize, usize)> = None;
+    let mut best_trailing: Option<(usize, usize)> = None;
+    for (idx, cfg) in config.servers.iter().enumerate() {
+        if cfg.host != default_cfg.host || cfg.port != default_cfg.port {
+            continue;
+        }
+        if cfg.host.eq_ignore_ascii_case(host_name) {
+            return idx;
+        }
+        for name in &cfg.server_names {
+            if name.eq_ignore_ascii_case(host_name) {
+                return idx;
+            }
+            let best = match wildcard_name_matches(name, host_name) {
+                Some(Wildcard::Leading) => &mut best_leading,
+                Some(Wildcard::Trailing) => &mut best_trailing,
+                None => {
+                    continue;
+                }
+            };
+            if best.is_none_or(|(_, len)| name.len() > len) {
+                *best = Some((idx, name.len()));
+            }
+        }
+    }
+
+    best_leading.or(best_trailing).map_or(default_idx, |(idx, _)| idx)
+}
+
+/// Whether `host_name` is one of the block's own names: its listen host, a
+/// `server_name`, or a match for a wildcard one.
+pub fn is_own_name(server_cfg: &ServerConfig, host_name: &str) -> bool {
+    server_cfg.host.eq_ignore_ascii_case(host_name) ||
+        server_cfg.server_names
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(host_name) || wildcard_name_matches(name, host_name).is_some())
+}
+
+enum Wildcard {
+    Leading,
+    Trailing,
+}
+
+/// `*.example.com` matches `a.example.com` and `a.b.example.com`, not
+/// `example.com`; `www.example.*` matches `www.example.org` and `www.example.co.uk`.
+fn wildcard_name_matches(pattern: &str, host_name: &str) -> Option<Wildcard> {
+    let host_name = host_name.to_ascii_lowercase();
+    if let Some(suffix) = pattern.strip_prefix('*') && suffix.starts_with('.') {
+        let matches = host_name.len() > suffix.len() && host_name.ends_with(&suffix.to_ascii_lowercase());
+        return matches.then_some(Wildcard::Leading);
+    }
+    if let Some(prefix) = pattern.strip_suffix('*') && prefix.ends_with('.') {
+        let matches = host_name.len() > prefix.len() && host_name.starts_with(&prefix.to_ascii_lowercase());
+        return matches.then_some(Wildcard::Trailing);
+    }
+    None
+}
+
+/// Longest route prefix matching `path_only`.
+pub fn find_route<'a>(server_cfg: &'a ServerConfig, path_only: &str) -> Option<&'a RouteConfig> {
+    server_cfg.routes
+        .iter()
+        .filter(|r| path_matches_route(path_only, &r.path))
+        .max_by_key(|r| r.path.len())
+}
+
+/// `/api` matches `/api` and `/api/users`, not `/apiary`; `/` matches everything.
+pub fn path_matches_route(path: &str, route_path: &str) -> bool {
+    if route_path == "/" {
+        return path.starts_with('/');
+    }
+
+    path == route_path || path.starts_with(&format!("{}/", route_path.trim_end_matches('/')))
+}
+
+/// Path lists in the config (`access_log_exclude`, `maintenance_allow`):
+/// an exact path, or a prefix when the pattern ends in `*`.
+pub fn path_matches_pattern(path: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+/// An empty `methods` list allows every method.
+pub fn method_allowed(route: &RouteConfig, method: &str) -> bool {
+    route.methods.is_empty() || route.methods.iter().any(|m| m == method)
+}
+
+/// The file `path_only` names under the route's root.
+pub fn resolve<'a>(route: &'a RouteConfig, path_only: &str) -> Target<'a> {
+    let relative_path = relative_path(route, path_only);
+    match route.root.strip_prefix(MOUNT_PREFIX) {
+        Some(name) => Target::Mounted { name, path: PathBuf::from(relative_path) },
+        None => Target::Disk(PathBuf::from(&route.root).join(relative_path)),
+    }
+}
+
+/// The same file under each of the route's `fallback_roots`, in the order
+/// they are tried when it is missing from `root`.
+pub fn fallbacks(route: &RouteConfig, path_only: &str) -> Vec<PathBuf> {
+    let relative_path = relative_path(route, path_only);
+    route.fallback_roots
+        .iter()
+        .map(|root| PathBuf::from(root).join(relative_path))
+        .collect()
+}
+
+/// The route's `canary` root, when the request selects a canary served from disk.
+pub fn canary_root<'a>(route: &'a RouteConfig, headers: &HashMap<String, String>) -> Option<&'a str> {
+    canary(route, headers)?.root.as_deref()
+}
+
+/// The route's `canary`, when the request carries its header or cookie (with
+/// the configured value, or any value when none is set).
+pub fn canary<'a>(route: &'a RouteConfig, headers: &HashMap<String, String>) -> Option<&'a Canary> {
+    let canary = route.canary.as_ref()?;
+    let cookies;
+    let sent = match &canary.trigger {
+        CanaryTrigger::Header(name) => headers.get(name).map(|value| value.trim()),
+        CanaryTrigger::Cookie(name) => {
+            cookies = cookie::parse(headers.get("cookie").map_or("", String::as_str));
+            cookies.get(name).map(String::as_str)
+        }
+    }?;
+    let selected = match &canary.value {
+        Some(expected) => sent == expected,
+        None => !sent.is_empty(),
+    };
+    selected.then_some(canary)
+}
+
+/// `path_only` with the route prefix removed, relative to a root.
+fn relative_path<'a>(route: &RouteConfig, path_only: &'a str) -> &'a str {
+    path_only.strip_prefix(&route.path).unwrap_or("").trim_start_matches('/')
+}
+
+/// Fills `$request_path` (the path after the route prefix), `$uri` (the
+/// whole path), `$query_string` / `$is_args` and `$host` into a redirect
+/// target. A target that is neither a URL nor an absolute path is taken
+/// relative to the route, so it means the same whatever path was requested.
+pub fn expand_redirect_target(
+    target: &str,
+    route_path: &str,
+    path_only: &str,
+    query_string: &str,
+    headers: &HashMap<String, String>
+) -> String {
+    if !target.contains('$') {
+        return resolve_redirect_target(target, route_path);
+    }
+
+    let remaining = match route_path.trim_end_matches('/') {
+        "" => path_only,
+        prefix => path_only.strip_prefix(prefix).unwrap_or(path_only),
+    };
+    let request_path = if remaining.starts_with('/') {
+        remaining.to_string()
+    } else {
+        format!("/{}", remaining)
+    };
+    let host = headers.get("host").map(|h| h.trim()).unwrap_or("");
+
+    // Longer names first so `$uri` does not eat a prefix of another variable
+    let expanded = target
+        .replace("$request_path", &request_path)
+        .replace("$query_string", query_string)
+        .replace("$is_args", if query_string.is_empty() { "" } else { "?" })
+        .replace("$host", host)
+        .replace("$uri", path_only);
+    resolve_redirect_target(&expanded, route_path)
+}
+
+/// `v2/intro` on route `/docs` is `/docs/v2/intro`, `../blog` is `/blog`;
+/// URLs (`https://...`, `//host/...`) and absolute paths are kept as they are.
+fn resolve_redirect_target(target: &str, route_path: &str) -> String {
+    let is_url = target.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    if is_url || target.starts_with('/') {
+        return target.to_string();
+    }
+
+    let split = target.find(['?', '#']).unwrap_or(target.len());
+    let (relative, suffix) = target.split_at(split);
+    let mut segments: Vec<&str> = route_path.split('/').filter(|segment| !segment.is_empty()).collect();
+    for segment in relative.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let trailing = if relative.ends_with('/') && !segments.is_empty() { "/" } else { "" };
+    format!("/{}{}{}", segments.join("/"), trailing, suffix)
+}
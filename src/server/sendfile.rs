@@ -0,0 +1,86 @@
+//! Static files from disk go out with `sendfile(2)` on Linux: the kernel copies
+//! them to the socket without the bytes passing through the server. Where the
+//! call is missing or refuses the file, the rest is streamed through a buffer.
+
+use std::fs::File;
+use std::io::{ self, Read, Seek, SeekFrom };
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+unsafe extern "C" {
+    fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+}
+
+/// Larger counts are split up so one client cannot hold the loop for long.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+const MAX_CHUNK: usize = 1024 * 1024;
+
+/// A file body and how far into it the client has got.
+pub(crate) struct FileBody {
+    file: File,
+    offset: u64,
+    remaining: u64,
+}
+
+pub(crate) enum Progress {
+    /// Everything was sent, or the file ended early.
+    Done,
+    /// The socket is full; carry on when it is writable again.
+    WouldBlock,
+    /// `sendfile` cannot be used here; `into_reader` gives the rest.
+    Unsupported,
+    Failed(io::Error),
+}
+
+impl FileBody {
+    pub(crate) fn new(file: File, len: u64) -> Self {
+        Self { file, offset: 0, remaining: len }
+    }
+
+    /// Sends as much as `socket` takes.
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    pub(crate) fn send(&mut self, socket: &impl std::os::fd::AsRawFd) -> Progress {
+        use std::os::fd::AsRawFd;
+
+        while self.remaining > 0 {
+            let mut offset = self.offset as i64;
+            let count = self.remaining.min(MAX_CHUNK as u64) as usize;
+            let sent = unsafe { sendfile(socket.as_raw_fd(), self.file.as_raw_fd(), &mut offset, count) };
+            if sent < 0 {
+                let error = io::Error::last_os_error();
+                match error.kind() {
+                    io::ErrorKind::WouldBlock => {
+                        return Progress::WouldBlock;
+                    }
+                    io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    // ENOSYS, EINVAL (a file system without it), EOPNOTSUPP
+                    _ if matches!(error.raw_os_error(), Some(38 | 22 | 95)) => {
+                        return Progress::Unsupported;
+                    }
+                    _ => {
+                        return Progress::Failed(error);
+                    }
+                }
+            }
+            if sent == 0 {
+                // Truncated since it was opened: nothing more to send
+                return Progress::Done;
+            }
+            self.offset += sent as u64;
+            self.remaining -= sent as u64;
+        }
+        Progress::Done
+    }
+
+    #[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+    pub(crate) fn send(&mut self, _socket: &impl std::os::fd::AsRawFd) -> Progress {
+        Progress::Unsupported
+    }
+
+    /// What is left, as a reader for the buffered path.
+    pub(crate) fn into_reader(mut self) -> io::Result<Box<dyn Read>> {
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        Ok(Box::new(self.file.take(self.remaining)))
+    }
+}
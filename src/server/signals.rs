@@ -0,0 +1,42 @@
+//! SIGINT and SIGTERM stop the event loop the same way `ShutdownSignal::trigger`
+//! does, so the server closes its connections and reaps its CGI scripts
+//! instead of leaving them orphaned.
+
+use crate::error::ServerError;
+use crate::server::ShutdownSignal;
+use std::sync::OnceLock;
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+const SIG_DFL: usize = 0;
+const SIG_ERR: usize = usize::MAX;
+
+unsafe extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+/// A handler cannot take arguments, so the signal it triggers lives here.
+static TARGET: OnceLock<ShutdownSignal> = OnceLock::new();
+
+extern "C" fn on_signal(signum: i32) {
+    // A second Ctrl-C while shutting down kills the process outright
+    unsafe {
+        signal(signum, SIG_DFL);
+    }
+    if let Some(shutdown) = TARGET.get() {
+        shutdown.trigger();
+    }
+}
+
+/// Only the first server in a process gets the signals.
+pub(crate) fn install(shutdown: ShutdownSignal) -> Result<(), ServerError> {
+    if TARGET.set(shutdown).is_err() {
+        return Ok(());
+    }
+    for signum in [SIGINT, SIGTERM] {
+        if unsafe { signal(signum, on_signal as *const () as usize) } == SIG_ERR {
+            return Err(ServerError::last_os_error(format!("Cannot handle signal {}", signum)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! `--trace-http`: the raw bytes of each request as they are read and of each
+//! response as it is queued, one log line per header line. Credentials are
+//! masked, control bytes are escaped, and bodies are shown only up to a cap:
+//!
+//! ```text
+//! [Trace] #7 <- GET /upload HTTP/1.1\r\n
+//! [Trace] #7 <- Authorization: [redacted]\r\n
+//! [Trace] #7 <- Transfer-Encoding: chunked\r\n
+//! [Trace] #7 <- \r\n
+//! [Trace] #7 <- body 0000  5\r\nhello\r\n0\r\n\r\n
+//! [Trace] #7 -> HTTP/1.1 201 Created\r\n
+//! ```
+
+use std::ops::Range;
+
+/// Headers whose values never appear in a trace.
+const REDACTED: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Read from the client.
+    Received,
+    /// Queued for the client.
+    Sent,
+}
+
+/// Trace lines for the bytes at `range` of `message`, the request or response
+/// they belong to as far as it is known. Up to `body_limit` bytes of the body
+/// are shown, as text when they are printable and as a hexdump otherwise.
+pub fn describe(connection: usize, direction: Direction, message: &[u8], range: Range<usize>, body_limit: usize) -> Vec<String> {
+    let arrow = match direction {
+        Direction::Received => "<-",
+        Direction::Sent => "->",
+    };
+    let prefix = format!("[Trace] #{} {}", connection, arrow);
+    let head_end = message.windows(4).position(|w| w == b"\r\n\r\n").map_or(message.len(), |pos| pos + 4);
+    let mut lines = Vec::new();
+
+    let head = range.start.min(head_end)..range.end.min(head_end);
+    let mut at = head.start;
+    while at < head.end {
+        let end = message[at..head.end].iter().position(|&b| b == b'\n').map_or(head.end, |pos| at + pos + 1);
+        lines.push(format!("{} {}", prefix, head_line(message, at..end)));
+        at = end;
+    }
+
+    let body = range.start.max(head_end)..range.end.max(head_end);
+    if body.is_empty() {
+        return lines;
+    }
+    // Offsets are counted from the start of the body
+    let (start, end) = (body.start - head_end, body.end - head_end);
+    let shown = start..end.min(body_limit.max(start));
+    let data = &message[head_end + shown.start..head_end + shown.end];
+    if is_text(data) {
+        if !data.is_empty() {
+            lines.push(format!("{} body {:04x}  {}", prefix, shown.start, escape(data)));
+        }
+    } else {
+        for (i, row) in data.chunks(16).enumerate() {
+            lines.push(format!("{} body {:04x}  {}", prefix, shown.start + i * 16, hex_row(row)));
+        }
+    }
+    if shown.end < end {
+        lines.push(format!("{} body ({} more bytes not shown)", prefix, end - shown.end));
+    }
+    lines
+}
+
+/// The part of a head line at `part`; the whole line is looked at, since a
+/// read can end in the middle of a header.
+fn head_line(message: &[u8], part: Range<usize>) -> String {
+    let line_start = message[..part.start].iter().rposition(|&b| b == b'\n').map_or(0, |pos| pos + 1);
+    let line_end = message[line_start..].iter().position(|&b| b == b'\n').map_or(message.len(), |pos| line_start + pos);
+    let colon = match message[line_start..line_end].iter().position(|&b| b == b':') {
+        Some(pos) => line_start + pos,
+        None => {
+            return escape(&message[part]);
+        }
+    };
+    let name = String::from_utf8_lossy(&message[line_start..colon]).trim().to_ascii_lowercase();
+    if !REDACTED.contains(&name.as_str()) {
+        return escape(&message[part]);
+    }
+
+    let name_end = (colon + 1).clamp(part.start, part.end);
+    let value = &message[name_end..part.end];
+    let value_len = value.iter().rposition(|&b| b != b'\r' && b != b'\n').map_or(0, |pos| pos + 1);
+    let mask = match value_len {
+        0 => "",
+        _ if part.start <= colon => " [redacted]",
+        _ => "[redacted]",
+    };
+    format!("{}{}{}", escape(&message[part.start..name_end]), mask, escape(&value[value_len..]))
+}
+
+/// Printable ASCII as-is, line breaks and tabs as `\r` `\n` `\t`, anything else as `\xNN`.
+fn escape(data: &[u8]) -> String {
+    let mut out = String::new();
+    for &b in data {
+        match b {
+            b'\r' => out.push_str("\\r"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out
+}
+
+/// Mostly printable: worth reading as text rather than as a hexdump.
+fn is_text(data: &[u8]) -> bool {
+    let printable = data.iter().filter(|&&b| matches!(b, 0x20..=0x7e | b'\r' | b'\n' | b'\t')).count();
+    printable * 10 >= data.len() * 9
+}
+
+fn hex_row(row: &[u8]) -> String {
+    let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+    let ascii: String = row
+        .iter()
+        .map(|&b| if (0x20..=0x7e).contains(&b) { b as char } else { '.' })
+        .collect();
+    format!("{:<47}  |{}|", hex.join(" "), ascii)
+}
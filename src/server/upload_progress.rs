@@ -0,0 +1,77 @@
+//! `upload_progress: /progress`: requests sent with an `X-Upload-ID` header are
+//! tracked while their body arrives, and `GET /progress?id=<id>` reports how
+//! far along they are, for upload forms that draw a progress bar.
+
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+
+/// Header the client picks the upload's id with.
+pub const ID_HEADER: &str = "x-upload-id";
+
+/// A finished upload stays visible this long, so the last poll sees it end.
+pub(crate) const KEEP_FINISHED: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum UploadState {
+    Uploading,
+    Done,
+    Error,
+}
+
+pub(crate) struct UploadProgress {
+    /// Body bytes read so far (chunked framing included).
+    pub(crate) received: usize,
+    /// `Content-Length`; `None` for a chunked body.
+    pub(crate) size: Option<usize>,
+    pub(crate) state: UploadState,
+    pub(crate) finished: Option<Instant>,
+}
+
+#[derive(Default)]
+pub(crate) struct UploadTracker {
+    uploads: HashMap<String, UploadProgress>,
+}
+
+impl UploadTracker {
+    /// Starts tracking `id`, replacing an earlier upload that used the same one.
+    pub(crate) fn start(&mut self, id: &str, size: Option<usize>) {
+        let progress = UploadProgress { received: 0, size, state: UploadState::Uploading, finished: None };
+        self.uploads.insert(id.to_string(), progress);
+    }
+
+    pub(crate) fn update(&mut self, id: &str, received: usize) {
+        if let Some(progress) = self.uploads.get_mut(id) {
+            progress.received = received;
+        }
+    }
+
+    /// Only the first outcome counts: the reply is sent before the connection closes.
+    pub(crate) fn finish(&mut self, id: &str, ok: bool, now: Instant) {
+        if let Some(progress) = self.uploads.get_mut(id) && progress.state == UploadState::Uploading {
+            progress.state = if ok { UploadState::Done } else { UploadState::Error };
+            progress.finished = Some(now);
+        }
+    }
+
+    pub(crate) fn prune(&mut self, now: Instant) {
+        self.uploads.retain(|_, progress| progress.finished.is_none_or(|at| now.duration_since(at) < KEEP_FINISHED));
+    }
+
+    /// `{"state":"uploading","received":1024,"size":4096}`; an id not seen yet is
+    /// `starting`, since the page usually polls before the upload's first bytes arrive.
+    pub(crate) fn report(&self, id: &str) -> String {
+        let progress = match self.uploads.get(id) {
+            Some(p) => p,
+            None => {
+                return "{\"state\":\"starting\"}".to_string();
+            }
+        };
+        let state = match progress.state {
+            UploadState::Uploading => "uploading",
+            UploadState::Done => "done",
+            UploadState::Error => "error",
+        };
+        let size = progress.size.map_or("null".to_string(), |s| s.to_string());
+        format!("{{\"state\":\"{}\",\"received\":{},\"size\":{}}}", state, progress.received, size)
+    }
+}
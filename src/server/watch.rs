@@ -0,0 +1,183 @@
+//! inotify watches. `--watch` watches the directory holding the config file,
+//! so edits (including editors that save by renaming a temp file over it) and
+//! changes to sibling profile or include files trigger a reload; `--dev`
+//! watches every route root to reload browsers.
+
+use crate::error::ServerError;
+use std::ffi::{ CString, OsStr, OsString };
+use std::fs::File;
+use std::io::{ self, Read };
+use std::os::fd::{ AsRawFd, FromRawFd, RawFd };
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Path, PathBuf };
+
+unsafe extern "C" {
+    fn inotify_init1(flags: i32) -> i32;
+    fn inotify_add_watch(fd: i32, path: *const std::ffi::c_char, mask: u32) -> i32;
+}
+
+const IN_NONBLOCK: i32 = 0o4000;
+const IN_CLOEXEC: i32 = 0o2000000;
+const IN_MODIFY: u32 = 0x2;
+const IN_CLOSE_WRITE: u32 = 0x8;
+const IN_MOVED_TO: u32 = 0x80;
+const IN_CREATE: u32 = 0x100;
+const IN_DELETE: u32 = 0x200;
+const IN_MOVED_FROM: u32 = 0x40;
+const IN_ISDIR: u32 = 0x40000000;
+
+/// Size of `struct inotify_event` without its trailing name.
+const EVENT_HEADER: usize = 16;
+
+const CONFIG_EXTENSIONS: [&str; 4] = ["yaml", "yml", "toml", "json"];
+
+pub struct ConfigWatcher {
+    inotify: File,
+    file_name: OsString,
+}
+
+impl ConfigWatcher {
+    pub fn new(config_path: &Path) -> Result<Self, ServerError> {
+        let file_name = config_path
+            .file_name()
+            .ok_or_else(|| ServerError::Config(format!("{}: not a file", config_path.display())))?
+            .to_os_string();
+        let dir = match config_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let inotify = init()?;
+        add_watch(&inotify, dir, IN_MODIFY | IN_CLOSE_WRITE | IN_MOVED_TO | IN_CREATE | IN_DELETE)?;
+        Ok(Self { inotify, file_name })
+    }
+
+    /// Drains pending events; true when one of them touched the config file or
+    /// another config file next to it (profile overlays, includes).
+    pub fn changed(&mut self) -> bool {
+        let mut relevant = false;
+        let file_name = &self.file_name;
+        drain_events(&mut self.inotify, |_, name| relevant |= is_config_file(file_name, name));
+        relevant
+    }
+}
+
+fn is_config_file(file_name: &OsStr, name: &OsStr) -> bool {
+    name == file_name ||
+        Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext))
+}
+
+impl AsRawFd for ConfigWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+/// Every directory under a set of roots. inotify is not recursive, so each
+/// directory gets its own watch and new ones are added as they appear.
+pub struct TreeWatcher {
+    inotify: File,
+    roots: Vec<PathBuf>,
+}
+
+const TREE_MASK: u32 = IN_MODIFY | IN_CLOSE_WRITE | IN_MOVED_TO | IN_MOVED_FROM | IN_CREATE | IN_DELETE;
+
+impl TreeWatcher {
+    pub fn new(roots: Vec<PathBuf>) -> Result<Self, ServerError> {
+        let inotify = init()?;
+        let watcher = Self { inotify, roots };
+        watcher.watch_all()?;
+        Ok(watcher)
+    }
+
+    /// Directories watched; for the startup message.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Drains pending events; true when anything under the roots changed.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        let mut new_dir = false;
+        drain_events(&mut self.inotify, |mask, _| {
+            changed = true;
+            new_dir |= mask & IN_ISDIR != 0 && mask & (IN_CREATE | IN_MOVED_TO) != 0;
+        });
+        // Watching a directory twice is harmless, so simply walk again
+        if new_dir {
+            let _ = self.watch_all();
+        }
+        changed
+    }
+
+    fn watch_all(&self) -> Result<(), ServerError> {
+        let mut pending = self.roots.clone();
+        while let Some(dir) = pending.pop() {
+            add_watch(&self.inotify, &dir, TREE_MASK)?;
+            if let Ok(entries) = std::fs::read_dir(&dir) {
+                pending.extend(entries.flatten().map(|e| e.path()).filter(|p| p.is_dir() && !p.is_symlink()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for TreeWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+fn init() -> Result<File, ServerError> {
+    let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(ServerError::last_os_error("inotify_init1 failed"));
+    }
+    // Owned from here on, so the descriptor is closed on every error path
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn add_watch(inotify: &File, dir: &Path, mask: u32) -> Result<(), ServerError> {
+    let c_dir = CString
+        ::new(dir.as_os_str().as_bytes())
+        .map_err(|_| ServerError::Config(format!("Invalid path: {}", dir.display())))?;
+    if unsafe { inotify_add_watch(inotify.as_raw_fd(), c_dir.as_ptr(), mask) } < 0 {
+        return Err(ServerError::last_os_error(format!("Cannot watch {}", dir.display())));
+    }
+    Ok(())
+}
+
+/// Reads every queued event, passing each one's mask and file name to `on_event`.
+fn drain_events(inotify: &mut File, mut on_event: impl FnMut(u32, &OsStr)) {
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = match inotify.read(&mut buf) {
+            Ok(0) => {
+                break;
+            }
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                continue;
+            }
+            Err(_) => {
+                break;
+            }
+        };
+
+        let mut offset = 0;
+        while offset + EVENT_HEADER <= n {
+            let mask = u32::from_ne_bytes(buf[offset + 4..offset + 8].try_into().unwrap());
+            let name_len = u32::from_ne_bytes(buf[offset + 12..offset + 16].try_into().unwrap()) as usize;
+            let end = (offset + EVENT_HEADER + name_len).min(n);
+            // The name is NUL-padded to the next alignment boundary
+            let raw = &buf[offset + EVENT_HEADER..end];
+            let name = &raw[..raw.iter().position(|&b| b == 0).unwrap_or(raw.len())];
+            on_event(mask, OsStr::from_bytes(name));
+            offset = end;
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! End-to-end test support: run a real server on an ephemeral port in a
+//! background thread and talk to it over TCP.
+//!
+//! ```no_run
+//! use localserver::config::{ ConfigBuilder, RouteBuilder, ServerBlockBuilder };
+//! use localserver::testing::TestServer;
+//!
+//! let config = ConfigBuilder::new()
+//!     .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root("./www")))
+//!     .build()
+//!     .unwrap();
+//! let server = TestServer::start(config).unwrap();
+//! let response = server.get("/missing");
+//! assert_eq!(response.status, 404);
+//! ```
+
+use crate::config::models::Config;
+use crate::error::ServerError;
+use crate::server::{ Server, ShutdownSignal };
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream };
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server running on its own thread; dropping it shuts the server down.
+pub struct TestServer {
+    addrs: Vec<SocketAddr>,
+    shutdown: ShutdownSignal,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Binds every server block (use port 0 for a free port) and starts serving.
+    pub fn start(config: Config) -> Result<Self, ServerError> {
+        Self::start_with(config, |_| {})
+    }
+
+    /// Like `start`, with `setup` run on the server first (hooks, pipeline stages, ...).
+    pub fn start_with(config: Config, setup: impl FnOnce(&mut Server) + Send + 'static) -> Result<Self, ServerError> {
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        // The server is built on its own thread: it is not `Send`
+        let thread = std::thread::spawn(move || {
+            let mut server = Server::from_config(config);
+            setup(&mut server);
+            if let Err(e) = server.bind() {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+            let shutdown = server.shutdown_signal();
+            let _ = ready_tx.send(Ok((server.local_addrs(), shutdown.clone())));
+            server.run_until(&shutdown);
+        });
+
+        let (addrs, shutdown) = ready_rx
+            .recv()
+            .map_err(|_| ServerError::Io {
+                context: "Test server".to_string(),
+                source: std::io::Error::other("thread exited during startup"),
+            })??;
+        Ok(Self { addrs, shutdown, thread: Some(thread) })
+    }
+
+    /// Address of the first server block.
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+
+    /// Sends `bytes` as-is to the first server block and reads until the server closes.
+    pub fn send_raw(&self, bytes: &[u8]) -> Vec<u8> {
+        send_raw(self.addr(), bytes)
+    }
+
+    /// Like `send_raw`, parsed into a `TestResponse`.
+    pub fn request(&self, bytes: &[u8]) -> TestResponse {
+        TestResponse::parse(&self.send_raw(bytes))
+    }
+
+    pub fn get(&self, path: &str) -> TestResponse {
+        self.request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes `bytes` to `addr` and returns everything read back until the server closes.
+pub fn send_raw(addr: SocketAddr, bytes: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).expect("connect to test server");
+    stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
+    stream.set_write_timeout(Some(IO_TIMEOUT)).ok();
+    // No half-close after writing: the server treats that as a client hanging up
+    stream.write_all(bytes).expect("write request");
+
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    response
+}
+
+/// A response as received; `status` is 0 when nothing parseable came back.
+pub struct TestResponse {
+    pub status: u16,
+    /// Lowercase names, in the order received.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn parse(raw: &[u8]) -> Self {
+        let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n");
+        let (head, body) = match head_end {
+            Some(pos) => (&raw[..pos], raw[pos + 4..].to_vec()),
+            None => (raw, Vec::new()),
+        };
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+
+        Self { status, headers, body }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
@@ -0,0 +1,310 @@
+//! Where static files come from. Routes read through a `Vfs`: the real
+//! filesystem by default, or a tree the embedding program mounts with
+//! `Server::mount` and routes select with `root: "vfs:<name>"`.
+//!
+//! ```no_run
+//! use localserver::vfs::{ MemoryFs, Overlay, DiskFs };
+//!
+//! let mut generated = MemoryFs::new();
+//! generated.insert("version.txt", "1.4.2");
+//! // Generated files first, then whatever is on disk under ./public
+//! let site = Overlay::new(vec![Box::new(generated), Box::new(DiskFs::rooted("./public"))]);
+//! # let mut server: localserver::Server = unimplemented!();
+//! server.mount("site", Box::new(site));
+//! ```
+//!
+//! Uploads, DELETE and CGI scripts always work on the real filesystem.
+//!
+//! `embed_assets!` bakes files into the binary at compile time, so a program
+//! can serve its UI with nothing on disk:
+//!
+//! ```ignore
+//! // Paths are relative to the crate's Cargo.toml
+//! let ui = localserver::embed_assets!("ui/dist", ["index.html", "app.js", "img/logo.png"]);
+//! server.mount("ui", Box::new(ui));
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{ self, Cursor, Read };
+use std::path::{ Component, Path, PathBuf };
+use std::time::SystemTime;
+
+/// Marks a route root as a mounted tree rather than a directory.
+pub const MOUNT_PREFIX: &str = "vfs:";
+
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Size in bytes; 0 for directories.
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// Read-only access to a tree of files. Paths are relative to the tree's root
+/// (a leading `/` is ignored) and never contain `..` by the time they get here.
+pub trait Vfs {
+    /// `None` when nothing exists at `path`.
+    fn metadata(&self, path: &Path) -> Option<Metadata>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Directory contents, in no particular order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_some()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_some_and(|m| m.is_dir)
+    }
+
+    /// A reader over the file, for trees that can avoid loading it whole.
+    fn stream(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(Cursor::new(self.read(path)?)))
+    }
+}
+
+/// The real filesystem. `DiskFs::default()` takes paths as they are (the
+/// server's own routes join their `root` first); `rooted` confines them to a directory.
+#[derive(Default)]
+pub struct DiskFs {
+    root: Option<PathBuf>,
+}
+
+impl DiskFs {
+    pub fn rooted(dir: impl Into<PathBuf>) -> Self {
+        Self { root: Some(dir.into()) }
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match &self.root {
+            Some(root) => root.join(normalize(path)),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+impl Vfs for DiskFs {
+    fn metadata(&self, path: &Path) -> Option<Metadata> {
+        let meta = std::fs::metadata(self.resolve(path)).ok()?;
+        Some(Metadata {
+            is_dir: meta.is_dir(),
+            len: if meta.is_dir() { 0 } else { meta.len() },
+            modified: meta.modified().ok(),
+        })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(self.resolve(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(self.resolve(path))?.flatten() {
+            if let Some(name) = entry.file_name().to_str() {
+                entries.push(DirEntry { name: name.to_string(), is_dir: entry.path().is_dir() });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stream(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(std::fs::File::open(self.resolve(path))?))
+    }
+}
+
+/// Files held in memory; directories exist implicitly above every file.
+#[derive(Default)]
+pub struct MemoryFs {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: impl AsRef<Path>, content: impl Into<Vec<u8>>) {
+        self.files.insert(normalize(path.as_ref()), content.into());
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn metadata(&self, path: &Path) -> Option<Metadata> {
+        let path = normalize(path);
+        if let Some(content) = self.files.get(&path) {
+            return Some(Metadata { is_dir: false, len: content.len() as u64, modified: None });
+        }
+        let is_dir = path.as_os_str().is_empty() || self.files.keys().any(|file| file.starts_with(&path));
+        is_dir.then_some(Metadata { is_dir: true, len: 0, modified: None })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        list_dir(self.files.keys().map(PathBuf::as_path), path)
+    }
+}
+
+/// Files compiled into the binary, usually made with `embed_assets!`.
+pub struct EmbeddedFs {
+    files: &'static [(&'static str, &'static [u8])],
+}
+
+impl EmbeddedFs {
+    /// `(path, content)` pairs; paths are relative to the tree's root.
+    pub const fn new(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        Self { files }
+    }
+
+    fn find(&self, path: &Path) -> Option<&'static [u8]> {
+        let path = normalize(path);
+        self.files
+            .iter()
+            .find(|(name, _)| normalize(Path::new(name)) == path)
+            .map(|(_, content)| *content)
+    }
+}
+
+impl Vfs for EmbeddedFs {
+    fn metadata(&self, path: &Path) -> Option<Metadata> {
+        if let Some(content) = self.find(path) {
+            return Some(Metadata { is_dir: false, len: content.len() as u64, modified: None });
+        }
+        let dir = normalize(path);
+        let is_dir =
+            dir.as_os_str().is_empty() || self.files.iter().any(|(name, _)| normalize(Path::new(name)).starts_with(&dir));
+        is_dir.then_some(Metadata { is_dir: true, len: 0, modified: None })
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.find(path)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let names: Vec<PathBuf> = self.files
+            .iter()
+            .map(|(name, _)| normalize(Path::new(name)))
+            .collect();
+        list_dir(names.iter().map(PathBuf::as_path), path)
+    }
+
+    fn stream(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        match self.find(path) {
+            Some(content) => Ok(Box::new(content)),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+}
+
+/// An `EmbeddedFs` holding the listed files of `dir`, read at compile time.
+/// `dir` is relative to the calling crate's `Cargo.toml`; each file keeps its
+/// listed path inside the tree.
+#[macro_export]
+macro_rules! embed_assets {
+    ($dir:literal, [$($file:literal),* $(,)?]) => {
+        $crate::vfs::EmbeddedFs::new(&[
+            $(($file, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $dir, "/", $file)) as &[u8])),*
+        ])
+    };
+}
+
+/// The entries directly under `dir` for a tree that only stores file paths;
+/// directories are the first component of any deeper path.
+fn list_dir<'a>(files: impl Iterator<Item = &'a Path>, dir: &Path) -> io::Result<Vec<DirEntry>> {
+    let dir = normalize(dir);
+    let mut entries: Vec<DirEntry> = Vec::new();
+    let mut found = dir.as_os_str().is_empty();
+    for file in files {
+        let mut rest = match file.strip_prefix(&dir) {
+            Ok(rest) => rest.components(),
+            Err(_) => {
+                continue;
+            }
+        };
+        let name = match rest.next() {
+            Some(first) => first.as_os_str().to_string_lossy().into_owned(),
+            None => {
+                // `dir` is itself a file
+                continue;
+            }
+        };
+        found = true;
+        if !entries.iter().any(|e| e.name == name) {
+            entries.push(DirEntry { name, is_dir: rest.next().is_some() });
+        }
+    }
+    if found { Ok(entries) } else { Err(io::Error::from(io::ErrorKind::NotFound)) }
+}
+
+/// Layers searched in order: the first one holding a path serves it, and
+/// directory listings merge every layer.
+pub struct Overlay {
+    layers: Vec<Box<dyn Vfs>>,
+}
+
+impl Overlay {
+    pub fn new(layers: Vec<Box<dyn Vfs>>) -> Self {
+        Self { layers }
+    }
+}
+
+impl Vfs for Overlay {
+    fn metadata(&self, path: &Path) -> Option<Metadata> {
+        self.layers.iter().find_map(|layer| layer.metadata(path))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.layers.iter().find(|layer| layer.exists(path)) {
+            Some(layer) => layer.read(path),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries: Vec<DirEntry> = Vec::new();
+        let mut found = false;
+        for layer in &self.layers {
+            if let Ok(layer_entries) = layer.read_dir(path) {
+                found = true;
+                for entry in layer_entries {
+                    if !entries.iter().any(|e| e.name == entry.name) {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+        if found { Ok(entries) } else { Err(io::Error::from(io::ErrorKind::NotFound)) }
+    }
+
+    fn stream(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        match self.layers.iter().find(|layer| layer.exists(path)) {
+            Some(layer) => layer.stream(path),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+}
+
+/// `/a/./b/` -> `a/b`; `..` is dropped so a path cannot leave the tree.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,183 @@
+use localserver::config::{ RouteBuilder, ServerBlockBuilder };
+use localserver::testing::TestServer;
+use std::fs;
+
+mod common;
+use common::{ builder, site, start };
+
+/// 1024-bit key; `RS256_TOKEN` is `{"sub":"alice"}` signed with its private half.
+const RSA_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDXVMgWqmpDzxhptAZnniPyXPvz
+7nk9L5zBSRhqmLKs2Imc9OgI96kXFRtU8Ei0BGmyrPWc0B7RCJQ2FzRrV4Qq9muW
+ntesJizE/Dl8dXoD7bmDCIZvfkYapsdYzUK8Ik1dsLAs+8Xe9Js7B5AR+XnCUKLU
+w1qSsvv+9cNU8WERdwIDAQAB
+-----END PUBLIC KEY-----
+";
+const RS256_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJhbGljZSJ9.\
+    qRHlK1Cv_YhmKGpBuq46fS8MG-gcpSecMU3j4u8dUG8dn5cDHDlZ4s34H-gS6jhxWALnGVvWw959Qe4Rp1cMWB2TNwdyHvS_eRCKJCQiuc_3SCulMaxFPYZWsa0X-1dUYmZCVmKmfJqPXJ2z1GcL0ET7ocgN7xGRu81hwCINDFA";
+
+fn get_with(server: &TestServer, path: &str, headers: &str) -> localserver::testing::TestResponse {
+    server.request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", path, headers).as_bytes())
+}
+
+#[test]
+fn jwt_public_keys_are_read_once_when_the_config_loads() {
+    let dir = site("rs256");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let key = dir.join("jwt.pem");
+    fs::write(&key, RSA_PUBLIC_KEY).unwrap();
+    let route = |key: &str| RouteBuilder::new("/").root(&root).index("index.html").jwt_public_key(key);
+
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(route(&key.to_string_lossy())))
+            .build()
+            .unwrap()
+    );
+    // Requests no longer need the file
+    fs::remove_file(&key).unwrap();
+    let bearer = format!("Authorization: Bearer {}\r\n", RS256_TOKEN);
+    assert_eq!(get_with(&server, "/", &bearer).status, 200);
+    assert_eq!(server.get("/").status, 401);
+
+    fs::write(&key, "-----BEGIN PUBLIC KEY-----\nnot a key\n-----END PUBLIC KEY-----\n").unwrap();
+    let error = builder()
+        .server(ServerBlockBuilder::new("127.0.0.1", 0).route(route(&key.to_string_lossy())))
+        .build()
+        .err()
+        .expect("a bad key fails the build")
+        .to_string();
+    assert!(error.contains("jwt_public_key"), "{}", error);
+}
+
+#[test]
+fn refused_bearer_tokens_get_an_invalid_token_challenge() {
+    let dir = site("bearer");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let key = dir.join("jwt.pem");
+    fs::write(&key, RSA_PUBLIC_KEY).unwrap();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/")
+                        .root(&root)
+                        .index("index.html")
+                        .jwt_public_key(&key.to_string_lossy())
+                        .jwt_claims(Some("https://issuer.test"), None)
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let anonymous = server.get("/");
+    assert_eq!(anonymous.status, 401);
+    assert!(anonymous.header("www-authenticate").is_some_and(|v| v.starts_with("Bearer realm=") && !v.contains("error")));
+    // Signed, but without the required issuer; then the same claims unsigned
+    for token in [RS256_TOKEN, "eyJhbGciOiJub25lIn0.eyJzdWIiOiJhbGljZSJ9."] {
+        let refused = get_with(&server, "/", &format!("Authorization: Bearer {}\r\n", token));
+        assert_eq!(refused.status, 401);
+        assert!(refused.header("www-authenticate").is_some_and(|v| v.contains("error=\"invalid_token\"")));
+    }
+}
+
+#[test]
+fn basic_auth_checks_htpasswd_entries() {
+    let dir = site("basic");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    // Written here rather than kept in the repository: SHA-512 crypt, bcrypt and a plain entry
+    let users = dir.join("htpasswd");
+    fs::write(
+        &users,
+        "alice:$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1\n\
+         bob:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\n\
+         carol:plaintext\n"
+    ).unwrap();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/").root(&root).index("index.html").basic_auth(&users.to_string_lossy())
+                )
+            )
+            .build()
+            .unwrap()
+    );
+    let login = |credentials: &str| {
+        get_with(&server, "/", &format!("Authorization: Basic {}\r\n", base64(credentials.as_bytes()))).status
+    };
+
+    let anonymous = server.get("/");
+    assert_eq!(anonymous.status, 401);
+    assert!(anonymous.header("www-authenticate").is_some_and(|v| v.starts_with("Basic realm=")));
+    assert_eq!(login("alice:Hello world!"), 200);
+    assert_eq!(login("bob:U*U"), 200);
+    assert_eq!(login("alice:hello world!"), 401);
+    assert_eq!(login("bob:Hello world!"), 401);
+    // Entries in formats the server does not accept never match
+    assert_eq!(login("carol:plaintext"), 401);
+    assert_eq!(login("dave:U*U"), 401);
+    assert_eq!(get_with(&server, "/", "Authorization: Basic not-base64\r\n").status, 401);
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in input.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            out.push(if i <= chunk.len() { ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char } else { '=' });
+        }
+    }
+    out
+}
+
+#[test]
+fn cached_cgi_routes_answer_each_user_themselves() {
+    let dir = site("cgi-cache-users");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("who.sh"), "printf 'Content-Type: text/plain\\r\\n\\r\\n%s' \"$REMOTE_USER\"\n").unwrap();
+    fs::write(bin.join("count.sh"), format!("echo x >> {}\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\nwc -l < {0}\n", dir.join("runs").display())).unwrap();
+    let users = dir.join("htpasswd");
+    fs::write(
+        &users,
+        "alice:$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1\n\
+         bob:$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW\n"
+    ).unwrap();
+    let cached = |path: &str| {
+        RouteBuilder::new(path)
+            .root(&bin.to_string_lossy())
+            .cgi(".sh", "/bin/sh")
+            .cgi_cache(std::time::Duration::from_secs(60), 1024)
+    };
+    let config = builder()
+        .server(
+            ServerBlockBuilder::new("127.0.0.1", 0)
+                .route(cached("/private").basic_auth(&users.to_string_lossy()))
+                .route(cached("/public"))
+        )
+        .build()
+        .unwrap();
+    let problems = localserver::config::validate_config(&config);
+    assert!(problems.iter().any(|p| p.ends_with("route '/private': cgi_cache cannot be shared on a route with auth or csrf")), "{:?}", problems);
+
+    // Built without the check, the route still never shares one user's answer
+    let server = start(config);
+    let login = |credentials: &str| {
+        let response = get_with(&server, "/private/who.sh", &format!("Authorization: Basic {}\r\n", base64(credentials.as_bytes())));
+        (response.text(), response.header("x-cache").map(str::to_string))
+    };
+    for _ in 0..2 {
+        assert_eq!(login("alice:Hello world!"), ("alice".to_string(), None));
+        assert_eq!(login("bob:U*U"), ("bob".to_string(), None));
+    }
+
+    // Nor does a public route once the client has a session
+    let count = |headers: &str| get_with(&server, "/public/count.sh", headers).text().trim().to_string();
+    assert_eq!(count(""), "1");
+    assert_eq!(count(""), "1");
+    assert_eq!(count("Cookie: SESSION_ID=first\r\n"), "2");
+    assert_eq!(count("Cookie: SESSION_ID=second\r\n"), "3");
+}
@@ -0,0 +1,26 @@
+//! Fixtures shared by the test files that run a server.
+
+use localserver::config::models::Config;
+use localserver::config::ConfigBuilder;
+use localserver::log::Level;
+use localserver::testing::TestServer;
+use std::fs;
+use std::path::PathBuf;
+
+/// A fresh document root under the system temp directory, holding `www/index.html`.
+pub fn site(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("localserver-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("www")).unwrap();
+    fs::write(dir.join("www/index.html"), "<h1>home</h1>").unwrap();
+    dir
+}
+
+pub fn start(config: Config) -> TestServer {
+    TestServer::start(config).expect("server starts")
+}
+
+/// A config builder that keeps the log quiet.
+pub fn builder() -> ConfigBuilder {
+    ConfigBuilder::new().log_level(Level::Error)
+}
@@ -0,0 +1,565 @@
+use localserver::config::models::Config;
+use localserver::config::parse_config;
+use std::fs;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Stdio };
+use std::time::Duration;
+
+/// A fresh directory under the system temp directory holding `files`.
+fn dir_with(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("localserver-config-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, content) in files {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+/// Parses `content` as the only file of a fresh directory, named `file`.
+fn parse(name: &str, file: &str, content: &str) -> Result<Config, String> {
+    let dir = dir_with(name, &[(file, content)]);
+    parse_config(&dir.join(file).to_string_lossy(), None, None).map_err(|e| e.to_string())
+}
+
+/// The error for `content`, without the directory part of the file name.
+fn error(name: &str, file: &str, content: &str) -> String {
+    let message = parse(name, file, content).err().unwrap_or_else(|| panic!("accepted {:?}", content));
+    message.rsplit_once('/').map_or(message.clone(), |(_, rest)| rest.to_string())
+}
+
+/// Runs the server binary in `dir` with `args` and `env`, feeding it `stdin`;
+/// returns whether it succeeded and what it printed to stdout, then stderr.
+fn run(dir: &Path, args: &[&str], env: &[(&str, &str)], stdin: &str) -> (bool, String) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_localserver"));
+    command.current_dir(dir).args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    // Only the overrides a test asks for, not ones from the calling shell
+    for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with("LOCALSERVER_")) {
+        command.env_remove(name);
+    }
+    for (name, value) in env {
+        command.env(name, value);
+    }
+    let mut child = command.spawn().unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    (output.status.success(), text)
+}
+
+#[test]
+fn yaml_reads_nested_blocks_flow_collections_and_quoting() {
+    let config = parse(
+        "yaml",
+        "config.yaml",
+        r#"
+# Leading comment
+servers:
+  - host: 127.0.0.1   # trailing comment
+    port: "8080"
+    add_headers:
+      X-Note: 'it''s # not a comment'
+      X-Escaped: "tab\there \"quoted # kept\" back\\slash" # dropped
+    routes:
+      - path: /
+        root: [./site, "./theme"]
+        methods: [GET, HEAD]
+        index: index.html
+      - path: /hello
+        return:
+          code: 200
+          body: |
+            line one
+            line two
+      - path: /empty
+        index: ~
+"#
+    ).unwrap();
+
+    let server = &config.servers[0];
+    assert_eq!((server.host.as_str(), server.port.as_str()), ("127.0.0.1", "8080"));
+    assert_eq!(
+        server.add_headers,
+        [
+            ("X-Note".to_string(), "it's # not a comment".to_string()),
+            ("X-Escaped".to_string(), "tab\there \"quoted # kept\" back\\slash".to_string()),
+        ]
+    );
+    let routes = &server.routes;
+    assert_eq!(routes.len(), 3);
+    assert_eq!(routes[0].root, "./site");
+    assert_eq!(routes[0].fallback_roots, ["./theme"]);
+    assert_eq!(routes[0].methods, ["GET", "HEAD"]);
+    assert_eq!(routes[1].fixed_response.as_ref().unwrap().body, "line one\nline two\n");
+    assert_eq!(routes[2].index, None);
+}
+
+#[test]
+fn yaml_errors_name_the_file_and_line() {
+    let cases = [
+        ("servers:\n  - host: a\n\tport: 80\n", "config.yaml:3: tabs are not allowed for indentation"),
+        ("servers:\n  - host: a\n      port: 80\n", "config.yaml:3: unexpected indentation"),
+        ("servers:\n  - host: \"a\n", "config.yaml:2: unterminated quoted string"),
+        ("servers:\n  - host: \"a\\q\"\n", "config.yaml:2: unsupported escape '\\q'"),
+        ("servers:\n  - host: a\n    host: b\n", "config.yaml:3: duplicate key 'host'"),
+        ("servers:\n  - routes: [/a\n", "config.yaml:2: unterminated flow sequence"),
+        ("base: &b 1\n", "config.yaml:1: anchors, aliases and tags are not supported"),
+        ("servers: []\n---\nservers: []\n", "config.yaml:2: only a single YAML document is supported"),
+        ("servers:\n  - port: 80\n    routes:\n      - path: /\n        autoindex: maybe\n", "config.yaml:5: 'autoindex'"),
+    ];
+    for (content, expected) in cases {
+        let message = error("yaml-errors", "config.yaml", content);
+        assert!(message.starts_with(expected), "{:?}: {}", content, message);
+    }
+}
+
+#[test]
+fn toml_maps_tables_and_arrays_of_tables_onto_the_same_config() {
+    let config = parse(
+        "toml",
+        "config.toml",
+        r#"
+# Comments may follow any value
+max_server_size = "2M"  # a size string
+timeout_seconds = 45
+trusted_proxies = [
+  "10.0.0.0/8",  # inside arrays too
+  "192.0.2.1",
+]
+
+[[servers]]
+host = "127.0.0.1"
+port = 8080
+add_headers = { "X-Literal" = 'C:\path\no escapes', X-Basic = "tab\tquote\" \u00e9 # kept" }
+
+[[servers.routes]]
+path = "/"
+root = ["./site", "./theme"]
+methods = ["GET", "HEAD"]
+return.code = 200
+return.body = """
+first
+second"""
+
+[[servers.routes]]
+path = "/files"
+autoindex = true
+
+[[servers]]
+port = "8081"
+"#
+    ).unwrap();
+
+    assert_eq!(config.max_server_size, 2 * 1024 * 1024);
+    assert_eq!(config.timeout_seconds.as_secs(), 45);
+    assert_eq!(config.trusted_proxies, ["10.0.0.0/8", "192.0.2.1"]);
+    assert_eq!(config.servers.len(), 2);
+    let server = &config.servers[0];
+    assert_eq!((server.host.as_str(), server.port.as_str()), ("127.0.0.1", "8080"));
+    assert_eq!(
+        server.add_headers,
+        [
+            ("X-Literal".to_string(), "C:\\path\\no escapes".to_string()),
+            ("X-Basic".to_string(), "tab\tquote\" é # kept".to_string()),
+        ]
+    );
+    assert_eq!(server.routes.len(), 2);
+    assert_eq!((server.routes[0].root.as_str(), server.routes[0].fallback_roots.as_slice()), ("./site", &["./theme".to_string()][..]));
+    assert_eq!(server.routes[0].methods, ["GET", "HEAD"]);
+    // The newline right after the opening quotes is not part of the string
+    assert_eq!(server.routes[0].fixed_response.as_ref().unwrap().body, "first\nsecond");
+    assert!(server.routes[1].autoindex);
+    assert_eq!(config.servers[1].port, "8081");
+}
+
+#[test]
+fn toml_errors_name_the_file_and_line() {
+    let cases = [
+        ("[[servers]]\nhost = 127.0.0.1\n", "config.toml:2: invalid value '127.0.0.1' (strings must be quoted)"),
+        ("[[servers]]\nhost = \"a\nport = 80\n", "config.toml:2: newline in single-line string"),
+        ("[[servers]]\nhost = \"a\\q\"\n", "config.toml:2: invalid escape '\\q'"),
+        ("[[servers]]\nhost = \"a\"\nhost = \"b\"\n", "config.toml:3: duplicate key 'host'"),
+        ("[[servers]]\nport = 80\n\nmethods = [\"GET\"\n", "config.toml:4: unterminated array"),
+        ("servers = 1\n[servers.x]\n", "config.toml:2: 'servers' is already defined as a value"),
+        ("[[servers]]\nport = 80 80\n", "config.toml:2: invalid value '80 80' (strings must be quoted)"),
+        ("[[servers]]\nport = 8080.0.1\n", "config.toml:2: invalid value '8080.0.1' (strings must be quoted)"),
+        ("[[servers]]\nport = 80\nresolve = \"some\"\n", "config.toml:3: 'resolve' expects all or first, found 'some'"),
+    ];
+    for (content, expected) in cases {
+        let message = error("toml-errors", "config.toml", content);
+        assert!(message.starts_with(expected), "{:?}: {}", content, message);
+    }
+
+    // Bare values the config never uses are still read, then ignored
+    let unused = "strict = false\nsizes = [1_000, -3, +0.5, 6.02e23, 0xff, inf]\n\
+        when = [1979-05-27, 07:32:00, 1979-05-27T07:32:00Z, 1979-05-27 07:32:00.999-07:00]\n";
+    assert!(parse("toml-values", "config.toml", unused).is_ok());
+}
+
+#[test]
+fn json_maps_objects_and_arrays_onto_the_same_config() {
+    let config = parse(
+        "json",
+        "config.json",
+        r#"{
+  "max_server_size": 1048576,
+  "servers": [
+    {
+      "host": "127.0.0.1",
+      "port": 8080,
+      "add_headers": { "X-Escaped": "tab\t\"quoted\" \\ \/ \u00e9 \ud83d\ude00" },
+      "routes": [
+        { "path": "/", "root": ["./site", "./theme"], "methods": ["GET", "HEAD"], "index": null },
+        { "path": "/hello", "return": { "code": 200, "body": "hi\n" } }
+      ]
+    }
+  ]
+}
+"#
+    ).unwrap();
+
+    assert_eq!(config.max_server_size, 1024 * 1024);
+    let server = &config.servers[0];
+    assert_eq!((server.host.as_str(), server.port.as_str()), ("127.0.0.1", "8080"));
+    assert_eq!(server.add_headers, [("X-Escaped".to_string(), "tab\t\"quoted\" \\ / é 😀".to_string())]);
+    assert_eq!(server.routes[0].fallback_roots, ["./theme"]);
+    assert_eq!(server.routes[0].methods, ["GET", "HEAD"]);
+    assert_eq!(server.routes[0].index, None);
+    assert_eq!(server.routes[1].fixed_response.as_ref().unwrap().body, "hi\n");
+}
+
+#[test]
+fn json_errors_name_the_file_and_line() {
+    let cases = [
+        ("{\n  \"servers\": [\n    {\"port\": 80,}\n  ]\n}\n", "config.json:3: object keys must be quoted strings"),
+        ("{\n  \"servers\": [\n    {\"port\": 80}\n  \n}\n", "config.json:5: expected ',' or ']' in array"),
+        ("{\n  \"servers\": []\n}\n}\n", "config.json:4: unexpected '}' after the document"),
+        ("{\n  // no comments in JSON\n  \"servers\": []\n}\n", "config.json:2: object keys must be quoted strings"),
+        ("{\n  \"servers\": [{\"host\": \"a\n\"}]\n}\n", "config.json:2: unterminated string"),
+        ("{\n  \"servers\": [{\"host\": \"\\x\"}]\n}\n", "config.json:2: invalid escape '\\x'"),
+        ("{\n  \"servers\": [{\"host\": \"\\ud83d\\u0041\"}]\n}\n", "config.json:2: invalid unicode escape '\\u0041'"),
+        ("{\n  \"servers\": [{\"port\": 80, \"port\": 81}]\n}\n", "config.json:2: duplicate key 'port'"),
+        ("{\n  \"servers\": [{\"port\": +80}]\n}\n", "config.json:2: unexpected '+80'"),
+        ("{\n  \"servers\": [{\"port\": 080}]\n}\n", "config.json:2: unexpected '080'"),
+        ("{\n  \"timeout_seconds\": NaN\n}\n", "config.json:2: unexpected 'NaN'"),
+        ("{\n  \"servers\": [{\"port\": 80}],\n  \"log_level\": \"loud\"\n}\n", "config.json:3: 'log_level'"),
+        ("{\n  \"servers\": [\n", "config.json:3: unexpected end of file"),
+    ];
+    for (content, expected) in cases {
+        let message = error("json-errors", "config.json", content);
+        assert!(message.starts_with(expected), "{:?}: {}", content, message);
+    }
+}
+
+#[test]
+fn configs_can_be_piped_in_on_stdin() {
+    let dir = dir_with("stdin", &[]);
+    let json = r#"{"servers": [{"host": "127.0.0.1", "port": 8099, "routes": [{"path": "/"}]}]}"#;
+    let (ok, output) = run(&dir, &["--config", "-", "--dump-config=json"], &[], json);
+    assert!(ok, "{}", output);
+    assert!(output.contains("\"port\": \"8099\""), "{}", output);
+
+    // Without a leading `{` it is YAML, unless the format says otherwise
+    let (ok, output) = run(&dir, &["--config", "-", "--dump-config=json"], &[], "servers:\n  - port: 8098\n");
+    assert!(ok, "{}", output);
+    assert!(output.contains("\"port\": \"8098\""), "{}", output);
+    let (ok, output) = run(&dir, &["--config", "-", "--config-format", "json", "--test-config"], &[], "servers: []\n");
+    assert!(!ok);
+    assert!(output.contains("<stdin>:1: unexpected 'servers'"), "{}", output);
+}
+
+#[test]
+fn includes_append_server_blocks_from_other_files() {
+    let dir = dir_with(
+        "include",
+        &[
+            ("config.yaml", "include: [sites-enabled/*.yaml, extra.toml, sites-enabled/a.yaml]\nservers:\n  - port: 8000\n"),
+            ("sites-enabled/a.yaml", "port: 8001\n"),
+            ("sites-enabled/b.yaml", "servers:\n  - port: 8002\n  - port: 8003\n"),
+            ("sites-enabled/c.yml", "port: 9999\n"),
+            ("sites-enabled/.hidden.yaml", "port: 9998\n"),
+            ("extra.toml", "port = 8004\n"),
+        ]
+    );
+    let config = parse_config(&dir.join("config.yaml").to_string_lossy(), None, None).unwrap();
+    let ports: Vec<&str> = config.servers.iter().map(|s| s.port.as_str()).collect();
+    // In order, sorted within a wildcard, each file once
+    assert_eq!(ports, ["8000", "8001", "8002", "8003", "8004"]);
+
+    // A wildcard that matches nothing is an empty directory; a missing file is a mistake
+    let empty = dir_with("include-empty", &[("config.yaml", "include: sites-enabled/*.yaml\n"), ("sites-enabled/.keep", "")]);
+    assert!(parse_config(&empty.join("config.yaml").to_string_lossy(), None, None).is_ok());
+    let message = error("include-missing", "config.yaml", "include: nope.yaml\n");
+    assert_eq!(message, "config.yaml: include 'nope.yaml' does not exist");
+}
+
+#[test]
+fn includes_cannot_loop() {
+    // The main file matches its own wildcard and is not read twice
+    let dir = dir_with("include-self", &[("config.yaml", "include: '*.yaml'\nservers:\n  - port: 8000\n"), ("site.yaml", "port: 8001\n")]);
+    let config = parse_config(&dir.join("config.yaml").to_string_lossy(), None, None).unwrap();
+    assert_eq!(config.servers.iter().map(|s| s.port.as_str()).collect::<Vec<_>>(), ["8000", "8001"]);
+
+    // Included files cannot include, so a pair pointing at each other is refused rather than followed
+    let dir = dir_with(
+        "include-cycle",
+        &[
+            ("config.yaml", "include: a.yaml\n"),
+            ("a.yaml", "include: b.yaml\nservers: []\n"),
+            ("b.yaml", "include: a.yaml\nservers: []\n"),
+        ]
+    );
+    let message = parse_config(&dir.join("config.yaml").to_string_lossy(), None, None).err().unwrap().to_string();
+    assert!(message.ends_with("a.yaml:1: 'include' only works in the main configuration file"), "{}", message);
+    let dir = dir_with("include-back", &[("config.yaml", "include: a.yaml\nstrict: false\n"), ("a.yaml", "include: config.yaml\nport: 80\n")]);
+    let message = parse_config(&dir.join("config.yaml").to_string_lossy(), None, None).err().unwrap().to_string();
+    assert!(message.ends_with("a.yaml:1: 'include' only works in the main configuration file"), "{}", message);
+}
+
+#[test]
+fn sizes_take_binary_suffixes() {
+    let size = |value: &str| parse("sizes", "config.yaml", &format!("max_server_size: {}\n", value)).map(|c| c.max_server_size);
+    for (value, bytes) in [
+        ("2048", 2048),
+        ("1k", 1024),
+        ("1K", 1024),
+        ("512KB", 512 * 1024),
+        ("1m", 1024 * 1024),
+        ("10M", 10 * 1024 * 1024),
+        ("10MiB", 10 * 1024 * 1024),
+        ("1g", 1024 * 1024 * 1024),
+        ("\"4 k\"", 4096),
+    ] {
+        assert_eq!(size(value), Ok(bytes), "{}", value);
+    }
+
+    let config = parse(
+        "sizes-blocks",
+        "config.yaml",
+        "servers:\n  - port: 80\n    max_body_size: 64k\n    routes:\n      - path: /up\n        max_body_size: 1m\n"
+    ).unwrap();
+    assert_eq!(config.servers[0].max_body_size, 64 * 1024);
+    assert_eq!(config.servers[0].routes[0].max_body_size, Some(1024 * 1024));
+
+    for value in ["1.5M", "10X", "-1", "M", "1 kilo", "99999999999999G"] {
+        let message = error("sizes-bad", "config.yaml", &format!("strict: true\nmax_server_size: {}\n", value));
+        assert_eq!(
+            message,
+            format!("config.yaml:2: 'max_server_size' expects a size such as 1048576, 512K, 10M or 1G, found '{}'", value)
+        );
+    }
+}
+
+#[test]
+fn durations_take_units() {
+    let timeout = |value: &str| parse("durations", "config.yaml", &format!("timeout_seconds: {}\n", value)).map(|c| c.timeout_seconds);
+    for (value, expected) in [
+        ("45", Duration::from_secs(45)),
+        ("30s", Duration::from_secs(30)),
+        ("5m", Duration::from_secs(300)),
+        ("500ms", Duration::from_millis(500)),
+        ("1h30m", Duration::from_secs(5400)),
+        ("1m30s250ms", Duration::from_millis(90_250)),
+        ("2d", Duration::from_secs(2 * 86400)),
+    ] {
+        assert_eq!(timeout(value), Ok(expected), "{}", value);
+    }
+
+    let config = parse(
+        "durations-keys",
+        "config.yaml",
+        "cgi_timeout: 2m\nauth_nonce_timeout: 90s\nban_window_seconds: 1m\nban_duration_seconds: 1h\nmaintenance_retry_after: 30s\n"
+    ).unwrap();
+    assert_eq!(config.cgi_timeout, Some(Duration::from_secs(120)));
+    assert_eq!(config.auth_nonce_timeout, Duration::from_secs(90));
+    assert_eq!((config.ban_window_seconds, config.ban_duration_seconds), (Duration::from_secs(60), Duration::from_secs(3600)));
+    assert_eq!(config.maintenance_retry_after, Duration::from_secs(30));
+    assert_eq!(parse("durations-off", "config.yaml", "cgi_timeout: ~\n").unwrap().cgi_timeout, None);
+
+    for value in ["1.5s", "5x", "m5", "30 s", "s", "-1s", "99999999999999999999d"] {
+        let message = error("durations-bad", "config.yaml", &format!("strict: true\ntimeout_seconds: {}\n", value));
+        assert_eq!(
+            message,
+            format!("config.yaml:2: 'timeout_seconds' expects a duration such as 30, 500ms, 30s, 5m or 1h, found '{}'", value)
+        );
+    }
+}
+
+#[test]
+fn profiles_merge_over_the_rest_of_the_file() {
+    let base = "\
+log_level: info
+timeout_seconds: 30s
+servers:
+  - port: 8080
+    routes:
+      - path: /
+        root: /srv/www
+        methods: [GET]
+      - path: /api
+        root: /srv/api
+profiles:
+  dev:
+    log_level: debug
+    timeout_seconds: 5m
+    servers:
+      - routes:
+          - path: /api
+            root: ./api
+          - path: /debug
+            autoindex: true
+  broken:
+    timeout_seconds: soon
+";
+    let dir = dir_with("profiles", &[("config.yaml", base), ("config.prod.toml", "timeout_seconds = \"10s\"\n")]);
+    let path = dir.join("config.yaml").to_string_lossy().into_owned();
+
+    let plain = parse_config(&path, None, None).unwrap();
+    assert_eq!(plain.timeout_seconds, Duration::from_secs(30));
+    assert_eq!(plain.servers[0].routes.len(), 2);
+
+    // Routes merge by path, so `/` keeps its own settings and `/debug` is added
+    let dev = parse_config(&path, None, Some("dev")).unwrap();
+    assert_eq!(dev.log_level, localserver::log::Level::Debug);
+    assert_eq!(dev.timeout_seconds, Duration::from_secs(300));
+    let routes = &dev.servers[0].routes;
+    assert_eq!(routes.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(), ["/", "/api", "/debug"]);
+    assert_eq!((routes[0].root.as_str(), routes[0].methods.as_slice()), ("/srv/www", &["GET".to_string()][..]));
+    assert_eq!(routes[1].root, "./api");
+    assert!(routes[2].autoindex);
+
+    // Without a `profiles.prod` section, the sibling file is the overlay, in its own format
+    let prod = parse_config(&path, None, Some("prod")).unwrap();
+    assert_eq!(prod.timeout_seconds, Duration::from_secs(10));
+    assert_eq!(prod.servers[0].routes[1].root, "/srv/api");
+
+    let broken = parse_config(&path, None, Some("broken")).err().unwrap().to_string();
+    assert!(broken.ends_with("config.yaml:22: 'timeout_seconds' expects a duration such as 30, 500ms, 30s, 5m or 1h, found 'soon'"), "{}", broken);
+    let missing = parse_config(&path, None, Some("stage")).err().unwrap().to_string();
+    assert!(missing.contains("profile 'stage' is not defined under 'profiles' and no config.stage.yaml exists"), "{}", missing);
+}
+
+#[test]
+fn port_lists_and_ranges_become_one_block_per_port() {
+    let ports = |config: &Config| config.servers.iter().map(|s| s.port.clone()).collect::<Vec<_>>();
+    let config = parse(
+        "ports",
+        "config.yaml",
+        "servers:\n  - host: 127.0.0.1\n    port: 8000-8002\n    routes:\n      - path: /\n        root: ./www\n  - port: [9000, 9010-9011]\n"
+    ).unwrap();
+    assert_eq!(ports(&config), ["8000", "8001", "8002", "9000", "9010", "9011"]);
+    // Every port of a block shares its settings and routes
+    for server in &config.servers[..3] {
+        assert_eq!(server.host, "127.0.0.1");
+        assert_eq!(server.routes[0].root, "./www");
+    }
+
+    let toml = parse("ports-toml", "config.toml", "[[servers]]\nport = [8080, \"8090-8091\"]\n").unwrap();
+    assert_eq!(ports(&toml), ["8080", "8090", "8091"]);
+    let json = parse("ports-json", "config.json", r#"{"servers": [{"port": "7000-7001"}]}"#).unwrap();
+    assert_eq!(ports(&json), ["7000", "7001"]);
+    let single = parse("ports-single", "config.yaml", "servers:\n  - port: 8080\n").unwrap();
+    assert_eq!(ports(&single), ["8080"]);
+
+    let expected = "expects a port, a list of ports or a range such as 8000-8005";
+    for (value, message) in [
+        ("8005-8000", format!("config.yaml:2: 'port' {}, found '8005-8000'", expected)),
+        ("80-x", format!("config.yaml:2: 'port' {}, found '80-x'", expected)),
+        ("70000", format!("config.yaml:2: 'port' {}, found '70000'", expected)),
+        ("[]", format!("config.yaml:2: 'port' {}, found ''", expected)),
+        ("[8080, 8079-8081]", "config.yaml:2: 'port' lists 8080 more than once".to_string()),
+    ] {
+        assert_eq!(error("ports-bad", "config.yaml", &format!("servers:\n  - port: {}\n", value)), message);
+    }
+}
+
+#[test]
+fn unknown_keys_are_refused_unless_strict_is_off() {
+    let hint = "(set 'strict: false' to ignore unknown keys)";
+    let server = "servers:\n  - port: 8080\n    routes:\n      - path: /\n        root: ./www\n";
+    for (content, message) in [
+        (format!("log_levle: debug\n{}", server), format!("config.yaml:1: unknown key 'log_levle' {}", hint)),
+        (format!("{}    hostname: example.com\n", server), format!("config.yaml:6: unknown key 'hostname' {}", hint)),
+        (format!("{}        methodes: [GET]\n", server), format!("config.yaml:6: unknown key 'methodes' {}", hint)),
+    ] {
+        assert_eq!(error("strict", "config.yaml", &content), message);
+    }
+    assert_eq!(error("strict-value", "config.yaml", "strict: maybe\n"), "config.yaml:1: 'strict' expects true or false, found 'maybe'");
+    assert_eq!(
+        error("strict-toml", "config.toml", "[[servers]]\nport = 8080\n\n[[servers.routes]]\npath = \"/\"\nmethodes = [\"GET\"]\n"),
+        format!("config.toml:6: unknown key 'methodes' {}", hint)
+    );
+
+    // Included files follow the main file's setting
+    let site = ("site.yaml", "port: 8081\nhostname: example.com\n");
+    let dir = dir_with("strict-include", &[("config.yaml", "include: site.yaml\n"), site]);
+    let message = parse_config(&dir.join("config.yaml").to_string_lossy(), None, None).err().unwrap().to_string();
+    assert!(message.ends_with(&format!("site.yaml:2: unknown key 'hostname' {}", hint)), "{}", message);
+    let dir = dir_with("relaxed-include", &[("config.yaml", "strict: false\ninclude: site.yaml\n"), site]);
+    let config = parse_config(&dir.join("config.yaml").to_string_lossy(), None, None).unwrap();
+    assert_eq!(config.servers[0].port, "8081");
+
+    let config = parse(
+        "relaxed",
+        "config.yaml",
+        "strict: false\nlog_levle: debug\nservers:\n  - port: 8080\n    hostname: example.com\n    routes:\n      - path: /\n        methodes: [GET]\n"
+    ).unwrap();
+    assert_eq!(config.servers[0].routes[0].path, "/");
+}
+
+#[test]
+fn environment_overrides_sit_between_the_file_and_the_flags() {
+    let dir = dir_with(
+        "env",
+        &[(
+            "config.yaml",
+            "log_level: warn\nservers:\n  - host: 127.0.0.1\n    port: 8000-8001\n    routes:\n      - path: /\n        root: ./www\n"
+        )]
+    );
+    let dump = |args: &[&str], env: &[(&str, &str)]| {
+        let args = [&["--config", "config.yaml", "--dump-config=json"], args].concat();
+        let (ok, output) = run(&dir, &args, env, "");
+        assert!(ok, "{}", output);
+        output
+    };
+
+    let file = dump(&[], &[]);
+    for line in ["\"log_level\": \"warn\"", "\"host\": \"127.0.0.1\"", "\"port\": \"8000\"", "\"port\": \"8001\"", "\"root\": \"./www\""] {
+        assert!(file.contains(line), "{} missing from {}", line, file);
+    }
+
+    let env = [
+        ("LOCALSERVER_HOST", "0.0.0.0"),
+        ("LOCALSERVER_PORT", "9000"),
+        ("LOCALSERVER_ROOT", "./env"),
+        ("LOCALSERVER_LOG_LEVEL", "debug"),
+    ];
+    let overridden = dump(&[], &env);
+    for line in ["\"log_level\": \"debug\"", "\"host\": \"0.0.0.0\"", "\"port\": \"9000\"", "\"root\": \"./env\""] {
+        assert!(overridden.contains(line), "{} missing from {}", line, overridden);
+    }
+    // The port range collapses onto the one overriding port
+    assert_eq!(overridden.matches("\"port\":").count(), 1, "{}", overridden);
+    assert!(!overridden.contains("8000") && !overridden.contains("./www"), "{}", overridden);
+
+    let flagged = dump(&["--port", "9100", "--root", "./flag"], &env);
+    for line in ["\"host\": \"0.0.0.0\"", "\"port\": \"9100\"", "\"root\": \"./flag\""] {
+        assert!(flagged.contains(line), "{} missing from {}", line, flagged);
+    }
+    assert!(!flagged.contains("9000") && !flagged.contains("./env"), "{}", flagged);
+
+    // Blank variables are ignored, bad ones stop the server
+    assert!(dump(&[], &[("LOCALSERVER_PORT", " ")]).contains("\"port\": \"8000\""));
+    for (name, value, message) in [
+        ("LOCALSERVER_PORT", "abc", "LOCALSERVER_PORT expects a port number, found 'abc'"),
+        ("LOCALSERVER_PORT", "70000", "LOCALSERVER_PORT expects a port number, found '70000'"),
+        ("LOCALSERVER_LOG_LEVEL", "loud", "LOCALSERVER_LOG_LEVEL expects error, warn, info or debug, found 'loud'"),
+    ] {
+        let (ok, output) = run(&dir, &["--config", "config.yaml", "--test-config"], &[(name, value)], "");
+        assert!(!ok, "{}={} accepted", name, value);
+        assert!(output.contains(message), "{}", output);
+    }
+}
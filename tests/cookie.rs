@@ -0,0 +1,35 @@
+use localserver::cookie::{ self, SameSite, SetCookie };
+use std::time::Duration;
+
+#[test]
+fn parses_cookie_headers() {
+    let cookies = cookie::parse("a=1; b=\"two words\";c=; =orphan; noequals; a=shadowed");
+    assert_eq!(cookies.get("a").map(String::as_str), Some("1"));
+    assert_eq!(cookies.get("b").map(String::as_str), Some("two words"));
+    assert_eq!(cookies.get("c").map(String::as_str), Some(""));
+    assert_eq!(cookies.len(), 3);
+    assert!(cookie::parse("").is_empty());
+}
+
+#[test]
+fn writes_set_cookie_values() {
+    let all = SetCookie::new("id", "abc")
+        .unwrap()
+        .path("/app")
+        .max_age(Duration::from_secs(90))
+        .http_only()
+        .secure()
+        .same_site(SameSite::Strict);
+    assert_eq!(all.to_string(), "id=abc; Path=/app; Max-Age=90; HttpOnly; Secure; SameSite=Strict");
+    assert_eq!(SetCookie::new("gone", "").unwrap().max_age(Duration::ZERO).to_string(), "gone=; Max-Age=0");
+    // Cross-site cookies need Secure
+    assert_eq!(SetCookie::new("x", "1").unwrap().same_site(SameSite::None).to_string(), "x=1; Secure; SameSite=None");
+    assert_eq!(SetCookie::new("x", "1").unwrap().path("/a;Domain=evil").to_string(), "x=1");
+}
+
+#[test]
+fn rejects_malformed_names_and_values() {
+    for (name, value) in [("", "v"), ("a b", "v"), ("a=b", "v"), ("a;", "v"), ("n", "a;b"), ("n", "a b"), ("n", "\"q\""), ("n", "line\n")] {
+        assert!(SetCookie::new(name, value).is_none(), "{:?}={:?}", name, value);
+    }
+}
@@ -0,0 +1,25 @@
+use localserver::date;
+use std::time::{ Duration, UNIX_EPOCH };
+
+#[test]
+fn formats_imf_fixdate() {
+    assert_eq!(date::format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    assert_eq!(date::format(UNIX_EPOCH + Duration::from_secs(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(date::format(UNIX_EPOCH + Duration::from_secs(951782400) + Duration::from_millis(999)), "Tue, 29 Feb 2000 00:00:00 GMT");
+}
+
+#[test]
+fn parses_all_three_forms() {
+    let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+    assert_eq!(date::parse("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+    assert_eq!(date::parse("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+    assert_eq!(date::parse("Sun Nov  6 08:49:37 1994"), expected);
+    assert_eq!(date::parse("Fri, 01 Jan 2038 00:00:00 GMT"), Some(UNIX_EPOCH + Duration::from_secs(2145916800)));
+}
+
+#[test]
+fn rejects_anything_else() {
+    for value in ["", "yesterday", "Sun, 06 Nov 1994 08:49:37 UTC", "Sun, 32 Nov 1994 08:49:37 GMT", "Sun, 06 Foo 1994 08:49:37 GMT", "Sun, 06 Nov 1994 24:00:00 GMT", "Sun, 06 Nov 1969 08:49:37 GMT"] {
+        assert_eq!(date::parse(value), None, "{}", value);
+    }
+}
@@ -0,0 +1,2459 @@
+use localserver::config::models::{ Balance, BindPolicy, HealthCheck, HostResolution, Probe, ProxyCache, ProxyProtocol, Robots, Sticky };
+use localserver::config::{ RouteBuilder, ServerBlockBuilder };
+use localserver::cookie::{ SameSite, SetCookie };
+use localserver::server::pipeline::{ Flow, Handler, Request, Response };
+use localserver::server::clock::ManualClock;
+use localserver::testing::{ TestResponse, TestServer };
+use localserver::vfs::{ DiskFs, MemoryFs, Overlay };
+use localserver::{ Server, ServerError, StatusCode };
+use std::fs;
+use std::io::{ self, Read, Write };
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::{ Duration, UNIX_EPOCH };
+
+mod common;
+use common::{ builder, site, start };
+
+#[test]
+fn serves_index_and_reports_missing_files() {
+    let dir = site("routing");
+    fs::create_dir(dir.join("www/docs")).unwrap();
+    fs::write(dir.join("www/docs/index.html"), "<a href=\"intro.html\">intro</a>").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/").root(&root).index("index.html").methods(["GET"])
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let home = server.get("/");
+    assert_eq!(home.status, 200);
+    assert_eq!(home.text(), "<h1>home</h1>");
+    assert_eq!(home.header("content-type"), Some("text/html"));
+
+    assert_eq!(server.get("/nope.html").status, 404);
+
+    // Directories get their trailing slash before the index is served
+    let docs = server.get("/docs?lang=en");
+    assert_eq!(docs.status, 301);
+    assert_eq!(docs.header("location"), Some("/docs/?lang=en"));
+    assert_eq!(server.get("/docs/").status, 200);
+
+    let post = server.request(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(post.status, 405);
+    assert_eq!(post.header("allow"), Some("GET"));
+    // Unknown to the server altogether rather than excluded by the route
+    let brew = server.request(b"BREW / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(brew.status, 501);
+    assert_eq!(brew.header("allow"), None);
+    // Known, but only served to `purge_allow` addresses
+    assert_eq!(server.request(b"PURGE / HTTP/1.1\r\nHost: localhost\r\n\r\n").status, 405);
+}
+
+#[test]
+fn redirect_routes_expand_the_target() {
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/old").redirect("/new$request_path$is_args$query_string", StatusCode::PERMANENT_REDIRECT)
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let response = server.get("/old/page?x=1");
+    assert_eq!(response.status, 308);
+    assert_eq!(response.header("location"), Some("/new/page?x=1"));
+}
+
+#[test]
+fn multipart_and_chunked_uploads_are_stored() {
+    let dir = site("upload");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let uploads = dir.join("uploads");
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/upload")
+                        .root(&root)
+                        .upload_dir(&uploads.to_string_lossy())
+                        .methods(["GET", "POST", "DELETE"])
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let body = "--XYZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"note.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\nhello upload\r\n--XYZ--\r\n";
+    let request = format!(
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=XYZ\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    assert_eq!(server.request(request.as_bytes()).status, 201);
+    assert_eq!(fs::read_to_string(uploads.join("note.txt")).unwrap(), "hello upload");
+
+    let chunked = b"POST /upload/raw.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+        5\r\nhello\r\n7\r\n chunks\r\n0\r\n\r\n";
+    assert_eq!(server.request(chunked).status, 201);
+    assert_eq!(fs::read_to_string(uploads.join("raw.txt")).unwrap(), "hello chunks");
+
+    let delete = server.request(b"DELETE /upload/note.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(delete.status, 204);
+    assert!(delete.header("Content-Length").is_none() && delete.body.is_empty());
+    assert!(!uploads.join("note.txt").exists());
+}
+
+#[test]
+fn delete_is_confined_to_files_under_the_route() {
+    let dir = site("delete");
+    let root = dir.join("www");
+    let uploads = dir.join("uploads");
+    let outside = dir.join("outside");
+    for path in [root.join("docs"), uploads.clone(), outside.clone()] {
+        fs::create_dir_all(path).unwrap();
+    }
+    fs::write(root.join("page.html"), "page").unwrap();
+    fs::write(uploads.join("upload.txt"), "upload").unwrap();
+    fs::write(outside.join("secret.txt"), "secret").unwrap();
+    std::os::unix::fs::symlink(&outside, uploads.join("escape")).unwrap();
+
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root(&root.to_string_lossy()).methods(["GET"]))
+                    .route(
+                        RouteBuilder::new("/files")
+                            .root(&root.to_string_lossy())
+                            .upload_dir(&uploads.to_string_lossy())
+                            .methods(["GET", "DELETE"])
+                    )
+            )
+            .build()
+            .unwrap()
+    );
+    let delete = |path: &str| {
+        server.request(format!("DELETE {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).status
+    };
+
+    assert_eq!(delete("/page.html"), 405);
+    assert_eq!(delete("/files/missing.txt"), 404);
+    assert_eq!(delete("/files/docs"), 403);
+    assert_eq!(delete("/files/../outside/secret.txt"), 403);
+    assert_eq!(delete("/files/escape/secret.txt"), 403);
+    assert!(outside.join("secret.txt").exists());
+
+    // The upload directory first, then the root
+    assert_eq!(delete("/files/upload.txt"), 204);
+    assert!(!uploads.join("upload.txt").exists());
+    assert_eq!(delete("/files/page.html"), 204);
+    assert!(!root.join("page.html").exists());
+}
+
+#[test]
+fn verified_uploads_must_match_their_claimed_type() {
+    let dir = site("magic");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let uploads = dir.join("uploads");
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/up").root(&root).upload_dir(&uploads.to_string_lossy()).verify_uploads(true))
+                    .route(RouteBuilder::new("/any").root(&root).upload_dir(&uploads.to_string_lossy()))
+            )
+            .build()
+            .unwrap()
+    );
+    let post = |path: &str, content_type: &str, body: &[u8]| {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            path,
+            content_type,
+            body.len()
+        ).into_bytes();
+        request.extend_from_slice(body);
+        server.request(&request).status
+    };
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    let elf = b"\x7fELF\x02\x01\x01\0";
+
+    assert_eq!(post("/up/pic.png", "image/png", png), 201);
+    assert_eq!(post("/up/fake.png", "image/png", b"<?php system($_GET['c']);"), 422);
+    assert_eq!(post("/up/pic2.png", "application/pdf", png), 422);
+    // Text has no signature, but a program is not text
+    assert_eq!(post("/up/notes.txt", "text/plain", b"just notes"), 201);
+    assert_eq!(post("/up/notes2.txt", "text/plain", elf), 422);
+    assert_eq!(post("/up/tool.bin", "application/octet-stream", elf), 201);
+    assert!(!uploads.join("fake.png").exists() && !uploads.join("notes2.txt").exists());
+
+    // One bad file rejects the whole form
+    let body = "--XYZ\r\nContent-Disposition: form-data; name=\"a\"; filename=\"ok.txt\"\r\n\r\nfine\r\n\
+        --XYZ\r\nContent-Disposition: form-data; name=\"b\"; filename=\"photo.jpg\"\r\n\
+        Content-Type: image/jpeg\r\n\r\nnot a jpeg\r\n--XYZ--\r\n";
+    assert_eq!(post("/up", "multipart/form-data; boundary=XYZ", body.as_bytes()), 422);
+    assert!(!uploads.join("ok.txt").exists());
+
+    assert_eq!(post("/any/fake.png", "image/png", b"anything goes"), 201);
+}
+
+#[test]
+fn csrf_routes_refuse_uploads_without_the_cookie_token() {
+    let dir = site("csrf");
+    fs::write(dir.join("www/form.html"), "<input name=\"csrf_token\" value=\"{{csrf_token}}\">").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let uploads = dir.join("uploads");
+    let server = start(
+        builder()
+            .session_secret("csrf-test-secret")
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root(&root).methods(["GET"]).csrf(true))
+                    .route(
+                        RouteBuilder::new("/upload")
+                            .root(&root)
+                            .upload_dir(&uploads.to_string_lossy())
+                            .methods(["POST"])
+                            .csrf(true)
+                    )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let form = server.get("/form.html");
+    assert_eq!(form.status, 200);
+    let set_cookie = form
+        .headers
+        .iter()
+        .find(|(name, value)| name == "set-cookie" && value.starts_with("CSRF_TOKEN="))
+        .map(|(_, value)| value.as_str())
+        .expect("a CSRF cookie");
+    assert!(set_cookie.contains("SameSite=Strict"), "{}", set_cookie);
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    let token = cookie.strip_prefix("CSRF_TOKEN=").unwrap().to_string();
+    assert!(form.text().contains(&format!("value=\"{}\"", token)));
+
+    let upload = |name: &str, headers: &str, field: Option<&str>| {
+        let mut body = String::new();
+        if let Some(field) = field {
+            body.push_str(&format!("--XYZ\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\n{}\r\n", field));
+        }
+        body.push_str(&format!(
+            "--XYZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\nhello\r\n--XYZ--\r\n",
+            name
+        ));
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\n{}Content-Type: multipart/form-data; boundary=XYZ\r\nContent-Length: {}\r\n\r\n{}",
+            headers,
+            body.len(),
+            body
+        );
+        server.request(request.as_bytes()).status
+    };
+    let with_cookie = format!("Cookie: {}\r\n", cookie);
+
+    assert_eq!(upload("none.txt", &with_cookie, None), 403);
+    assert_eq!(upload("wrong.txt", &with_cookie, Some("0123.abcd")), 403);
+    // The field alone is not enough, and neither is a cookie the server did not sign
+    assert_eq!(upload("nocookie.txt", "", Some(&token)), 403);
+    let forged = format!("{}.{}", "0".repeat(32), "f".repeat(64));
+    assert_eq!(upload("forged.txt", &format!("Cookie: CSRF_TOKEN={}\r\n", forged), Some(&forged)), 403);
+    for name in ["none.txt", "wrong.txt", "nocookie.txt", "forged.txt"] {
+        assert!(!uploads.join(name).exists(), "{}", name);
+    }
+
+    assert_eq!(upload("field.txt", &with_cookie, Some(&token)), 201);
+    assert_eq!(upload("header.txt", &format!("{}X-CSRF-Token: {}\r\n", with_cookie, token), None), 201);
+    assert!(uploads.join("field.txt").exists() && uploads.join("header.txt").exists());
+}
+
+#[test]
+fn cgi_scripts_see_the_request_environment() {
+    let dir = site("cgi");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    let script = bin.join("echo.sh");
+    fs::write(&script, "printf 'Content-Type: text/plain\\r\\n\\r\\n'\nprintf '%s %s %s' \"$REQUEST_METHOD\" \"$QUERY_STRING\" \"$REMOTE_ADDR\"\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin").root(&bin.to_string_lossy()).cgi(".sh", "/bin/sh")
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let response = server.get("/cgi-bin/echo.sh?name=test");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "GET name=test 127.0.0.1");
+}
+
+#[test]
+fn cgi_scripts_get_only_safe_request_headers() {
+    let dir = site("cgi-headers");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    let script = bin.join("env.sh");
+    fs::write(&script, "printf 'Content-Type: text/plain\\r\\n\\r\\n'\nenv | grep '^HTTP_' | sort\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin").root(&bin.to_string_lossy()).cgi(".sh", "/bin/sh")
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let response = server.request(
+        b"GET /cgi-bin/env.sh HTTP/1.1\r\nHost: localhost\r\nProxy: http://evil.test:3128\r\n\
+        Authorization: Basic YWxpY2U6c2VjcmV0\r\nX-Forwarded_For: 10.0.0.1\r\nX-Custom-Thing: kept\r\nConnection: close\r\n\r\n"
+    );
+    assert_eq!(response.status, 200);
+    let env = response.text();
+    assert!(env.contains("HTTP_X_CUSTOM_THING=kept\n"), "{}", env);
+    assert!(env.contains("HTTP_HOST=localhost\n"), "{}", env);
+    // httpoxy: a script's HTTP client must not pick the client's proxy up from HTTP_PROXY.
+    // Values rather than names, since the server's own environment may carry HTTP_PROXY
+    for value in ["evil.test", "YWxpY2U6c2VjcmV0", "10.0.0.1"] {
+        assert!(!env.contains(value), "{} in {}", value, env);
+    }
+}
+
+#[test]
+fn forwarded_addresses_are_believed_only_from_trusted_proxies() {
+    let dir = site("cgi-forwarded");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    let script = bin.join("addr.sh");
+    fs::write(&script, "printf 'Content-Type: text/plain\\r\\n\\r\\n'\nprintf '%s' \"$REMOTE_ADDR\"\n").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let start_trusting = |proxies: &[&str]| {
+        let config = proxies
+            .iter()
+            .fold(builder(), |builder, proxy| builder.trusted_proxy(proxy))
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin").root(&bin.to_string_lossy()).cgi(".sh", "/bin/sh")
+                )
+            )
+            .build()
+            .unwrap();
+        start(config)
+    };
+    let remote_addr = |server: &TestServer, headers: &str| {
+        server
+            .request(format!("GET /cgi-bin/addr.sh HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", headers).as_bytes())
+            .text()
+    };
+
+    // The chain is walked from the right, past every trusted hop
+    let trusting = start_trusting(&["127.0.0.1", "10.0.0.0/8"]);
+    assert_eq!(remote_addr(&trusting, "X-Forwarded-For: 198.51.100.4, 10.0.0.2\r\n"), "198.51.100.4");
+    assert_eq!(remote_addr(&trusting, "X-Forwarded-For: 203.0.113.9, 198.51.100.4, 10.0.0.2\r\n"), "198.51.100.4");
+    assert_eq!(remote_addr(&trusting, "Forwarded: for=\"[2001:db8::5]:4711\"\r\nX-Forwarded-For: 198.51.100.4\r\n"), "2001:db8::5");
+    assert_eq!(remote_addr(&trusting, ""), "127.0.0.1");
+
+    let untrusting = start_trusting(&["10.0.0.0/8"]);
+    assert_eq!(remote_addr(&untrusting, "X-Forwarded-For: 198.51.100.4\r\n"), "127.0.0.1");
+    assert_eq!(remote_addr(&untrusting, "Forwarded: for=198.51.100.4\r\n"), "127.0.0.1");
+}
+
+#[test]
+fn hooks_can_veto_requests_and_add_headers() {
+    let server = TestServer::start_with(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap(),
+        |server| {
+            let hooks = server.hooks_mut();
+            hooks.on_request(|request| (request.path == "/blocked").then_some(StatusCode::FORBIDDEN));
+            hooks.on_response(|response| {
+                let status = response.status.map_or(0, |s| s.as_u16());
+                response.add_header("X-Hooked", &status.to_string());
+            });
+        }
+    ).expect("server starts");
+
+    let blocked = server.get("/blocked");
+    assert_eq!(blocked.status, 403);
+    assert_eq!(blocked.header("x-hooked"), Some("403"));
+    assert_eq!(server.get("/missing").header("x-hooked"), Some("404"));
+}
+
+/// Takes `spent` on the server's clock, then answers with the request's age.
+struct Slow {
+    clock: ManualClock,
+    spent: Duration,
+}
+
+impl Handler for Slow {
+    fn handle(&self, server: &mut Server, _token: mio::Token, request: &mut Request) -> Flow {
+        self.clock.advance(self.spent);
+        let age = request.context.elapsed(server).as_millis().to_string();
+        Flow::Reply(Response::new(StatusCode::OK, "text/plain", age))
+    }
+}
+
+#[test]
+fn request_timings_follow_the_server_clock() {
+    let server = TestServer::start_with(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/slow").handler("slow")))
+            .build()
+            .unwrap(),
+        |server| {
+            let clock = ManualClock::new();
+            server.set_clock(clock.clone());
+            let spent = Duration::from_millis(1500);
+            server.pipeline_mut().register_handler("slow", Box::new(Slow { clock, spent }));
+            server.hooks_mut().on_response(|response| {
+                let took = response.elapsed.as_millis().to_string();
+                response.add_header("X-Took", &took);
+            });
+        }
+    ).expect("server starts");
+
+    let response = server.get("/slow");
+    assert_eq!(response.text(), "1500");
+    assert_eq!(response.header("x-took"), Some("1500"));
+}
+
+#[test]
+fn routes_dispatch_to_named_handlers() {
+    let server = TestServer::start_with(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/api").handler("echo").methods(["GET"]))
+                    .route(RouteBuilder::new("/broken").handler("missing"))
+            )
+            .build()
+            .unwrap(),
+        |server| {
+            server.pipeline_mut().register_fn("echo", |request| {
+                Response::new(StatusCode::OK, "text/plain", format!("{} {}", request.path, request.query))
+            });
+        }
+    ).expect("server starts");
+
+    let response = server.get("/api/users?id=7");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "/api/users id=7");
+    // The route's methods still apply before the handler runs
+    let post = server.request(b"POST /api HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(post.status, 405);
+    assert_eq!(server.get("/broken").status, 500);
+}
+
+#[test]
+fn config_file_routes_run_named_handlers_inside_the_server() {
+    let dir = site("named-handler");
+    let file = dir.join("config.yaml");
+    fs::write(
+        &file,
+        "log_level: error\nservers:\n  - host: 127.0.0.1\n    port: 0\n    routes:\n      - path: /greet\n        handler: greet\n        methods: [POST]\n"
+    ).unwrap();
+    let config = localserver::config::parse_config(&file.to_string_lossy(), None, None).unwrap();
+
+    let server = TestServer::start_with(config, |server| {
+        // Setup runs on the server's thread; so does the handler, with no process started
+        let server_thread = thread::current().id();
+        server.pipeline_mut().register_fn("greet", move |request| {
+            let name = String::from_utf8_lossy(&request.body).into_owned();
+            let same_thread = thread::current().id() == server_thread;
+            let mut response = Response::new(StatusCode::CREATED, "text/plain", format!("hello {}", name));
+            response.headers.push(("X-Same-Thread".to_string(), same_thread.to_string()));
+            response
+        });
+    }).expect("server starts");
+
+    let response = server.request(b"POST /greet HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nConnection: close\r\n\r\nada");
+    assert_eq!(response.status, 201);
+    assert_eq!(response.text(), "hello ada");
+    assert_eq!(response.header("X-Same-Thread"), Some("true"));
+    assert_eq!(server.get("/greet").status, 405);
+}
+
+#[test]
+fn mounted_trees_serve_files_and_listings() {
+    let dir = site("vfs");
+    fs::write(dir.join("www/style.css"), "body {}").unwrap();
+    let disk = dir.join("www");
+    let server = TestServer::start_with(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root("vfs:site").autoindex(true).methods(["GET", "DELETE"]))
+            )
+            .build()
+            .unwrap(),
+        move |server| {
+            let mut generated = MemoryFs::new();
+            generated.insert("index.html", "<h1>generated</h1>");
+            generated.insert("docs/a.txt", "a");
+            server.mount("site", Box::new(Overlay::new(vec![Box::new(generated), Box::new(DiskFs::rooted(disk))])));
+        }
+    ).expect("server starts");
+
+    // The in-memory layer shadows the file on disk
+    assert_eq!(server.get("/index.html").text(), "<h1>generated</h1>");
+    assert_eq!(server.get("/style.css").text(), "body {}");
+    assert_eq!(server.get("/docs/a.txt").text(), "a");
+    assert_eq!(server.get("/docs/b.txt").status, 404);
+
+    let listing = server.get("/").text();
+    assert!(listing.contains("href=\"/docs/\"") && listing.contains("href=\"/style.css\""));
+    let delete = server.request(b"DELETE /style.css HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(delete.status, 405);
+    assert!(dir.join("www/style.css").exists());
+}
+
+#[test]
+fn embedded_assets_are_served_without_files() {
+    let server = TestServer::start_with(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/ui").root("vfs:ui").index("index.html").methods(["GET"]))
+            )
+            .build()
+            .unwrap(),
+        |server| {
+            server.mount("ui", Box::new(localserver::embed_assets!("www", ["html/index.html", "errors/404.html"])));
+        }
+    ).expect("server starts");
+
+    let page = server.get("/ui/html/");
+    assert_eq!(page.status, 200);
+    assert_eq!(page.body, fs::read("www/html/index.html").unwrap());
+    assert_eq!(page.header("content-type"), Some("text/html"));
+    assert_eq!(server.get("/ui/errors/404.html").status, 200);
+    assert_eq!(server.get("/ui/errors/500.html").status, 404);
+}
+
+#[test]
+fn canary_requests_are_served_from_the_canary_root() {
+    let dir = site("canary");
+    fs::create_dir_all(dir.join("next")).unwrap();
+    fs::write(dir.join("next/index.html"), "<h1>next</h1>").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let next = dir.join("next").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/").root(&root).index("index.html").canary_cookie("release", Some("next"), &next)
+                )
+            )
+            .build()
+            .unwrap()
+    );
+    let get = |cookie: &str| {
+        server.request(format!("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\n\r\n", cookie).as_bytes())
+    };
+
+    let canary = get("release=next");
+    assert_eq!(canary.text(), "<h1>next</h1>");
+    assert_eq!(canary.header("vary"), Some("Cookie"));
+    assert_eq!(get("release=stable").text(), "<h1>home</h1>");
+    let stable = server.get("/");
+    assert_eq!(stable.text(), "<h1>home</h1>");
+    assert_eq!(stable.header("vary"), Some("Cookie"));
+}
+
+#[test]
+fn canary_requests_can_go_to_an_upstream() {
+    let next = named_upstream(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), "next");
+    let stable = named_upstream(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), "stable");
+    let dir = site("canary-upstream");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root(&root).index("index.html").canary_header_proxy("X-Canary", Some("1"), &next))
+                    .route(RouteBuilder::new("/api").proxy_pass(&stable).canary_cookie_proxy("release", Some("next"), &next))
+                    .route(RouteBuilder::new("/app").proxy_pass(&stable).canary_header("X-Canary", None, &root))
+            )
+            .build()
+            .unwrap()
+    );
+    let get = |path: &str, header: &str| {
+        server.request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\nConnection: close\r\n\r\n", path, header).as_bytes())
+    };
+
+    let canary = get("/", "X-Canary: 1");
+    assert_eq!(canary.text(), "next");
+    assert_eq!(canary.header("vary"), Some("x-canary"));
+    assert_eq!(get("/", "X-Canary: 0").text(), "<h1>home</h1>");
+    assert_eq!(get("/api/a", "Cookie: release=next").text(), "next");
+    assert_eq!(get("/api/a", "Cookie: release=stable").text(), "stable");
+    // A canary root takes a proxied route's requests off the upstream
+    assert_eq!(get("/app/index.html", "X-Canary: yes").text(), "<h1>home</h1>");
+    assert_eq!(get("/app/index.html", "X-Other: yes").text(), "stable");
+}
+
+#[test]
+fn root_lists_fall_back_to_later_directories() {
+    let dir = site("fallback");
+    fs::create_dir_all(dir.join("theme/docs")).unwrap();
+    fs::write(dir.join("www/style.css"), "local").unwrap();
+    fs::write(dir.join("theme/style.css"), "theme").unwrap();
+    fs::write(dir.join("theme/base.css"), "base").unwrap();
+    fs::write(dir.join("theme/docs/index.html"), "theme docs").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let theme = dir.join("theme").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/").root(&root).fallback_root(&theme).index("index.html").methods(["GET", "DELETE"])
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    assert_eq!(server.get("/style.css").text(), "local");
+    assert_eq!(server.get("/base.css").text(), "base");
+    assert_eq!(server.get("/docs/").text(), "theme docs");
+    assert_eq!(server.get("/").text(), "<h1>home</h1>");
+    assert_eq!(server.get("/nope.css").status, 404);
+
+    // Only reads fall through: the shared directory is never deleted from
+    let delete = server.request(b"DELETE /base.css HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(delete.status, 404);
+    assert!(dir.join("theme/base.css").exists());
+}
+
+#[test]
+fn error_page_templates_fill_in_the_request() {
+    let dir = site("errors");
+    let template = dir.join("error.html");
+    fs::write(&template, "<p>{{code}} {{reason}} at {{request_path}} ({{request_id}})</p>").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .default_error_page(&template.to_string_lossy())
+                    .route(RouteBuilder::new("/").root(&root).methods(["GET"]))
+            )
+            .build()
+            .unwrap()
+    );
+
+    let missing = server.get("/<b>nope?x=1").text();
+    let (prefix, id) = missing.split_once(" (").unwrap();
+    assert_eq!(prefix, "<p>404 Not Found at /&lt;b&gt;nope");
+    assert!(id.len() > 3 && id.ends_with(")</p>"));
+
+    let post = server.request(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+    assert!(post.text().starts_with("<p>405 Method Not Allowed at / ("));
+}
+
+#[test]
+fn error_pages_follow_accept_language() {
+    let dir = site("languages");
+    for (file, text) in [("en.html", "Not found"), ("fr.html", "Introuvable"), ("fr-ca.html", "Pas trouvé")] {
+        fs::write(dir.join(file), text).unwrap();
+    }
+    let config_path = dir.join("config.yaml");
+    fs::write(
+        &config_path,
+        format!(
+            "log_level: error\nservers:\n  - host: 127.0.0.1\n    port: 0\n    error_pages:\n      \
+             404: {{ en: {0}/en.html, fr: {0}/fr.html, FR-CA: {0}/fr-ca.html }}\n    \
+             routes:\n      - path: /\n        root: {0}/www\n",
+            dir.display()
+        )
+    ).unwrap();
+    let server = start(localserver::config::parse_config(&config_path.to_string_lossy(), None, None).unwrap());
+    let missing = |accept: &str| {
+        let request = format!("GET /nope HTTP/1.1\r\nHost: localhost\r\nAccept-Language: {}\r\n\r\n", accept);
+        let reply = server.request(request.as_bytes());
+        assert_eq!(reply.status, 404);
+        assert_eq!(reply.header("Vary"), Some("Accept-Language"));
+        (reply.text(), reply.header("Content-Language").map(str::to_string))
+    };
+
+    assert_eq!(missing("fr-CH, en;q=0.8"), ("Introuvable".to_string(), Some("fr".to_string())));
+    assert_eq!(missing("de, en-GB;q=0.5, fr;q=0.4"), ("Not found".to_string(), Some("en".to_string())));
+    assert_eq!(missing("fr-ca"), ("Pas trouvé".to_string(), Some("fr-ca".to_string())));
+    assert_eq!(missing("fr;q=0, en"), ("Not found".to_string(), Some("en".to_string())));
+    // Nothing acceptable: the first language listed, without claiming a language
+    assert_eq!(missing("de"), ("Not found".to_string(), None));
+    assert_eq!(server.get("/nope").text(), "Not found");
+}
+
+#[test]
+fn return_routes_answer_from_the_config() {
+    let dir = site("return");
+    let config_path = dir.join("config.yaml");
+    fs::write(
+        &config_path,
+        format!(
+            "log_level: error\nservers:\n  - host: 127.0.0.1\n    port: 0\n    routes:\n      \
+             - path: /\n        root: {0}/www\n      \
+             - path: /healthz\n        return: {{ body: ok }}\n      \
+             - path: /teapot\n        methods: [GET]\n        \
+             return: {{ code: 418, body: '{{\"short\":\"stout\"}}', content_type: application/json }}\n      \
+             - path: /gone\n        return: 204\n",
+            dir.display()
+        )
+    ).unwrap();
+    let server = start(localserver::config::parse_config(&config_path.to_string_lossy(), None, None).unwrap());
+
+    let health = server.get("/healthz");
+    assert_eq!(health.status, 200);
+    assert_eq!(health.header("Content-Type"), Some("text/plain"));
+    assert_eq!(health.text(), "ok");
+
+    // Answered before the method check
+    let teapot = server.request(b"POST /teapot HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(teapot.status, 418);
+    assert_eq!(teapot.header("Content-Type"), Some("application/json"));
+    assert_eq!(teapot.text(), "{\"short\":\"stout\"}");
+
+    let head = server.request(b"HEAD /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(head.header("Content-Length"), Some("2"));
+    assert!(head.body.is_empty());
+
+    let gone = server.get("/gone");
+    assert_eq!(gone.status, 204);
+    assert!(gone.body.is_empty());
+}
+
+#[test]
+fn strict_bind_policy_refuses_partial_startup() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken_port = taken.local_addr().unwrap().port();
+    let config = |policy| {
+        builder()
+            .bind_policy(policy)
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/")))
+            .server(ServerBlockBuilder::new("127.0.0.1", taken_port).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap()
+    };
+
+    match TestServer::start(config(BindPolicy::Strict)) {
+        Err(ServerError::Config(message)) => {
+            assert!(message.contains("bound 1 of 2"), "{}", message);
+            assert!(message.contains(&format!("127.0.0.1:{}", taken_port)), "{}", message);
+        }
+        Err(other) => panic!("unexpected error: {}", other),
+        Ok(_) => panic!("strict bind started with a port in use"),
+    }
+
+    let server = start(config(BindPolicy::BestEffort));
+    assert_eq!(server.addrs().len(), 1);
+    assert_eq!(server.get("/missing").status, 404);
+}
+
+#[test]
+fn listener_hosts_may_be_names() {
+    for resolution in [HostResolution::All, HostResolution::First] {
+        let server = start(
+            builder()
+                .server(ServerBlockBuilder::new("localhost", 0).resolve(resolution).route(RouteBuilder::new("/")))
+                .build()
+                .unwrap()
+        );
+        assert!(server.addrs().iter().all(|addr| addr.ip().is_loopback()));
+        if resolution == HostResolution::First {
+            assert_eq!(server.addrs().len(), 1);
+        }
+        assert_eq!(server.get("/missing").status, 404);
+    }
+
+    let unresolvable = builder()
+        .server(ServerBlockBuilder::new("no-such-host.invalid", 0).route(RouteBuilder::new("/")))
+        .build();
+    match unresolvable {
+        Err(ServerError::Config(message)) => {
+            assert!(message.contains("cannot resolve host 'no-such-host.invalid'"), "{}", message);
+        }
+        Err(other) => panic!("unexpected error: {}", other),
+        Ok(_) => panic!("an unresolvable host was accepted"),
+    }
+}
+
+#[test]
+fn server_header_can_be_renamed_versioned_or_hidden() {
+    let server_with = |value: Option<&str>| {
+        let mut config = builder();
+        if let Some(value) = value {
+            config = config.server_header(value);
+        }
+        start(config.server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/"))).build().unwrap())
+    };
+
+    assert_eq!(server_with(None).get("/").header("server"), Some("LocalServer"));
+    assert_eq!(server_with(Some("edge-7")).get("/").header("server"), Some("edge-7"));
+    let versioned = format!("LocalServer/{}", env!("CARGO_PKG_VERSION"));
+    assert_eq!(server_with(Some("version")).get("/missing").header("server"), Some(versioned.as_str()));
+    assert_eq!(server_with(Some("off")).get("/").header("server"), None);
+}
+
+#[test]
+fn missing_favicon_and_robots_fall_back_to_built_ins() {
+    let dir = site("builtins");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).robots(Robots::DisallowAll).route(RouteBuilder::new("/").root(&root)))
+            .build()
+            .unwrap()
+    );
+
+    let favicon = server.get("/favicon.ico");
+    assert_eq!(favicon.status, 200);
+    assert_eq!(favicon.header("content-type"), Some("image/x-icon"));
+    assert_eq!(server.get("/robots.txt").text(), "User-agent: *\nDisallow: /\n");
+
+    // The site's own file wins
+    fs::write(dir.join("www/robots.txt"), "User-agent: *\nDisallow: /private\n").unwrap();
+    assert_eq!(server.get("/robots.txt").text(), "User-agent: *\nDisallow: /private\n");
+}
+
+#[test]
+fn markdown_routes_render_pages() {
+    let dir = site("markdown");
+    fs::write(dir.join("www/notes.md"), "# Notes\n\nSee [the index](index.html).\n").unwrap();
+    fs::write(dir.join("page.html"), "<h1>{{title}}</h1>{{content}}").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let template = dir.join("page.html").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root(&root).markdown(None))
+                    .route(RouteBuilder::new("/themed").root(&root).markdown(Some(&template)))
+                    .route(RouteBuilder::new("/raw").root(&root))
+            )
+            .build()
+            .unwrap()
+    );
+
+    let page = server.get("/notes.md");
+    assert_eq!(page.status, 200);
+    assert_eq!(page.header("content-type"), Some("text/html; charset=utf-8"));
+    assert!(page.text().contains("<title>Notes</title>"));
+    assert!(page.text().contains("<p>See <a href=\"index.html\">the index</a>.</p>"));
+
+    assert_eq!(
+        server.get("/themed/notes.md").text(),
+        "<h1>Notes</h1><h1 id=\"notes\">Notes</h1>\n<p>See <a href=\"index.html\">the index</a>.</p>\n"
+    );
+    assert!(server.get("/raw/notes.md").text().starts_with("# Notes"));
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn compressed_request_bodies_are_decoded_within_the_limit() {
+    let dir = site("encoded");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let uploads = dir.join("uploads");
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/upload").root(&root).upload_dir(&uploads.to_string_lossy()).max_body_size(64 * 1024))
+                    .route(RouteBuilder::new("/small").root(&root).upload_dir(&uploads.to_string_lossy()).max_body_size(1024))
+            )
+            .build()
+            .unwrap()
+    );
+    let post = |path: &str, coding: &str, body: &[u8]| {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+            path,
+            coding,
+            body.len()
+        ).into_bytes();
+        request.extend_from_slice(body);
+        server.request(&request).status
+    };
+
+    // Twelve "line N: the quick brown fox" lines, gzipped
+    let gzipped = unhex(
+        "1f8b0800000000000203cbc9cc4b5530b05228c94855282ccd4cce56482aca2fcf5348cbafe0ca01c919e29133c223678c47ce048f9c291e39333c72e678e42cf0c859e2f33bde80c1113200d142064b52010000"
+    );
+    assert_eq!(post("/upload/fox.txt", "gzip", &gzipped), 201);
+    let fox = fs::read_to_string(uploads.join("fox.txt")).unwrap();
+    assert_eq!(fox.lines().count(), 12);
+    assert_eq!(fox.lines().last(), Some("line 11: the quick brown fox"));
+
+    assert_eq!(post("/upload/zlib.txt", "deflate", &unhex("789c4b494dcb492c494dd15148c1602902009f2a0a65")), 201);
+    assert_eq!(fs::read_to_string(uploads.join("zlib.txt")).unwrap(), "deflated, deflated, deflated!");
+
+    // 54 bytes that expand to 20000 zeros: fine on /upload, too much for /small
+    let zeros = unhex(
+        "1f8b0800000000000203edc13101000000c2a0f54f6d0d0fa000000000000000000000000000000000000000783002532f97204e0000"
+    );
+    assert_eq!(post("/upload/zeros.bin", "gzip", &zeros), 201);
+    assert_eq!(fs::read(uploads.join("zeros.bin")).unwrap().len(), 20000);
+    assert_eq!(post("/small/zeros.bin", "gzip", &zeros), 413);
+
+    assert_eq!(post("/upload/x.txt", "br", b"whatever"), 415);
+    assert_eq!(post("/upload/x.txt", "gzip", b"not gzip at all"), 400);
+}
+
+#[test]
+fn maintenance_file_answers_503_except_for_health_checks() {
+    let dir = site("maintenance");
+    fs::write(dir.join("www/healthz"), "ok").unwrap();
+    let flag = dir.join("maintenance");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .maintenance(&flag.to_string_lossy(), Duration::from_secs(90))
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root(&root).index("index.html")))
+            .build()
+            .unwrap()
+    );
+
+    assert_eq!(server.get("/").status, 200);
+
+    fs::write(&flag, "").unwrap();
+    let down = server.get("/");
+    assert_eq!(down.status, 503);
+    assert_eq!(down.header("retry-after"), Some("90"));
+    assert_eq!(server.get("/healthz").text(), "ok");
+
+    fs::remove_file(&flag).unwrap();
+    assert_eq!(server.get("/").status, 200);
+}
+
+#[test]
+fn more_than_a_hundred_listeners_can_be_bound() {
+    // Every 127.x.y.z address is loopback on Linux, so each block gets its own listener
+    let mut config = builder();
+    for i in 1..=150 {
+        config = config.server(ServerBlockBuilder::new(&format!("127.0.{}.{}", i / 100, i % 100 + 1), 0).route(RouteBuilder::new("/")));
+    }
+    let server = start(config.build().unwrap());
+
+    assert_eq!(server.addrs().len(), 150);
+    for addr in [server.addrs()[0], server.addrs()[99], server.addrs()[149]] {
+        let response = TestResponse::parse(&localserver::testing::send_raw(addr, b"GET /missing HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(response.status, 404);
+    }
+}
+
+#[test]
+fn sub_filters_rewrite_matching_content_types() {
+    let dir = site("sub-filter");
+    fs::write(dir.join("www/page.html"), "<body>Hello OLD</body>").unwrap();
+    fs::write(dir.join("www/notes.txt"), "OLD").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/").root(&root).sub_filter("OLD", "NEW").sub_filter("</body>", "<footer>f</footer></body>")
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let page = server.get("/page.html");
+    assert_eq!(page.text(), "<body>Hello NEW<footer>f</footer></body>");
+    assert_eq!(page.header("content-length"), Some(page.body.len().to_string().as_str()));
+    // Only HTML by default
+    assert_eq!(server.get("/notes.txt").text(), "OLD");
+}
+
+#[test]
+fn dev_mode_reloads_pages_when_files_change() {
+    let dir = site("live-reload");
+    fs::write(dir.join("www/page.html"), "<body>v1</body>").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let config = builder()
+        .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root(&root)))
+        .build()
+        .unwrap();
+    let server = TestServer::start_with(config, |server| server.enable_live_reload().unwrap()).unwrap();
+
+    let page = server.get("/page.html");
+    assert!(page.text().contains("/__livereload"));
+    assert!(page.text().ends_with("</script></body>"));
+
+    let mut events = TcpStream::connect(server.addr()).unwrap();
+    events.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    events.write_all(b"GET /__livereload HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = events.read(&mut buf).unwrap();
+        assert!(n > 0, "stream closed before its headers");
+        received.extend_from_slice(&buf[..n]);
+    }
+    assert!(String::from_utf8_lossy(&received).contains("text/event-stream"));
+
+    fs::write(dir.join("www/page.html"), "<body>v2</body>").unwrap();
+    while !String::from_utf8_lossy(&received).contains("data: reload") {
+        let n = events.read(&mut buf).unwrap();
+        assert!(n > 0, "stream closed before the reload event");
+        received.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[test]
+fn reloads_do_not_look_up_upstream_names() {
+    let dir = site("reload");
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let file = dir.join("config.yaml");
+    let write = |body: &str, extra: &str| {
+        let yaml = format!(
+            "log_level: error\nservers:\n  - host: 127.0.0.1\n    port: {}\n    routes:\n      - path: /\n        return: {{ code: 200, body: {} }}\n{}",
+            port,
+            body,
+            extra
+        );
+        fs::write(&file, yaml).unwrap();
+    };
+    write("one", "");
+
+    let path = file.to_string_lossy().into_owned();
+    let config = localserver::config::parse_config(&path, None, None).unwrap();
+    let server = TestServer::start_with(config, move |server| {
+        let loader = path.clone();
+        server
+            .watch_config(Path::new(&path), Box::new(move || localserver::config::parse_config(&loader, None, None)))
+            .unwrap();
+    }).unwrap();
+    assert_eq!(server.get("/").text(), "one");
+
+    // `.invalid` never resolves; the reload goes through regardless
+    write("two", "      - path: /api\n        proxy_pass: http://upstream.invalid:8080\n");
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while server.get("/").text() != "two" {
+        assert!(std::time::Instant::now() < deadline, "the reload was not applied");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(server.get("/api/x").status, 502);
+}
+
+#[test]
+fn oversized_bodies_are_drained_after_the_413() {
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).max_body_size(1024).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap()
+    );
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 200000\r\n\r\n").unwrap();
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"HTTP/1.1 413");
+
+    // Sending the body anyway neither fails nor resets the response
+    stream.write_all(&vec![b'x'; 200_000]).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(String::from_utf8_lossy(&rest).contains("Connection: close"));
+}
+
+#[test]
+fn upload_progress_reports_bytes_received() {
+    let dir = site("progress");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let uploads = dir.join("uploads").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .max_body_size(1024)
+                    .upload_progress("/progress")
+                    .route(RouteBuilder::new("/upload").root(&root).upload_dir(&uploads))
+            )
+            .build()
+            .unwrap()
+    );
+    // The upload is read on the same event loop, so poll until it has caught up
+    let progress_becomes = |id: &str, expected: &str| {
+        let mut last = String::new();
+        for _ in 0..100 {
+            last = server.get(&format!("/progress?id={}", id)).text();
+            if last == expected {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("progress stayed at {}", last);
+    };
+
+    progress_becomes("abc", "{\"state\":\"starting\"}");
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"POST /upload/big.bin HTTP/1.1\r\nHost: x\r\nX-Upload-ID: abc\r\nContent-Length: 1000\r\n\r\n").unwrap();
+    stream.write_all(&[b'x'; 400]).unwrap();
+    progress_becomes("abc", "{\"state\":\"uploading\",\"received\":400,\"size\":1000}");
+
+    stream.write_all(&[b'x'; 600]).unwrap();
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"HTTP/1.1 201");
+    progress_becomes("abc", "{\"state\":\"done\",\"received\":1000,\"size\":1000}");
+
+    let progress = server.get("/progress?id=abc");
+    assert_eq!(progress.header("content-type"), Some("application/json"));
+    assert_eq!(progress.header("cache-control"), Some("no-store"));
+    assert_eq!(server.get("/progress").status, 400);
+
+    // Over the body limit: rejected as soon as the head arrives
+    let rejected = server.request(b"POST /upload/huge.bin HTTP/1.1\r\nHost: x\r\nX-Upload-ID: huge\r\nContent-Length: 5000\r\n\r\n");
+    assert_eq!(rejected.status, 413);
+    progress_becomes("huge", "{\"state\":\"error\",\"received\":0,\"size\":5000}");
+}
+
+/// Opening a connection wakes the event loop, which checks timeouts on every turn.
+fn nudge(server: &TestServer) {
+    drop(TcpStream::connect(server.addr()).unwrap());
+}
+
+#[test]
+fn stalled_requests_time_out_on_the_server_clock() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .timeout(Duration::from_secs(30))
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // Not yet idle for 30 seconds as far as the server knows
+    clock.advance(Duration::from_secs(29));
+    nudge(&server);
+    thread::sleep(Duration::from_millis(100));
+    stream.set_nonblocking(true).unwrap();
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).unwrap();
+
+    clock.advance(Duration::from_secs(2));
+    nudge(&server);
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let response = TestResponse::parse(&response);
+    assert_eq!(response.status, 408);
+    assert_eq!(response.header("connection"), Some("close"));
+}
+
+#[test]
+fn idle_connections_close_without_a_response() {
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .timeout(Duration::from_secs(30))
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    // Nothing was asked, so there is nothing to answer
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(31));
+    nudge(&server);
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.is_empty());
+}
+
+#[test]
+fn repeated_client_errors_ban_the_client_until_the_ban_ends() {
+    let dir = site("ban");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .ban(3, Duration::from_secs(60), Duration::from_secs(300))
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root(&root).index("index.html")))
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+    // A banned client is closed on at accept, possibly before the request is written
+    let answered = || {
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let _ = stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        TestResponse::parse(&response).status
+    };
+
+    // Strikes older than the window are forgotten
+    assert_eq!(server.get("/missing").status, 404);
+    assert_eq!(server.get("/missing").status, 404);
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(server.get("/missing").status, 404);
+    assert_eq!(answered(), 200);
+
+    assert_eq!(server.get("/missing").status, 404);
+    assert_eq!(server.get("/missing").status, 404);
+    assert_eq!(answered(), 0);
+    clock.advance(Duration::from_secs(299));
+    assert_eq!(answered(), 0);
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(answered(), 200);
+}
+
+#[test]
+fn clients_behind_a_trusted_proxy_are_banned_by_forwarded_address() {
+    let dir = site("ban-forwarded");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .ban(2, Duration::from_secs(60), Duration::from_secs(300))
+            .trusted_proxy("127.0.0.1")
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root(&root).index("index.html")))
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+    let get_for = |client: &str, path: &str| {
+        server
+            .request(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: {}\r\nConnection: close\r\n\r\n",
+                    path,
+                    client
+                ).as_bytes()
+            )
+            .status
+    };
+
+    assert_eq!(get_for("203.0.113.7", "/missing"), 404);
+    assert_eq!(get_for("203.0.113.7", "/missing"), 404);
+    // The proxy itself and its other clients are still served
+    assert_eq!(get_for("203.0.113.7", "/"), 403);
+    assert_eq!(get_for("203.0.113.8", "/"), 200);
+    assert_eq!(server.get("/").status, 200);
+    clock.advance(Duration::from_secs(301));
+    assert_eq!(get_for("203.0.113.7", "/"), 200);
+}
+
+#[test]
+fn slow_cgi_scripts_get_504() {
+    let dir = site("cgi-timeout");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("slow.sh"), "sleep 30\n").unwrap();
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .cgi_timeout(Duration::from_secs(5))
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin").root(&bin.to_string_lossy()).cgi(".sh", "/bin/sh")
+                )
+            )
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET /cgi-bin/slow.sh HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    clock.advance(Duration::from_secs(6));
+    nudge(&server);
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(TestResponse::parse(&response).status, 504);
+}
+
+#[test]
+fn running_cgi_scripts_are_reaped_on_shutdown() {
+    let dir = site("cgi-shutdown");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    let pid_file = dir.join("pid");
+    fs::write(bin.join("hang.sh"), format!("echo $$ > {}\nexec sleep 30\n", pid_file.display())).unwrap();
+
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin").root(&bin.to_string_lossy()).cgi(".sh", "/bin/sh")
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.write_all(b"GET /cgi-bin/hang.sh HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut pid = String::new();
+    for _ in 0..100 {
+        pid = fs::read_to_string(&pid_file).unwrap_or_default().trim().to_string();
+        if !pid.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!pid.is_empty(), "the script never started");
+    assert!(Path::new(&format!("/proc/{}", pid)).exists());
+
+    drop(server);
+    assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+}
+
+#[test]
+fn cached_cgi_routes_run_the_script_once_per_ttl() {
+    let dir = site("cgi-cache");
+    let bin = dir.join("cgi-bin");
+    fs::create_dir_all(&bin).unwrap();
+    let count = dir.join("count");
+    fs::write(
+        bin.join("slow.sh"),
+        format!(
+            "n=$(($(cat {0} 2>/dev/null || echo 0) + 1)); echo $n > {0}; sleep 0.3\n\
+             [ \"$QUERY_STRING\" = cookie ] && printf 'Set-Cookie: a=b\\r\\n'\n\
+             printf 'Content-Type: text/plain\\r\\n\\r\\nrun %s' $n\n",
+            count.display()
+        )
+    ).unwrap();
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin")
+                        .root(&bin.to_string_lossy())
+                        .cgi(".sh", "/bin/sh")
+                        .cgi_cache(Duration::from_secs(10), 1024)
+                )
+            )
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    // A burst of identical requests while the script runs
+    let burst: Vec<TcpStream> = (0..3)
+        .map(|_| {
+            let mut stream = TcpStream::connect(server.addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            stream.write_all(b"GET /cgi-bin/slow.sh HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream
+        })
+        .collect();
+    let mut states = Vec::new();
+    for mut stream in burst {
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).unwrap();
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.text(), "run 1");
+        states.push(response.header("X-Cache").unwrap().to_string());
+    }
+    assert_eq!(states, ["MISS", "HIT", "HIT"]);
+
+    assert_eq!(server.get("/cgi-bin/slow.sh").text(), "run 1");
+    assert_eq!(server.get("/cgi-bin/slow.sh?page=2").text(), "run 2");
+    // Responses setting cookies are never shared
+    assert_eq!(server.get("/cgi-bin/slow.sh?cookie").text(), "run 3");
+    assert_eq!(server.get("/cgi-bin/slow.sh?cookie").text(), "run 4");
+
+    clock.advance(Duration::from_secs(11));
+    let expired = server.get("/cgi-bin/slow.sh");
+    assert_eq!(expired.text(), "run 5");
+    assert_eq!(expired.header("X-Cache"), Some("MISS"));
+}
+
+#[test]
+fn cached_cgi_routes_keep_canary_answers_apart() {
+    let dir = site("cgi-cache-canary");
+    for variant in ["stable", "next"] {
+        fs::create_dir_all(dir.join(variant)).unwrap();
+        fs::write(dir.join(variant).join("who.sh"), format!("printf 'Content-Type: text/plain\\r\\n\\r\\n{}'\n", variant)).unwrap();
+    }
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/cgi-bin")
+                        .root(&dir.join("stable").to_string_lossy())
+                        .cgi(".sh", "/bin/sh")
+                        .cgi_cache(Duration::from_secs(60), 1024)
+                        .canary_header("X-Canary", None, &dir.join("next").to_string_lossy())
+                )
+            )
+            .build()
+            .unwrap()
+    );
+    let get = |canary: bool| {
+        let header = if canary { "X-Canary: 1\r\n" } else { "" };
+        let response = server.request(
+            format!("GET /cgi-bin/who.sh HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", header).as_bytes()
+        );
+        (response.text(), response.header("X-Cache").unwrap_or("").to_string())
+    };
+
+    assert_eq!(get(false), ("stable".to_string(), "MISS".to_string()));
+    assert_eq!(get(true), ("next".to_string(), "MISS".to_string()));
+    assert_eq!(get(false), ("stable".to_string(), "HIT".to_string()));
+    assert_eq!(get(true), ("next".to_string(), "HIT".to_string()));
+}
+
+#[test]
+fn static_files_answer_conditional_gets_with_304() {
+    let dir = site("conditional");
+    let modified = UNIX_EPOCH + Duration::from_secs(784111777);
+    fs::File::options().write(true).open(dir.join("www/index.html")).unwrap().set_modified(modified).unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root(&root).index("index.html")))
+            .build()
+            .unwrap()
+    );
+
+    let fresh = server.get("/index.html");
+    assert_eq!(fresh.status, 200);
+    assert_eq!(fresh.header("Last-Modified"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+    let conditional = |since: &str| {
+        server.request(format!("GET /index.html HTTP/1.1\r\nHost: localhost\r\nIf-Modified-Since: {}\r\n\r\n", since).as_bytes())
+    };
+    for since in ["Sun, 06 Nov 1994 08:49:37 GMT", "Sunday, 06-Nov-94 08:49:38 GMT", "Sun Nov  6 09:00:00 1994"] {
+        let cached = conditional(since);
+        assert_eq!(cached.status, 304, "{}", since);
+        assert!(cached.body.is_empty());
+        assert_eq!(cached.header("Content-Length"), None);
+        assert_eq!(cached.header("Last-Modified"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+    }
+    // Older dates and ones that do not parse get the file
+    assert_eq!(conditional("Sun, 06 Nov 1994 08:49:36 GMT").text(), "<h1>home</h1>");
+    assert_eq!(conditional("yesterday").status, 200);
+}
+
+#[test]
+fn precompressed_sidecars_are_served_to_clients_accepting_them() {
+    let dir = site("sidecars");
+    fs::write(dir.join("www/index.html.gz"), "gzip bytes").unwrap();
+    fs::write(dir.join("www/style.css"), "body {}").unwrap();
+    fs::write(dir.join("www/style.css.gz"), "gzip css").unwrap();
+    fs::write(dir.join("www/style.css.br"), "brotli css").unwrap();
+    fs::write(dir.join("www/plain.txt"), "plain").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/").root(&root)))
+            .build()
+            .unwrap()
+    );
+    let get = |path: &str, accept: &str| {
+        server.request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n", path, accept).as_bytes())
+    };
+
+    let identity = server.get("/index.html");
+    assert_eq!(identity.text(), "<h1>home</h1>");
+    assert_eq!(identity.header("Content-Encoding"), None);
+    assert_eq!(identity.header("Vary"), Some("Accept-Encoding"));
+
+    let gzipped = get("/index.html", "gzip, deflate");
+    assert_eq!(gzipped.text(), "gzip bytes");
+    assert_eq!(gzipped.header("Content-Encoding"), Some("gzip"));
+    assert!(gzipped.header("Content-Type").unwrap().starts_with("text/html"));
+    assert_eq!(gzipped.header("Vary"), Some("Accept-Encoding"));
+
+    // Brotli is preferred when both are accepted, unless refused with q=0
+    assert_eq!(get("/style.css", "gzip, br").text(), "brotli css");
+    assert_eq!(get("/style.css", "br;q=0, gzip").text(), "gzip css");
+    assert_eq!(get("/style.css", "*").header("Content-Encoding"), Some("br"));
+    assert_eq!(get("/style.css", "deflate").text(), "body {}");
+    assert_eq!(get("/index.html", "br").text(), "<h1>home</h1>");
+
+    let plain = get("/plain.txt", "gzip, br");
+    assert_eq!(plain.text(), "plain");
+    assert_eq!(plain.header("Vary"), None);
+}
+
+#[test]
+fn large_static_files_are_streamed_whole() {
+    let dir = site("streaming");
+    let content: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(dir.join("www/video.bin"), &content).unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let mut memory = MemoryFs::new();
+    memory.insert("video.bin", content.clone());
+    let server = TestServer::start_with(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root(&root))
+                    .route(RouteBuilder::new("/mem").root("vfs:mem"))
+            )
+            .build()
+            .unwrap(),
+        move |server| server.mount("mem", Box::new(memory))
+    ).expect("server starts");
+
+    // From disk with sendfile, and from a mounted tree through a buffer
+    for path in ["/video.bin", "/mem/video.bin"] {
+        let response = server.get(path);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Length"), Some(content.len().to_string().as_str()));
+        assert!(response.body == content, "{} differs from the file", path);
+    }
+
+    let head = server.request(b"HEAD /video.bin HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(head.header("Content-Length"), Some(content.len().to_string().as_str()));
+    assert!(head.body.is_empty());
+}
+
+/// Responses read back to back off one connection, split by their `Content-Length`.
+fn split_responses(mut raw: &[u8], heads: &[bool]) -> Vec<TestResponse> {
+    let mut responses = Vec::new();
+    for &head_only in heads {
+        let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n").expect("a full head") + 4;
+        let parsed = TestResponse::parse(&raw[..head_end]);
+        let len = match head_only {
+            true => 0,
+            false => parsed.header("Content-Length").map_or(0, |len| len.parse().unwrap()),
+        };
+        responses.push(TestResponse::parse(&raw[..head_end + len]));
+        raw = &raw[head_end + len..];
+    }
+    assert!(raw.is_empty(), "unexpected bytes after the responses");
+    responses
+}
+
+#[test]
+fn https_redirects_only_echo_the_blocks_own_names() {
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .server_name("example.com")
+                    .server_name("*.example.org")
+                    .https_redirect(true)
+                    .route(RouteBuilder::new("/"))
+            )
+            .build()
+            .unwrap()
+    );
+    let location = |host: &str| {
+        let response = server.request(
+            format!("GET /a?b=1 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host).as_bytes()
+        );
+        assert_eq!(response.status, 301);
+        response.header("location").unwrap().to_string()
+    };
+
+    assert_eq!(location("example.com:8080"), "https://example.com/a?b=1");
+    assert_eq!(location("shop.example.org"), "https://shop.example.org/a?b=1");
+    assert_eq!(location("127.0.0.1"), "https://127.0.0.1/a?b=1");
+    // Anything else goes to the block's first name
+    assert_eq!(location("attacker.example"), "https://example.com/a?b=1");
+    assert_eq!(location("example.com@attacker.example"), "https://example.com/a?b=1");
+    assert_eq!(location(""), "https://example.com/a?b=1");
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let dir = site("pipelining");
+    fs::write(dir.join("www/about.txt"), "about").unwrap();
+    fs::write(dir.join("www/slow.sh"), "sleep 0.3\nprintf 'Content-Type: text/plain\\r\\n\\r\\nslow'\n").unwrap();
+    let root = dir.join("www").to_string_lossy().into_owned();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/").root(&root).methods(["GET", "HEAD", "POST"]).cgi(".sh", "/bin/sh"))
+            )
+            .build()
+            .unwrap()
+    );
+
+    let raw = server.send_raw(
+        b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          POST /about.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello\
+          HEAD /about.txt HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          GET /about.txt HTTP/1.1\r\nHost: localhost\r\n\r\n"
+    );
+    let responses = split_responses(&raw, &[false, false, true, false, false]);
+    let statuses: Vec<u16> = responses.iter().map(|r| r.status).collect();
+    assert_eq!(statuses, [200, 201, 200, 404, 200]);
+    assert_eq!(responses[0].text(), "<h1>home</h1>");
+    assert_eq!(responses[4].text(), "about");
+    let connection: Vec<&str> = responses.iter().map(|r| r.header("Connection").unwrap()).collect();
+    assert_eq!(connection, ["keep-alive", "keep-alive", "keep-alive", "keep-alive", "close"]);
+
+    // The rest of a request split across reads is still waited for
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET /about.txt HTTP/1.1\r\nHost: localhost\r\n\r\nGET /index.html HTT").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(b"P/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+    let responses = split_responses(&raw, &[false, false]);
+    assert_eq!(responses[0].text(), "about");
+    assert_eq!(responses[1].text(), "<h1>home</h1>");
+
+    // A request arriving in a later read, while the one before it is still being answered
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET /slow.sh HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(b"GET /about.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+    let responses = split_responses(&raw, &[false, false]);
+    assert_eq!((responses[0].text(), responses[0].header("Connection")), ("slow".to_string(), Some("keep-alive")));
+    assert_eq!((responses[1].text(), responses[1].header("Connection")), ("about".to_string(), Some("close")));
+
+    // One that starts exactly where the server's first read stops
+    let head = |len: usize| format!("POST /about.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", len);
+    let body_len = 4096 - head(1000).len();
+    let mut bytes = format!("{}{}", head(body_len), "x".repeat(body_len)).into_bytes();
+    assert_eq!(bytes.len(), 4096);
+    bytes.extend_from_slice(b"GET /about.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let responses = split_responses(&server.send_raw(&bytes), &[false, false]);
+    assert_eq!(responses.iter().map(|r| r.status).collect::<Vec<_>>(), [201, 200]);
+
+    // `Connection: close` ends the exchange whatever follows
+    let raw = server.send_raw(
+        b"GET /about.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\nGET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n"
+    );
+    assert_eq!(split_responses(&raw, &[false])[0].text(), "about");
+}
+
+#[test]
+fn handlers_read_cookies_and_set_several() {
+    let server = TestServer::start_with(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/prefs").handler("prefs")))
+            .build()
+            .unwrap(),
+        |server| {
+            server.pipeline_mut().register_fn("prefs", |request| {
+                let theme = request.cookies.get("theme").map_or("none", String::as_str);
+                let mut response = Response::new(StatusCode::OK, "text/plain", theme.to_string());
+                response.set_cookie(&SetCookie::new("theme", "light").unwrap().path("/").max_age(Duration::from_secs(60)));
+                response.set_cookie(&SetCookie::new("seen", "1").unwrap().http_only().same_site(SameSite::Lax));
+                response
+            });
+        }
+    ).expect("server starts");
+
+    let response = server.request(b"GET /prefs HTTP/1.1\r\nHost: localhost\r\nCookie: lang=fr; theme=\"dark\"\r\n\r\n");
+    assert_eq!(response.text(), "dark");
+    let set: Vec<&str> = response.headers
+        .iter()
+        .filter(|(name, _)| name == "set-cookie")
+        .map(|(_, value)| value.as_str())
+        .collect();
+    // The session cookie stays alongside the handler's own
+    assert_eq!(set.len(), 3, "{:?}", set);
+    assert!(set.iter().any(|value| value.starts_with("SESSION_ID=")));
+    assert!(set.contains(&"theme=light; Path=/; Max-Age=60"));
+    assert!(set.contains(&"seen=1; HttpOnly; SameSite=Lax"));
+}
+
+#[test]
+fn malformed_requests_get_400() {
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap()
+    );
+
+    assert_eq!(server.request(b"NONSENSE\r\n\r\n").status, 400);
+}
+
+#[test]
+fn request_lines_are_refused_with_their_own_status() {
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/")))
+            .build()
+            .unwrap()
+    );
+    let status = |line: &[u8]| {
+        let mut request = line.to_vec();
+        request.extend_from_slice(b"\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        server.request(&request).status
+    };
+
+    assert_eq!(status(b"GET /a\x01b HTTP/1.1"), 400);
+    assert_eq!(status(b"GET  / HTTP/1.1"), 400);
+    assert_eq!(status(b"GET / HTTP/1.1 extra"), 400);
+    assert_eq!(status(b"GET / HTTP/2.0"), 505);
+    assert_eq!(status(b"BREW /pot HTTP/1.1"), 501);
+    assert_eq!(status(format!("GET /{} HTTP/1.1", "a".repeat(9000)).as_bytes()), 414);
+    // A line that never ends is refused once it is too long, without waiting for the rest
+    let endless = server.request(format!("GET /{}", "a".repeat(9000)).as_bytes());
+    assert_eq!(endless.status, 414);
+}
+
+/// Reads one request, head and `Content-Length` body, as an upstream server.
+fn read_request(stream: &mut TcpStream) -> String {
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf).unwrap();
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request).to_string();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+            if n == 0 || request.len() >= end + 4 + length {
+                break;
+            }
+        }
+    }
+    String::from_utf8(request).unwrap()
+}
+
+/// A one-shot upstream: answers the first connection with `response` and
+/// returns the request it received.
+fn upstream(response: &'static [u8]) -> (u16, thread::JoinHandle<String>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_request(&mut stream);
+        stream.write_all(response).unwrap();
+        request
+    });
+    (port, handle)
+}
+
+/// An upstream answering every request with `name` until the test ends.
+fn named_upstream(listener: std::net::TcpListener, name: &'static str) -> String {
+    let target = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            read_request(&mut stream);
+            let _ = stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", name.len(), name).as_bytes());
+        }
+    });
+    target
+}
+
+#[test]
+fn proxied_routes_relay_the_upstream_answer() {
+    let (port, received) = upstream(
+        b"HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\nServer: upstream\r\nX-Backend: one\r\n\r\nmade it"
+    );
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .timeout(Duration::from_secs(30))
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/api").proxy_pass(&format!("http://127.0.0.1:{}/v1", port)))
+                    .route(RouteBuilder::new("/down").proxy_pass(&format!("http://127.0.0.1:{}", closed)))
+                    .route(
+                        RouteBuilder::new("/slow").proxy_pass(&format!("http://{}", silent.local_addr().unwrap()))
+                    )
+            )
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    let response = server.request(
+        b"POST /api/items?sort=new HTTP/1.1\r\nHost: site.test\r\nConnection: keep-alive, X-Secret\r\nX-Secret: hop\r\nX-Forwarded-For: 10.0.0.9\r\nContent-Length: 5\r\n\r\nhello"
+    );
+    assert_eq!(response.status, 201);
+    assert_eq!(response.text(), "made it");
+    assert_eq!(response.header("x-backend"), Some("one"));
+    assert_eq!(response.header("keep-alive"), None);
+    assert_eq!(response.header("connection"), Some("close"));
+    assert_ne!(response.header("server"), Some("upstream"));
+
+    // The route prefix is swapped for the target's, and hop-by-hop headers stay behind
+    let request = received.join().unwrap();
+    assert!(request.starts_with("POST /v1/items?sort=new HTTP/1.0\r\n"), "{}", request);
+    assert!(request.contains(&format!("Host: 127.0.0.1:{}\r\n", port)));
+    assert!(request.contains("X-Forwarded-For: 10.0.0.9, 127.0.0.1\r\n"));
+    assert!(request.contains("X-Forwarded-Host: site.test\r\n"));
+    assert!(request.contains("X-Forwarded-Proto: http\r\n"));
+    assert!(!request.to_ascii_lowercase().contains("x-secret"));
+    assert!(request.ends_with("\r\n\r\nhello"));
+
+    assert_eq!(server.get("/down/").status, 502);
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(31));
+    nudge(&server);
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(TestResponse::parse(&response).status, 504);
+}
+
+#[test]
+fn proxy_upstreams_are_balanced_and_failed_ones_skipped() {
+    let bind = || std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let one = named_upstream(bind(), "one");
+    let two = named_upstream(bind(), "two");
+    let held = bind();
+    let held_target = format!("http://{}", held.local_addr().unwrap());
+    let dead = bind().local_addr().unwrap();
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .timeout(Duration::from_secs(60))
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/rr").proxy_pass(&one).proxy_pass(&two))
+                    .route(
+                        RouteBuilder::new("/least")
+                            .proxy_pass(&held_target)
+                            .proxy_pass(&two)
+                            .proxy_balance(Balance::LeastConnections)
+                    )
+                    .route(
+                        RouteBuilder::new("/failover")
+                            .proxy_pass(&format!("http://{}", dead))
+                            .proxy_pass(&one)
+                            .proxy_fail_timeout(Duration::from_secs(10))
+                    )
+            )
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+    let answer = |path: &str| server.get(path).text();
+
+    let answers: Vec<String> = (0..4).map(|_| answer("/rr")).collect();
+    assert_eq!(answers, ["one", "two", "one", "two"]);
+
+    // While the first upstream holds a request, the other one takes the rest
+    let mut waiting = TcpStream::connect(server.addr()).unwrap();
+    waiting.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    waiting.write_all(b"GET /least HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let (mut upstream, _) = held.accept().unwrap();
+    read_request(&mut upstream);
+    let answers: Vec<String> = (0..3).map(|_| answer("/least")).collect();
+    assert_eq!(answers, ["two", "two", "two"]);
+    upstream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nheld").unwrap();
+    drop(upstream);
+    let mut response = Vec::new();
+    waiting.read_to_end(&mut response).unwrap();
+    assert_eq!(TestResponse::parse(&response).text(), "held");
+
+    // A refused connection moves on to the next upstream and keeps the dead one out for its cooldown
+    assert_eq!(answer("/failover"), "one");
+    let revived = named_upstream(std::net::TcpListener::bind(dead).unwrap(), "revived");
+    assert_eq!(revived, format!("http://{}", dead));
+    assert_eq!(answer("/failover"), "one");
+    assert_eq!(answer("/failover"), "one");
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(answer("/failover"), "revived");
+}
+
+#[test]
+fn silent_upstreams_are_retried_within_the_tries_and_time_budget() {
+    let mut silent = Vec::new();
+    let mut hold = || {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = format!("http://{}", listener.local_addr().unwrap());
+        silent.push(listener);
+        target
+    };
+    let route = |path: &str, first: String, second: String| {
+        RouteBuilder::new(path).proxy_pass(&first).proxy_pass(&second).proxy_fail_timeout(Duration::ZERO)
+    };
+    let (all, tries, budget, post) = (
+        route("/all", hold(), hold()),
+        route("/tries", hold(), hold()).proxy_next_upstream(2, Duration::ZERO),
+        route("/budget", hold(), hold()).proxy_next_upstream(0, Duration::from_secs(45)),
+        route("/post", hold(), hold()),
+    );
+    let one = named_upstream(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), "one");
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .timeout(Duration::from_secs(30))
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(all.proxy_pass(&one))
+                    .route(tries.proxy_pass(&one))
+                    .route(budget.proxy_pass(&one))
+                    .route(post.proxy_pass(&one))
+            )
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    // Each silent upstream is given up on after `timeout`
+    let ask = |request: &[u8], timeouts: usize| {
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream.write_all(request).unwrap();
+        for _ in 0..timeouts {
+            thread::sleep(Duration::from_millis(100));
+            clock.advance(Duration::from_secs(31));
+            nudge(&server);
+        }
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        TestResponse::parse(&response)
+    };
+
+    assert_eq!(ask(b"GET /all HTTP/1.1\r\nHost: localhost\r\n\r\n", 2).text(), "one");
+    assert_eq!(ask(b"GET /tries HTTP/1.1\r\nHost: localhost\r\n\r\n", 2).status, 504);
+    assert_eq!(ask(b"GET /budget HTTP/1.1\r\nHost: localhost\r\n\r\n", 2).status, 504);
+    // A POST the upstream may have acted on is not sent again
+    assert_eq!(ask(b"POST /post HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n", 1).status, 504);
+}
+
+#[test]
+fn upstreams_failing_health_checks_are_left_out_until_they_pass() {
+    let well = named_upstream(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), "well");
+    let sick_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let sick_address = sick_listener.local_addr().unwrap().to_string();
+    let failing = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let failing_upstream = failing.clone();
+    thread::spawn(move || {
+        for stream in sick_listener.incoming() {
+            let mut stream = stream.unwrap();
+            let request = read_request(&mut stream);
+            let answer = if request.starts_with("GET /healthz ") && failing_upstream.load(std::sync::atomic::Ordering::SeqCst) {
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nsick"
+            };
+            let _ = stream.write_all(answer.as_bytes());
+        }
+    });
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let server = TestServer::start_with(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).upstream_status("/upstreams").route(
+                    RouteBuilder::new("/")
+                        .proxy_pass(&format!("http://{}", sick_address))
+                        .proxy_pass(&well)
+                        .proxy_health(HealthCheck {
+                            probe: Probe::Get("/healthz".to_string()),
+                            interval: Duration::from_secs(5),
+                            fails: 2,
+                            passes: 2,
+                        })
+                )
+            )
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+
+    let healthy = || {
+        let status = server.get("/upstreams");
+        assert_eq!(status.header("Cache-Control"), Some("no-store"));
+        status.text().contains(&format!("{{\"address\":\"{}\",\"healthy\":true", sick_address))
+    };
+    // One probe runs at once; the next only once the interval has passed
+    let probe_again = || {
+        thread::sleep(Duration::from_millis(200));
+        clock.advance(Duration::from_secs(5));
+        nudge(&server);
+        thread::sleep(Duration::from_millis(200));
+    };
+
+    // The first probes go out as the server starts
+    thread::sleep(Duration::from_millis(200));
+    assert!(healthy());
+    probe_again();
+    assert!(!healthy());
+    let answers: Vec<String> = (0..3).map(|_| server.get("/a").text()).collect();
+    assert_eq!(answers, ["well", "well", "well"]);
+
+    failing.store(false, std::sync::atomic::Ordering::SeqCst);
+    probe_again();
+    assert!(!healthy());
+    probe_again();
+    assert!(healthy());
+    let answers: Vec<String> = (0..2).map(|_| server.get("/a").text()).collect();
+    assert!(answers.contains(&"sick".to_string()) && answers.contains(&"well".to_string()));
+    assert_eq!(server.request(b"POST /upstreams HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n").status, 405);
+}
+
+#[test]
+fn proxied_websockets_become_tunnels_to_the_upstream() {
+    // Completes the handshake, then sends every frame back with a prefix
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let received = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let request = read_request(&mut stream);
+        stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\nhello").unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            let mut echo = b"echo:".to_vec();
+            echo.extend_from_slice(&buf[..n]);
+            stream.write_all(&echo).unwrap();
+        }
+        request
+    });
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/live").proxy_pass(&format!("http://127.0.0.1:{}", port)))
+            )
+            .build()
+            .unwrap()
+    );
+
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET /live/chat HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let read_until = |stream: &mut TcpStream, end: &[u8]| {
+        let mut got = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !got.ends_with(end) {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "closed after {:?}", String::from_utf8_lossy(&got));
+            got.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(got).unwrap()
+    };
+    let handshake = read_until(&mut stream, b"hello");
+    assert!(handshake.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", handshake);
+    assert!(handshake.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert!(handshake.contains("\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"));
+
+    // Bytes flow both ways for as long as either side keeps the connection
+    for message in ["ping", "pong"] {
+        stream.write_all(message.as_bytes()).unwrap();
+        assert_eq!(read_until(&mut stream, message.as_bytes()), format!("echo:{}", message));
+    }
+    drop(stream);
+    let request = received.join().unwrap();
+    assert!(request.starts_with("GET /live/chat HTTP/1.1\r\n"), "{}", request);
+    assert!(request.contains("\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+    assert!(request.ends_with("Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n"));
+}
+
+#[test]
+fn proxy_caches_serve_copies_and_refresh_stale_ones_in_the_background() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("http://{}", listener.local_addr().unwrap());
+    let asked = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = asked.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            read_request(&mut stream);
+            let version = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            let body = format!("v{}", version);
+            let _ = stream.write_all(
+                format!("HTTP/1.1 200 OK\r\nCache-Control: max-age=10, stale-while-revalidate=30\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()
+            );
+        }
+    });
+
+    let clock = ManualClock::new();
+    let server_clock = clock.clone();
+    let cache = ProxyCache {
+        ttl: Duration::ZERO,
+        force_ttl: false,
+        max_size: 1024 * 1024,
+        stale: Duration::ZERO,
+        key: Vec::new(),
+    };
+    let server = TestServer::start_with(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/c").proxy_pass(&target).proxy_cache(cache)))
+            .build()
+            .unwrap(),
+        move |server| server.set_clock(server_clock)
+    ).expect("server starts");
+    let asked = || asked.load(std::sync::atomic::Ordering::SeqCst);
+    let get = |path: &str| {
+        let response = server.get(path);
+        (response.text(), response.header("X-Cache").unwrap_or("").to_string())
+    };
+
+    assert_eq!(get("/c/a"), ("v1".to_string(), "MISS".to_string()));
+    assert_eq!(get("/c/a"), ("v1".to_string(), "HIT".to_string()));
+    assert_eq!(asked(), 1);
+    // The query is part of the key; a POST is never answered from the cache
+    assert_eq!(get("/c/a?page=2"), ("v2".to_string(), "MISS".to_string()));
+    let post = server.request(b"POST /c/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx");
+    assert_eq!((post.text(), post.header("X-Cache")), ("v3".to_string(), None));
+
+    // Past max-age the old copy still goes out while a new one is fetched
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(get("/c/a"), ("v1".to_string(), "STALE".to_string()));
+    for _ in 0..100 {
+        if asked() == 4 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(get("/c/a"), ("v4".to_string(), "HIT".to_string()));
+
+    // Past the stale window as well, the client waits for the upstream
+    clock.advance(Duration::from_secs(45));
+    assert_eq!(get("/c/a"), ("v5".to_string(), "MISS".to_string()));
+    assert_eq!(asked(), 5);
+}
+
+#[test]
+fn purge_requests_from_allowed_addresses_drop_cached_copies() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("http://{}", listener.local_addr().unwrap());
+    let asked = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = asked.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            read_request(&mut stream);
+            let version = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            let body = format!("v{}", version);
+            let _ = stream.write_all(
+                format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()
+            );
+        }
+    });
+
+    let cache = ProxyCache {
+        ttl: Duration::from_secs(60),
+        force_ttl: false,
+        max_size: 1024 * 1024,
+        stale: Duration::ZERO,
+        key: Vec::new(),
+    };
+    // Only 10.0.0.0/8 may purge; the test client is 127.0.0.1 unless it forwards for one
+    let server = start(
+        builder()
+            .trusted_proxy("127.0.0.1")
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .purge_allow(["10.0.0.0/8"])
+                    .route(RouteBuilder::new("/c").proxy_pass(&target).proxy_cache(cache))
+            )
+            .build()
+            .unwrap()
+    );
+    let get = |path: &str| {
+        let response = server.get(path);
+        (response.text(), response.header("X-Cache").unwrap_or("").to_string())
+    };
+    let purge = |path: &str, from: Option<&str>| {
+        let forwarded = from.map(|ip| format!("X-Forwarded-For: {}\r\n", ip)).unwrap_or_default();
+        server.request(
+            format!("PURGE {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", path, forwarded).as_bytes()
+        )
+    };
+
+    assert_eq!(get("/c/a"), ("v1".to_string(), "MISS".to_string()));
+    assert_eq!(get("/c/a?page=2"), ("v2".to_string(), "MISS".to_string()));
+    assert_eq!(get("/c/b"), ("v3".to_string(), "MISS".to_string()));
+
+    assert_eq!(purge("/c/a", None).status, 403);
+    assert_eq!(purge("/c/a", Some("192.168.1.5")).status, 403);
+    assert_eq!(get("/c/a"), ("v1".to_string(), "HIT".to_string()));
+
+    // Every query of the path goes, other paths stay
+    let purged = purge("/c/a", Some("10.1.2.3"));
+    assert_eq!((purged.status, purged.text()), (200, "{\"purged\":2}".to_string()));
+    assert_eq!(get("/c/a"), ("v4".to_string(), "MISS".to_string()));
+    assert_eq!(get("/c/b"), ("v3".to_string(), "HIT".to_string()));
+
+    // A trailing `*` takes everything under a prefix
+    assert_eq!(purge("/c/*", Some("10.1.2.3")).text(), "{\"purged\":2}");
+    assert_eq!(get("/c/b"), ("v5".to_string(), "MISS".to_string()));
+    assert_eq!(purge("/c/*", Some("10.1.2.3")).text(), "{\"purged\":1}");
+    assert_eq!(purge("/nothing", Some("10.1.2.3")).text(), "{\"purged\":0}");
+
+    let typo = builder()
+        .server(ServerBlockBuilder::new("127.0.0.1", 0).purge_allow(["10.0.0.0/88"]).route(RouteBuilder::new("/")))
+        .build();
+    assert!(matches!(typo, Err(ServerError::Config(message)) if message.contains("purge_allow: invalid prefix")));
+}
+
+#[test]
+fn proxy_protocol_preambles_count_only_from_trusted_peers() {
+    let dir = site("proxy-protocol");
+    let root = dir.join("www").to_string_lossy().into_owned();
+    // The preamble claims an address allowed to purge; the TCP peer is 127.0.0.1
+    let purge = b"PROXY TCP4 10.1.2.3 127.0.0.1 40000 80\r\nPURGE /x HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let serve = |trusted: &str, mode: ProxyProtocol| {
+        start(
+            builder()
+                .trusted_proxy(trusted)
+                .server(
+                    ServerBlockBuilder::new("127.0.0.1", 0)
+                        .proxy_protocol(mode)
+                        .purge_allow(["10.0.0.0/8"])
+                        .route(RouteBuilder::new("/").root(&root).index("index.html"))
+                )
+                .build()
+                .unwrap()
+        )
+    };
+
+    let trusted = serve("127.0.0.1", ProxyProtocol::Optional);
+    assert_eq!(trusted.request(purge).status, 200);
+
+    // Anyone else's preamble is stripped, and they stay who they are
+    let untrusted = serve("192.0.2.1", ProxyProtocol::Optional);
+    assert_eq!(untrusted.request(purge).status, 403);
+    assert_eq!(untrusted.get("/").status, 200);
+
+    let required = serve("192.0.2.1", ProxyProtocol::Required);
+    assert_eq!(required.send_raw(purge), b"");
+}
+
+#[test]
+fn sticky_routes_keep_clients_on_one_upstream() {
+    let one = named_upstream(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), "one");
+    let two = named_upstream(std::net::TcpListener::bind("127.0.0.1:0").unwrap(), "two");
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let closed = format!("http://127.0.0.1:{}", closed);
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0)
+                    .route(RouteBuilder::new("/ip").proxy_pass(&one).proxy_pass(&two).proxy_sticky(Sticky::IpHash))
+                    .route(RouteBuilder::new("/cookie").proxy_pass(&one).proxy_pass(&two).proxy_sticky(Sticky::Cookie))
+                    .route(RouteBuilder::new("/gone").proxy_pass(&closed).proxy_pass(&one).proxy_sticky(Sticky::Cookie))
+            )
+            .build()
+            .unwrap()
+    );
+
+    let first = server.get("/ip/a").text();
+    assert!((0..4).all(|_| server.get("/ip/a").text() == first));
+
+    let with_cookie = |path: &str, cookie: &str| {
+        server.request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\nConnection: close\r\n\r\n", path, cookie).as_bytes())
+    };
+    let sticky = |response: &TestResponse| {
+        response.headers
+            .iter()
+            .find(|(name, value)| name == "set-cookie" && value.starts_with("UPSTREAM_ID="))
+            .map(|(_, value)| value.clone())
+    };
+    let response = server.get("/cookie/a");
+    let set_cookie = sticky(&response).unwrap();
+    assert!(set_cookie.contains("Path=/cookie") && set_cookie.contains("HttpOnly"));
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    for _ in 0..4 {
+        let again = with_cookie("/cookie/a", &cookie);
+        assert_eq!(again.text(), response.text());
+        // Already where its cookie points
+        assert_eq!(sticky(&again), None);
+    }
+    // Without the cookie the turns go on as usual
+    let answers: Vec<String> = (0..2).map(|_| server.get("/cookie/a").text()).collect();
+    assert!(answers.contains(&"one".to_string()) && answers.contains(&"two".to_string()));
+
+    // An upstream that is gone hands the client on, with a cookie for the new one
+    let stale = format!("UPSTREAM_ID={}", localserver::server::proxy::Upstream::parse(&closed).unwrap().id());
+    let moved = with_cookie("/gone", &stale);
+    assert_eq!(moved.text(), "one");
+    assert!(sticky(&moved).is_some_and(|cookie| !cookie.starts_with(&stale)));
+}
+
+#[test]
+fn mirrors_get_one_request_each_under_the_target_path() {
+    let listener = std::net::TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (copies, received) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            copies.send(read_request(&mut stream)).unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n");
+        }
+    });
+    let root = site("mirror");
+    fs::write(root.join("a.txt"), "a").unwrap();
+    let server = start(
+        builder()
+            .server(
+                ServerBlockBuilder::new("127.0.0.1", 0).route(
+                    RouteBuilder::new("/m")
+                        .root(root.to_str().unwrap())
+                        .mirror(&format!("localhost:{}/shadow", port))
+                )
+            )
+            .build()
+            .unwrap()
+    );
+
+    // Two pipelined requests make two copies, each holding only its own request
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream
+        .write_all(
+            b"GET /m/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\nGET /m/a.txt?again HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(split_responses(&response, &[false, false]).len(), 2);
+
+    let mut copies: Vec<String> = (0..2).map(|_| received.recv_timeout(Duration::from_secs(10)).unwrap()).collect();
+    copies.sort();
+    assert_eq!(copies, [
+        "GET /shadow/a.txt HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /shadow/a.txt?again HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    ]);
+}
+
+#[test]
+fn connect_tunnels_relay_both_ways_at_the_pace_of_the_reader() {
+    let payload: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 253) as u8).collect();
+    let listener = std::net::TcpListener::bind("localhost:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let expected = payload.clone();
+    let target = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        // Not reading for a while makes the tunnel stop taking the client's bytes
+        thread::sleep(Duration::from_millis(300));
+        let mut received = vec![0u8; 5 + expected.len()];
+        stream.read_exact(&mut received).unwrap();
+        stream.write_all(&received[5..]).unwrap();
+        received
+    });
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).connect_proxy(["localhost:*", "*.invalid:*"]))
+            .build()
+            .unwrap()
+    );
+
+    // Bytes sent along with the CONNECT head reach the target first
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(format!("CONNECT localhost:{} HTTP/1.1\r\nHost: localhost\r\n\r\nearly", port).as_bytes()).unwrap();
+    let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    let mut head = vec![0u8; established.len()];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head, established);
+    stream.write_all(&payload).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+
+    let received = target.join().unwrap();
+    assert_eq!(&received[..5], b"early");
+    assert!(received[5..] == payload[..], "the target got different bytes");
+    assert!(echoed == payload, "{} bytes came back", echoed.len());
+
+    let refused = server.request(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(refused.status, 403);
+    let unknown = server.request(b"CONNECT nowhere.invalid:80 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(unknown.status, 502);
+}
+
+#[test]
+fn large_proxied_answers_reach_slow_clients_whole() {
+    let body: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let listener = std::net::TcpListener::bind("localhost:0").unwrap();
+    let target = format!("http://localhost:{}", listener.local_addr().unwrap().port());
+    let sent = body.clone();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_request(&mut stream);
+        stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", sent.len()).as_bytes()).unwrap();
+        stream.write_all(&sent).unwrap();
+    });
+    let server = start(
+        builder()
+            .server(ServerBlockBuilder::new("127.0.0.1", 0).route(RouteBuilder::new("/big").proxy_pass(&target)))
+            .build()
+            .unwrap()
+    );
+
+    // The name is looked up off the loop; upstream reads pause while the client is not reading
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let response = TestResponse::parse(&response);
+    assert_eq!(response.status, 200);
+    assert!(response.body == body, "body of {} bytes differs", response.body.len());
+}
@@ -0,0 +1,56 @@
+use localserver::markdown;
+
+#[test]
+fn blocks_render_to_their_elements() {
+    let html = markdown::to_html(
+        "# Getting Started\n\nSome text\nover two lines.\n\n> quoted\n\n---\n\n```rust\nlet x = 1 < 2;\n```\n"
+    );
+
+    assert_eq!(
+        html,
+        "<h1 id=\"getting-started\">Getting Started</h1>\n\
+         <p>Some text\nover two lines.</p>\n\
+         <blockquote>\n<p>quoted</p>\n</blockquote>\n\
+         <hr>\n\
+         <pre><code class=\"language-rust\">let x = 1 &lt; 2;\n</code></pre>\n"
+    );
+}
+
+#[test]
+fn lists_nest_by_indentation() {
+    let html = markdown::to_html("- one\n- two\n  - two.a\n  - two.b\n\n- three\n\n1. first\n2) second\n");
+
+    assert_eq!(
+        html,
+        "<ul>\n<li>one</li>\n<li>two\n<ul>\n<li>two.a</li>\n<li>two.b</li>\n</ul>\n</li>\n<li>three</li>\n</ul>\n\
+         <ol>\n<li>first</li>\n<li>second</li>\n</ol>\n"
+    );
+}
+
+#[test]
+fn inline_markup_and_escaping() {
+    assert_eq!(
+        markdown::to_html("**bold**, *em*, `a<b>`, [docs](/docs/ \"Docs\") and ![logo](logo.png)"),
+        "<p><strong>bold</strong>, <em>em</em>, <code>a&lt;b&gt;</code>, <a href=\"/docs/\">docs</a> and <img src=\"logo.png\" alt=\"logo\"></p>\n"
+    );
+    // Raw HTML is shown, not run; so are snake_case names and escaped markers
+    assert_eq!(
+        markdown::to_html("<script>x</script> my_var_name \\*not em\\*"),
+        "<p>&lt;script&gt;x&lt;/script&gt; my_var_name *not em*</p>\n"
+    );
+    assert_eq!(markdown::to_html("[x](javascript:alert(1))"), "<p><a href=\"#\">x</a>)</p>\n");
+}
+
+#[test]
+fn pages_take_their_title_from_the_first_heading() {
+    let template = "<title>{{title}}</title><main>{{content}}</main>";
+
+    assert_eq!(
+        markdown::render_page("intro\n\n## The *Guide*", "notes", Some(template)),
+        "<title>The Guide</title><main><p>intro</p>\n<h2 id=\"the-guide\">The <em>Guide</em></h2>\n</main>"
+    );
+    assert_eq!(
+        markdown::render_page("no heading", "notes", Some(template)),
+        "<title>notes</title><main><p>no heading</p>\n</main>"
+    );
+}
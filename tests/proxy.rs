@@ -0,0 +1,312 @@
+use localserver::config::models::{ Balance, HealthCheck, Probe, ProxyCache, Sticky };
+use localserver::server::forwarded::TrustedProxies;
+use localserver::server::proxy::{ self, Balancer, Upstream };
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{ Duration, Instant, UNIX_EPOCH };
+
+#[test]
+fn targets_map_the_route_prefix_onto_their_path() {
+    let plain = Upstream::parse("http://backend").unwrap();
+    assert_eq!(plain.address, "backend:80");
+    assert_eq!(plain.uri("/api/users?page=2", "/api"), "/api/users?page=2");
+
+    let prefixed = Upstream::parse("http://127.0.0.1:8080/v1").unwrap();
+    assert_eq!((prefixed.host.as_str(), prefixed.address.as_str()), ("127.0.0.1:8080", "127.0.0.1:8080"));
+    assert_eq!(prefixed.uri("/api/users?page=2", "/api"), "/v1/users?page=2");
+    assert_eq!(prefixed.uri("/api?page=2", "/api"), "/v1?page=2");
+    assert_eq!(prefixed.uri("/api/users", "/api/"), "/v1/users");
+    assert_eq!(Upstream::parse("http://[::1]:9000/").unwrap().uri("/api/users", "/api"), "/users");
+    assert_eq!(Upstream::parse("http://[::1]").unwrap().address, "[::1]:80");
+
+    assert_eq!(Upstream::parse("https://backend"), None);
+    assert_eq!(Upstream::parse("backend:8080"), None);
+    assert_eq!(Upstream::parse("http:///path"), None);
+}
+
+#[test]
+fn mirrors_get_the_request_as_sent_with_the_target_path() {
+    let bare = Upstream::parse_bare("shadow:9000/copy").unwrap();
+    assert_eq!((bare.address.as_str(), bare.path.as_str()), ("shadow:9000", "/copy"));
+    assert_eq!(Upstream::parse_bare("http://shadow:9000"), Upstream::parse("http://shadow:9000"));
+    assert_eq!(Upstream::parse_bare(":9000 x"), None);
+
+    let raw = b"POST /api/items?x=1 HTTP/1.1\r\nHost: site\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+    assert_eq!(
+        proxy::mirror_request(raw, &bare, "/api"),
+        b"POST /copy/items?x=1 HTTP/1.1\r\nHost: site\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"
+    );
+    let plain = Upstream::parse_bare("shadow:9000").unwrap();
+    assert_eq!(proxy::mirror_request(raw, &plain, "/api"), raw);
+}
+
+#[test]
+fn upstream_requests_describe_the_client() {
+    let upstream = Upstream::parse("http://backend:8080").unwrap();
+    let mut headers = HashMap::new();
+    headers.insert("host".to_string(), "site.test:8443".to_string());
+    headers.insert("forwarded".to_string(), "for=192.0.2.60;proto=https".to_string());
+    headers.insert("x-forwarded-for".to_string(), "192.0.2.60".to_string());
+    let peer: IpAddr = "2001:db8::7".parse().unwrap();
+
+    let plain = String::from_utf8(proxy::request_head("GET", "/", &headers, &upstream, peer, 0, false)).unwrap();
+    assert_eq!(
+        plain,
+        "GET / HTTP/1.0\r\nHost: backend:8080\r\nforwarded: for=192.0.2.60;proto=https\r\nX-Forwarded-For: 192.0.2.60, 2001:db8::7\r\nX-Forwarded-Host: site.test:8443\r\nX-Forwarded-Proto: http\r\nConnection: close\r\n\r\n"
+    );
+
+    // RFC 7239: IPv6 nodes and values with a colon are quoted, earlier hops kept
+    let head = String::from_utf8(proxy::request_head("POST", "/a", &headers, &upstream, peer, 2, true)).unwrap();
+    assert!(
+        head.contains("\r\nForwarded: for=192.0.2.60;proto=https, for=\"[2001:db8::7]\";proto=http;host=\"site.test:8443\"\r\n"),
+        "{}",
+        head
+    );
+    assert!(!head.contains("forwarded: "));
+    assert!(head.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\n"));
+
+    let v4 = String::from_utf8(proxy::request_head("GET", "/", &HashMap::new(), &upstream, "10.1.2.3".parse().unwrap(), 0, true)).unwrap();
+    assert!(v4.contains("\r\nForwarded: for=10.1.2.3;proto=http\r\n"), "{}", v4);
+}
+
+#[test]
+fn forwarded_chains_are_walked_only_from_trusted_peers() {
+    let trusted = TrustedProxies::parse(&["10.0.0.0/8".to_string(), "2001:db8:ff::/48".to_string(), "192.0.2.1".to_string()]).unwrap();
+    let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+    let headers = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    };
+
+    let xff = headers(&[("x-forwarded-for", "203.0.113.9, 198.51.100.4, 10.1.1.1")]);
+    assert_eq!(trusted.client_ip(ip("10.0.0.1"), &xff), ip("198.51.100.4"));
+    assert_eq!(trusted.client_ip(ip("192.0.2.1"), &xff), ip("198.51.100.4"));
+    // Anyone else's headers are ignored, whatever they claim
+    assert_eq!(trusted.client_ip(ip("192.0.2.2"), &xff), ip("192.0.2.2"));
+    assert_eq!(trusted.client_ip(ip("11.0.0.1"), &xff), ip("11.0.0.1"));
+    assert_eq!(trusted.client_ip(ip("10.0.0.1"), &HashMap::new()), ip("10.0.0.1"));
+
+    // An all-trusted chain gives its first hop
+    let internal = headers(&[("x-forwarded-for", "10.9.9.9, 10.1.1.1")]);
+    assert_eq!(trusted.client_ip(ip("10.0.0.1"), &internal), ip("10.9.9.9"));
+    // `Forwarded` wins over `X-Forwarded-For`, with ports and brackets stripped
+    let both = headers(&[
+        ("forwarded", "for=198.51.100.4:5000;proto=https, for=\"[2001:db8:ff::2]:80\""),
+        ("x-forwarded-for", "203.0.113.9"),
+    ]);
+    assert_eq!(trusted.client_ip(ip("2001:db8:ff::1"), &both), ip("198.51.100.4"));
+    let garbage = headers(&[("x-forwarded-for", "unknown, 198.51.100.4:81, not-an-ip")]);
+    assert_eq!(trusted.client_ip(ip("10.0.0.1"), &garbage), ip("198.51.100.4"));
+
+    assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+    assert!(TrustedProxies::parse(&["proxy.local".to_string()]).err().is_some_and(|e| e.contains("trusted_proxies")));
+}
+
+#[test]
+fn websocket_handshakes_keep_their_upgrade_both_ways() {
+    let upstream = Upstream::parse("http://backend:8080").unwrap();
+    let mut headers = HashMap::new();
+    headers.insert("connection".to_string(), "keep-alive, Upgrade".to_string());
+    headers.insert("upgrade".to_string(), "websocket".to_string());
+    headers.insert("sec-websocket-key".to_string(), "dGhlIHNhbXBsZSBub25jZQ==".to_string());
+    headers.insert("sec-websocket-version".to_string(), "13".to_string());
+    let peer: IpAddr = "127.0.0.1".parse().unwrap();
+
+    assert!(proxy::is_upgrade("GET", &headers));
+    assert!(!proxy::is_upgrade("POST", &headers));
+    let head = String::from_utf8(proxy::request_head("GET", "/ws", &headers, &upstream, peer, 0, false)).unwrap();
+    assert!(head.starts_with("GET /ws HTTP/1.1\r\nHost: backend:8080\r\n"), "{}", head);
+    assert!(head.contains("\r\nsec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\nsec-websocket-version: 13\r\n"), "{}", head);
+    assert!(head.ends_with("\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"), "{}", head);
+
+    // Without `Connection: upgrade` it is an ordinary request
+    headers.insert("connection".to_string(), "keep-alive".to_string());
+    assert!(!proxy::is_upgrade("GET", &headers));
+    let head = String::from_utf8(proxy::request_head("GET", "/ws", &headers, &upstream, peer, 0, false)).unwrap();
+    assert!(head.starts_with("GET /ws HTTP/1.0\r\n") && head.ends_with("Connection: close\r\n\r\n"), "{}", head);
+
+    let switching = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+    assert_eq!(
+        String::from_utf8(proxy::upgrade_head(switching, &[], Some("localserver")).unwrap()).unwrap(),
+        "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nServer: localserver\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+    );
+}
+
+#[test]
+fn response_heads_lose_hop_by_hop_headers() {
+    let upstream = b"HTTP/1.0 200 OK\r\nContent-Type: text/html\r\nConnection: close, X-Trace\r\nX-Trace: 1\r\nKeep-Alive: timeout=5\r\nServer: backend\r\nCache-Control: no-store\r\nSet-Cookie: a=1\r\n\r\n";
+    let extra = [
+        ("Cache-Control".to_string(), "max-age=60".to_string()),
+        ("Set-Cookie".to_string(), "b=2".to_string()),
+    ];
+    let head = proxy::response_head(upstream, &extra, Some("localserver")).unwrap();
+    assert_eq!(
+        String::from_utf8(head).unwrap(),
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nSet-Cookie: a=1\r\nCache-Control: max-age=60\r\nSet-Cookie: b=2\r\nServer: localserver\r\nConnection: close\r\n\r\n"
+    );
+
+    // Chunked bodies are relayed as they come, so the framing header stays
+    let chunked = proxy::response_head(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n", &[], None).unwrap();
+    assert_eq!(chunked, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n");
+
+    assert_eq!(proxy::response_head(b"SSH-2.0-OpenSSH\r\n\r\n", &[], None), None);
+    assert_eq!(proxy::head_len(b"HTTP/1.1 204 No Content\r\n\r\nrest"), Some(27));
+    assert_eq!(proxy::head_len(b"HTTP/1.1 204 No Content\r\n"), None);
+}
+
+#[test]
+fn balancers_rotate_prefer_idle_upstreams_and_skip_failed_ones() {
+    let upstreams: Vec<Upstream> = ["http://a:1", "http://b:1", "http://c:1"]
+        .iter()
+        .map(|target| Upstream::parse(target).unwrap())
+        .collect();
+    let now = Instant::now();
+
+    let mut balancer = Balancer::default();
+    let picks: Vec<usize> = (0..4).map(|_| balancer.pick(&upstreams, Balance::RoundRobin, &[], now).unwrap()).collect();
+    assert_eq!(picks, [0, 1, 2, 0]);
+    // A retry leaves out what the request already tried, and gives up after the last
+    assert_eq!(balancer.pick(&upstreams, Balance::RoundRobin, &[1], now), Some(2));
+    assert_eq!(balancer.pick(&upstreams, Balance::RoundRobin, &[0, 1, 2], now), None);
+
+    let mut balancer = Balancer::default();
+    balancer.opened("a:1");
+    balancer.opened("a:1");
+    balancer.opened("b:1");
+    assert_eq!(balancer.pick(&upstreams, Balance::LeastConnections, &[], now), Some(2));
+    balancer.opened("c:1");
+    assert_eq!(balancer.pick(&upstreams, Balance::LeastConnections, &[], now), Some(1));
+    balancer.closed("a:1");
+    balancer.closed("a:1");
+    assert_eq!(balancer.active("a:1"), 0);
+    assert_eq!(balancer.pick(&upstreams, Balance::LeastConnections, &[], now), Some(0));
+
+    let mut balancer = Balancer::default();
+    balancer.failed("a:1", now, Duration::from_secs(10));
+    balancer.failed("b:1", now, Duration::from_secs(10));
+    assert!(balancer.is_down("a:1", now + Duration::from_secs(9)));
+    assert_eq!(balancer.pick(&upstreams, Balance::RoundRobin, &[], now), Some(2));
+    assert_eq!(balancer.pick(&upstreams, Balance::RoundRobin, &[], now), Some(2));
+    // With every one left down, they are tried anyway
+    assert_eq!(balancer.pick(&upstreams, Balance::RoundRobin, &[2], now), Some(0));
+    assert_eq!(balancer.pick(&upstreams, Balance::RoundRobin, &[], now + Duration::from_secs(10)), Some(1));
+}
+
+#[test]
+fn health_checks_take_upstreams_out_and_back_after_their_thresholds() {
+    let upstreams: Vec<Upstream> = ["http://a:1", "http://b:1"]
+        .iter()
+        .map(|target| Upstream::parse(target).unwrap())
+        .collect();
+    let check = HealthCheck {
+        probe: Probe::Get("/healthz".to_string()),
+        interval: Duration::from_secs(5),
+        fails: 2,
+        passes: 3,
+    };
+    let now = Instant::now();
+    let mut balancer = Balancer::default();
+
+    assert_eq!(balancer.probed("a:1", false, &check), None);
+    // A pass in between starts the count again
+    assert_eq!(balancer.probed("a:1", true, &check), None);
+    assert_eq!(balancer.probed("a:1", false, &check), None);
+    assert_eq!(balancer.probed("a:1", false, &check), Some(false));
+    assert!(balancer.is_down("a:1", now));
+    let picks: Vec<usize> = (0..3).map(|_| balancer.pick(&upstreams, Balance::RoundRobin, &[], now).unwrap()).collect();
+    assert_eq!(picks, [1, 1, 1]);
+
+    assert_eq!(balancer.probed("a:1", true, &check), None);
+    assert_eq!(balancer.probed("a:1", true, &check), None);
+    assert_eq!(balancer.probed("a:1", true, &check), Some(true));
+    assert!(!balancer.is_down("a:1", now));
+
+    balancer.probed("b:1", false, &check);
+    balancer.probed("b:1", false, &check);
+    balancer.failed("a:1", now, Duration::from_secs(10));
+    balancer.opened("a:1");
+    let addresses = vec!["a:1".to_string(), "b:1".to_string()];
+    assert_eq!(
+        balancer.report(&addresses, now),
+        "{\"upstreams\":[{\"address\":\"a:1\",\"healthy\":true,\"cooling_off\":true,\"active\":1},{\"address\":\"b:1\",\"healthy\":false,\"cooling_off\":false,\"active\":0}]}"
+    );
+}
+
+#[test]
+fn sticky_clients_keep_to_one_upstream() {
+    let upstreams: Vec<Upstream> = ["http://a:1", "http://b:1", "http://c:1/v1"]
+        .iter()
+        .map(|target| Upstream::parse(target).unwrap())
+        .collect();
+    let client: IpAddr = "192.0.2.7".parse().unwrap();
+
+    let picked = proxy::affinity(Sticky::IpHash, client, None, &upstreams).unwrap();
+    assert!(picked < 3);
+    assert_eq!(proxy::affinity(Sticky::IpHash, client, Some("ignored"), &upstreams), Some(picked));
+    let spread: Vec<usize> = (1..=40u8)
+        .filter_map(|last| proxy::affinity(Sticky::IpHash, IpAddr::from([192, 0, 2, last]), None, &upstreams))
+        .collect();
+    assert!((0..3).all(|idx| spread.contains(&idx)));
+    assert_eq!(proxy::affinity(Sticky::IpHash, client, None, &[]), None);
+
+    // The cookie names an upstream by a hash of its address
+    let id = upstreams[2].id();
+    assert_eq!(id.len(), 16);
+    assert_ne!(id, upstreams[1].id());
+    assert_eq!(Upstream::parse("http://c:1/v2").unwrap().id(), id);
+    assert_eq!(proxy::affinity(Sticky::Cookie, client, Some(&id), &upstreams), Some(2));
+    assert_eq!(proxy::affinity(Sticky::Cookie, client, Some("0123456789abcdef"), &upstreams), None);
+    assert_eq!(proxy::affinity(Sticky::Cookie, client, None, &upstreams), None);
+}
+
+#[test]
+fn cached_answers_live_as_long_as_the_upstream_allows() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let fresh = |head: &str| proxy::freshness(head.as_bytes(), now);
+    assert_eq!(fresh("HTTP/1.1 200 OK\r\nCache-Control: public, max-age=60, s-maxage=\"120\"\r\n\r\n"), Some(Duration::from_secs(120)));
+    assert_eq!(fresh("HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\nExpires: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n"), Some(Duration::from_secs(60)));
+    // Expires counts from the upstream's own Date
+    assert_eq!(
+        fresh("HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nExpires: Sun, 06 Nov 1994 08:59:37 GMT\r\n\r\n"),
+        Some(Duration::from_secs(600))
+    );
+    assert_eq!(fresh("HTTP/1.1 200 OK\r\nExpires: 0\r\n\r\n"), Some(Duration::ZERO));
+    assert_eq!(fresh("HTTP/1.1 200 OK\r\nCache-Control: public\r\n\r\n"), None);
+
+    let cache = ProxyCache {
+        ttl: Duration::from_secs(5),
+        force_ttl: false,
+        max_size: 1024,
+        stale: Duration::from_secs(30),
+        key: vec!["accept-language".to_string()],
+    };
+    let entry = |response: &str, cache: &ProxyCache| proxy::cache_entry(response.as_bytes(), cache, Some("localserver"), now);
+
+    // An answer ended by closing gets its length, and the cache's defaults apply
+    let (copy, fresh, stale) = entry("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nServer: backend\r\n\r\nhello", &cache).unwrap();
+    assert_eq!(
+        String::from_utf8(copy).unwrap(),
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nServer: localserver\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+    );
+    assert_eq!((fresh, stale), (Duration::from_secs(5), Duration::from_secs(30)));
+
+    let (_, fresh, stale) = entry(
+        "HTTP/1.1 200 OK\r\nCache-Control: max-age=60, stale-while-revalidate=10\r\nVary: Accept-Language\r\nContent-Length: 2\r\n\r\nok",
+        &cache
+    ).unwrap();
+    assert_eq!((fresh, stale), (Duration::from_secs(60), Duration::from_secs(10)));
+    let forced = ProxyCache { force_ttl: true, ..cache.clone() };
+    assert_eq!(entry("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\r\nok", &forced).unwrap().1, Duration::from_secs(5));
+
+    for kept_out in [
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\n\r\nok",
+        "HTTP/1.1 200 OK\r\nCache-Control: private, max-age=60\r\n\r\nok",
+        "HTTP/1.1 200 OK\r\nCache-Control: max-age=0\r\n\r\nok",
+        "HTTP/1.1 200 OK\r\nVary: Accept-Encoding\r\n\r\nok",
+        "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\ncut",
+    ] {
+        assert_eq!(entry(kept_out, &cache), None, "{}", kept_out);
+    }
+    let no_default = ProxyCache { ttl: Duration::ZERO, ..cache };
+    assert_eq!(entry("HTTP/1.1 200 OK\r\n\r\nok", &no_default), None);
+}
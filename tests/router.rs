@@ -0,0 +1,207 @@
+use localserver::config::models::Config;
+use localserver::config::{ ConfigBuilder, RouteBuilder, ServerBlockBuilder };
+use localserver::server::router::{ self, RouteDecision, Target };
+use localserver::StatusCode;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+fn config(server: ServerBlockBuilder) -> Config {
+    ConfigBuilder::new().server(server).build().unwrap()
+}
+
+/// The route path `decide` picked, or what it answered instead.
+fn outcome(config: &Config, method: &str, path: &str) -> String {
+    match router::decide(&config.servers[0], method, path, "", &HashMap::new()) {
+        RouteDecision::NotFound => "404".to_string(),
+        RouteDecision::MethodNotAllowed { .. } => "405".to_string(),
+        RouteDecision::Redirect { location, .. } => format!("-> {}", location),
+        RouteDecision::Fixed { response, .. } => format!("{} {}", response.code, response.body),
+        RouteDecision::Serve { route, .. } => route.path.clone(),
+    }
+}
+
+#[test]
+fn the_longest_matching_prefix_wins() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080)
+            .route(RouteBuilder::new("/"))
+            .route(RouteBuilder::new("/api"))
+            .route(RouteBuilder::new("/api/v2"))
+    );
+
+    assert_eq!(outcome(&config, "GET", "/"), "/");
+    assert_eq!(outcome(&config, "GET", "/api"), "/api");
+    assert_eq!(outcome(&config, "GET", "/api/users"), "/api");
+    assert_eq!(outcome(&config, "GET", "/api/v2/users"), "/api/v2");
+    // Prefixes match whole segments only
+    assert_eq!(outcome(&config, "GET", "/apiary"), "/");
+    assert_eq!(outcome(&config, "GET", "/api/v20"), "/api");
+}
+
+#[test]
+fn unmatched_paths_and_methods_are_refused() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080)
+            .route(RouteBuilder::new("/static").methods(["GET", "HEAD"]))
+            .route(RouteBuilder::new("/any"))
+    );
+
+    assert_eq!(outcome(&config, "GET", "/"), "404");
+    assert_eq!(outcome(&config, "HEAD", "/static/a.css"), "/static");
+    assert_eq!(outcome(&config, "POST", "/static/a.css"), "405");
+    // No `methods` allows everything
+    assert_eq!(outcome(&config, "DELETE", "/any/thing"), "/any");
+}
+
+#[test]
+fn fixed_responses_answer_any_method() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080)
+            .route(RouteBuilder::new("/"))
+            .route(RouteBuilder::new("/healthz").methods(["GET"]).fixed_response(StatusCode::OK, "text/plain", "ok"))
+    );
+
+    assert_eq!(outcome(&config, "GET", "/healthz"), "200 ok");
+    assert_eq!(outcome(&config, "POST", "/healthz"), "200 ok");
+    assert_eq!(outcome(&config, "GET", "/healthzz"), "/");
+
+    let redirect = ConfigBuilder::new()
+        .server(
+            ServerBlockBuilder::new("127.0.0.1", 8080).route(
+                RouteBuilder::new("/").fixed_response(StatusCode::FOUND, "text/plain", "")
+            )
+        )
+        .build();
+    assert!(redirect.is_err());
+}
+
+#[test]
+fn redirects_are_decided_before_methods() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080).route(
+            RouteBuilder::new("/old").methods(["GET"]).redirect("/new$request_path", StatusCode::FOUND)
+        )
+    );
+
+    assert_eq!(outcome(&config, "POST", "/old/page"), "-> /new/page");
+    match router::decide(&config.servers[0], "GET", "/old", "", &HashMap::new()) {
+        RouteDecision::Redirect { code, .. } => assert_eq!(code, StatusCode::FOUND),
+        _ => panic!("expected a redirect"),
+    }
+}
+
+#[test]
+fn relative_redirect_targets_resolve_against_the_route() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080)
+            .route(RouteBuilder::new("/docs").redirect("v2/intro?from=docs", StatusCode::PERMANENT_REDIRECT))
+            .route(RouteBuilder::new("/guide/old").redirect("../new/", StatusCode::TEMPORARY_REDIRECT))
+            .route(RouteBuilder::new("/wiki").redirect("pages$request_path", StatusCode::FOUND))
+            .route(RouteBuilder::new("/away").redirect("https://example.com/x", StatusCode::MOVED_PERMANENTLY))
+            .route(RouteBuilder::new("/cdn").redirect("//cdn.example.com/lib.js", StatusCode::FOUND))
+    );
+
+    // The same target whatever depth was requested
+    assert_eq!(outcome(&config, "GET", "/docs"), "-> /docs/v2/intro?from=docs");
+    assert_eq!(outcome(&config, "GET", "/docs/a/b/c"), "-> /docs/v2/intro?from=docs");
+    assert_eq!(outcome(&config, "GET", "/guide/old/page"), "-> /guide/new/");
+    assert_eq!(outcome(&config, "GET", "/wiki/Rust"), "-> /wiki/pages/Rust");
+    assert_eq!(outcome(&config, "GET", "/away"), "-> https://example.com/x");
+    assert_eq!(outcome(&config, "GET", "/cdn"), "-> //cdn.example.com/lib.js");
+}
+
+#[test]
+fn paths_resolve_under_the_route_root() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080)
+            .route(RouteBuilder::new("/docs").root("./www/html"))
+            .route(RouteBuilder::new("/ui").root("vfs:assets"))
+    );
+    let routes = &config.servers[0].routes;
+    let docs = routes.iter().find(|r| r.path == "/docs").unwrap();
+    let ui = routes.iter().find(|r| r.path == "/ui").unwrap();
+
+    assert_eq!(router::resolve(docs, "/docs/guide/intro.html"), Target::Disk(PathBuf::from("./www/html/guide/intro.html")));
+    assert_eq!(router::resolve(docs, "/docs"), Target::Disk(PathBuf::from("./www/html/")));
+    assert_eq!(router::resolve(ui, "/ui/app.js"), Target::Mounted { name: "assets", path: PathBuf::from("app.js") });
+}
+
+#[test]
+fn canary_requests_resolve_under_the_canary_root() {
+    let config = config(
+        ServerBlockBuilder::new("127.0.0.1", 8080)
+            .route(RouteBuilder::new("/").root("./www").canary_header("X-Canary", Some("1"), "./www-next"))
+            .route(RouteBuilder::new("/beta").root("./www").canary_cookie("beta", None, "./www-beta"))
+            .route(RouteBuilder::new("/api").root("./www").canary_header_proxy("X-Canary", None, "http://127.0.0.1:9000"))
+    );
+    let target = |path: &str, headers: &[(&str, &str)]| {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        match router::decide(&config.servers[0], "GET", path, "", &headers) {
+            RouteDecision::Serve { target, .. } => target,
+            _ => panic!("expected {} to be served", path),
+        }
+    };
+
+    assert_eq!(target("/a.html", &[]), Target::Disk(PathBuf::from("./www/a.html")));
+    assert_eq!(target("/a.html", &[("x-canary", "1")]), Target::Disk(PathBuf::from("./www-next/a.html")));
+    assert_eq!(target("/a.html", &[("x-canary", "0")]), Target::Disk(PathBuf::from("./www/a.html")));
+    // Without a configured value, any non-empty cookie value selects the canary
+    assert_eq!(target("/beta/b.js", &[("cookie", "a=1; beta=yes")]), Target::Disk(PathBuf::from("./www-beta/b.js")));
+    assert_eq!(target("/beta/b.js", &[("cookie", "alphabeta=yes")]), Target::Disk(PathBuf::from("./www/b.js")));
+
+    // A canary sent upstream has no root of its own
+    let route = router::find_route(&config.servers[0], "/api/users").unwrap();
+    let headers: HashMap<String, String> = [("x-canary".to_string(), "1".to_string())].into();
+    let canary = router::canary(route, &headers).unwrap();
+    assert_eq!(canary.proxy_pass, ["http://127.0.0.1:9000"]);
+    assert_eq!(router::canary_root(route, &headers), None);
+    assert_eq!(target("/api/users", &[("x-canary", "1")]), Target::Disk(PathBuf::from("./www/users")));
+    assert!(router::canary(route, &HashMap::new()).is_none());
+}
+
+#[test]
+fn exact_server_names_beat_wildcards() {
+    let config = ConfigBuilder::new()
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).route(RouteBuilder::new("/")))
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).server_name("*.example.com").route(RouteBuilder::new("/")))
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).server_name("*.api.example.com").route(RouteBuilder::new("/")))
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).server_name("www.example.com").route(RouteBuilder::new("/")))
+        .build()
+        .unwrap();
+
+    assert_eq!(router::select_server(&config, 0, Some("www.example.com:8080")), 3);
+    assert_eq!(router::select_server(&config, 0, Some("v1.api.example.com")), 2);
+    assert_eq!(router::select_server(&config, 0, Some("blog.example.com")), 1);
+    assert_eq!(router::select_server(&config, 0, Some("example.com")), 0);
+    assert_eq!(router::select_server(&config, 0, None), 0);
+}
+
+#[test]
+fn trailing_wildcards_come_after_leading_ones() {
+    let config = ConfigBuilder::new()
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).route(RouteBuilder::new("/")))
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).server_name("www.example.*").route(RouteBuilder::new("/")))
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).server_name("www.example.co.*").route(RouteBuilder::new("/")))
+        .server(
+            ServerBlockBuilder::new("127.0.0.1", 8080)
+                .server_name("*.example.org")
+                .server_name("shop.test")
+                .route(RouteBuilder::new("/"))
+        )
+        .build()
+        .unwrap();
+
+    assert_eq!(router::select_server(&config, 0, Some("www.example.net")), 1);
+    assert_eq!(router::select_server(&config, 0, Some("WWW.Example.co.uk")), 2);
+    assert_eq!(router::select_server(&config, 0, Some("www.example.org")), 3);
+    assert_eq!(router::select_server(&config, 0, Some("shop.test:8080")), 3);
+    assert_eq!(router::select_server(&config, 0, Some("www.example")), 0);
+
+    let misplaced = ConfigBuilder::new()
+        .server(ServerBlockBuilder::new("127.0.0.1", 8080).server_name("www.*.com").route(RouteBuilder::new("/")))
+        .build();
+    assert!(misplaced.is_err());
+}
@@ -0,0 +1,47 @@
+use localserver::server::trace::{ self, Direction };
+
+const REQUEST: &[u8] = b"POST /upload HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic c2VjcmV0\r\n\r\nhello world";
+
+#[test]
+fn heads_are_logged_line_by_line_with_credentials_masked() {
+    let lines = trace::describe(7, Direction::Received, REQUEST, 0..REQUEST.len(), 0);
+    assert_eq!(
+        lines,
+        [
+            "[Trace] #7 <- POST /upload HTTP/1.1\\r\\n",
+            "[Trace] #7 <- Host: localhost\\r\\n",
+            "[Trace] #7 <- Authorization: [redacted]\\r\\n",
+            "[Trace] #7 <- \\r\\n",
+            "[Trace] #7 <- body (11 more bytes not shown)",
+        ]
+    );
+}
+
+#[test]
+fn a_header_split_across_reads_stays_masked() {
+    let split = REQUEST.windows(5).position(|w| w == b"Basic").unwrap() + 2;
+    let first = trace::describe(1, Direction::Received, &REQUEST[..split], 0..split, 0);
+    let second = trace::describe(1, Direction::Received, REQUEST, split..REQUEST.len(), 0);
+    assert_eq!(first.last().unwrap(), "[Trace] #1 <- Authorization: [redacted]");
+    assert_eq!(second[0], "[Trace] #1 <- [redacted]\\r\\n");
+    assert!(!first.iter().chain(&second).any(|line| line.contains("c2VjcmV0")));
+}
+
+#[test]
+fn bodies_are_shown_up_to_the_limit() {
+    let head_len = REQUEST.len() - "hello world".len();
+    // A later read continues the body where the earlier one stopped
+    let lines = trace::describe(2, Direction::Received, REQUEST, head_len + 6..REQUEST.len(), 8);
+    assert_eq!(lines, ["[Trace] #2 <- body 0006  wo", "[Trace] #2 <- body (3 more bytes not shown)"]);
+
+    let response = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0";
+    let lines = trace::describe(3, Direction::Sent, response, 0..response.len(), 64);
+    assert_eq!(lines[0], "[Trace] #3 -> HTTP/1.1 200 OK\\r\\n");
+    assert_eq!(
+        &lines[3..],
+        [
+            "[Trace] #3 -> body 0000  89 50 4e 47 0d 0a 1a 0a 00 00 00 0d 49 48 44 52  |.PNG........IHDR|",
+            "[Trace] #3 -> body 0010  00 00                                            |..|",
+        ]
+    );
+}